@@ -0,0 +1,10 @@
+/* Memory layout for the bootloader, see oxidane-boot for the full flash map */
+MEMORY
+{
+  FLASH : ORIGIN = 0x08000000, LENGTH = 8K
+  RAM : ORIGIN = 0x20000000, LENGTH = 16K
+}
+
+/* The bootloader must leave the active slot untouched */
+ASSERT(LOADADDR(.data) + SIZEOF(.data) <= ORIGIN(FLASH) + LENGTH(FLASH),
+       "the bootloader does not fit in its region of oxidane-boot");
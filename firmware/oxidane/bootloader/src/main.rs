@@ -0,0 +1,58 @@
+#![no_main]
+#![no_std]
+
+extern crate cortex_m;
+#[macro_use]
+extern crate cortex_m_rt as rt;
+extern crate oxidane_boot as boot;
+extern crate panic_abort;
+extern crate stm32l151_hal as hal;
+
+use core::ptr;
+
+use cortex_m::peripheral::SCB;
+use hal::prelude::*;
+use hal::stm32l151;
+use rt::ExceptionFrame;
+
+entry!(main);
+
+fn main() -> ! {
+    let p = stm32l151::Peripherals::take().unwrap();
+    let mut cp = cortex_m::Peripherals::take().unwrap();
+
+    let mut flash = p.FLASH.constrain();
+
+    {
+        let mut memory = flash.pecr.unlock_program();
+
+        // An interrupted swap is resumed at the next reset
+        if boot::boot(&mut memory).is_err() {
+            cp.SCB.system_reset();
+        }
+    }
+
+    // NOTE(unsafe) the active slot holds a valid image, either the previous or the updated one
+    unsafe { start(&mut cp.SCB, boot::ACTIVE.start) }
+}
+
+/// Starts the application whose vector table is at `address`
+unsafe fn start(scb: &mut SCB, address: u32) -> ! {
+    let sp = ptr::read_volatile(address as *const u32);
+    let reset = ptr::read_volatile((address + 4) as *const extern "C" fn() -> !);
+
+    scb.vtor.write(address);
+    cortex_m::register::msp::write(sp);
+
+    reset()
+}
+
+exception!(*, default_handler);
+
+fn default_handler(_irqn: i16) {}
+
+exception!(HardFault, hard_fault);
+
+fn hard_fault(_ef: &ExceptionFrame) -> ! {
+    loop {}
+}
@@ -0,0 +1,78 @@
+//! Embeds the public half of the firmware update signing key and the firmware version
+//!
+//! `OXIDANE_OTA_KEY` names the file holding the raw 32-byte ed25519 public key of the release
+//! signing key. There is deliberately no default: a key whose secret half is known would let
+//! anyone sign images that the nodes accept.
+//!
+//! The package version is encoded as `major << 16 | minor << 8 | patch`, the number that the
+//! update manifests of this firmware carry, see `src/version.rs`.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Public key of the first RFC 8032 test vector, whose secret key is published
+const TEST_KEY: [u8; 32] = [
+    0xD7, 0x5A, 0x98, 0x01, 0x82, 0xB1, 0x0A, 0xB7, 0xD5, 0x4B, 0xFE, 0xD3, 0xC9, 0x64, 0x07, 0x3A,
+    0x0E, 0xE1, 0x72, 0xF3, 0xDA, 0xA6, 0x23, 0x25, 0xAF, 0x02, 0x1A, 0x68, 0xF7, 0x07, 0x51, 0x1A,
+];
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=OXIDANE_OTA_KEY");
+
+    let path = match env::var_os("OXIDANE_OTA_KEY") {
+        Some(path) => PathBuf::from(path),
+        None => panic!(
+            "OXIDANE_OTA_KEY must name the file holding the 32-byte ed25519 public key of the \
+             release signing key"
+        ),
+    };
+
+    // Relative paths start from the package root, not from `src/ota_key.rs`
+    let path = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap()).join(path);
+
+    let key = fs::read(&path)
+        .unwrap_or_else(|e| panic!("cannot read the OTA key {}: {}", path.display(), e));
+
+    assert!(
+        key.len() == TEST_KEY.len(),
+        "invalid OTA key {}: {} bytes instead of {}",
+        path.display(),
+        key.len(),
+        TEST_KEY.len()
+    );
+    assert!(
+        key[..] != TEST_KEY[..],
+        "invalid OTA key {}: this is the RFC 8032 test key, its secret half is public",
+        path.display()
+    );
+
+    println!("cargo:rerun-if-changed={}", path.display());
+    println!("cargo:rustc-env=OXIDANE_OTA_KEY_PATH={}", path.display());
+
+    let version = |part: &str| -> u32 {
+        env::var(format!("CARGO_PKG_VERSION_{}", part))
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+    let (major, minor, patch) = (version("MAJOR"), version("MINOR"), version("PATCH"));
+    assert!(
+        major <= 0xFFFF && minor <= 0xFF && patch <= 0xFF,
+        "invalid version {}.{}.{}: does not fit the update manifest",
+        major,
+        minor,
+        patch
+    );
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(
+        out.join("version.rs"),
+        format!(
+            "/// Firmware version, `major << 16 | minor << 8 | patch`\n\
+             pub const VERSION: u32 = {:#08X};\n",
+            major << 16 | minor << 8 | patch
+        ),
+    ).unwrap();
+}
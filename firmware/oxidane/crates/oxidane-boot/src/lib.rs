@@ -0,0 +1,312 @@
+//! Firmware images and boot management
+//!
+//! The program memory is split in fixed regions:
+//!
+//! | Region        | Start         | Size    |
+//! |---------------|---------------|---------|
+//! | Bootloader    | `0x0800_0000` | 8 KiB   |
+//! | Active slot   | `0x0800_2000` | 26 KiB  |
+//! | Staging slot  | `0x0800_8800` | 26 KiB  |
+//! | Swap scratch  | `0x0800_F000` | 256 B   |
+//! | Boot state    | `0x0800_F100` | 256 B   |
+//! | Swap progress | `0x0800_F200` | 2.5 KiB |
+//!
+//! The application always runs from the active slot. A firmware update is written to the staging
+//! slot and verified by the application (see `update`), then the bootloader swaps the two slots at
+//! the next reset and boots the new image once. The new image must `confirm` itself during that
+//! trial boot: if it resets before doing so, the bootloader swaps the slots back, restoring the
+//! previous image.
+//!
+//! Every step of the process is recorded in flash as it completes, so a swap interrupted by a
+//! reset or a power loss is resumed by the bootloader instead of leaving a broken image behind.
+
+#![deny(missing_docs)]
+#![deny(warnings)]
+#![no_std]
+
+#[cfg(feature = "update")]
+extern crate ed25519_dalek;
+#[cfg(feature = "update")]
+extern crate oxidane_proto as proto;
+#[cfg(feature = "update")]
+extern crate sha2;
+#[cfg(feature = "stm32l151")]
+extern crate stm32l151_hal as hal;
+
+#[cfg(test)]
+mod mock;
+pub mod state;
+#[cfg(feature = "stm32l151")]
+mod stm32l151;
+mod swap;
+#[cfg(feature = "update")]
+pub mod update;
+
+use state::{BootState, Step};
+
+/// Size of a program memory page, the smallest erasable unit
+pub const PAGE_SIZE: u32 = 256;
+
+/// Value of erased program memory
+pub const ERASED: u32 = 0;
+
+/// Bootloader
+pub const BOOTLOADER: Region = Region {
+    start: 0x0800_0000,
+    len: 0x2000,
+};
+
+/// Slot the application runs from
+pub const ACTIVE: Region = Region {
+    start: 0x0800_2000,
+    len: 0x6800,
+};
+
+/// Slot receiving firmware updates
+pub const STAGING: Region = Region {
+    start: 0x0800_8800,
+    len: 0x6800,
+};
+
+/// Temporary copy of the page being swapped
+pub const SCRATCH: Region = Region {
+    start: 0x0800_F000,
+    len: PAGE_SIZE,
+};
+
+/// Update progress record
+pub const STATE: Region = Region {
+    start: 0x0800_F100,
+    len: PAGE_SIZE,
+};
+
+/// Swap progress records, one half for installing an update and one for reverting it
+pub const PROGRESS: Region = Region {
+    start: 0x0800_F200,
+    len: 0xA00,
+};
+
+/// Erasable and programmable memory holding the firmware images
+pub trait Flash {
+    /// Flash error
+    type Error;
+
+    /// Erases the `PAGE_SIZE` bytes page starting at `address`
+    fn erase_page(&mut self, address: u32) -> Result<(), Self::Error>;
+
+    /// Programs the erased word at `address`
+    fn program_word(&mut self, address: u32, word: u32) -> Result<(), Self::Error>;
+
+    /// Reads the word at `address`
+    fn read_word(&self, address: u32) -> u32;
+}
+
+/// A contiguous range of program memory
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    /// Address of the first byte
+    pub start: u32,
+    /// Size in bytes
+    pub len: u32,
+}
+
+impl Region {
+    /// Returns the address following the last byte
+    pub fn end(&self) -> u32 {
+        self.start + self.len
+    }
+
+    /// Returns the number of pages in the region
+    pub fn pages(&self) -> u32 {
+        self.len / PAGE_SIZE
+    }
+}
+
+/// Installs or reverts an update as needed, to be called by the bootloader before starting the
+/// application in the active slot
+///
+/// If this function fails the slots may be in an intermediate state: it must be called again,
+/// typically after a reset, before starting the application.
+pub fn boot<F>(flash: &mut F) -> Result<(), F::Error>
+where
+    F: Flash,
+{
+    let state = BootState::read(flash);
+
+    if state.trial {
+        if !state.confirmed && !state.reverted {
+            // The new image reset without confirming itself
+            swap::swap(flash, swap::Direction::Revert)?;
+            state::mark(flash, Step::Reverted)?;
+        }
+
+        return Ok(());
+    }
+
+    if state.pending {
+        if !state.swapped {
+            swap::swap(flash, swap::Direction::Install)?;
+            state::mark(flash, Step::Swapped)?;
+        }
+
+        // The new image is about to run for the first time
+        state::mark(flash, Step::Trial)?;
+    }
+
+    Ok(())
+}
+
+/// Confirms the running image, to be called by the application once it knows it works properly
+///
+/// Returns `true` if the running image has just been confirmed, `false` if there was nothing to
+/// confirm.
+pub fn confirm<F>(flash: &mut F) -> Result<bool, F::Error>
+where
+    F: Flash,
+{
+    let state = BootState::read(flash);
+
+    if state.trial && !state.confirmed && !state.reverted {
+        state::mark(flash, Step::Confirmed)?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::MockFlash;
+
+    const OLD: u32 = 1;
+    const NEW: u32 = 2;
+
+    // Word `i` of image `image`, some words are left erased
+    fn word(image: u32, i: u32) -> u32 {
+        if i % 7 == 3 {
+            ERASED
+        } else {
+            image << 24 | i
+        }
+    }
+
+    fn holds(flash: &MockFlash, slot: Region, image: u32) -> bool {
+        (0..slot.len / 4).all(|i| flash.read_word(slot.start + 4 * i) == word(image, i))
+    }
+
+    /// The old image is running, the new one is verified and waiting in the staging slot
+    fn pending() -> MockFlash {
+        let mut flash = MockFlash::new();
+
+        for i in 0..ACTIVE.len / 4 {
+            flash.write(ACTIVE.start + 4 * i, word(OLD, i));
+            flash.write(STAGING.start + 4 * i, word(NEW, i));
+        }
+        state::mark(&mut flash, Step::Pending).unwrap();
+
+        flash
+    }
+
+    /// Boots, losing power every `interval` flash operations until the boot completes
+    ///
+    /// Copying a page takes up to 65 operations, the swap makes no progress with less.
+    fn boot_with_power_losses(flash: &mut MockFlash, interval: u32) -> u32 {
+        let mut power_losses = 0;
+
+        flash.lose_power_after(interval);
+        while boot(flash).is_err() {
+            power_losses += 1;
+            flash.lose_power_after(interval);
+        }
+
+        power_losses
+    }
+
+    #[test]
+    fn nothing_to_do() {
+        let mut flash = MockFlash::new();
+
+        boot(&mut flash).unwrap();
+        let state = BootState::read(&flash);
+        assert!(!state.pending && !state.trial);
+        assert!(state.can_stage());
+
+        assert_eq!(confirm(&mut flash), Ok(false));
+    }
+
+    #[test]
+    fn install_and_confirm() {
+        let mut flash = pending();
+        assert!(BootState::read(&flash).can_stage());
+
+        boot(&mut flash).unwrap();
+        assert!(holds(&flash, ACTIVE, NEW));
+        assert!(holds(&flash, STAGING, OLD));
+
+        let state = BootState::read(&flash);
+        assert!(state.swapped && state.trial && !state.confirmed);
+        // The staging slot holds the fallback image
+        assert!(!state.can_stage());
+
+        assert_eq!(confirm(&mut flash), Ok(true));
+        assert_eq!(confirm(&mut flash), Ok(false));
+        assert!(BootState::read(&flash).can_stage());
+
+        // Confirmed images stay
+        boot(&mut flash).unwrap();
+        assert!(holds(&flash, ACTIVE, NEW));
+        assert!(!BootState::read(&flash).reverted);
+    }
+
+    #[test]
+    fn rollback() {
+        let mut flash = pending();
+
+        boot(&mut flash).unwrap();
+        assert!(holds(&flash, ACTIVE, NEW));
+
+        // The new image reset without confirming itself
+        boot(&mut flash).unwrap();
+        assert!(holds(&flash, ACTIVE, OLD));
+        assert!(holds(&flash, STAGING, NEW));
+
+        let state = BootState::read(&flash);
+        assert!(state.reverted && !state.confirmed);
+        assert!(state.can_stage());
+
+        // Too late to confirm, and the old image keeps running
+        assert_eq!(confirm(&mut flash), Ok(false));
+        boot(&mut flash).unwrap();
+        assert!(holds(&flash, ACTIVE, OLD));
+    }
+
+    #[test]
+    fn interrupted_install() {
+        for &interval in &[65, 66, 100, 1000, 5000] {
+            let mut flash = pending();
+
+            let power_losses = boot_with_power_losses(&mut flash, interval);
+            assert!(power_losses > 0);
+
+            assert!(holds(&flash, ACTIVE, NEW), "interval {}", interval);
+            assert!(holds(&flash, STAGING, OLD), "interval {}", interval);
+            assert!(BootState::read(&flash).trial);
+        }
+    }
+
+    #[test]
+    fn interrupted_rollback() {
+        for &interval in &[65, 100, 5000] {
+            let mut flash = pending();
+            boot(&mut flash).unwrap();
+
+            let power_losses = boot_with_power_losses(&mut flash, interval);
+            assert!(power_losses > 0);
+
+            assert!(holds(&flash, ACTIVE, OLD), "interval {}", interval);
+            assert!(holds(&flash, STAGING, NEW), "interval {}", interval);
+            assert!(BootState::read(&flash).reverted);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! In-memory program memory for the tests, with power losses on demand
+
+use {Flash, ERASED, PAGE_SIZE};
+
+const BASE: u32 = 0x0800_0000;
+const WORDS: usize = 0x1_0000 / 4;
+
+/// Error of an operation interrupted by a power loss
+#[derive(Debug, PartialEq)]
+pub struct PowerLoss;
+
+/// 64 KiB of program memory
+pub struct MockFlash {
+    words: [u32; WORDS],
+    // Operations left before the next power loss, if any
+    power_loss: Option<u32>,
+}
+
+impl MockFlash {
+    /// Creates an erased memory
+    pub fn new() -> Self {
+        MockFlash {
+            words: [ERASED; WORDS],
+            power_loss: None,
+        }
+    }
+
+    /// Makes the erase or program operation following the next `operations` ones fail
+    pub fn lose_power_after(&mut self, operations: u32) {
+        self.power_loss = Some(operations);
+    }
+
+    /// Writes `word` at `address`, as a debugger would
+    pub fn write(&mut self, address: u32, word: u32) {
+        self.words[index(address)] = word;
+    }
+
+    fn operation(&mut self) -> Result<(), PowerLoss> {
+        match self.power_loss {
+            Some(0) => {
+                self.power_loss = None;
+                Err(PowerLoss)
+            }
+            Some(ref mut left) => {
+                *left -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Flash for MockFlash {
+    type Error = PowerLoss;
+
+    fn erase_page(&mut self, address: u32) -> Result<(), PowerLoss> {
+        assert!(address.is_multiple_of(PAGE_SIZE), "invalid page address");
+        self.operation()?;
+
+        let start = index(address);
+        for word in &mut self.words[start..start + PAGE_SIZE as usize / 4] {
+            *word = ERASED;
+        }
+
+        Ok(())
+    }
+
+    fn program_word(&mut self, address: u32, word: u32) -> Result<(), PowerLoss> {
+        assert!(
+            self.read_word(address) == ERASED,
+            "invalid program of a programmed word at {:#010X}",
+            address
+        );
+        self.operation()?;
+
+        self.words[index(address)] = word;
+
+        Ok(())
+    }
+
+    fn read_word(&self, address: u32) -> u32 {
+        self.words[index(address)]
+    }
+}
+
+fn index(address: u32) -> usize {
+    assert!(address.is_multiple_of(4), "invalid word address");
+
+    (address - BASE) as usize / 4
+}
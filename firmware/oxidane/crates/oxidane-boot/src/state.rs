@@ -0,0 +1,89 @@
+//! Update progress record
+//!
+//! The record is a sequence of words in the `STATE` page, one per `Step`: a step has been reached
+//! when its word holds `MARK`. Steps are only ever added, so the record never needs to be erased
+//! while an update is in progress.
+
+use {Flash, STATE};
+#[cfg(feature = "update")]
+use {PAGE_SIZE, PROGRESS};
+
+/// Value of a reached step
+pub(crate) const MARK: u32 = 0x4F58_4944;
+
+/// Update steps, in order
+#[derive(Clone, Copy)]
+pub(crate) enum Step {
+    /// A verified image is waiting in the staging slot
+    Pending = 0,
+    /// The new image has been swapped into the active slot
+    Swapped = 1,
+    /// The new image has been started
+    Trial = 2,
+    /// The new image confirmed itself
+    Confirmed = 3,
+    /// The previous image has been restored
+    Reverted = 4,
+}
+
+/// Progress of the last update
+#[derive(Debug, Clone, Copy)]
+pub struct BootState {
+    /// A verified image is waiting in the staging slot
+    pub pending: bool,
+    /// The new image has been swapped into the active slot
+    pub swapped: bool,
+    /// The new image has been started
+    pub trial: bool,
+    /// The new image confirmed itself
+    pub confirmed: bool,
+    /// The previous image has been restored
+    pub reverted: bool,
+}
+
+impl BootState {
+    /// Reads the progress of the last update
+    pub fn read<F>(flash: &F) -> Self
+    where
+        F: Flash,
+    {
+        let reached = |step: Step| flash.read_word(STATE.start + 4 * step as u32) == MARK;
+
+        BootState {
+            pending: reached(Step::Pending),
+            swapped: reached(Step::Swapped),
+            trial: reached(Step::Trial),
+            confirmed: reached(Step::Confirmed),
+            reverted: reached(Step::Reverted),
+        }
+    }
+
+    /// Returns `true` if a new image can be written to the staging slot
+    ///
+    /// The staging slot holds the fallback image until the running one confirms itself.
+    pub fn can_stage(&self) -> bool {
+        !self.swapped || self.confirmed || self.reverted
+    }
+}
+
+/// Records that `step` has been reached
+pub(crate) fn mark<F>(flash: &mut F, step: Step) -> Result<(), F::Error>
+where
+    F: Flash,
+{
+    flash.program_word(STATE.start + 4 * step as u32, MARK)
+}
+
+/// Forgets the last update, making room for a new one
+#[cfg(feature = "update")]
+pub(crate) fn clear<F>(flash: &mut F) -> Result<(), F::Error>
+where
+    F: Flash,
+{
+    // Progress records first: a stale swap progress must never outlive its state record
+    for page in 0..PROGRESS.pages() {
+        flash.erase_page(PROGRESS.start + page * PAGE_SIZE)?;
+    }
+
+    flash.erase_page(STATE.start)
+}
@@ -0,0 +1,21 @@
+//! `Flash` implementation for the STM32L151 program memory
+
+use hal::flash::{Error, ProgramMemory};
+
+use Flash;
+
+impl<'a> Flash for ProgramMemory<'a> {
+    type Error = Error;
+
+    fn erase_page(&mut self, address: u32) -> Result<(), Error> {
+        ProgramMemory::erase_page(self, address)
+    }
+
+    fn program_word(&mut self, address: u32, word: u32) -> Result<(), Error> {
+        ProgramMemory::program_word(self, address, word)
+    }
+
+    fn read_word(&self, address: u32) -> u32 {
+        ProgramMemory::read_word(self, address)
+    }
+}
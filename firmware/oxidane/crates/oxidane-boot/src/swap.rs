@@ -0,0 +1,70 @@
+//! Resumable slot swap
+//!
+//! Each page is swapped in three steps through the `SCRATCH` page; every completed step is
+//! recorded with a `MARK` word in the progress record, so an interrupted swap restarts from the
+//! first step that did not complete.
+
+use state::MARK;
+use {Flash, ACTIVE, ERASED, PAGE_SIZE, PROGRESS, SCRATCH, STAGING};
+
+// Steps per page
+const STEPS: u32 = 3;
+
+#[derive(Clone, Copy)]
+pub(crate) enum Direction {
+    /// Moves the update into the active slot
+    Install = 0,
+    /// Moves the previous image back into the active slot
+    Revert = 1,
+}
+
+/// Swaps the contents of the active and staging slots
+pub(crate) fn swap<F>(flash: &mut F, direction: Direction) -> Result<(), F::Error>
+where
+    F: Flash,
+{
+    let progress = PROGRESS.start + direction as u32 * PROGRESS.len / 2;
+
+    for page in 0..ACTIVE.pages() {
+        let active = ACTIVE.start + page * PAGE_SIZE;
+        let staging = STAGING.start + page * PAGE_SIZE;
+        let steps = progress + 4 * STEPS * page;
+
+        // 1. scratch <- active
+        if flash.read_word(steps) != MARK {
+            copy_page(flash, active, SCRATCH.start)?;
+            flash.program_word(steps, MARK)?;
+        }
+
+        // 2. active <- staging
+        if flash.read_word(steps + 4) != MARK {
+            copy_page(flash, staging, active)?;
+            flash.program_word(steps + 4, MARK)?;
+        }
+
+        // 3. staging <- scratch
+        if flash.read_word(steps + 8) != MARK {
+            copy_page(flash, SCRATCH.start, staging)?;
+            flash.program_word(steps + 8, MARK)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn copy_page<F>(flash: &mut F, from: u32, to: u32) -> Result<(), F::Error>
+where
+    F: Flash,
+{
+    flash.erase_page(to)?;
+
+    for offset in (0..PAGE_SIZE).step_by(4) {
+        let word = flash.read_word(from + offset);
+
+        if word != ERASED {
+            flash.program_word(to + offset, word)?;
+        }
+    }
+
+    Ok(())
+}
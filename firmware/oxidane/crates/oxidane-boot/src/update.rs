@@ -0,0 +1,410 @@
+//! Reception of firmware updates into the staging slot
+//!
+//! `Updater` runs in the application and handles the `proto::ota` requests sent by the gateway.
+//! The image is accepted only if the manifest is signed by the release key, its version is greater
+//! than the running one and the staged image matches the manifest digest; the bootloader then
+//! installs it at the next reset.
+
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use proto::ota::{ErrorCode, Manifest, Request, State, Status};
+use sha2::{Digest, Sha256};
+
+use state::{self, BootState, Step};
+use {Flash, ERASED, PAGE_SIZE, STAGING};
+
+/// Firmware update receiver
+pub struct Updater {
+    public_key: [u8; 32],
+    version: u32,
+    manifest: Option<Manifest>,
+    state: State,
+    offset: u32,
+}
+
+impl Updater {
+    /// Creates a new updater accepting images signed with the ed25519 `public_key`, whose version
+    /// is greater than the `version` of the running image
+    pub fn new(public_key: [u8; 32], version: u32) -> Self {
+        Updater {
+            public_key,
+            version,
+            manifest: None,
+            state: State::Idle,
+            offset: 0,
+        }
+    }
+
+    /// Handles an update request, returning the status to send back to the gateway
+    pub fn handle<F>(&mut self, flash: &mut F, request: &Request) -> Status
+    where
+        F: Flash,
+    {
+        let result = match *request {
+            Request::Manifest(ref manifest) => self.start(flash, manifest),
+            Request::Chunk { offset, data } => self.write(flash, offset, data),
+            Request::Finish => self.finish(flash),
+            Request::Abort => {
+                self.manifest = None;
+                self.state = State::Idle;
+                self.offset = 0;
+                Ok(())
+            }
+        };
+
+        let error = result.err();
+        if error.is_some() {
+            self.state = State::Failed;
+        }
+
+        Status {
+            state: self.state,
+            offset: self.offset,
+            error,
+        }
+    }
+
+    fn start<F>(&mut self, flash: &mut F, manifest: &Manifest) -> Result<(), ErrorCode>
+    where
+        F: Flash,
+    {
+        self.manifest = None;
+        self.offset = 0;
+
+        if !BootState::read(flash).can_stage() {
+            return Err(ErrorCode::Busy);
+        }
+
+        if manifest.image_len == 0 || manifest.image_len > STAGING.len {
+            return Err(ErrorCode::TooLarge);
+        }
+
+        let public_key =
+            PublicKey::from_bytes(&self.public_key).map_err(|_| ErrorCode::BadSignature)?;
+        let signature = Signature::from(manifest.signature);
+        public_key
+            .verify(&manifest.signed(), &signature)
+            .map_err(|_| ErrorCode::BadSignature)?;
+
+        // Older images may be signed too, but they could reintroduce fixed bugs
+        if manifest.version <= self.version {
+            return Err(ErrorCode::Downgrade);
+        }
+
+        // The staging slot is about to be overwritten, forget the previous update
+        state::clear(flash).map_err(|_| ErrorCode::Flash)?;
+
+        self.manifest = Some(*manifest);
+        self.state = State::Receiving;
+
+        Ok(())
+    }
+
+    fn write<F>(&mut self, flash: &mut F, offset: u32, data: &[u8]) -> Result<(), ErrorCode>
+    where
+        F: Flash,
+    {
+        let image_len = match (self.state, self.manifest) {
+            (State::Receiving, Some(ref manifest)) => manifest.image_len,
+            _ => return Err(ErrorCode::Malformed),
+        };
+
+        // Out of order chunk, the status tells the gateway where to resume from
+        if offset != self.offset {
+            return Ok(());
+        }
+
+        let end = offset + data.len() as u32;
+        if end > image_len {
+            return Err(ErrorCode::TooLarge);
+        }
+
+        // Words can only be programmed once, so only the last chunk may end mid-word
+        if !data.len().is_multiple_of(4) && end != image_len {
+            return Err(ErrorCode::Malformed);
+        }
+
+        for (i, bytes) in data.chunks(4).enumerate() {
+            let address = STAGING.start + offset + 4 * i as u32;
+
+            if address.is_multiple_of(PAGE_SIZE) {
+                flash.erase_page(address).map_err(|_| ErrorCode::Flash)?;
+            }
+
+            let mut word = [0; 4];
+            word[..bytes.len()].copy_from_slice(bytes);
+            let word = u32::from(word[0])
+                | u32::from(word[1]) << 8
+                | u32::from(word[2]) << 16
+                | u32::from(word[3]) << 24;
+
+            if word != ERASED {
+                flash
+                    .program_word(address, word)
+                    .map_err(|_| ErrorCode::Flash)?;
+            }
+        }
+
+        self.offset = end;
+
+        Ok(())
+    }
+
+    fn finish<F>(&mut self, flash: &mut F) -> Result<(), ErrorCode>
+    where
+        F: Flash,
+    {
+        let manifest = match (self.state, self.manifest) {
+            (State::Receiving, Some(manifest)) if self.offset == manifest.image_len => manifest,
+            _ => return Err(ErrorCode::Malformed),
+        };
+
+        let mut hasher = Sha256::new();
+        for offset in (0..manifest.image_len).step_by(4) {
+            let word = flash.read_word(STAGING.start + offset);
+            let bytes = [
+                word as u8,
+                (word >> 8) as u8,
+                (word >> 16) as u8,
+                (word >> 24) as u8,
+            ];
+            let len = (manifest.image_len - offset).min(4) as usize;
+
+            hasher.update(&bytes[..len]);
+        }
+
+        if hasher.finalize()[..] != manifest.digest[..] {
+            return Err(ErrorCode::BadDigest);
+        }
+
+        state::mark(flash, Step::Pending).map_err(|_| ErrorCode::Flash)?;
+        self.state = State::Verified;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Keypair, SecretKey, Signer};
+
+    use super::*;
+    use mock::MockFlash;
+    use {boot, confirm, ACTIVE};
+
+    /// Secret key of the first RFC 8032 test vector
+    const SECRET_KEY: [u8; 32] = [
+        0x9D, 0x61, 0xB1, 0x9D, 0xEF, 0xFD, 0x5A, 0x60, 0xBA, 0x84, 0x4A, 0xF4, 0x92, 0xEC, 0x2C,
+        0xC4, 0x44, 0x49, 0xC5, 0x69, 0x7B, 0x32, 0x69, 0x19, 0x70, 0x3B, 0xAC, 0x03, 0x1C, 0xAE,
+        0x7F, 0x60,
+    ];
+
+    const VERSION: u32 = 0x0001_0200;
+
+    const IMAGE_LEN: usize = 1003;
+
+    fn keypair(secret: &[u8; 32]) -> Keypair {
+        let secret = SecretKey::from_bytes(secret).unwrap();
+        let public = PublicKey::from(&secret);
+
+        Keypair { secret, public }
+    }
+
+    fn updater() -> Updater {
+        Updater::new(keypair(&SECRET_KEY).public.to_bytes(), VERSION)
+    }
+
+    fn image() -> [u8; IMAGE_LEN] {
+        let mut image = [0; IMAGE_LEN];
+
+        for (i, byte) in image.iter_mut().enumerate() {
+            *byte = (i * 31 % 251) as u8;
+        }
+
+        image
+    }
+
+    fn manifest(version: u32, image: &[u8], secret: &[u8; 32]) -> Manifest {
+        let mut manifest = Manifest {
+            version,
+            image_len: image.len() as u32,
+            digest: [0; 32],
+            signature: [0; 64],
+        };
+        manifest.digest.copy_from_slice(&Sha256::digest(image));
+        manifest.signature = keypair(secret).sign(&manifest.signed()).to_bytes();
+
+        manifest
+    }
+
+    /// Sends `image` in chunks and finishes the update
+    fn send(updater: &mut Updater, flash: &mut MockFlash, image: &[u8]) -> Status {
+        for (i, data) in image.chunks(256).enumerate() {
+            let offset = 256 * i as u32;
+            let status = updater.handle(flash, &Request::Chunk { offset, data });
+
+            assert_eq!(status.error, None);
+            assert_eq!(status.offset, offset + data.len() as u32);
+        }
+
+        updater.handle(flash, &Request::Finish)
+    }
+
+    fn failed(offset: u32, error: ErrorCode) -> Status {
+        Status {
+            state: State::Failed,
+            offset,
+            error: Some(error),
+        }
+    }
+
+    #[test]
+    fn update() {
+        let mut flash = MockFlash::new();
+        let mut updater = updater();
+        let image = image();
+
+        let manifest = manifest(VERSION + 1, &image, &SECRET_KEY);
+        let status = updater.handle(&mut flash, &Request::Manifest(manifest));
+        assert_eq!(
+            status,
+            Status {
+                state: State::Receiving,
+                offset: 0,
+                error: None,
+            }
+        );
+
+        let status = send(&mut updater, &mut flash, &image);
+        assert_eq!(
+            status,
+            Status {
+                state: State::Verified,
+                offset: IMAGE_LEN as u32,
+                error: None,
+            }
+        );
+        assert!(BootState::read(&flash).pending);
+
+        // The bootloader installs the image at the next reset
+        boot(&mut flash).unwrap();
+        for (i, bytes) in image.chunks(4).enumerate() {
+            let word = flash.read_word(ACTIVE.start + 4 * i as u32);
+
+            for (j, &byte) in bytes.iter().enumerate() {
+                assert_eq!((word >> (8 * j)) as u8, byte);
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_order_chunk() {
+        let mut flash = MockFlash::new();
+        let mut updater = updater();
+        let image = image();
+
+        let manifest = manifest(VERSION + 1, &image, &SECRET_KEY);
+        updater.handle(&mut flash, &Request::Manifest(manifest));
+
+        // The status tells the gateway where to resume from
+        let chunk = Request::Chunk {
+            offset: 256,
+            data: &image[256..512],
+        };
+        let status = updater.handle(&mut flash, &chunk);
+        assert_eq!(
+            status,
+            Status {
+                state: State::Receiving,
+                offset: 0,
+                error: None,
+            }
+        );
+
+        assert_eq!(
+            send(&mut updater, &mut flash, &image).state,
+            State::Verified
+        );
+    }
+
+    #[test]
+    fn bad_signature() {
+        let mut flash = MockFlash::new();
+        let mut updater = updater();
+        let image = image();
+
+        let mut tampered = manifest(VERSION + 1, &image, &SECRET_KEY);
+        tampered.signature[5] ^= 0x01;
+        let status = updater.handle(&mut flash, &Request::Manifest(tampered));
+        assert_eq!(status, failed(0, ErrorCode::BadSignature));
+
+        let mut tampered = manifest(VERSION + 1, &image, &SECRET_KEY);
+        tampered.version += 1;
+        let status = updater.handle(&mut flash, &Request::Manifest(tampered));
+        assert_eq!(status, failed(0, ErrorCode::BadSignature));
+
+        let foreign = manifest(VERSION + 1, &image, &[0x42; 32]);
+        let status = updater.handle(&mut flash, &Request::Manifest(foreign));
+        assert_eq!(status, failed(0, ErrorCode::BadSignature));
+
+        // Nothing can be written without a valid manifest
+        let chunk = Request::Chunk {
+            offset: 0,
+            data: &image[..256],
+        };
+        let status = updater.handle(&mut flash, &chunk);
+        assert_eq!(status, failed(0, ErrorCode::Malformed));
+        assert_eq!(flash.read_word(STAGING.start), ERASED);
+    }
+
+    #[test]
+    fn bad_digest() {
+        let mut flash = MockFlash::new();
+        let mut updater = updater();
+        let image = image();
+
+        let manifest = manifest(VERSION + 1, &image, &SECRET_KEY);
+        updater.handle(&mut flash, &Request::Manifest(manifest));
+
+        let mut corrupted = image;
+        corrupted[IMAGE_LEN - 1] ^= 0x80;
+        let status = send(&mut updater, &mut flash, &corrupted);
+        assert_eq!(status, failed(IMAGE_LEN as u32, ErrorCode::BadDigest));
+        assert!(!BootState::read(&flash).pending);
+    }
+
+    #[test]
+    fn downgrade() {
+        let mut flash = MockFlash::new();
+        let mut updater = updater();
+        let image = image();
+
+        for &version in &[VERSION, VERSION - 1, 0] {
+            let manifest = manifest(version, &image, &SECRET_KEY);
+            let status = updater.handle(&mut flash, &Request::Manifest(manifest));
+
+            assert_eq!(status, failed(0, ErrorCode::Downgrade));
+        }
+    }
+
+    #[test]
+    fn busy() {
+        let mut flash = MockFlash::new();
+        let image = image();
+
+        let mut updater = updater();
+        let first = manifest(VERSION + 1, &image, &SECRET_KEY);
+        updater.handle(&mut flash, &Request::Manifest(first));
+        send(&mut updater, &mut flash, &image);
+        boot(&mut flash).unwrap();
+
+        // The staging slot holds the fallback image until the new one confirms itself
+        let mut updater = Updater::new(keypair(&SECRET_KEY).public.to_bytes(), VERSION + 1);
+        let next = manifest(VERSION + 2, &image, &SECRET_KEY);
+        let status = updater.handle(&mut flash, &Request::Manifest(next));
+        assert_eq!(status, failed(0, ErrorCode::Busy));
+
+        confirm(&mut flash).unwrap();
+        let status = updater.handle(&mut flash, &Request::Manifest(next));
+        assert_eq!(status.state, State::Receiving);
+    }
+}
@@ -0,0 +1,376 @@
+//! Time synchronisation
+//!
+//! The gateway broadcasts a `Kind::Beacon` frame every few seconds, carrying its UTC time, the
+//! interval until the next beacon and the channels used in hopping mode (see `channel`):
+//!
+//! | Offset | Size | Field                                            |
+//! |--------|------|--------------------------------------------------|
+//! | 0      | 4    | UTC time, seconds since the Unix epoch           |
+//! | 4      | 2    | Milliseconds (`0..1000`)                         |
+//! | 6      | 2    | Beacon interval, seconds                         |
+//! | 8      | 1    | Hopping channels bitmap, `0` = hopping disabled  |
+//!
+//! The time is sampled when the gateway starts transmitting the beacon, so every node sees it late
+//! by the same air time. Multi-byte fields are big endian.
+//!
+//! Nodes feed the beacons to `TimeSync` together with their local time, in milliseconds from any
+//! monotonic clock: it estimates the drift of the local clock from consecutive beacons, converts
+//! local time to UTC and predicts when the next beacon will arrive.
+
+use channel::ChannelMask;
+
+/// Size of an encoded beacon
+pub const BEACON_LEN: usize = 9;
+
+/// Largest plausible local clock drift, in parts per million
+///
+/// Measurements above this are caused by a jump of the gateway clock rather than by the local
+/// oscillator, and restart the synchronisation.
+pub const MAX_DRIFT: i32 = 50_000;
+
+/// Drift left after the correction, in parts per million, used to widen the RX window
+const RESIDUAL_DRIFT: u64 = 100;
+
+/// Longest gap between two beacons used for drift estimation, in milliseconds
+///
+/// The local millisecond clock wraps after about 49 days; a much shorter limit keeps the
+/// differences well within range.
+const MAX_GAP: u64 = 24 * 60 * 60 * 1000;
+
+/// Beacon decoding error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The output buffer is too small for the encoded beacon
+    BufferTooSmall,
+    /// The input ends before the end of the beacon
+    Truncated,
+    /// The milliseconds field is out of range
+    InvalidTime,
+}
+
+/// Gateway time beacon
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Beacon {
+    /// UTC time, seconds since the Unix epoch
+    pub time: u32,
+    /// Milliseconds
+    pub millis: u16,
+    /// Interval until the next beacon, in seconds
+    pub interval: u16,
+    /// Channels to hop over
+    pub channels: ChannelMask,
+}
+
+impl Beacon {
+    /// Returns the UTC time in milliseconds since the Unix epoch
+    pub fn utc_millis(&self) -> u64 {
+        u64::from(self.time) * 1000 + u64::from(self.millis)
+    }
+
+    /// Encodes the beacon into `buf`, returning the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < BEACON_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+
+        buf[0] = (self.time >> 24) as u8;
+        buf[1] = (self.time >> 16) as u8;
+        buf[2] = (self.time >> 8) as u8;
+        buf[3] = self.time as u8;
+        buf[4] = (self.millis >> 8) as u8;
+        buf[5] = self.millis as u8;
+        buf[6] = (self.interval >> 8) as u8;
+        buf[7] = self.interval as u8;
+        buf[8] = self.channels.0;
+
+        Ok(BEACON_LEN)
+    }
+
+    /// Decodes a beacon from `buf`
+    pub fn decode(buf: &[u8]) -> Result<Beacon, Error> {
+        if buf.len() < BEACON_LEN {
+            return Err(Error::Truncated);
+        }
+
+        let beacon = Beacon {
+            time: (buf[0] as u32) << 24
+                | (buf[1] as u32) << 16
+                | (buf[2] as u32) << 8
+                | buf[3] as u32,
+            millis: (buf[4] as u16) << 8 | buf[5] as u16,
+            interval: (buf[6] as u16) << 8 | buf[7] as u16,
+            channels: ChannelMask(buf[8]),
+        };
+
+        if beacon.millis >= 1000 {
+            return Err(Error::InvalidTime);
+        }
+
+        Ok(beacon)
+    }
+}
+
+/// Local time and UTC time of the same instant, both in milliseconds
+#[derive(Clone, Copy)]
+struct SyncPoint {
+    local: u32,
+    utc: u64,
+}
+
+/// Local clock synchronisation against the gateway beacons
+pub struct TimeSync {
+    last: Option<SyncPoint>,
+    interval: u16,
+    drift: i32,
+    samples: u16,
+}
+
+impl TimeSync {
+    /// Creates an unsynchronised clock
+    pub fn new() -> Self {
+        TimeSync {
+            last: None,
+            interval: 0,
+            drift: 0,
+            samples: 0,
+        }
+    }
+
+    /// Synchronises the clock with `beacon`, received at local time `local`
+    pub fn update(&mut self, beacon: &Beacon, local: u32) {
+        let utc = beacon.utc_millis();
+
+        if let Some(last) = self.last {
+            let local_elapsed = u64::from(local.wrapping_sub(last.local));
+
+            if utc > last.utc && utc - last.utc <= MAX_GAP {
+                let utc_elapsed = (utc - last.utc) as i64;
+                let measured = (local_elapsed as i64 - utc_elapsed) * 1_000_000 / utc_elapsed;
+
+                if measured.abs() <= i64::from(MAX_DRIFT) {
+                    // Average out the reception jitter, quickly at first
+                    let weight = i64::from(self.samples.min(7)) + 1;
+                    let drift = i64::from(self.drift);
+
+                    self.drift = (drift + (measured - drift) / weight) as i32;
+                    self.samples = self.samples.saturating_add(1);
+                } else {
+                    self.samples = 0;
+                }
+            } else {
+                // The gateway clock jumped, or the last beacon is too old to be trusted
+                self.samples = 0;
+            }
+        }
+
+        self.last = Some(SyncPoint { local, utc });
+        self.interval = beacon.interval;
+    }
+
+    /// Returns `true` if at least one beacon has been received
+    pub fn is_synchronised(&self) -> bool {
+        self.last.is_some()
+    }
+
+    /// Returns the local time elapsed since the last beacon, in milliseconds
+    pub fn age(&self, local: u32) -> Option<u32> {
+        self.last.map(|last| local.wrapping_sub(last.local))
+    }
+
+    /// Returns the estimated drift of the local clock in parts per million, positive if it runs
+    /// faster than the gateway one
+    pub fn drift(&self) -> i32 {
+        self.drift
+    }
+
+    /// Converts the local time `local` to UTC, in milliseconds since the Unix epoch
+    pub fn utc_millis(&self, local: u32) -> Option<u64> {
+        self.last.map(|last| {
+            let elapsed = u64::from(local.wrapping_sub(last.local));
+
+            last.utc + self.to_utc(elapsed)
+        })
+    }
+
+    /// Returns the local time at which the next beacon is expected, after `local`
+    ///
+    /// Missed beacons are skipped: the result is always in the future.
+    pub fn next_beacon(&self, local: u32) -> Option<u32> {
+        let last = self.last?;
+        if self.interval == 0 {
+            return None;
+        }
+
+        let period = self.to_local(u64::from(self.interval) * 1000).max(1);
+        let elapsed = u64::from(local.wrapping_sub(last.local));
+        let beacons = elapsed / period + 1;
+
+        Some(last.local.wrapping_add((beacons * period) as u32))
+    }
+
+    /// Returns the local time at which to start listening for the next beacon after `local`
+    ///
+    /// The RX window opens `guard` milliseconds before the expected beacon, plus a margin for the
+    /// drift left uncorrected since the last beacon.
+    pub fn wakeup(&self, local: u32, guard: u32) -> Option<u32> {
+        let last = self.last?;
+        let next = self.next_beacon(local)?;
+
+        let unsynced = u64::from(next.wrapping_sub(last.local));
+        let margin = guard as u64 + unsynced * RESIDUAL_DRIFT / 1_000_000;
+
+        Some(next.wrapping_sub(margin.min(u64::from(next.wrapping_sub(local))) as u32))
+    }
+
+    // Local clock duration to gateway clock duration
+    fn to_utc(&self, local: u64) -> u64 {
+        local * 1_000_000 / (1_000_000 + self.drift as i64) as u64
+    }
+
+    // Gateway clock duration to local clock duration
+    fn to_local(&self, utc: u64) -> u64 {
+        utc * (1_000_000 + self.drift as i64) as u64 / 1_000_000
+    }
+}
+
+impl Default for TimeSync {
+    fn default() -> Self {
+        TimeSync::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2018-06-01T00:00:00Z, in milliseconds
+    const EPOCH: u64 = 1_527_811_200_000;
+
+    fn beacon(utc: u64, interval: u16) -> Beacon {
+        Beacon {
+            time: (utc / 1000) as u32,
+            millis: (utc % 1000) as u16,
+            interval,
+            channels: ChannelMask::NONE,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let beacon = Beacon {
+            time: 0x5B10_8C80,
+            millis: 999,
+            interval: 30,
+            channels: ChannelMask(0b1010_0101),
+        };
+        let mut buf = [0; BEACON_LEN];
+
+        assert_eq!(beacon.encode(&mut buf), Ok(BEACON_LEN));
+        assert_eq!(buf, [0x5B, 0x10, 0x8C, 0x80, 0x03, 0xE7, 0x00, 0x1E, 0xA5]);
+        assert_eq!(Beacon::decode(&buf), Ok(beacon));
+
+        assert_eq!(beacon.encode(&mut buf[..8]), Err(Error::BufferTooSmall));
+        assert_eq!(Beacon::decode(&buf[..8]), Err(Error::Truncated));
+
+        buf[4] = 0x03;
+        buf[5] = 0xE8;
+        assert_eq!(Beacon::decode(&buf), Err(Error::InvalidTime));
+    }
+
+    #[test]
+    fn drift() {
+        let mut sync = TimeSync::default();
+        assert!(!sync.is_synchronised());
+        assert_eq!(sync.utc_millis(0), None);
+
+        // The local clock runs 1000 ppm fast, with 1 ms of reception jitter
+        let jitter = [0, 1, -1, 1, 0, -1, 0, 1, -1, 0, 0, 1, -1, 0, 0, 0];
+        for (i, &jitter) in jitter.iter().enumerate() {
+            let local = (10_010 * i as i64 + jitter) as u32;
+
+            sync.update(&beacon(EPOCH + 10_000 * i as u64, 10), local);
+        }
+
+        assert!(sync.is_synchronised());
+        assert_eq!(sync.samples, 15);
+        assert!((sync.drift() - 1000).abs() <= 50, "{}", sync.drift());
+
+        // Local time is converted back to the gateway time
+        let last = 10_010 * 15;
+        let utc = sync.utc_millis(last + 5005).unwrap();
+        assert!(
+            (utc as i64 - (EPOCH + 155_000) as i64).abs() <= 1,
+            "{}",
+            utc
+        );
+    }
+
+    #[test]
+    fn gateway_clock_jump() {
+        let mut sync = TimeSync::new();
+
+        sync.update(&beacon(EPOCH, 10), 0);
+        sync.update(&beacon(EPOCH + 10_000, 10), 10_001);
+        sync.update(&beacon(EPOCH + 20_000, 10), 20_002);
+        assert_eq!(sync.samples, 2);
+        assert_eq!(sync.drift(), 100);
+
+        // Set back by an hour
+        sync.update(&beacon(EPOCH + 30_000 - 3_600_000, 10), 30_003);
+        assert_eq!(sync.samples, 0);
+
+        // Set forward by a few seconds, an implausible drift
+        sync.update(&beacon(EPOCH + 40_000 - 3_595_000, 10), 40_004);
+        assert_eq!(sync.samples, 0);
+
+        // Set forward by more than `MAX_GAP`
+        sync.update(&beacon(EPOCH + 2 * MAX_GAP, 10), 50_005);
+        assert_eq!(sync.samples, 0);
+
+        // The estimate survives the jumps, and improves again from the new reference
+        assert_eq!(sync.drift(), 100);
+        sync.update(&beacon(EPOCH + 2 * MAX_GAP + 10_000, 10), 60_006);
+        assert_eq!(sync.samples, 1);
+        assert_eq!(sync.utc_millis(60_006), Some(EPOCH + 2 * MAX_GAP + 10_000));
+    }
+
+    #[test]
+    fn next_beacon() {
+        let mut sync = TimeSync::new();
+        assert_eq!(sync.next_beacon(0), None);
+
+        sync.update(&beacon(EPOCH, 10), 1000);
+        assert_eq!(sync.next_beacon(1000), Some(11_000));
+        assert_eq!(sync.next_beacon(10_999), Some(11_000));
+
+        // Missed beacons are skipped
+        assert_eq!(sync.next_beacon(11_000), Some(21_000));
+        assert_eq!(sync.next_beacon(35_000), Some(41_000));
+
+        // The window opens early by the guard time and the residual drift, but never in the past
+        assert_eq!(sync.wakeup(1000, 50), Some(10_949));
+        assert_eq!(sync.wakeup(10_990, 50), Some(10_990));
+
+        // Without an interval, no beacon is expected
+        sync.update(&beacon(EPOCH + 10_000, 0), 11_000);
+        assert_eq!(sync.next_beacon(11_000), None);
+        assert_eq!(sync.wakeup(11_000, 50), None);
+    }
+
+    #[test]
+    fn local_clock_wrap_around() {
+        let mut sync = TimeSync::new();
+        let start = u32::MAX - 4999;
+
+        sync.update(&beacon(EPOCH, 10), start);
+        assert_eq!(sync.next_beacon(start), Some(5000));
+
+        sync.update(&beacon(EPOCH + 10_000, 10), start.wrapping_add(10_000));
+        assert_eq!(sync.samples, 1);
+        assert_eq!(sync.drift(), 0);
+
+        assert_eq!(sync.age(6000), Some(1000));
+        assert_eq!(sync.utc_millis(6000), Some(EPOCH + 11_000));
+        assert_eq!(sync.next_beacon(6000), Some(15_000));
+    }
+}
@@ -0,0 +1,353 @@
+//! Channel plan and frequency hopping
+//!
+//! The radio configuration sets a 868 MHz base frequency with a 250 kHz channel spacing:
+//!
+//! | Channel | Frequency  |
+//! |---------|------------|
+//! | 0       | 868.00 MHz |
+//! | 1       | 868.25 MHz |
+//! | ...     | ...        |
+//! | 7       | 869.75 MHz |
+//!
+//! Without channel agility everything happens on channel 0. In hopping mode the gateway and the
+//! nodes switch channel every `DWELL` milliseconds, following a pseudo-random sequence over the
+//! enabled channels that is derived from the UTC time of the beacons (see `beacon`). The gateway
+//! keeps noisy or unreliable channels out of the sequence with a `Blacklist`, and broadcasts the
+//! enabled channels in every beacon so that all the nodes agree on the sequence.
+//!
+//! Nodes that are not synchronised yet slowly cycle through the channels with `scan_channel`:
+//! sooner or later the gateway visits the channel they are listening on, and its beacon brings
+//! them in sync.
+
+/// Number of channels in the channel plan
+pub const CHANNELS: u8 = 8;
+
+/// Time spent on every channel in hopping mode, in milliseconds
+pub const DWELL: u32 = 4000;
+
+/// Time spent on every channel while scanning for a beacon, in milliseconds
+///
+/// Long enough for the gateway to visit every channel a couple of times on average.
+pub const SCAN_DWELL: u32 = 2 * CHANNELS as u32 * DWELL;
+
+/// Idle channels noisier than this, in dBm, are blacklisted
+pub const NOISE_THRESHOLD: i16 = -90;
+
+/// Channels failing this many times in a row are blacklisted
+pub const MAX_FAILURES: u8 = 5;
+
+/// The blacklist never leaves fewer channels than this enabled
+pub const MIN_CHANNELS: u8 = 2;
+
+/// Set of channels, bit `n` being channel `n`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelMask(pub u8);
+
+impl ChannelMask {
+    /// Every channel of the plan, the default hopping sequence
+    pub const ALL: ChannelMask = ChannelMask(0xFF);
+
+    /// No channel, i.e. hopping disabled
+    pub const NONE: ChannelMask = ChannelMask(0x00);
+
+    /// Returns `true` if `channel` is in the set
+    pub fn contains(&self, channel: u8) -> bool {
+        channel < CHANNELS && self.0 & (1 << channel) != 0
+    }
+
+    /// Adds `channel` to the set
+    pub fn insert(&mut self, channel: u8) {
+        if channel < CHANNELS {
+            self.0 |= 1 << channel;
+        }
+    }
+
+    /// Removes `channel` from the set
+    pub fn remove(&mut self, channel: u8) {
+        if channel < CHANNELS {
+            self.0 &= !(1 << channel);
+        }
+    }
+
+    /// Returns the number of channels in the set
+    pub fn count(&self) -> u8 {
+        self.0.count_ones() as u8
+    }
+
+    /// Returns the `n`-th channel of the set, in increasing order
+    pub fn nth(&self, n: u8) -> Option<u8> {
+        (0..CHANNELS).filter(|&c| self.contains(c)).nth(n as usize)
+    }
+}
+
+/// Returns the channel to use at UTC time `utc` (milliseconds), hopping over `channels`
+///
+/// Hopping is disabled if `channels` is empty: channel 0 is used all the time.
+pub fn hop_channel(channels: ChannelMask, utc: u64) -> u8 {
+    let count = channels.count();
+    if count == 0 {
+        return 0;
+    }
+
+    // splitmix64 finalizer: consecutive slots map to unrelated channels
+    let mut x = utc / u64::from(DWELL);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^= x >> 31;
+
+    channels.nth((x % u64::from(count)) as u8).unwrap_or(0)
+}
+
+/// Returns the time left on the current hopping channel at UTC time `utc`, in milliseconds
+///
+/// A frame that does not fit in the time left should wait for the next channel.
+pub fn dwell_left(utc: u64) -> u32 {
+    DWELL - (utc % u64::from(DWELL)) as u32
+}
+
+/// Returns the channel to listen on while scanning for a beacon, at local time `local`
+/// (milliseconds)
+pub fn scan_channel(local: u32) -> u8 {
+    ((local / SCAN_DWELL) % u32::from(CHANNELS)) as u8
+}
+
+/// Channel quality tracker, deciding which channels to hop over
+///
+/// Time is measured in caller-defined ticks, as for `fragment::Reassembler`.
+pub struct Blacklist {
+    noise: [Option<i16>; CHANNELS as usize],
+    failures: [u8; CHANNELS as usize],
+    banned: [Option<u32>; CHANNELS as usize],
+    timeout: u32,
+}
+
+impl Blacklist {
+    /// Creates a tracker with every channel enabled, giving blacklisted channels another chance
+    /// after `timeout` ticks
+    pub fn new(timeout: u32) -> Self {
+        Blacklist {
+            noise: [None; CHANNELS as usize],
+            failures: [0; CHANNELS as usize],
+            banned: [None; CHANNELS as usize],
+            timeout,
+        }
+    }
+
+    /// Records the RSSI of `channel` while nobody is transmitting, in dBm
+    pub fn noise(&mut self, channel: u8, rssi: i16) {
+        if let Some(noise) = self.noise.get_mut(channel as usize) {
+            // Average out the bursts of the other users of the band
+            *noise = Some(noise.map(|n| (n * 7 + rssi) / 8).unwrap_or(rssi));
+        }
+    }
+
+    /// Records a failure on `channel`, e.g. a corrupted frame or a missing response
+    pub fn failure(&mut self, channel: u8) {
+        if let Some(failures) = self.failures.get_mut(channel as usize) {
+            *failures = failures.saturating_add(1);
+        }
+    }
+
+    /// Records a successful exchange on `channel`
+    pub fn success(&mut self, channel: u8) {
+        if let Some(failures) = self.failures.get_mut(channel as usize) {
+            *failures = 0;
+        }
+    }
+
+    /// Updates the blacklist at time `now`, returning the channels to hop over
+    pub fn channels(&mut self, now: u32) -> ChannelMask {
+        for channel in 0..CHANNELS as usize {
+            match self.banned[channel] {
+                Some(since) if now.wrapping_sub(since) > self.timeout => {
+                    // Start over with a clean record
+                    self.banned[channel] = None;
+                    self.noise[channel] = None;
+                    self.failures[channel] = 0;
+                }
+                Some(_) => {}
+                None => {
+                    let noisy = self.noise[channel]
+                        .map(|n| n > NOISE_THRESHOLD)
+                        .unwrap_or(false);
+
+                    if noisy || self.failures[channel] >= MAX_FAILURES {
+                        self.banned[channel] = Some(now);
+                    }
+                }
+            }
+        }
+
+        let mut channels = ChannelMask::NONE;
+        for channel in 0..CHANNELS {
+            if self.banned[channel as usize].is_none() {
+                channels.insert(channel);
+            }
+        }
+
+        // Too few channels left: bring back the quietest ones
+        while channels.count() < MIN_CHANNELS {
+            let quietest = (0..CHANNELS)
+                .filter(|&c| !channels.contains(c))
+                .min_by_key(|&c| {
+                    let c = c as usize;
+                    (self.noise[c].unwrap_or(i16::MIN), self.failures[c])
+                });
+
+            match quietest {
+                Some(channel) => channels.insert(channel),
+                None => break,
+            }
+        }
+
+        channels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mask() {
+        let mut mask = ChannelMask::NONE;
+        assert_eq!(mask.count(), 0);
+        assert_eq!(mask.nth(0), None);
+
+        mask.insert(6);
+        mask.insert(1);
+        mask.insert(CHANNELS);
+        assert_eq!(mask, ChannelMask(0b0100_0010));
+        assert!(mask.contains(1) && mask.contains(6));
+        assert!(!mask.contains(0) && !mask.contains(CHANNELS));
+        assert_eq!(mask.count(), 2);
+        assert_eq!(
+            (mask.nth(0), mask.nth(1), mask.nth(2)),
+            (Some(1), Some(6), None)
+        );
+
+        mask.remove(1);
+        mask.remove(CHANNELS);
+        assert_eq!(mask, ChannelMask(0b0100_0000));
+
+        assert_eq!(ChannelMask::ALL.count(), CHANNELS);
+        assert_eq!(ChannelMask::ALL.nth(CHANNELS - 1), Some(CHANNELS - 1));
+    }
+
+    #[test]
+    fn hop_channel_disabled() {
+        for slot in 0..100 {
+            assert_eq!(hop_channel(ChannelMask::NONE, slot * u64::from(DWELL)), 0);
+            assert_eq!(hop_channel(ChannelMask(0b1000), slot * u64::from(DWELL)), 3);
+        }
+    }
+
+    #[test]
+    fn hop_channel_sequence() {
+        let channels = ChannelMask(0b1011_0110);
+        let slots = 1000 * u64::from(channels.count());
+        let mut visits = [0; CHANNELS as usize];
+        let mut repeats = 0;
+
+        // Around the start of 2018, in milliseconds
+        let start = 1_514_764_800_000 / u64::from(DWELL);
+        for slot in start..start + slots {
+            let utc = slot * u64::from(DWELL);
+            let channel = hop_channel(channels, utc);
+
+            assert!(channels.contains(channel), "{}", channel);
+            assert_eq!(hop_channel(channels, utc + u64::from(DWELL) - 1), channel);
+
+            if hop_channel(channels, utc + u64::from(DWELL)) == channel {
+                repeats += 1;
+            }
+            visits[channel as usize] += 1;
+        }
+
+        // Every enabled channel is used about as often
+        for channel in 0..CHANNELS {
+            let visits = visits[channel as usize];
+
+            if channels.contains(channel) {
+                assert!(visits > 900 && visits < 1100, "{}: {}", channel, visits);
+            } else {
+                assert_eq!(visits, 0);
+            }
+        }
+
+        // Consecutive slots are unrelated, staying on the same channel 1 time out of 5
+        assert!(repeats > 900 && repeats < 1100, "{}", repeats);
+    }
+
+    #[test]
+    fn dwell_and_scan() {
+        assert_eq!(dwell_left(0), DWELL);
+        assert_eq!(dwell_left(u64::from(DWELL) * 10 + 1), DWELL - 1);
+
+        assert_eq!(scan_channel(0), 0);
+        assert_eq!(scan_channel(SCAN_DWELL - 1), 0);
+        assert_eq!(scan_channel(SCAN_DWELL), 1);
+        assert_eq!(scan_channel(SCAN_DWELL * u32::from(CHANNELS)), 0);
+    }
+
+    #[test]
+    fn blacklist() {
+        let mut blacklist = Blacklist::new(100);
+        assert_eq!(blacklist.channels(0), ChannelMask::ALL);
+
+        // The average follows the noise slowly
+        blacklist.noise(2, -110);
+        blacklist.noise(2, -30);
+        assert_eq!(blacklist.channels(1), ChannelMask::ALL);
+        for _ in 0..10 {
+            blacklist.noise(2, -30);
+        }
+
+        for _ in 0..MAX_FAILURES - 1 {
+            blacklist.failure(5);
+        }
+        blacklist.success(5);
+        blacklist.failure(5);
+        assert_eq!(blacklist.channels(2), ChannelMask(0b1111_1011));
+
+        for _ in 0..MAX_FAILURES - 1 {
+            blacklist.failure(5);
+        }
+        assert_eq!(blacklist.channels(3), ChannelMask(0b1101_1011));
+
+        // Out of range channels are ignored
+        blacklist.noise(CHANNELS, -30);
+        blacklist.failure(CHANNELS);
+
+        // Another chance after the timeout, with a clean record
+        assert_eq!(blacklist.channels(102), ChannelMask(0b1101_1011));
+        assert_eq!(blacklist.channels(103), ChannelMask(0b1101_1111));
+        assert_eq!(blacklist.channels(104), ChannelMask::ALL);
+    }
+
+    #[test]
+    fn blacklist_keeps_min_channels() {
+        let mut blacklist = Blacklist::new(100);
+
+        // Every channel is noisy, 6 the least, then 1
+        for channel in 0..CHANNELS {
+            blacklist.noise(channel, -40 - i16::from(channel % 4));
+        }
+        blacklist.noise(6, -80);
+        blacklist.noise(1, -85);
+        blacklist.noise(1, -85);
+
+        let channels = blacklist.channels(0);
+        assert_eq!(channels.count(), MIN_CHANNELS);
+        assert_eq!(channels, ChannelMask(0b0100_0010));
+
+        // Failures count after the noise
+        let mut blacklist = Blacklist::new(100);
+        for channel in 0..CHANNELS {
+            for _ in 0..MAX_FAILURES + channel {
+                blacklist.failure(channel);
+            }
+        }
+        assert_eq!(blacklist.channels(0), ChannelMask(0b0000_0011));
+    }
+}
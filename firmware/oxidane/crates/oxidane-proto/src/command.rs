@@ -0,0 +1,496 @@
+//! Downlink commands
+//!
+//! A command payload is the command id followed by its arguments; the node answers every command
+//! addressed to it with a response carrying the command id, the sequence number of the command
+//! frame and a status byte (`0` on success, an `ErrorCode` otherwise):
+//!
+//! | Id     | Command             | Arguments                                  |
+//! |--------|---------------------|--------------------------------------------|
+//! | `0x01` | `StartIrrigation`   | duration, s (u16)                          |
+//! | `0x02` | `StopIrrigation`    | -                                          |
+//! | `0x03` | `SetThresholds`     | humidity on/off, minimum temperature (i16) |
+//! | `0x04` | `RequestReport`     | -                                          |
+//! | `0x05` | `Reboot`            | -                                          |
+//! | `0x06` | `SetReportInterval` | interval, s (u16)                          |
+//!
+//! Multi-byte fields are big endian.
+
+/// Size of an encoded response
+pub const RESPONSE_LEN: usize = 3;
+
+/// Command decoding error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The output buffer is too small for the encoded message
+    BufferTooSmall,
+    /// The input ends before the end of the message
+    Truncated,
+    /// The command id is unknown
+    UnknownCommand(u8),
+    /// The response status is unknown
+    UnknownStatus(u8),
+}
+
+/// Error reported by a node in response to a command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    /// The command id is unknown to the node
+    UnknownCommand = 0x01,
+    /// The command arguments are missing or truncated
+    Malformed = 0x02,
+    /// An argument is out of range
+    InvalidArgument = 0x03,
+    /// The node cannot execute the command right now
+    Busy = 0x04,
+    /// The command was accepted but its execution failed
+    Failed = 0x05,
+    /// The node does not implement the command
+    Unsupported = 0x06,
+}
+
+impl ErrorCode {
+    fn from_u8(code: u8) -> Option<ErrorCode> {
+        match code {
+            0x01 => Some(ErrorCode::UnknownCommand),
+            0x02 => Some(ErrorCode::Malformed),
+            0x03 => Some(ErrorCode::InvalidArgument),
+            0x04 => Some(ErrorCode::Busy),
+            0x05 => Some(ErrorCode::Failed),
+            0x06 => Some(ErrorCode::Unsupported),
+            _ => None,
+        }
+    }
+}
+
+/// Automatic irrigation thresholds
+///
+/// Values use the same fixed point scale as the corresponding telemetry readings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    /// Start irrigating when the humidity drops below this value (0.01 %RH)
+    pub humidity_on: i16,
+    /// Stop irrigating when the humidity rises above this value (0.01 %RH)
+    pub humidity_off: i16,
+    /// Never irrigate below this temperature (0.01 °C)
+    pub temperature_min: i16,
+}
+
+/// Downlink command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Turns the pump on for the given number of seconds
+    StartIrrigation {
+        /// Irrigation duration in seconds
+        duration_s: u16,
+    },
+    /// Turns the pump off
+    StopIrrigation,
+    /// Updates the automatic irrigation thresholds
+    SetThresholds(Thresholds),
+    /// Sends a telemetry report as soon as possible
+    RequestReport,
+    /// Reboots the node after the response has been sent
+    Reboot,
+    /// Updates the telemetry reporting interval
+    SetReportInterval {
+        /// Reporting interval in seconds
+        interval_s: u16,
+    },
+}
+
+impl Command {
+    /// Returns the command id
+    pub fn id(&self) -> u8 {
+        match *self {
+            Command::StartIrrigation { .. } => 0x01,
+            Command::StopIrrigation => 0x02,
+            Command::SetThresholds(_) => 0x03,
+            Command::RequestReport => 0x04,
+            Command::Reboot => 0x05,
+            Command::SetReportInterval { .. } => 0x06,
+        }
+    }
+
+    /// Encodes the command into `buf`, returning the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut args = [0; 6];
+
+        let len = match *self {
+            Command::StartIrrigation { duration_s: value }
+            | Command::SetReportInterval { interval_s: value } => {
+                args[0] = (value >> 8) as u8;
+                args[1] = value as u8;
+                2
+            }
+            Command::SetThresholds(ref t) => {
+                for (chunk, value) in args
+                    .chunks_mut(2)
+                    .zip(&[t.humidity_on, t.humidity_off, t.temperature_min])
+                {
+                    chunk[0] = (value >> 8) as u8;
+                    chunk[1] = *value as u8;
+                }
+                6
+            }
+            Command::StopIrrigation | Command::RequestReport | Command::Reboot => 0,
+        };
+
+        if buf.len() < 1 + len {
+            return Err(Error::BufferTooSmall);
+        }
+
+        buf[0] = self.id();
+        buf[1..1 + len].copy_from_slice(&args[..len]);
+
+        Ok(1 + len)
+    }
+
+    /// Decodes a command from `buf`
+    pub fn decode(buf: &[u8]) -> Result<Command, Error> {
+        let (&id, args) = buf.split_first().ok_or(Error::Truncated)?;
+
+        let arg = |i: usize| -> Result<u16, Error> {
+            match args.get(2 * i..2 * i + 2) {
+                Some(b) => Ok((b[0] as u16) << 8 | b[1] as u16),
+                None => Err(Error::Truncated),
+            }
+        };
+
+        Ok(match id {
+            0x01 => Command::StartIrrigation { duration_s: arg(0)? },
+            0x02 => Command::StopIrrigation,
+            0x03 => Command::SetThresholds(Thresholds {
+                humidity_on: arg(0)? as i16,
+                humidity_off: arg(1)? as i16,
+                temperature_min: arg(2)? as i16,
+            }),
+            0x04 => Command::RequestReport,
+            0x05 => Command::Reboot,
+            0x06 => Command::SetReportInterval { interval_s: arg(0)? },
+            _ => return Err(Error::UnknownCommand(id)),
+        })
+    }
+}
+
+/// Response to a command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Response {
+    /// Id of the command being answered
+    pub command: u8,
+    /// Sequence number of the command frame
+    pub seq: u8,
+    /// Outcome of the command
+    pub result: Result<(), ErrorCode>,
+}
+
+impl Response {
+    /// Encodes the response into `buf`, returning the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < RESPONSE_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+
+        buf[0] = self.command;
+        buf[1] = self.seq;
+        buf[2] = match self.result {
+            Ok(()) => 0,
+            Err(code) => code as u8,
+        };
+
+        Ok(RESPONSE_LEN)
+    }
+
+    /// Decodes a response from `buf`
+    pub fn decode(buf: &[u8]) -> Result<Response, Error> {
+        if buf.len() < RESPONSE_LEN {
+            return Err(Error::Truncated);
+        }
+
+        Ok(Response {
+            command: buf[0],
+            seq: buf[1],
+            result: match buf[2] {
+                0 => Ok(()),
+                code => Err(ErrorCode::from_u8(code).ok_or(Error::UnknownStatus(code))?),
+            },
+        })
+    }
+}
+
+/// Application side of the command set
+///
+/// Each method executes the corresponding command and reports its outcome, which is sent back to
+/// the gateway in the response.
+pub trait Handler {
+    /// Turns the pump on for `duration_s` seconds
+    fn start_irrigation(&mut self, duration_s: u16) -> Result<(), ErrorCode>;
+
+    /// Turns the pump off
+    fn stop_irrigation(&mut self) -> Result<(), ErrorCode>;
+
+    /// Updates the automatic irrigation thresholds
+    fn set_thresholds(&mut self, thresholds: Thresholds) -> Result<(), ErrorCode>;
+
+    /// Schedules a telemetry report
+    fn request_report(&mut self) -> Result<(), ErrorCode>;
+
+    /// Schedules a reboot
+    ///
+    /// The reboot must be deferred until the response has been transmitted.
+    fn reboot(&mut self) -> Result<(), ErrorCode>;
+
+    /// Updates the telemetry reporting interval
+    fn set_report_interval(&mut self, interval_s: u16) -> Result<(), ErrorCode>;
+}
+
+/// Decodes the command in `payload` and routes it to `handler`
+///
+/// `seq` is the sequence number of the frame carrying the command. The returned response must be
+/// sent back to the gateway.
+pub fn dispatch<H>(handler: &mut H, seq: u8, payload: &[u8]) -> Response
+where
+    H: Handler,
+{
+    let command = Command::decode(payload);
+
+    let result = match command {
+        Ok(Command::StartIrrigation { duration_s }) => handler.start_irrigation(duration_s),
+        Ok(Command::StopIrrigation) => handler.stop_irrigation(),
+        Ok(Command::SetThresholds(thresholds)) => handler.set_thresholds(thresholds),
+        Ok(Command::RequestReport) => handler.request_report(),
+        Ok(Command::Reboot) => handler.reboot(),
+        Ok(Command::SetReportInterval { interval_s }) => handler.set_report_interval(interval_s),
+        Err(Error::UnknownCommand(_)) => Err(ErrorCode::UnknownCommand),
+        Err(_) => Err(ErrorCode::Malformed),
+    };
+
+    Response {
+        command: payload.first().cloned().unwrap_or(0),
+        seq,
+        result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: Thresholds = Thresholds {
+        humidity_on: 40_00,
+        humidity_off: 60_00,
+        temperature_min: -5_00,
+    };
+
+    /// Records the commands it receives, answering every one with `result`
+    struct Recorder {
+        commands: [Option<Command>; 4],
+        len: usize,
+        result: Result<(), ErrorCode>,
+    }
+
+    impl Recorder {
+        fn new(result: Result<(), ErrorCode>) -> Self {
+            Recorder {
+                commands: [None; 4],
+                len: 0,
+                result,
+            }
+        }
+
+        fn record(&mut self, command: Command) -> Result<(), ErrorCode> {
+            self.commands[self.len] = Some(command);
+            self.len += 1;
+            self.result
+        }
+    }
+
+    impl Handler for Recorder {
+        fn start_irrigation(&mut self, duration_s: u16) -> Result<(), ErrorCode> {
+            self.record(Command::StartIrrigation { duration_s })
+        }
+
+        fn stop_irrigation(&mut self) -> Result<(), ErrorCode> {
+            self.record(Command::StopIrrigation)
+        }
+
+        fn set_thresholds(&mut self, thresholds: Thresholds) -> Result<(), ErrorCode> {
+            self.record(Command::SetThresholds(thresholds))
+        }
+
+        fn request_report(&mut self) -> Result<(), ErrorCode> {
+            self.record(Command::RequestReport)
+        }
+
+        fn reboot(&mut self) -> Result<(), ErrorCode> {
+            self.record(Command::Reboot)
+        }
+
+        fn set_report_interval(&mut self, interval_s: u16) -> Result<(), ErrorCode> {
+            self.record(Command::SetReportInterval { interval_s })
+        }
+    }
+
+    #[test]
+    fn command_round_trip() {
+        let commands: [(Command, &[u8]); 6] = [
+            (
+                Command::StartIrrigation { duration_s: 600 },
+                &[0x01, 0x02, 0x58],
+            ),
+            (Command::StopIrrigation, &[0x02]),
+            (
+                Command::SetThresholds(THRESHOLDS),
+                &[0x03, 0x0F, 0xA0, 0x17, 0x70, 0xFE, 0x0C],
+            ),
+            (Command::RequestReport, &[0x04]),
+            (Command::Reboot, &[0x05]),
+            (
+                Command::SetReportInterval { interval_s: 0xABCD },
+                &[0x06, 0xAB, 0xCD],
+            ),
+        ];
+
+        for &(command, encoded) in commands.iter() {
+            let mut buf = [0; 8];
+
+            let n = command.encode(&mut buf).unwrap();
+            assert_eq!(&buf[..n], encoded);
+            assert_eq!(buf[0], command.id());
+            assert_eq!(Command::decode(&buf[..n]), Ok(command));
+        }
+    }
+
+    #[test]
+    fn command_errors() {
+        let mut buf = [0; 8];
+
+        let command = Command::SetThresholds(THRESHOLDS);
+        assert_eq!(command.encode(&mut buf[..6]), Err(Error::BufferTooSmall));
+        assert_eq!(
+            Command::StopIrrigation.encode(&mut []),
+            Err(Error::BufferTooSmall)
+        );
+
+        let n = command.encode(&mut buf).unwrap();
+        for len in 0..n {
+            assert_eq!(Command::decode(&buf[..len]), Err(Error::Truncated));
+        }
+        assert_eq!(Command::decode(&[0x01, 0x02]), Err(Error::Truncated));
+        assert_eq!(Command::decode(&[0x06]), Err(Error::Truncated));
+
+        assert_eq!(Command::decode(&[0x00]), Err(Error::UnknownCommand(0x00)));
+        assert_eq!(
+            Command::decode(&[0x07, 0x00, 0x01]),
+            Err(Error::UnknownCommand(0x07))
+        );
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let results = [
+            (Ok(()), 0x00),
+            (Err(ErrorCode::UnknownCommand), 0x01),
+            (Err(ErrorCode::Malformed), 0x02),
+            (Err(ErrorCode::InvalidArgument), 0x03),
+            (Err(ErrorCode::Busy), 0x04),
+            (Err(ErrorCode::Failed), 0x05),
+            (Err(ErrorCode::Unsupported), 0x06),
+        ];
+
+        for &(result, status) in results.iter() {
+            let response = Response {
+                command: 0x03,
+                seq: 42,
+                result,
+            };
+            let mut buf = [0; RESPONSE_LEN];
+
+            assert_eq!(response.encode(&mut buf), Ok(RESPONSE_LEN));
+            assert_eq!(buf, [0x03, 42, status]);
+            assert_eq!(Response::decode(&buf), Ok(response));
+        }
+    }
+
+    #[test]
+    fn response_errors() {
+        let response = Response {
+            command: 0x01,
+            seq: 1,
+            result: Ok(()),
+        };
+
+        assert_eq!(response.encode(&mut [0; 2]), Err(Error::BufferTooSmall));
+        assert_eq!(Response::decode(&[0x01, 1]), Err(Error::Truncated));
+        assert_eq!(
+            Response::decode(&[0x01, 1, 0x07]),
+            Err(Error::UnknownStatus(0x07))
+        );
+    }
+
+    #[test]
+    fn dispatch_commands() {
+        let mut handler = Recorder::new(Ok(()));
+
+        let response = dispatch(&mut handler, 9, &[0x01, 0x00, 0x3C]);
+        assert_eq!(
+            response,
+            Response {
+                command: 0x01,
+                seq: 9,
+                result: Ok(()),
+            }
+        );
+
+        dispatch(
+            &mut handler,
+            10,
+            &[0x03, 0x0F, 0xA0, 0x17, 0x70, 0xFE, 0x0C],
+        );
+        dispatch(&mut handler, 11, &[0x05]);
+        assert_eq!(
+            &handler.commands[..handler.len],
+            &[
+                Some(Command::StartIrrigation { duration_s: 60 }),
+                Some(Command::SetThresholds(THRESHOLDS)),
+                Some(Command::Reboot),
+            ]
+        );
+
+        // The error of the handler is sent back
+        let mut handler = Recorder::new(Err(ErrorCode::Busy));
+        let response = dispatch(&mut handler, 12, &[0x04]);
+        assert_eq!(response.result, Err(ErrorCode::Busy));
+    }
+
+    #[test]
+    fn dispatch_errors() {
+        let mut handler = Recorder::new(Ok(()));
+
+        let response = dispatch(&mut handler, 1, &[0x42]);
+        assert_eq!(
+            response,
+            Response {
+                command: 0x42,
+                seq: 1,
+                result: Err(ErrorCode::UnknownCommand),
+            }
+        );
+
+        let response = dispatch(&mut handler, 2, &[0x06, 0x01]);
+        assert_eq!(response.result, Err(ErrorCode::Malformed));
+        assert_eq!(response.command, 0x06);
+
+        let response = dispatch(&mut handler, 3, &[]);
+        assert_eq!(
+            response,
+            Response {
+                command: 0,
+                seq: 3,
+                result: Err(ErrorCode::Malformed),
+            }
+        );
+
+        // Invalid commands never reach the handler
+        assert_eq!(handler.len, 0);
+    }
+}
@@ -0,0 +1,613 @@
+//! Fragmentation and reassembly
+//!
+//! Messages larger than a link-layer frame (firmware images, configuration dumps, logs) are split
+//! in up to `MAX_FRAGMENTS` fragments, each sent in a `Kind::Fragment` frame:
+//!
+//! | Offset | Size | Field                                                     |
+//! |--------|------|-----------------------------------------------------------|
+//! | 0      | 1    | Transfer id, chosen by the sender                         |
+//! | 1      | 1    | Fragment index                                            |
+//! | 2      | 1    | Fragment count                                            |
+//! | 3      | n    | Data (`MAX_DATA` bytes, the last fragment may be shorter) |
+//!
+//! After the last fragment, or whenever it needs to, the receiver answers with a
+//! `Kind::FragmentAck` frame carrying a bitmap of the fragments received so far; the sender then
+//! retransmits only the missing ones, until the ack reports the transfer as complete.
+//!
+//! | Offset | Size | Field                                             |
+//! |--------|------|---------------------------------------------------|
+//! | 0      | 1    | Transfer id                                       |
+//! | 1      | 1    | Fragment count                                    |
+//! | 2      | 8    | Received fragments bitmap, bit `i` = fragment `i` |
+
+use route;
+
+/// Size of the fragment header
+pub const HEADER_LEN: usize = 3;
+
+/// Amount of message data carried by every fragment but the last one
+///
+/// Fragments are sized to fit in a routed frame, so that they can reach nodes behind a relay.
+pub const MAX_DATA: usize = route::MAX_PAYLOAD - HEADER_LEN;
+
+/// Maximum number of fragments in a message
+pub const MAX_FRAGMENTS: usize = 64;
+
+/// Largest message that can be fragmented
+pub const MAX_MESSAGE: usize = MAX_FRAGMENTS * MAX_DATA;
+
+/// Size of an encoded ack
+pub const ACK_LEN: usize = 10;
+
+/// Fragmentation error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The output buffer is too small for the encoded fragment or ack
+    BufferTooSmall,
+    /// The input ends before the end of the fragment or ack
+    Truncated,
+    /// The message does not fit in `MAX_FRAGMENTS` fragments or in the reassembly buffer
+    MessageTooLarge,
+    /// The fragment index, count or size is inconsistent
+    InvalidFragment,
+}
+
+/// A single fragment of a message
+#[derive(Debug, Clone, Copy)]
+pub struct Fragment<'a> {
+    /// Transfer id
+    pub transfer: u8,
+    /// Index of this fragment
+    pub index: u8,
+    /// Number of fragments in the message
+    pub count: u8,
+    /// Message data
+    pub data: &'a [u8],
+}
+
+impl<'a> Fragment<'a> {
+    /// Encodes the fragment into `buf`, returning the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = HEADER_LEN + self.data.len();
+
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall);
+        }
+
+        buf[0] = self.transfer;
+        buf[1] = self.index;
+        buf[2] = self.count;
+        buf[HEADER_LEN..len].copy_from_slice(self.data);
+
+        Ok(len)
+    }
+
+    /// Decodes a fragment from `buf`
+    pub fn decode(buf: &'a [u8]) -> Result<Fragment<'a>, Error> {
+        if buf.len() < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+
+        Ok(Fragment {
+            transfer: buf[0],
+            index: buf[1],
+            count: buf[2],
+            data: &buf[HEADER_LEN..],
+        })
+    }
+}
+
+/// Reassembly status of a transfer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ack {
+    /// Transfer id
+    pub transfer: u8,
+    /// Number of fragments in the message
+    pub count: u8,
+    /// Received fragments, bit `i` is set if fragment `i` has been received
+    pub received: u64,
+}
+
+impl Ack {
+    /// Returns `true` if fragment `index` has been received
+    pub fn is_received(&self, index: u8) -> bool {
+        index < MAX_FRAGMENTS as u8 && self.received & (1 << index) != 0
+    }
+
+    /// Returns `true` if all the fragments have been received
+    pub fn is_complete(&self) -> bool {
+        (0..self.count).all(|i| self.is_received(i))
+    }
+
+    /// Encodes the ack into `buf`, returning the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < ACK_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+
+        buf[0] = self.transfer;
+        buf[1] = self.count;
+        for (i, byte) in buf[2..ACK_LEN].iter_mut().enumerate() {
+            *byte = (self.received >> (56 - 8 * i)) as u8;
+        }
+
+        Ok(ACK_LEN)
+    }
+
+    /// Decodes an ack from `buf`
+    pub fn decode(buf: &[u8]) -> Result<Ack, Error> {
+        if buf.len() < ACK_LEN {
+            return Err(Error::Truncated);
+        }
+
+        Ok(Ack {
+            transfer: buf[0],
+            count: buf[1],
+            received: buf[2..ACK_LEN]
+                .iter()
+                .fold(0, |acc, &byte| acc << 8 | byte as u64),
+        })
+    }
+}
+
+/// Splits a message into fragments
+pub struct Fragmenter<'a> {
+    transfer: u8,
+    message: &'a [u8],
+}
+
+impl<'a> Fragmenter<'a> {
+    /// Prepares `message` to be sent as transfer `transfer`
+    pub fn new(transfer: u8, message: &'a [u8]) -> Result<Self, Error> {
+        if message.len() > MAX_MESSAGE {
+            return Err(Error::MessageTooLarge);
+        }
+
+        Ok(Fragmenter { transfer, message })
+    }
+
+    /// Returns the number of fragments in the message
+    pub fn count(&self) -> u8 {
+        // An empty message is still sent as a single empty fragment
+        ((self.message.len().max(1) - 1) / MAX_DATA + 1) as u8
+    }
+
+    /// Returns fragment `index`, or `None` if it is out of range
+    pub fn fragment(&self, index: u8) -> Option<Fragment<'a>> {
+        if index >= self.count() {
+            return None;
+        }
+
+        let start = index as usize * MAX_DATA;
+        let end = (start + MAX_DATA).min(self.message.len());
+
+        Some(Fragment {
+            transfer: self.transfer,
+            index,
+            count: self.count(),
+            data: &self.message[start..end],
+        })
+    }
+
+    /// Returns the first fragment not reported as received by `ack`, starting from `index`
+    ///
+    /// Acks for other transfers are ignored, so all fragments are considered missing.
+    pub fn next_missing(&self, ack: &Ack, index: u8) -> Option<Fragment<'a>> {
+        (index..self.count())
+            .find(|&i| ack.transfer != self.transfer || !ack.is_received(i))
+            .and_then(|i| self.fragment(i))
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Transfer {
+    id: u8,
+    count: u8,
+    received: u64,
+    len: usize,
+    last_seen: u32,
+}
+
+/// Reassembles fragmented messages into a caller-provided buffer
+///
+/// One transfer is reassembled at a time; a fragment belonging to a new transfer aborts the one in
+/// progress. Time is measured in caller-defined ticks (e.g. seconds from the application clock).
+pub struct Reassembler<'a> {
+    buf: &'a mut [u8],
+    timeout: u32,
+    transfer: Option<Transfer>,
+}
+
+impl<'a> Reassembler<'a> {
+    /// Creates a reassembler dropping transfers idle for more than `timeout` ticks
+    ///
+    /// `buf` limits the size of the messages that can be received, it usually is a `static`.
+    pub fn new(buf: &'a mut [u8], timeout: u32) -> Self {
+        Reassembler {
+            buf,
+            timeout,
+            transfer: None,
+        }
+    }
+
+    /// Stores `fragment`, received at time `now`
+    ///
+    /// Returns the whole message when the last missing fragment is stored. Duplicate fragments are
+    /// ignored.
+    pub fn push(&mut self, fragment: &Fragment, now: u32) -> Result<Option<&[u8]>, Error> {
+        let count = fragment.count as usize;
+        let index = fragment.index as usize;
+
+        if count == 0 || count > MAX_FRAGMENTS || index >= count {
+            return Err(Error::InvalidFragment);
+        }
+
+        let last = index == count - 1;
+
+        if (!last && fragment.data.len() != MAX_DATA) || fragment.data.len() > MAX_DATA {
+            return Err(Error::InvalidFragment);
+        }
+
+        if (count - 1) * MAX_DATA + if last { fragment.data.len() } else { 0 } > self.buf.len() {
+            return Err(Error::MessageTooLarge);
+        }
+
+        let mut transfer = match self.transfer {
+            Some(t) if t.id == fragment.transfer && t.count == fragment.count => t,
+            _ => Transfer {
+                id: fragment.transfer,
+                count: fragment.count,
+                received: 0,
+                len: 0,
+                last_seen: now,
+            },
+        };
+
+        let complete = transfer.received == Reassembler::mask(transfer.count);
+
+        if transfer.received & (1 << index) == 0 {
+            let start = index * MAX_DATA;
+            self.buf[start..start + fragment.data.len()].copy_from_slice(fragment.data);
+
+            transfer.received |= 1 << index;
+            if last {
+                transfer.len = start + fragment.data.len();
+            }
+        }
+
+        transfer.last_seen = now;
+        self.transfer = Some(transfer);
+
+        // Only report the message once, retransmitted fragments of a complete transfer are dropped
+        if !complete && transfer.received == Reassembler::mask(transfer.count) {
+            Ok(Some(&self.buf[..transfer.len]))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the reassembly status of the current transfer, to be sent back to the sender
+    pub fn ack(&self) -> Option<Ack> {
+        self.transfer.map(|t| Ack {
+            transfer: t.id,
+            count: t.count,
+            received: t.received,
+        })
+    }
+
+    /// Drops the current transfer if it has been idle for too long, returning `true` if it did
+    pub fn expire(&mut self, now: u32) -> bool {
+        match self.transfer {
+            Some(t) if now.wrapping_sub(t.last_seen) > self.timeout => {
+                self.transfer = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn mask(count: u8) -> u64 {
+        if count as usize == MAX_FRAGMENTS {
+            !0
+        } else {
+            (1 << count) - 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> [u8; MAX_MESSAGE] {
+        let mut message = [0; MAX_MESSAGE];
+
+        for (i, byte) in message.iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+
+        message
+    }
+
+    fn receive_all<'a>(
+        reassembler: &'a mut Reassembler,
+        fragmenter: &Fragmenter,
+        order: &[u8],
+    ) -> Option<&'a [u8]> {
+        let (last, rest) = order.split_last().unwrap();
+
+        for &i in rest {
+            assert_eq!(
+                reassembler.push(&fragmenter.fragment(i).unwrap(), 0),
+                Ok(None)
+            );
+        }
+
+        reassembler
+            .push(&fragmenter.fragment(*last).unwrap(), 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn fragment_round_trip() {
+        let fragment = Fragment {
+            transfer: 7,
+            index: 2,
+            count: 5,
+            data: &[1, 2, 3],
+        };
+        let mut buf = [0; 8];
+
+        let n = fragment.encode(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &[7, 2, 5, 1, 2, 3]);
+
+        let decoded = Fragment::decode(&buf[..n]).unwrap();
+        assert_eq!(
+            (decoded.transfer, decoded.index, decoded.count, decoded.data),
+            (7, 2, 5, &[1, 2, 3][..])
+        );
+
+        assert_eq!(fragment.encode(&mut buf[..5]), Err(Error::BufferTooSmall));
+        assert_eq!(Fragment::decode(&buf[..2]).err(), Some(Error::Truncated));
+    }
+
+    #[test]
+    fn split() {
+        let message = message();
+
+        let fragmenter = Fragmenter::new(1, &message[..2 * MAX_DATA + 1]).unwrap();
+        assert_eq!(fragmenter.count(), 3);
+        assert_eq!(fragmenter.fragment(0).unwrap().data.len(), MAX_DATA);
+        assert_eq!(
+            fragmenter.fragment(2).unwrap().data,
+            &message[2 * MAX_DATA..][..1]
+        );
+        assert!(fragmenter.fragment(3).is_none());
+
+        let fragmenter = Fragmenter::new(1, &message[..2 * MAX_DATA]).unwrap();
+        assert_eq!(fragmenter.count(), 2);
+
+        let fragmenter = Fragmenter::new(1, &[]).unwrap();
+        assert_eq!(fragmenter.count(), 1);
+        assert_eq!(fragmenter.fragment(0).unwrap().data.len(), 0);
+
+        let too_large = [0; MAX_MESSAGE + 1];
+        assert!(Fragmenter::new(1, &too_large).is_err());
+    }
+
+    #[test]
+    fn out_of_order() {
+        let message = message();
+        let message = &message[..3 * MAX_DATA + 10];
+        let fragmenter = Fragmenter::new(3, message).unwrap();
+        let mut buf = [0; MAX_MESSAGE];
+        let mut reassembler = Reassembler::new(&mut buf, 10);
+
+        assert_eq!(
+            receive_all(&mut reassembler, &fragmenter, &[3, 0, 2, 1]),
+            Some(message)
+        );
+        assert!(reassembler.ack().unwrap().is_complete());
+    }
+
+    #[test]
+    fn duplicates() {
+        let message = message();
+        let message = &message[..2 * MAX_DATA + 1];
+        let fragmenter = Fragmenter::new(3, message).unwrap();
+        let mut buf = [0; MAX_MESSAGE];
+        let mut reassembler = Reassembler::new(&mut buf, 10);
+
+        // Before completion, a duplicate does not count as a new fragment
+        assert_eq!(
+            receive_all(&mut reassembler, &fragmenter, &[0, 0, 2, 2, 1]),
+            Some(message)
+        );
+
+        // After completion, retransmitted fragments do not report the message again
+        for i in 0..fragmenter.count() {
+            assert_eq!(
+                reassembler.push(&fragmenter.fragment(i).unwrap(), 1),
+                Ok(None)
+            );
+        }
+        assert!(reassembler.ack().unwrap().is_complete());
+    }
+
+    #[test]
+    fn max_fragments() {
+        let message = message();
+        let fragmenter = Fragmenter::new(9, &message).unwrap();
+        let mut buf = [0; MAX_MESSAGE];
+        let mut reassembler = Reassembler::new(&mut buf, 10);
+        let mut order = [0; MAX_FRAGMENTS];
+
+        for (i, slot) in order.iter_mut().enumerate() {
+            *slot = (MAX_FRAGMENTS - 1 - i) as u8;
+        }
+
+        assert_eq!(fragmenter.count() as usize, MAX_FRAGMENTS);
+        assert_eq!(
+            receive_all(&mut reassembler, &fragmenter, &order),
+            Some(&message[..])
+        );
+
+        let ack = reassembler.ack().unwrap();
+        assert_eq!(ack.received, !0);
+        assert!(ack.is_complete());
+        assert!(fragmenter.next_missing(&ack, 0).is_none());
+    }
+
+    #[test]
+    fn selective_retransmission() {
+        let message = message();
+        let fragmenter = Fragmenter::new(5, &message[..4 * MAX_DATA]).unwrap();
+        let mut buf = [0; MAX_MESSAGE];
+        let mut reassembler = Reassembler::new(&mut buf, 10);
+
+        for &i in &[0, 2] {
+            reassembler
+                .push(&fragmenter.fragment(i).unwrap(), 0)
+                .unwrap();
+        }
+
+        let ack = reassembler.ack().unwrap();
+        assert_eq!(ack.received, 0b0101);
+        assert!(!ack.is_complete());
+        assert_eq!(fragmenter.next_missing(&ack, 0).unwrap().index, 1);
+        assert_eq!(fragmenter.next_missing(&ack, 2).unwrap().index, 3);
+        assert!(fragmenter.next_missing(&ack, 4).is_none());
+
+        // An ack for another transfer reports nothing as received
+        let other = Ack { transfer: 6, ..ack };
+        assert_eq!(fragmenter.next_missing(&other, 0).unwrap().index, 0);
+    }
+
+    #[test]
+    fn ack_round_trip() {
+        let ack = Ack {
+            transfer: 0x42,
+            count: 64,
+            received: 0x8000_0000_0000_0101,
+        };
+        let mut buf = [0; ACK_LEN];
+
+        assert_eq!(ack.encode(&mut buf), Ok(ACK_LEN));
+        assert_eq!(
+            buf,
+            [0x42, 64, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01]
+        );
+        assert_eq!(Ack::decode(&buf), Ok(ack));
+        assert!(ack.is_received(63) && ack.is_received(8) && !ack.is_received(1));
+        assert!(!ack.is_received(64));
+
+        assert_eq!(
+            ack.encode(&mut buf[..ACK_LEN - 1]),
+            Err(Error::BufferTooSmall)
+        );
+        assert_eq!(Ack::decode(&buf[..ACK_LEN - 1]), Err(Error::Truncated));
+    }
+
+    #[test]
+    fn invalid_fragments() {
+        let data = [0; MAX_DATA + 1];
+        let mut buf = [0; MAX_MESSAGE];
+        let mut reassembler = Reassembler::new(&mut buf, 10);
+        // Only reports whether the message is complete, the closure cannot return the message
+        let mut push = |index, count, len| {
+            reassembler
+                .push(
+                    &Fragment {
+                        transfer: 1,
+                        index,
+                        count,
+                        data: &data[..len],
+                    },
+                    0,
+                ).map(|message| message.is_some())
+        };
+
+        // Short fragment that is not the last one
+        assert_eq!(push(0, 2, MAX_DATA - 1), Err(Error::InvalidFragment));
+        // Oversized fragments
+        assert_eq!(push(0, 2, MAX_DATA + 1), Err(Error::InvalidFragment));
+        assert_eq!(push(1, 2, MAX_DATA + 1), Err(Error::InvalidFragment));
+        // Inconsistent index and count
+        assert_eq!(push(0, 0, 0), Err(Error::InvalidFragment));
+        assert_eq!(push(2, 2, 0), Err(Error::InvalidFragment));
+        assert_eq!(
+            push(0, MAX_FRAGMENTS as u8 + 1, MAX_DATA),
+            Err(Error::InvalidFragment)
+        );
+
+        // A short last fragment is fine
+        assert_eq!(push(1, 2, 1), Ok(false));
+    }
+
+    #[test]
+    fn message_too_large() {
+        let data = [0; MAX_DATA];
+        let mut buf = [0; 2 * MAX_DATA];
+        let mut reassembler = Reassembler::new(&mut buf, 10);
+        let fragment = |index, count, len| Fragment {
+            transfer: 1,
+            index,
+            count,
+            data: &data[..len],
+        };
+
+        assert_eq!(
+            reassembler.push(&fragment(0, 4, MAX_DATA), 0),
+            Err(Error::MessageTooLarge)
+        );
+        assert_eq!(
+            reassembler.push(&fragment(2, 3, 1), 0),
+            Err(Error::MessageTooLarge)
+        );
+        assert_eq!(reassembler.push(&fragment(1, 2, MAX_DATA), 0), Ok(None));
+    }
+
+    #[test]
+    fn expire() {
+        let message = message();
+        let fragmenter = Fragmenter::new(1, &message[..2 * MAX_DATA]).unwrap();
+        let mut buf = [0; MAX_MESSAGE];
+        let mut reassembler = Reassembler::new(&mut buf, 10);
+
+        reassembler
+            .push(&fragmenter.fragment(0).unwrap(), 100)
+            .unwrap();
+
+        assert!(!reassembler.expire(110));
+        assert!(reassembler.ack().is_some());
+
+        assert!(reassembler.expire(111));
+        assert!(reassembler.ack().is_none());
+        assert!(!reassembler.expire(200));
+
+        // The fragments received before the timeout are lost
+        reassembler
+            .push(&fragmenter.fragment(1).unwrap(), 200)
+            .unwrap();
+        assert_eq!(reassembler.ack().unwrap().received, 0b10);
+    }
+
+    #[test]
+    fn new_transfer_aborts_current() {
+        let message = message();
+        let first = Fragmenter::new(1, &message[..2 * MAX_DATA]).unwrap();
+        let second = Fragmenter::new(2, &message[MAX_DATA..2 * MAX_DATA]).unwrap();
+        let mut buf = [0; MAX_MESSAGE];
+        let mut reassembler = Reassembler::new(&mut buf, 10);
+
+        reassembler.push(&first.fragment(0).unwrap(), 0).unwrap();
+
+        assert_eq!(
+            reassembler.push(&second.fragment(0).unwrap(), 0),
+            Ok(Some(&message[MAX_DATA..2 * MAX_DATA]))
+        );
+        assert_eq!(reassembler.push(&first.fragment(1).unwrap(), 0), Ok(None));
+        assert_eq!(reassembler.ack().unwrap().transfer, 1);
+        assert_eq!(reassembler.ack().unwrap().received, 0b10);
+    }
+}
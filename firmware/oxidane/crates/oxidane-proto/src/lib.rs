@@ -0,0 +1,22 @@
+//! Oxidane radio protocol
+//!
+//! Wire formats exchanged by the field nodes and the gateway over the Si4455 link. Everything in
+//! this crate is `no_std` and allocation free, so the very same encoders and decoders run on the
+//! node firmware and on the host (gateway, tools and tests).
+
+#![deny(missing_docs)]
+#![deny(warnings)]
+#![no_std]
+
+pub mod beacon;
+pub mod channel;
+pub mod command;
+pub mod fragment;
+pub mod link;
+pub mod ota;
+pub mod route;
+pub mod serial;
+pub mod telemetry;
+
+/// Size of the Si4455 TX/RX FIFO, i.e. the largest frame that fits in a single radio packet
+pub const FIFO_SIZE: usize = 64;
@@ -0,0 +1,158 @@
+//! Link layer
+//!
+//! Every radio packet starts with a fixed size header followed by the payload:
+//!
+//! | Offset | Size | Field                 |
+//! |--------|------|-----------------------|
+//! | 0      | 1    | Frame kind (`Kind`)   |
+//! | 1      | 1    | Destination address   |
+//! | 2      | 1    | Source address        |
+//! | 3      | 1    | Sequence number       |
+//! | 4      | n    | Payload               |
+
+use FIFO_SIZE;
+
+/// Size of the link-layer header
+pub const HEADER_LEN: usize = 4;
+
+/// Largest payload that fits in a single frame
+pub const MAX_PAYLOAD: usize = FIFO_SIZE - HEADER_LEN;
+
+/// Link-layer error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The output buffer is too small for the encoded frame
+    BufferTooSmall,
+    /// The payload does not fit in a single frame
+    PayloadTooLarge,
+    /// The input is shorter than the link-layer header
+    Truncated,
+    /// The frame kind is unknown
+    UnknownKind(u8),
+}
+
+/// Node address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address(pub u8);
+
+impl Address {
+    /// Address of the gateway
+    pub const GATEWAY: Address = Address(0x00);
+
+    /// Address matching every node
+    pub const BROADCAST: Address = Address(0xFF);
+
+    /// Returns `true` if a frame sent to this address must be processed by `node`
+    pub fn accepts(&self, node: Address) -> bool {
+        *self == node || *self == Address::BROADCAST
+    }
+}
+
+/// Kind of payload carried by a frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// Sensor telemetry report (node to gateway)
+    Telemetry = 0x01,
+    /// Command (gateway to node)
+    Command = 0x02,
+    /// Command response (node to gateway)
+    Response = 0x03,
+    /// Fragment of a message larger than a frame
+    Fragment = 0x04,
+    /// Reassembly status of a fragmented message
+    FragmentAck = 0x05,
+    /// Firmware update status (node to gateway)
+    OtaStatus = 0x06,
+    /// Time synchronisation beacon (gateway to every node)
+    Beacon = 0x07,
+    /// Route advertisement (relay or gateway to every node)
+    RouteAdvert = 0x08,
+    /// Frame relayed on behalf of another node (see `route`)
+    Routed = 0x09,
+}
+
+impl Kind {
+    pub(crate) fn from_u8(kind: u8) -> Option<Kind> {
+        match kind {
+            0x01 => Some(Kind::Telemetry),
+            0x02 => Some(Kind::Command),
+            0x03 => Some(Kind::Response),
+            0x04 => Some(Kind::Fragment),
+            0x05 => Some(Kind::FragmentAck),
+            0x06 => Some(Kind::OtaStatus),
+            0x07 => Some(Kind::Beacon),
+            0x08 => Some(Kind::RouteAdvert),
+            0x09 => Some(Kind::Routed),
+            _ => None,
+        }
+    }
+}
+
+/// Link-layer header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    /// Kind of payload
+    pub kind: Kind,
+    /// Destination address
+    pub dst: Address,
+    /// Source address
+    pub src: Address,
+    /// Sequence number, incremented by the sender for every new frame
+    pub seq: u8,
+}
+
+/// Link-layer frame
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<'a> {
+    /// Frame header
+    pub header: Header,
+    /// Frame payload
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Creates a new frame
+    pub fn new(header: Header, payload: &'a [u8]) -> Self {
+        Frame { header, payload }
+    }
+
+    /// Encodes the frame into `buf`, returning the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if self.payload.len() > MAX_PAYLOAD {
+            return Err(Error::PayloadTooLarge);
+        }
+
+        let len = HEADER_LEN + self.payload.len();
+
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall);
+        }
+
+        buf[0] = self.header.kind as u8;
+        buf[1] = self.header.dst.0;
+        buf[2] = self.header.src.0;
+        buf[3] = self.header.seq;
+        buf[HEADER_LEN..len].copy_from_slice(self.payload);
+
+        Ok(len)
+    }
+
+    /// Decodes a frame from `buf`
+    ///
+    /// The payload borrows the rest of the buffer, padding included.
+    pub fn decode(buf: &'a [u8]) -> Result<Frame<'a>, Error> {
+        if buf.len() < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+
+        Ok(Frame {
+            header: Header {
+                kind: Kind::from_u8(buf[0]).ok_or(Error::UnknownKind(buf[0]))?,
+                dst: Address(buf[1]),
+                src: Address(buf[2]),
+                seq: buf[3],
+            },
+            payload: &buf[HEADER_LEN..],
+        })
+    }
+}
@@ -0,0 +1,461 @@
+//! Over-the-air firmware update
+//!
+//! The gateway sends update requests as fragmented messages (see `fragment`), the first byte being
+//! the request id:
+//!
+//! | Id     | Request    | Arguments                                                   |
+//! |--------|------------|-------------------------------------------------------------|
+//! | `0x01` | `Manifest` | version (u32), image size (u32), SHA-256 (32), ed25519 (64) |
+//! | `0x02` | `Chunk`    | offset (u32), image data (up to `MAX_CHUNK` bytes)          |
+//! | `0x03` | `Finish`   | -                                                           |
+//! | `0x04` | `Abort`    | -                                                           |
+//!
+//! The manifest signature covers the version, the image size and the image digest. The node only
+//! accepts versions greater than its own, so that older signed images cannot be installed again.
+//! It answers every request with a `Kind::OtaStatus` frame:
+//!
+//! | Offset | Size | Field                                      |
+//! |--------|------|--------------------------------------------|
+//! | 0      | 1    | Update state (`State`)                     |
+//! | 1      | 4    | Offset of the next expected image byte     |
+//! | 5      | 1    | Error code (`ErrorCode`), `0` if none      |
+//!
+//! Chunks must be sent in order; after a lost chunk the gateway resumes from the offset reported
+//! by the node. Multi-byte fields are big endian.
+
+/// Largest amount of image data in a single chunk
+pub const MAX_CHUNK: usize = 1024;
+
+/// Largest encoded request, i.e. the reassembly buffer size needed by the node
+pub const MAX_REQUEST_LEN: usize = 5 + MAX_CHUNK;
+
+/// Size of the signed part of the manifest
+pub const SIGNED_LEN: usize = 40;
+
+/// Size of an encoded status
+pub const STATUS_LEN: usize = 6;
+
+const MANIFEST_LEN: usize = SIGNED_LEN + 64;
+
+/// OTA decoding error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The output buffer is too small for the encoded message
+    BufferTooSmall,
+    /// The input ends before the end of the message
+    Truncated,
+    /// The chunk carries more than `MAX_CHUNK` bytes
+    ChunkTooLarge,
+    /// The request id is unknown
+    UnknownRequest(u8),
+    /// The status carries an unknown state or error code
+    InvalidStatus,
+}
+
+/// Description of a firmware image, signed by the release key
+#[derive(Clone, Copy)]
+pub struct Manifest {
+    /// Firmware version, greater for newer images
+    pub version: u32,
+    /// Image size in bytes
+    pub image_len: u32,
+    /// SHA-256 digest of the image
+    pub digest: [u8; 32],
+    /// ed25519 signature of the first `SIGNED_LEN` bytes of the encoded manifest
+    pub signature: [u8; 64],
+}
+
+impl Manifest {
+    /// Returns the part of the manifest covered by the signature
+    pub fn signed(&self) -> [u8; SIGNED_LEN] {
+        let mut signed = [0; SIGNED_LEN];
+
+        put_u32(&mut signed[0..4], self.version);
+        put_u32(&mut signed[4..8], self.image_len);
+        signed[8..].copy_from_slice(&self.digest);
+
+        signed
+    }
+}
+
+/// Update request
+#[derive(Clone, Copy)]
+pub enum Request<'a> {
+    /// Starts a new update
+    Manifest(Manifest),
+    /// Image data
+    Chunk {
+        /// Offset of the data in the image
+        offset: u32,
+        /// Image data
+        data: &'a [u8],
+    },
+    /// Verifies the received image and schedules its installation
+    Finish,
+    /// Aborts the update in progress
+    Abort,
+}
+
+impl<'a> Request<'a> {
+    /// Encodes the request into `buf`, returning the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = 1 + match *self {
+            Request::Manifest(_) => MANIFEST_LEN,
+            Request::Chunk { data, .. } if data.len() > MAX_CHUNK => {
+                return Err(Error::ChunkTooLarge)
+            }
+            Request::Chunk { data, .. } => 4 + data.len(),
+            Request::Finish | Request::Abort => 0,
+        };
+
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall);
+        }
+
+        match *self {
+            Request::Manifest(ref manifest) => {
+                buf[0] = 0x01;
+                buf[1..1 + SIGNED_LEN].copy_from_slice(&manifest.signed());
+                buf[1 + SIGNED_LEN..len].copy_from_slice(&manifest.signature);
+            }
+            Request::Chunk { offset, data } => {
+                buf[0] = 0x02;
+                put_u32(&mut buf[1..5], offset);
+                buf[5..len].copy_from_slice(data);
+            }
+            Request::Finish => buf[0] = 0x03,
+            Request::Abort => buf[0] = 0x04,
+        }
+
+        Ok(len)
+    }
+
+    /// Decodes a request from `buf`
+    pub fn decode(buf: &'a [u8]) -> Result<Request<'a>, Error> {
+        let (&id, args) = buf.split_first().ok_or(Error::Truncated)?;
+
+        Ok(match id {
+            0x01 => {
+                if args.len() < MANIFEST_LEN {
+                    return Err(Error::Truncated);
+                }
+
+                let mut manifest = Manifest {
+                    version: get_u32(&args[0..4]),
+                    image_len: get_u32(&args[4..8]),
+                    digest: [0; 32],
+                    signature: [0; 64],
+                };
+                manifest.digest.copy_from_slice(&args[8..SIGNED_LEN]);
+                manifest
+                    .signature
+                    .copy_from_slice(&args[SIGNED_LEN..MANIFEST_LEN]);
+
+                Request::Manifest(manifest)
+            }
+            0x02 => {
+                if args.len() < 4 {
+                    return Err(Error::Truncated);
+                }
+
+                if args.len() - 4 > MAX_CHUNK {
+                    return Err(Error::ChunkTooLarge);
+                }
+
+                Request::Chunk {
+                    offset: get_u32(&args[0..4]),
+                    data: &args[4..],
+                }
+            }
+            0x03 => Request::Finish,
+            0x04 => Request::Abort,
+            _ => return Err(Error::UnknownRequest(id)),
+        })
+    }
+}
+
+/// State of the update on the node
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    /// No update in progress
+    Idle = 0x00,
+    /// Receiving the image
+    Receiving = 0x01,
+    /// The image has been verified and will be installed at the next reboot
+    Verified = 0x02,
+    /// The update failed, see the error code
+    Failed = 0x03,
+}
+
+/// Error reported by a node during an update
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    /// The request is malformed or unexpected in the current state
+    Malformed = 0x01,
+    /// The image does not fit in the staging area
+    TooLarge = 0x02,
+    /// The manifest signature is invalid
+    BadSignature = 0x03,
+    /// The image digest does not match the manifest
+    BadDigest = 0x04,
+    /// Erasing or programming the flash failed
+    Flash = 0x05,
+    /// The previous update has not been confirmed yet
+    Busy = 0x06,
+    /// The image is not newer than the running one
+    Downgrade = 0x07,
+}
+
+/// Update status, sent by the node in response to every request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Status {
+    /// Update state
+    pub state: State,
+    /// Offset of the next expected image byte
+    pub offset: u32,
+    /// Error that caused the last request to fail, if any
+    pub error: Option<ErrorCode>,
+}
+
+impl Status {
+    /// Encodes the status into `buf`, returning the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < STATUS_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+
+        buf[0] = self.state as u8;
+        put_u32(&mut buf[1..5], self.offset);
+        buf[5] = self.error.map(|e| e as u8).unwrap_or(0);
+
+        Ok(STATUS_LEN)
+    }
+
+    /// Decodes a status from `buf`
+    pub fn decode(buf: &[u8]) -> Result<Status, Error> {
+        if buf.len() < STATUS_LEN {
+            return Err(Error::Truncated);
+        }
+
+        let state = match buf[0] {
+            0x00 => State::Idle,
+            0x01 => State::Receiving,
+            0x02 => State::Verified,
+            0x03 => State::Failed,
+            _ => return Err(Error::InvalidStatus),
+        };
+
+        let error = match buf[5] {
+            0x00 => None,
+            0x01 => Some(ErrorCode::Malformed),
+            0x02 => Some(ErrorCode::TooLarge),
+            0x03 => Some(ErrorCode::BadSignature),
+            0x04 => Some(ErrorCode::BadDigest),
+            0x05 => Some(ErrorCode::Flash),
+            0x06 => Some(ErrorCode::Busy),
+            0x07 => Some(ErrorCode::Downgrade),
+            _ => return Err(Error::InvalidStatus),
+        };
+
+        Ok(Status {
+            state,
+            offset: get_u32(&buf[1..5]),
+            error,
+        })
+    }
+}
+
+fn put_u32(buf: &mut [u8], value: u32) {
+    buf[0] = (value >> 24) as u8;
+    buf[1] = (value >> 16) as u8;
+    buf[2] = (value >> 8) as u8;
+    buf[3] = value as u8;
+}
+
+fn get_u32(buf: &[u8]) -> u32 {
+    (buf[0] as u32) << 24 | (buf[1] as u32) << 16 | (buf[2] as u32) << 8 | buf[3] as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> Manifest {
+        let mut manifest = Manifest {
+            version: 0x0001_0203,
+            image_len: 0x0000_6789,
+            digest: [0; 32],
+            signature: [0; 64],
+        };
+
+        for (i, byte) in manifest.digest.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        for (i, byte) in manifest.signature.iter_mut().enumerate() {
+            *byte = 0xFF - i as u8;
+        }
+
+        manifest
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let manifest = manifest();
+        let mut buf = [0; 1 + MANIFEST_LEN];
+
+        let n = Request::Manifest(manifest).encode(&mut buf).unwrap();
+        assert_eq!(n, 1 + MANIFEST_LEN);
+        assert_eq!(
+            buf[..9],
+            [0x01, 0x00, 0x01, 0x02, 0x03, 0x00, 0x00, 0x67, 0x89]
+        );
+        assert_eq!(buf[1..1 + SIGNED_LEN], manifest.signed()[..]);
+
+        match Request::decode(&buf).unwrap() {
+            Request::Manifest(decoded) => {
+                assert_eq!(decoded.version, manifest.version);
+                assert_eq!(decoded.image_len, manifest.image_len);
+                assert_eq!(decoded.digest, manifest.digest);
+                assert_eq!(decoded.signature[..], manifest.signature[..]);
+            }
+            _ => panic!("invalid request"),
+        }
+    }
+
+    #[test]
+    fn chunk_round_trip() {
+        let data = [0xA5; MAX_CHUNK];
+        let mut buf = [0; MAX_REQUEST_LEN];
+
+        for &len in &[0, 1, MAX_CHUNK] {
+            let chunk = Request::Chunk {
+                offset: 0x0102_0304,
+                data: &data[..len],
+            };
+
+            let n = chunk.encode(&mut buf).unwrap();
+            assert_eq!(n, 5 + len);
+            assert_eq!(buf[..5], [0x02, 0x01, 0x02, 0x03, 0x04]);
+
+            match Request::decode(&buf[..n]).unwrap() {
+                Request::Chunk { offset, data } => {
+                    assert_eq!(offset, 0x0102_0304);
+                    assert_eq!(data, &[0xA5; MAX_CHUNK][..len]);
+                }
+                _ => panic!("invalid request"),
+            }
+        }
+    }
+
+    #[test]
+    fn finish_and_abort() {
+        let mut buf = [0; 1];
+
+        assert_eq!(Request::Finish.encode(&mut buf), Ok(1));
+        assert_eq!(buf, [0x03]);
+        match Request::decode(&buf).unwrap() {
+            Request::Finish => {}
+            _ => panic!("invalid request"),
+        }
+
+        assert_eq!(Request::Abort.encode(&mut buf), Ok(1));
+        assert_eq!(buf, [0x04]);
+        match Request::decode(&buf).unwrap() {
+            Request::Abort => {}
+            _ => panic!("invalid request"),
+        }
+    }
+
+    #[test]
+    fn request_errors() {
+        let data = [0; MAX_CHUNK + 1];
+        let mut buf = [0; MAX_REQUEST_LEN + 1];
+
+        let chunk = Request::Chunk {
+            offset: 0,
+            data: &data,
+        };
+        assert_eq!(chunk.encode(&mut buf), Err(Error::ChunkTooLarge));
+
+        let chunk = Request::Chunk {
+            offset: 0,
+            data: &data[..4],
+        };
+        assert_eq!(chunk.encode(&mut buf[..8]), Err(Error::BufferTooSmall));
+        assert_eq!(
+            Request::Manifest(manifest()).encode(&mut buf[..MANIFEST_LEN]),
+            Err(Error::BufferTooSmall)
+        );
+
+        // Oversized chunks are rejected on decoding too
+        buf[0] = 0x02;
+        assert_eq!(Request::decode(&buf).err(), Some(Error::ChunkTooLarge));
+
+        assert_eq!(Request::decode(&[]).err(), Some(Error::Truncated));
+        assert_eq!(Request::decode(&[0x02, 0x00]).err(), Some(Error::Truncated));
+
+        let n = Request::Manifest(manifest()).encode(&mut buf).unwrap();
+        assert_eq!(Request::decode(&buf[..n - 1]).err(), Some(Error::Truncated));
+
+        assert_eq!(
+            Request::decode(&[0x05]).err(),
+            Some(Error::UnknownRequest(0x05))
+        );
+    }
+
+    #[test]
+    fn status_round_trip() {
+        let errors = [
+            None,
+            Some(ErrorCode::Malformed),
+            Some(ErrorCode::TooLarge),
+            Some(ErrorCode::BadSignature),
+            Some(ErrorCode::BadDigest),
+            Some(ErrorCode::Flash),
+            Some(ErrorCode::Busy),
+            Some(ErrorCode::Downgrade),
+        ];
+        let states = [
+            State::Idle,
+            State::Receiving,
+            State::Verified,
+            State::Failed,
+        ];
+        let mut buf = [0; STATUS_LEN];
+
+        for &state in &states {
+            for &error in &errors {
+                let status = Status {
+                    state,
+                    offset: 0x0A0B_0C0D,
+                    error,
+                };
+
+                assert_eq!(status.encode(&mut buf), Ok(STATUS_LEN));
+                assert_eq!(buf[0], state as u8);
+                assert_eq!(buf[1..5], [0x0A, 0x0B, 0x0C, 0x0D]);
+                assert_eq!(Status::decode(&buf), Ok(status));
+            }
+        }
+    }
+
+    #[test]
+    fn status_errors() {
+        let status = Status {
+            state: State::Failed,
+            offset: 0,
+            error: Some(ErrorCode::Flash),
+        };
+
+        assert_eq!(status.encode(&mut [0; 5]), Err(Error::BufferTooSmall));
+        assert_eq!(Status::decode(&[0; 5]), Err(Error::Truncated));
+        assert_eq!(
+            Status::decode(&[0x04, 0, 0, 0, 0, 0]),
+            Err(Error::InvalidStatus)
+        );
+        assert_eq!(
+            Status::decode(&[0x03, 0, 0, 0, 0, 0x08]),
+            Err(Error::InvalidStatus)
+        );
+    }
+}
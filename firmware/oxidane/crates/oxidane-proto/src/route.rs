@@ -0,0 +1,564 @@
+//! Multi-hop routing
+//!
+//! Nodes out of the gateway radio range reach it through relays: mains-powered nodes that forward
+//! frames on behalf of the others. Routes form a tree rooted at the gateway.
+//!
+//! The gateway and every relay attached to the tree periodically broadcast a `Kind::RouteAdvert`
+//! frame with their distance from the gateway:
+//!
+//! | Offset | Size | Field                                    |
+//! |--------|------|------------------------------------------|
+//! | 0      | 1    | Hop count to the gateway (`0` = gateway) |
+//! | 1      | 1    | Parent address                           |
+//!
+//! Every node picks as parent the neighbour with the fewest hops to the gateway, preferring links
+//! stronger than `MIN_RSSI` and breaking ties by RSSI. A frame whose source or destination is not
+//! the sender or the receiver of the hop travels in a `Kind::Routed` frame, addressed to the next
+//! hop and carrying the end-to-end addressing in front of the original payload:
+//!
+//! | Offset | Size | Field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 1    | Source address                          |
+//! | 1      | 1    | Destination address                     |
+//! | 2      | 1    | Time to live, decremented at every hop  |
+//! | 3      | 1    | Frame kind (`Kind`) of the payload      |
+//! | 4      | 1    | Sequence number assigned by the source  |
+//! | 5      | n    | Payload                                 |
+//!
+//! Frames travel up the tree to the gateway following the parents, and down the tree following
+//! the routes learnt from the upward traffic. Routing loops are prevented by ignoring neighbours
+//! whose parent is the receiving node, by limiting the hop count to `MAX_HOPS` and by dropping
+//! frames whose time to live runs out or that would bounce back to the previous hop.
+//!
+//! Single-hop traffic between a node and its parent gateway uses plain frames, so nodes in range
+//! of the gateway work unchanged.
+
+use link::{self, Address, Frame, Header, Kind};
+
+/// Size of the routing header of a `Kind::Routed` frame
+pub const HEADER_LEN: usize = 5;
+
+/// Largest payload that can be routed, i.e. that fits in a `Kind::Routed` frame
+pub const MAX_PAYLOAD: usize = link::MAX_PAYLOAD - HEADER_LEN;
+
+/// Size of an encoded route advertisement
+pub const ADVERT_LEN: usize = 2;
+
+/// Longest route to the gateway, in hops
+pub const MAX_HOPS: u8 = 8;
+
+/// Links weaker than this, in dBm, are used only when there is no alternative
+pub const MIN_RSSI: i8 = -100;
+
+/// Number of neighbours tracked by a router
+pub const MAX_NEIGHBOURS: usize = 8;
+
+/// Number of downward routes tracked by a router
+pub const MAX_ROUTES: usize = 32;
+
+// A parent with as many hops is replaced only by a neighbour stronger by this much, in dB
+const RSSI_HYSTERESIS: i16 = 6;
+
+/// Routing error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The output buffer is too small for the encoded frame
+    BufferTooSmall,
+    /// The input ends before the end of the routing header or advertisement
+    Truncated,
+    /// The payload does not fit in a routed frame
+    PayloadTooLarge,
+    /// The routed frame kind is unknown
+    UnknownKind(u8),
+}
+
+impl From<link::Error> for Error {
+    fn from(e: link::Error) -> Error {
+        match e {
+            link::Error::BufferTooSmall => Error::BufferTooSmall,
+            link::Error::PayloadTooLarge => Error::PayloadTooLarge,
+            link::Error::Truncated => Error::Truncated,
+            link::Error::UnknownKind(kind) => Error::UnknownKind(kind),
+        }
+    }
+}
+
+/// Routing role of a node
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    /// Root of the routing tree
+    Gateway,
+    /// Node forwarding frames for others, usually mains-powered
+    Relay,
+    /// Node that only sends and receives its own frames
+    Leaf,
+}
+
+/// Route advertisement
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Advert {
+    /// Hop count to the gateway
+    pub hops: u8,
+    /// Parent of the advertising node, the gateway advertises itself
+    pub parent: Address,
+}
+
+impl Advert {
+    /// Encodes the advertisement into `buf`, returning the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < ADVERT_LEN {
+            return Err(Error::BufferTooSmall);
+        }
+
+        buf[0] = self.hops;
+        buf[1] = self.parent.0;
+
+        Ok(ADVERT_LEN)
+    }
+
+    /// Decodes an advertisement from `buf`
+    pub fn decode(buf: &[u8]) -> Result<Advert, Error> {
+        if buf.len() < ADVERT_LEN {
+            return Err(Error::Truncated);
+        }
+
+        Ok(Advert {
+            hops: buf[0],
+            parent: Address(buf[1]),
+        })
+    }
+}
+
+/// End-to-end packet, carried by a plain frame or by one `Kind::Routed` frame per hop
+#[derive(Debug, Clone, Copy)]
+pub struct Packet<'a> {
+    /// Address of the node that created the packet
+    pub src: Address,
+    /// Address of the final recipient
+    pub dst: Address,
+    /// Remaining hops
+    pub ttl: u8,
+    /// Kind of payload
+    pub kind: Kind,
+    /// Sequence number assigned by the source
+    pub seq: u8,
+    /// Payload
+    pub payload: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    /// Creates a new packet from `src` to `dst`
+    pub fn new(src: Address, dst: Address, kind: Kind, seq: u8, payload: &'a [u8]) -> Self {
+        Packet {
+            src,
+            dst,
+            ttl: MAX_HOPS,
+            kind,
+            seq,
+            payload,
+        }
+    }
+
+    /// Extracts the end-to-end packet carried by `frame`, unwrapping `Kind::Routed` frames
+    ///
+    /// This is for observers of the traffic such as sniffers; nodes go through `Router::receive`.
+    /// Plain frames give packets with a time to live of `0`.
+    pub fn decode(frame: &Frame<'a>) -> Result<Packet<'a>, Error> {
+        let header = frame.header;
+
+        match header.kind {
+            Kind::Routed => Packet::decode_routed(frame.payload),
+            _ => Ok(Packet {
+                src: header.src,
+                dst: header.dst,
+                ttl: 0,
+                kind: header.kind,
+                seq: header.seq,
+                payload: frame.payload,
+            }),
+        }
+    }
+
+    fn encode_routed(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if self.payload.len() > MAX_PAYLOAD {
+            return Err(Error::PayloadTooLarge);
+        }
+
+        let len = HEADER_LEN + self.payload.len();
+
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall);
+        }
+
+        buf[0] = self.src.0;
+        buf[1] = self.dst.0;
+        buf[2] = self.ttl;
+        buf[3] = self.kind as u8;
+        buf[4] = self.seq;
+        buf[HEADER_LEN..len].copy_from_slice(self.payload);
+
+        Ok(len)
+    }
+
+    fn decode_routed(buf: &'a [u8]) -> Result<Packet<'a>, Error> {
+        if buf.len() < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+
+        Ok(Packet {
+            src: Address(buf[0]),
+            dst: Address(buf[1]),
+            ttl: buf[2],
+            kind: Kind::from_u8(buf[3]).ok_or(Error::UnknownKind(buf[3]))?,
+            seq: buf[4],
+            payload: &buf[HEADER_LEN..],
+        })
+    }
+}
+
+/// Outcome of the reception of a frame
+#[derive(Debug, Clone, Copy)]
+pub enum Received<'a> {
+    /// The packet is addressed to this node
+    Local(Packet<'a>),
+    /// The packet must be forwarded with `Router::encode`
+    Relay(Packet<'a>),
+    /// The frame has been consumed by the router, or it must be dropped
+    Ignored,
+}
+
+#[derive(Clone, Copy)]
+struct Neighbour {
+    address: Address,
+    hops: u8,
+    rssi: i16,
+    last_seen: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Route {
+    dst: Address,
+    next: Address,
+    last_seen: u32,
+}
+
+/// Routing table and parent selection
+///
+/// Time is measured in caller-defined ticks, as for `fragment::Reassembler`.
+pub struct Router {
+    address: Address,
+    role: Role,
+    timeout: u32,
+    parent: Option<Neighbour>,
+    neighbours: [Option<Neighbour>; MAX_NEIGHBOURS],
+    routes: [Option<Route>; MAX_ROUTES],
+}
+
+impl Router {
+    /// Creates a router for the node at `address`, forgetting neighbours and routes not heard
+    /// from for more than `timeout` ticks
+    pub fn new(address: Address, role: Role, timeout: u32) -> Self {
+        Router {
+            address,
+            role,
+            timeout,
+            parent: None,
+            neighbours: [None; MAX_NEIGHBOURS],
+            routes: [None; MAX_ROUTES],
+        }
+    }
+
+    /// Returns the address of this node
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Returns the current parent, if any
+    pub fn parent(&self) -> Option<Address> {
+        self.parent.map(|p| p.address)
+    }
+
+    /// Returns the hop count to the gateway, if known
+    pub fn hops(&self) -> Option<u8> {
+        match self.role {
+            Role::Gateway => Some(0),
+            _ => self.parent.map(|p| p.hops + 1),
+        }
+    }
+
+    /// Returns the advertisement to broadcast, if this node accepts traffic from others
+    pub fn advert(&self) -> Option<Advert> {
+        match self.role {
+            Role::Gateway => Some(Advert {
+                hops: 0,
+                parent: self.address,
+            }),
+            Role::Relay => self.parent.map(|p| Advert {
+                hops: p.hops + 1,
+                parent: p.address,
+            }),
+            Role::Leaf => None,
+        }
+    }
+
+    /// Returns the neighbour to send a packet for `dst` to
+    pub fn next_hop(&self, dst: Address) -> Option<Address> {
+        if dst == Address::BROADCAST {
+            return None;
+        }
+
+        if dst == Address::GATEWAY && self.role != Role::Gateway {
+            if let Some(parent) = self.parent {
+                return Some(parent.address);
+            }
+        }
+
+        self.routes
+            .iter()
+            .filter_map(|r| *r)
+            .find(|r| r.dst == dst)
+            .map(|r| r.next)
+    }
+
+    /// Processes a received `frame`, whose RSSI was `rssi` dBm, at time `now`
+    pub fn receive<'a>(
+        &mut self,
+        frame: &Frame<'a>,
+        rssi: Option<i8>,
+        now: u32,
+    ) -> Result<Received<'a>, Error> {
+        let header = frame.header;
+
+        match header.kind {
+            Kind::RouteAdvert => {
+                let advert = Advert::decode(frame.payload)?;
+                self.update_neighbour(header.src, &advert, rssi.unwrap_or(i8::MIN), now);
+
+                Ok(Received::Ignored)
+            }
+            Kind::Routed if header.dst == self.address => {
+                let packet = Packet::decode_routed(frame.payload)?;
+
+                if packet.src != self.address {
+                    self.learn(packet.src, header.src, now);
+                }
+
+                if packet.dst.accepts(self.address) {
+                    return Ok(Received::Local(packet));
+                }
+
+                if self.role == Role::Leaf || packet.ttl <= 1 {
+                    return Ok(Received::Ignored);
+                }
+
+                match self.next_hop(packet.dst) {
+                    // Never send a packet back where it came from
+                    Some(next) if next != header.src => Ok(Received::Relay(Packet {
+                        ttl: packet.ttl - 1,
+                        ..packet
+                    })),
+                    _ => Ok(Received::Ignored),
+                }
+            }
+            Kind::Routed => Ok(Received::Ignored),
+            _ if header.dst.accepts(self.address) => {
+                if header.src != self.address {
+                    self.learn(header.src, header.src, now);
+                }
+
+                Ok(Received::Local(Packet::decode(frame)?))
+            }
+            _ => Ok(Received::Ignored),
+        }
+    }
+
+    /// Encodes the frame carrying `packet` to the next hop into `buf`, returning the number of
+    /// bytes written
+    ///
+    /// Packets with no known route are sent directly to their destination.
+    pub fn encode(&self, packet: &Packet, buf: &mut [u8]) -> Result<usize, Error> {
+        let next = self.next_hop(packet.dst).unwrap_or(packet.dst);
+
+        if packet.src == self.address && next == packet.dst {
+            let header = Header {
+                kind: packet.kind,
+                dst: packet.dst,
+                src: packet.src,
+                seq: packet.seq,
+            };
+
+            return Ok(Frame::new(header, packet.payload).encode(buf)?);
+        }
+
+        let mut routed = [0; link::MAX_PAYLOAD];
+        let len = packet.encode_routed(&mut routed)?;
+        let header = Header {
+            kind: Kind::Routed,
+            dst: next,
+            src: self.address,
+            seq: packet.seq,
+        };
+
+        Ok(Frame::new(header, &routed[..len]).encode(buf)?)
+    }
+
+    /// Forgets the neighbours and routes not heard from for too long, at time `now`
+    pub fn expire(&mut self, now: u32) {
+        let timeout = self.timeout;
+        let stale = |last_seen: u32| now.wrapping_sub(last_seen) > timeout;
+
+        for slot in self.neighbours.iter_mut() {
+            if slot.map(|n| stale(n.last_seen)).unwrap_or(false) {
+                *slot = None;
+            }
+        }
+
+        for slot in self.routes.iter_mut() {
+            if slot.map(|r| stale(r.last_seen)).unwrap_or(false) {
+                *slot = None;
+            }
+        }
+
+        self.select_parent();
+    }
+
+    fn update_neighbour(&mut self, address: Address, advert: &Advert, rssi: i8, now: u32) {
+        // Children are reachable even before they send any traffic
+        if advert.parent == self.address && address != self.address {
+            self.learn(address, address, now);
+        }
+
+        if self.role == Role::Gateway || address == self.address {
+            return;
+        }
+
+        let slot = self
+            .neighbours
+            .iter()
+            .position(|n| n.map(|n| n.address == address).unwrap_or(false));
+
+        // A neighbour routing through this node, or too far away, cannot be a parent
+        if advert.parent == self.address || advert.hops >= MAX_HOPS {
+            if let Some(i) = slot {
+                self.neighbours[i] = None;
+            }
+
+            self.select_parent();
+            return;
+        }
+
+        let rssi = i16::from(rssi);
+        let neighbour = match slot {
+            Some(i) => {
+                let old = self.neighbours[i].unwrap();
+
+                Neighbour {
+                    address,
+                    hops: advert.hops,
+                    // Smooth out fading
+                    rssi: (old.rssi * 3 + rssi) / 4,
+                    last_seen: now,
+                }
+            }
+            None => Neighbour {
+                address,
+                hops: advert.hops,
+                rssi,
+                last_seen: now,
+            },
+        };
+
+        let slot = slot.or_else(|| self.neighbours.iter().position(|n| n.is_none()));
+        let slot = match slot {
+            Some(i) => i,
+            None => {
+                // Table full: replace the worst neighbour, if the new one is better
+                let (i, worst) = self
+                    .neighbours
+                    .iter()
+                    .filter_map(|n| *n)
+                    .enumerate()
+                    .max_by_key(|&(_, n)| Router::cost(&n))
+                    .unwrap();
+
+                if Router::cost(&neighbour) >= Router::cost(&worst) {
+                    return;
+                }
+
+                i
+            }
+        };
+
+        self.neighbours[slot] = Some(neighbour);
+        self.select_parent();
+    }
+
+    fn select_parent(&mut self) {
+        if self.role == Role::Gateway {
+            return;
+        }
+
+        let current = self.parent.and_then(|p| {
+            self.neighbours
+                .iter()
+                .filter_map(|n| *n)
+                .find(|n| n.address == p.address)
+        });
+
+        let best = self
+            .neighbours
+            .iter()
+            .filter_map(|n| *n)
+            .min_by_key(Router::cost);
+
+        self.parent = match (current, best) {
+            (Some(current), Some(best))
+                if Router::cost(&best).0 == Router::cost(&current).0
+                    && best.hops == current.hops
+                    && best.rssi < current.rssi + RSSI_HYSTERESIS =>
+            {
+                Some(current)
+            }
+            (_, best) => best,
+        };
+    }
+
+    // Weak links first, then hop count, then signal strength: lower is better
+    fn cost(neighbour: &Neighbour) -> (bool, u8, i16) {
+        (
+            neighbour.rssi < i16::from(MIN_RSSI),
+            neighbour.hops,
+            -neighbour.rssi,
+        )
+    }
+
+    fn learn(&mut self, dst: Address, next: Address, now: u32) {
+        let route = Route {
+            dst,
+            next,
+            last_seen: now,
+        };
+
+        let slot = self
+            .routes
+            .iter()
+            .position(|r| r.map(|r| r.dst == dst).unwrap_or(false))
+            .or_else(|| self.routes.iter().position(|r| r.is_none()))
+            .unwrap_or_else(|| {
+                // Table full: replace the least recently used route
+                let mut oldest = 0;
+
+                for (i, r) in self.routes.iter().enumerate() {
+                    if let (Some(r), Some(o)) = (*r, self.routes[oldest]) {
+                        if now.wrapping_sub(r.last_seen) > now.wrapping_sub(o.last_seen) {
+                            oldest = i;
+                        }
+                    }
+                }
+
+                oldest
+            });
+
+        self.routes[slot] = Some(route);
+    }
+}
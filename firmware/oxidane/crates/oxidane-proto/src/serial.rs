@@ -0,0 +1,517 @@
+//! Serial link between the gateway board and the host
+//!
+//! The gateway board forwards the radio frames to the host over its UART, and the other way round.
+//! Every message carries a CRC and is framed with COBS (Consistent Overhead Byte Stuffing): a zero
+//! byte always marks the end of a message, so a receiver joining mid-stream resynchronises at the
+//! next one. Before COBS encoding a message is:
+//!
+//! | Offset | Size | Field                                               |
+//! |--------|------|-----------------------------------------------------|
+//! | 0      | 1    | Message type                                        |
+//! | 1      | n    | Message body                                        |
+//! | 1 + n  | 2    | CRC-16/CCITT-FALSE of the type and body, big endian |
+//!
+//! | Type   | Message         | Direction       | Body                                                  |
+//! |--------|-----------------|-----------------|-------------------------------------------------------|
+//! | `0x01` | `Received`      | gateway to host | timestamp (u32), RSSI (i8), channel (u8), radio frame |
+//! | `0x02` | `Transmit`      | host to gateway | channel (u8), radio frame                             |
+//! | `0x03` | `ChannelStatus` | gateway to host | channel (u8), noise RSSI (i8), CRC errors (u8)        |
+//! | `0x04` | `Listen`        | host to gateway | channel (u8)                                          |
+//!
+//! The timestamp of a received frame comes from the free-running millisecond clock of the gateway
+//! board and wraps after about 49 days; an RSSI of `-128` dBm means unknown. Multi-byte fields
+//! are big endian.
+//!
+//! The board listens on the channel of the last frame it transmitted, or of the last `Listen`
+//! message once its frames are sent. About once a second it reports the noise floor of that
+//! channel and the frames it dropped for a bad CRC, so that the host can keep the bad channels out
+//! of the hopping sequence (see `channel::Blacklist`).
+
+use FIFO_SIZE;
+
+/// Largest message before COBS encoding
+pub const MAX_MESSAGE_LEN: usize = 7 + FIFO_SIZE + 2;
+
+/// Largest COBS encoded message, delimiter included
+pub const MAX_ENCODED_LEN: usize = MAX_MESSAGE_LEN + MAX_MESSAGE_LEN / 254 + 2;
+
+/// Serial link error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The output buffer is too small for the encoded message
+    BufferTooSmall,
+    /// The radio frame does not fit in the Si4455 FIFO
+    FrameTooLarge,
+    /// The received message is longer than `MAX_ENCODED_LEN`
+    Overflow,
+    /// The received message is not valid COBS
+    Framing,
+    /// The received message is shorter than its header and CRC
+    Truncated,
+    /// The CRC of the received message does not match its contents
+    Crc,
+    /// The message type is unknown
+    UnknownMessage(u8),
+}
+
+/// Serial link message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Message<'a> {
+    /// Radio frame received by the gateway
+    Received {
+        /// Reception time, in milliseconds of the gateway clock
+        timestamp: u32,
+        /// RSSI of the frame in dBm, if known
+        rssi: Option<i8>,
+        /// Channel the frame was received on
+        channel: u8,
+        /// Radio frame
+        frame: &'a [u8],
+    },
+    /// Radio frame to be sent by the gateway
+    Transmit {
+        /// Channel to send the frame on
+        channel: u8,
+        /// Radio frame
+        frame: &'a [u8],
+    },
+    /// Quality of the channel the gateway listens on
+    ChannelStatus {
+        /// Channel the gateway listens on
+        channel: u8,
+        /// RSSI of the channel between frames in dBm, if known
+        noise: Option<i8>,
+        /// Frames dropped for a bad CRC since the last status
+        crc_errors: u8,
+    },
+    /// Channel for the gateway to listen on
+    Listen {
+        /// Channel to listen on
+        channel: u8,
+    },
+}
+
+impl<'a> Message<'a> {
+    /// Encodes the message into `buf`, delimiter included, returning the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut raw = [0; MAX_MESSAGE_LEN];
+
+        let len = match *self {
+            Message::Received {
+                timestamp,
+                rssi,
+                channel,
+                frame,
+            } => {
+                if frame.len() > FIFO_SIZE {
+                    return Err(Error::FrameTooLarge);
+                }
+
+                raw[0] = 0x01;
+                raw[1] = (timestamp >> 24) as u8;
+                raw[2] = (timestamp >> 16) as u8;
+                raw[3] = (timestamp >> 8) as u8;
+                raw[4] = timestamp as u8;
+                raw[5] = rssi.unwrap_or(i8::MIN) as u8;
+                raw[6] = channel;
+                raw[7..7 + frame.len()].copy_from_slice(frame);
+                7 + frame.len()
+            }
+            Message::Transmit { channel, frame } => {
+                if frame.len() > FIFO_SIZE {
+                    return Err(Error::FrameTooLarge);
+                }
+
+                raw[0] = 0x02;
+                raw[1] = channel;
+                raw[2..2 + frame.len()].copy_from_slice(frame);
+                2 + frame.len()
+            }
+            Message::ChannelStatus {
+                channel,
+                noise,
+                crc_errors,
+            } => {
+                raw[0] = 0x03;
+                raw[1] = channel;
+                raw[2] = noise.unwrap_or(i8::MIN) as u8;
+                raw[3] = crc_errors;
+                4
+            }
+            Message::Listen { channel } => {
+                raw[0] = 0x04;
+                raw[1] = channel;
+                2
+            }
+        };
+
+        let crc = crc16(&raw[..len]);
+        raw[len] = (crc >> 8) as u8;
+        raw[len + 1] = crc as u8;
+
+        cobs_encode(&raw[..len + 2], buf)
+    }
+
+    fn decode(buf: &'a [u8]) -> Result<Message<'a>, Error> {
+        if buf.len() < 3 {
+            return Err(Error::Truncated);
+        }
+
+        let (body, crc) = buf.split_at(buf.len() - 2);
+        if crc16(body) != (crc[0] as u16) << 8 | crc[1] as u16 {
+            return Err(Error::Crc);
+        }
+
+        match body[0] {
+            0x01 if body.len() >= 7 => Ok(Message::Received {
+                timestamp: (body[1] as u32) << 24
+                    | (body[2] as u32) << 16
+                    | (body[3] as u32) << 8
+                    | body[4] as u32,
+                rssi: rssi(body[5]),
+                channel: body[6],
+                frame: &body[7..],
+            }),
+            0x02 if body.len() >= 2 => Ok(Message::Transmit {
+                channel: body[1],
+                frame: &body[2..],
+            }),
+            0x03 if body.len() >= 4 => Ok(Message::ChannelStatus {
+                channel: body[1],
+                noise: rssi(body[2]),
+                crc_errors: body[3],
+            }),
+            0x04 if body.len() >= 2 => Ok(Message::Listen { channel: body[1] }),
+            0x01..=0x04 => Err(Error::Truncated),
+            kind => Err(Error::UnknownMessage(kind)),
+        }
+    }
+}
+
+/// Incremental decoder of the messages received from the serial link
+pub struct Decoder {
+    buf: [u8; MAX_ENCODED_LEN],
+    len: usize,
+    overflow: bool,
+}
+
+impl Decoder {
+    /// Creates a new decoder
+    pub fn new() -> Self {
+        Decoder {
+            buf: [0; MAX_ENCODED_LEN],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Feeds a received byte to the decoder, returning a message once it is complete
+    pub fn feed(&mut self, byte: u8) -> Result<Option<Message<'_>>, Error> {
+        if byte != 0 {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+
+            return Ok(None);
+        }
+
+        let len = self.len;
+        let overflow = self.overflow;
+        self.len = 0;
+        self.overflow = false;
+
+        if overflow {
+            return Err(Error::Overflow);
+        }
+
+        // Back to back delimiters
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let len = cobs_decode(&mut self.buf[..len])?;
+
+        Message::decode(&self.buf[..len]).map(Some)
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new()
+    }
+}
+
+fn rssi(byte: u8) -> Option<i8> {
+    match byte as i8 {
+        -128 => None,
+        rssi => Some(rssi),
+    }
+}
+
+/// CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xFFFF)
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF_u16;
+
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+fn cobs_encode(data: &[u8], buf: &mut [u8]) -> Result<usize, Error> {
+    if buf.len() < data.len() + data.len() / 254 + 2 {
+        return Err(Error::BufferTooSmall);
+    }
+
+    let mut code_index = 0;
+    let mut code = 1;
+    let mut out = 1;
+
+    for &byte in data {
+        if byte != 0 {
+            buf[out] = byte;
+            out += 1;
+            code += 1;
+        }
+
+        if byte == 0 || code == 0xFF {
+            buf[code_index] = code;
+            code_index = out;
+            code = 1;
+            out += 1;
+        }
+    }
+
+    buf[code_index] = code;
+    buf[out] = 0;
+
+    Ok(out + 1)
+}
+
+// Decodes in place, returning the decoded length
+fn cobs_decode(buf: &mut [u8]) -> Result<usize, Error> {
+    let mut read = 0;
+    let mut write = 0;
+
+    while read < buf.len() {
+        let code = buf[read] as usize;
+        read += 1;
+
+        if code == 0 || read + code - 1 > buf.len() {
+            return Err(Error::Framing);
+        }
+
+        for _ in 1..code {
+            buf[write] = buf[read];
+            write += 1;
+            read += 1;
+        }
+
+        // A full block is not followed by an implicit zero, neither is the last block
+        if code != 0xFF && read < buf.len() {
+            buf[write] = 0;
+            write += 1;
+        }
+    }
+
+    Ok(write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: &Message) {
+        let mut buf = [0; MAX_ENCODED_LEN];
+        let n = message.encode(&mut buf).unwrap();
+
+        // Only the delimiter is zero
+        assert!(buf[..n - 1].iter().all(|&b| b != 0));
+        assert_eq!(buf[n - 1], 0);
+
+        let mut decoder = Decoder::new();
+        for &byte in &buf[..n - 1] {
+            assert_eq!(decoder.feed(byte), Ok(None));
+        }
+        assert_eq!(decoder.feed(0), Ok(Some(*message)));
+    }
+
+    #[test]
+    fn messages() {
+        let frame = [0x12, 0x00, 0x34, 0x00, 0x00, 0x56];
+
+        round_trip(&Message::Received {
+            timestamp: 0xDEAD_BEEF,
+            rssi: Some(-72),
+            channel: 3,
+            frame: &frame,
+        });
+        round_trip(&Message::Received {
+            timestamp: 0,
+            rssi: None,
+            channel: 0,
+            frame: &[],
+        });
+        round_trip(&Message::Transmit {
+            channel: 7,
+            frame: &frame,
+        });
+        round_trip(&Message::ChannelStatus {
+            channel: 5,
+            noise: Some(-105),
+            crc_errors: 3,
+        });
+        round_trip(&Message::ChannelStatus {
+            channel: 0,
+            noise: None,
+            crc_errors: 0,
+        });
+        round_trip(&Message::Listen { channel: 2 });
+
+        // Largest frames, without and with zeros to stuff
+        round_trip(&Message::Transmit {
+            channel: 1,
+            frame: &[0xA5; FIFO_SIZE],
+        });
+        round_trip(&Message::Received {
+            timestamp: 1,
+            rssi: Some(-1),
+            channel: 1,
+            frame: &[0; FIFO_SIZE],
+        });
+
+        let mut buf = [0; MAX_ENCODED_LEN];
+        assert_eq!(
+            Message::Transmit {
+                channel: 0,
+                frame: &[0; FIFO_SIZE + 1],
+            }
+            .encode(&mut buf),
+            Err(Error::FrameTooLarge)
+        );
+    }
+
+    // Messages are shorter than a full COBS block, which only the framing itself can be tested with
+    #[test]
+    fn cobs() {
+        let mut data = [0; 600];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = (i % 255) as u8 + 1;
+        }
+
+        let mut mixed = [0; 301];
+        mixed[..254].copy_from_slice(&data[..254]);
+        mixed[255..300].copy_from_slice(&data[255..300]);
+
+        // Zeros only, runs of non-zero bytes around the 254 bytes of a full block, and both
+        let inputs: [&[u8]; 8] = [
+            &[0],
+            &[0; 3],
+            &data[..253],
+            &data[..254],
+            &data[..255],
+            &data[..508],
+            &data[..],
+            &mixed,
+        ];
+
+        for input in &inputs {
+            let mut buf = [0; 1024];
+            let n = cobs_encode(input, &mut buf).unwrap();
+
+            assert!(buf[..n - 1].iter().all(|&b| b != 0));
+            assert_eq!(buf[n - 1], 0);
+
+            let len = cobs_decode(&mut buf[..n - 1]).unwrap();
+            assert_eq!(&buf[..len], *input);
+        }
+
+        let mut buf = [0; 256];
+        assert_eq!(
+            cobs_encode(&data[..255], &mut buf),
+            Err(Error::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let mut raw = [0x04, 2, 0, 0];
+        let crc = crc16(&raw[..2]) ^ 0x0001;
+        raw[2] = (crc >> 8) as u8;
+        raw[3] = crc as u8;
+
+        let mut buf = [0; MAX_ENCODED_LEN];
+        let n = cobs_encode(&raw, &mut buf).unwrap();
+
+        let mut decoder = Decoder::new();
+        for &byte in &buf[..n - 1] {
+            assert_eq!(decoder.feed(byte), Ok(None));
+        }
+        assert_eq!(decoder.feed(0), Err(Error::Crc));
+    }
+
+    #[test]
+    fn overflow() {
+        let mut decoder = Decoder::new();
+        for _ in 0..MAX_ENCODED_LEN + 1 {
+            assert_eq!(decoder.feed(0x55), Ok(None));
+        }
+        assert_eq!(decoder.feed(0), Err(Error::Overflow));
+
+        // The next message is decoded normally
+        let message = Message::Listen { channel: 9 };
+        let mut buf = [0; MAX_ENCODED_LEN];
+        let n = message.encode(&mut buf).unwrap();
+
+        let mut last = Ok(None);
+        for &byte in &buf[..n] {
+            last = decoder.feed(byte);
+        }
+        assert_eq!(last, Ok(Some(message)));
+    }
+
+    #[test]
+    fn resynchronisation() {
+        let message = Message::Transmit {
+            channel: 4,
+            frame: &[1, 0, 2, 0, 3],
+        };
+        let mut buf = [0; MAX_ENCODED_LEN];
+        let n = message.encode(&mut buf).unwrap();
+
+        let mut decoder = Decoder::new();
+
+        // Line noise, then the tail of a message cut by a reset
+        let garbage = [0x13, 0x37, 0xFF, 0x42];
+        let mut errors = 0;
+        for &byte in garbage.iter().chain(&buf[3..n]) {
+            match decoder.feed(byte) {
+                Ok(None) => {}
+                Ok(Some(message)) => panic!("unexpected message: {:?}", message),
+                Err(_) => errors += 1,
+            }
+        }
+        assert_eq!(errors, 1);
+
+        // Delimiters alone are ignored
+        assert_eq!(decoder.feed(0), Ok(None));
+
+        let mut last = Ok(None);
+        for &byte in &buf[..n] {
+            last = decoder.feed(byte);
+        }
+        assert_eq!(last, Ok(Some(message)));
+    }
+}
@@ -0,0 +1,464 @@
+//! Sensor telemetry
+//!
+//! A telemetry report is a short header followed by a list of fixed point readings:
+//!
+//! | Offset | Size | Field                                               |
+//! |--------|------|-----------------------------------------------------|
+//! | 0      | 1    | Format version (`VERSION`)                          |
+//! | 1      | 1    | Number of readings `n`                              |
+//! | 2      | 2    | Battery voltage, mV                                 |
+//! | 4      | 1    | RSSI of the last downlink, dBm (`-128` if unknown)  |
+//! | 5      | 3n   | Readings: sensor tag (1 byte) + value (2 bytes)     |
+//!
+//! Multi-byte fields are big endian, like the Si4455 command set. Bytes following the last reading
+//! are ignored, so padded fixed-length packets decode fine.
+
+use core::fmt;
+
+/// Current version of the telemetry format
+pub const VERSION: u8 = 1;
+
+/// Maximum number of readings in a single report
+///
+/// A full report takes 53 bytes, which leaves room for the link-layer header in the Si4455 FIFO.
+pub const MAX_READINGS: usize = 16;
+
+const HEADER_LEN: usize = 5;
+const READING_LEN: usize = 3;
+
+// Encoding of a missing RSSI value
+const RSSI_UNKNOWN: i8 = -128;
+
+/// Telemetry error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The output buffer is too small for the encoded report
+    BufferTooSmall,
+    /// The report cannot hold more than `MAX_READINGS` readings
+    TooManyReadings,
+    /// The input ends before the end of the report
+    Truncated,
+    /// The report uses a format version unknown to this decoder
+    UnsupportedVersion(u8),
+    /// A reading carries an unknown sensor tag
+    UnknownSensor(u8),
+}
+
+/// Type of sensor a reading comes from
+///
+/// Each sensor type defines the fixed point scale of its readings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SensorType {
+    /// Temperature (DS18B20), in 0.01 °C
+    Temperature = 0x01,
+    /// Relative humidity (DHT11), in 0.01 %RH
+    Humidity = 0x02,
+    /// Light intensity (photoresistor), in 0.1 % of the full scale
+    Light = 0x03,
+    /// Atmospheric pressure (BMP180), in 0.1 hPa
+    Pressure = 0x04,
+    /// Water level switches, as a bit mask of `WATER_LEVEL_LOW` and `WATER_LEVEL_HIGH`
+    WaterLevel = 0x05,
+}
+
+/// The low water level switch is closed
+pub const WATER_LEVEL_LOW: i16 = 1 << 0;
+
+/// The high water level switch is closed
+pub const WATER_LEVEL_HIGH: i16 = 1 << 1;
+
+impl SensorType {
+    /// Returns the sensor type identified by `tag`, if any
+    pub fn from_tag(tag: u8) -> Option<SensorType> {
+        match tag {
+            0x01 => Some(SensorType::Temperature),
+            0x02 => Some(SensorType::Humidity),
+            0x03 => Some(SensorType::Light),
+            0x04 => Some(SensorType::Pressure),
+            0x05 => Some(SensorType::WaterLevel),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of raw units in one unit of measure
+    pub fn scale(&self) -> i32 {
+        match *self {
+            SensorType::Temperature | SensorType::Humidity => 100,
+            SensorType::Light | SensorType::Pressure => 10,
+            SensorType::WaterLevel => 1,
+        }
+    }
+
+    /// Returns the unit of measure of the readings
+    pub fn unit(&self) -> &'static str {
+        match *self {
+            SensorType::Temperature => "°C",
+            SensorType::Humidity => "%RH",
+            SensorType::Light => "%",
+            SensorType::Pressure => "hPa",
+            SensorType::WaterLevel => "",
+        }
+    }
+
+    /// Returns a short lowercase name for the sensor type
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SensorType::Temperature => "temperature",
+            SensorType::Humidity => "humidity",
+            SensorType::Light => "light",
+            SensorType::Pressure => "pressure",
+            SensorType::WaterLevel => "water_level",
+        }
+    }
+}
+
+/// A single sensor reading
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    /// Sensor the reading comes from
+    pub sensor: SensorType,
+    /// Fixed point value, see `SensorType` for the scale
+    pub value: i16,
+}
+
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scale = self.sensor.scale();
+        let value = i32::from(self.value);
+
+        write!(f, "{} ", self.sensor.name())?;
+
+        match scale {
+            1 => write!(f, "{:#04x}", value)?,
+            _ => {
+                let digits = if scale == 100 { 2 } else { 1 };
+                let sign = if value < 0 { "-" } else { "" };
+
+                write!(
+                    f,
+                    "{}{}.{:0width$} {}",
+                    sign,
+                    value.abs() / scale,
+                    value.abs() % scale,
+                    self.sensor.unit(),
+                    width = digits
+                )?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A telemetry report
+#[derive(Clone, Copy)]
+pub struct Telemetry {
+    /// Battery voltage in millivolts
+    pub battery_mv: u16,
+    /// RSSI of the last received downlink frame in dBm, if any
+    pub rssi: Option<i8>,
+    readings: [Reading; MAX_READINGS],
+    len: usize,
+}
+
+impl Telemetry {
+    /// Creates a new report without readings
+    pub fn new(battery_mv: u16, rssi: Option<i8>) -> Self {
+        Telemetry {
+            battery_mv,
+            rssi,
+            readings: [Reading {
+                sensor: SensorType::Temperature,
+                value: 0,
+            }; MAX_READINGS],
+            len: 0,
+        }
+    }
+
+    /// Appends a reading to the report
+    pub fn push(&mut self, reading: Reading) -> Result<(), Error> {
+        if self.len == MAX_READINGS {
+            return Err(Error::TooManyReadings);
+        }
+
+        self.readings[self.len] = reading;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Returns the readings in the report
+    pub fn readings(&self) -> &[Reading] {
+        &self.readings[..self.len]
+    }
+
+    /// Returns the size in bytes of the encoded report
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + READING_LEN * self.len
+    }
+
+    /// Encodes the report into `buf`, returning the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = self.encoded_len();
+
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall);
+        }
+
+        buf[0] = VERSION;
+        buf[1] = self.len as u8;
+        buf[2] = (self.battery_mv >> 8) as u8;
+        buf[3] = self.battery_mv as u8;
+        buf[4] = self.rssi.unwrap_or(RSSI_UNKNOWN) as u8;
+
+        for (reading, chunk) in self
+            .readings()
+            .iter()
+            .zip(buf[HEADER_LEN..len].chunks_mut(READING_LEN))
+        {
+            chunk[0] = reading.sensor as u8;
+            chunk[1] = (reading.value >> 8) as u8;
+            chunk[2] = reading.value as u8;
+        }
+
+        Ok(len)
+    }
+
+    /// Decodes a report from `buf`
+    pub fn decode(buf: &[u8]) -> Result<Telemetry, Error> {
+        if buf.len() < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+
+        if buf[0] != VERSION {
+            return Err(Error::UnsupportedVersion(buf[0]));
+        }
+
+        let count = buf[1] as usize;
+
+        if count > MAX_READINGS {
+            return Err(Error::TooManyReadings);
+        }
+
+        if buf.len() < HEADER_LEN + READING_LEN * count {
+            return Err(Error::Truncated);
+        }
+
+        let battery_mv = (buf[2] as u16) << 8 | buf[3] as u16;
+        let rssi = match buf[4] as i8 {
+            RSSI_UNKNOWN => None,
+            rssi => Some(rssi),
+        };
+
+        let mut telemetry = Telemetry::new(battery_mv, rssi);
+
+        for chunk in buf[HEADER_LEN..].chunks(READING_LEN).take(count) {
+            let sensor = SensorType::from_tag(chunk[0]).ok_or(Error::UnknownSensor(chunk[0]))?;
+
+            telemetry.push(Reading {
+                sensor,
+                value: ((chunk[1] as u16) << 8 | chunk[2] as u16) as i16,
+            })?;
+        }
+
+        Ok(telemetry)
+    }
+}
+
+impl fmt::Display for Telemetry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "battery {}.{:03} V",
+            self.battery_mv / 1000,
+            self.battery_mv % 1000
+        )?;
+
+        if let Some(rssi) = self.rssi {
+            write!(f, ", rssi {} dBm", rssi)?;
+        }
+
+        for reading in self.readings() {
+            write!(f, ", {}", reading)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use route;
+
+    fn report() -> Telemetry {
+        let mut telemetry = Telemetry::new(3012, Some(-87));
+
+        telemetry
+            .push(Reading {
+                sensor: SensorType::Temperature,
+                value: -1250,
+            })
+            .unwrap();
+        telemetry
+            .push(Reading {
+                sensor: SensorType::WaterLevel,
+                value: WATER_LEVEL_LOW,
+            })
+            .unwrap();
+
+        telemetry
+    }
+
+    #[test]
+    fn round_trip() {
+        let telemetry = report();
+        let mut buf = [0; 32];
+
+        let n = telemetry.encode(&mut buf).unwrap();
+        assert_eq!(n, telemetry.encoded_len());
+        assert_eq!(
+            &buf[..n],
+            &[VERSION, 2, 0x0B, 0xC4, 0xA9, 0x01, 0xFB, 0x1E, 0x05, 0x00, 0x01]
+        );
+
+        let decoded = Telemetry::decode(&buf[..n]).unwrap();
+        assert_eq!(decoded.battery_mv, 3012);
+        assert_eq!(decoded.rssi, Some(-87));
+        assert_eq!(decoded.readings(), telemetry.readings());
+    }
+
+    #[test]
+    fn unknown_rssi() {
+        let mut buf = [0; HEADER_LEN];
+
+        let n = Telemetry::new(0, None).encode(&mut buf).unwrap();
+        assert_eq!(buf[4], RSSI_UNKNOWN as u8);
+        assert_eq!(Telemetry::decode(&buf[..n]).unwrap().rssi, None);
+    }
+
+    #[test]
+    fn padding_is_ignored() {
+        let mut buf = [0xFF; 32];
+
+        report().encode(&mut buf).unwrap();
+        assert_eq!(
+            Telemetry::decode(&buf).unwrap().readings(),
+            report().readings()
+        );
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let mut buf = [0; 10];
+
+        assert_eq!(report().encode(&mut buf), Err(Error::BufferTooSmall));
+    }
+
+    #[test]
+    fn truncated() {
+        let mut buf = [0; 32];
+        let n = report().encode(&mut buf).unwrap();
+
+        for len in 0..n {
+            assert_eq!(
+                Telemetry::decode(&buf[..len]).err(),
+                Some(Error::Truncated),
+                "length {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut buf = [0; 32];
+        let n = report().encode(&mut buf).unwrap();
+        buf[0] = VERSION + 1;
+
+        assert_eq!(
+            Telemetry::decode(&buf[..n]).err(),
+            Some(Error::UnsupportedVersion(VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn unknown_sensor() {
+        let mut buf = [0; 32];
+        let n = report().encode(&mut buf).unwrap();
+        buf[HEADER_LEN + READING_LEN] = 0x7F;
+
+        assert_eq!(
+            Telemetry::decode(&buf[..n]).err(),
+            Some(Error::UnknownSensor(0x7F))
+        );
+    }
+
+    #[test]
+    fn too_many_readings() {
+        let mut telemetry = Telemetry::new(0, None);
+        let reading = Reading {
+            sensor: SensorType::Humidity,
+            value: 0,
+        };
+
+        for _ in 0..MAX_READINGS {
+            telemetry.push(reading).unwrap();
+        }
+        assert_eq!(telemetry.push(reading), Err(Error::TooManyReadings));
+
+        let mut buf = [0; 64];
+        telemetry.encode(&mut buf).unwrap();
+        buf[1] = MAX_READINGS as u8 + 1;
+        assert_eq!(Telemetry::decode(&buf).err(), Some(Error::TooManyReadings));
+    }
+
+    #[test]
+    fn max_size_fits_fifo() {
+        let mut telemetry = Telemetry::new(0, None);
+
+        for _ in 0..MAX_READINGS {
+            telemetry
+                .push(Reading {
+                    sensor: SensorType::Pressure,
+                    value: 10_132,
+                })
+                .unwrap();
+        }
+
+        assert!(telemetry.encoded_len() <= route::MAX_PAYLOAD);
+
+        let mut buf = [0; route::MAX_PAYLOAD];
+        assert_eq!(telemetry.encode(&mut buf), Ok(telemetry.encoded_len()));
+    }
+
+    #[test]
+    fn display() {
+        use core::fmt::Write;
+
+        struct Buf {
+            data: [u8; 128],
+            len: usize,
+        }
+
+        impl Write for Buf {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                let end = self.len + s.len();
+                self.data[self.len..end].copy_from_slice(s.as_bytes());
+                self.len = end;
+                Ok(())
+            }
+        }
+
+        let mut buf = Buf {
+            data: [0; 128],
+            len: 0,
+        };
+        write!(&mut buf, "{}", report()).unwrap();
+
+        assert_eq!(
+            &buf.data[..buf.len],
+            "battery 3.012 V, rssi -87 dBm, temperature -12.50 °C, water_level 0x01".as_bytes()
+        );
+    }
+}
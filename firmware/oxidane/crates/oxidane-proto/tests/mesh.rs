@@ -0,0 +1,444 @@
+//! Multi-hop routing on a simulated field
+//!
+//! Nodes are laid out on a grid with the gateway in a corner; radio links exist between nodes
+//! closer than `RANGE` and their RSSI follows a log-distance path loss model. The simulation lets
+//! the routing tree form, checks the hop counts and that every node reaches the gateway and back,
+//! then switches off a relay and checks that its children find another way. Single routers are
+//! fed hand-made adverts and frames to check parent selection and loop prevention.
+//!
+//! The firmware directory targets the MCU by default, so run these on the host with:
+//!
+//! ```text
+//! cargo test --target x86_64-unknown-linux-gnu
+//! ```
+
+extern crate oxidane_proto as proto;
+
+use std::collections::VecDeque;
+
+use proto::link::{Address, Frame, Header, Kind};
+use proto::route::{self, Advert, Packet, Received, Role, Router, MAX_HOPS};
+use proto::FIFO_SIZE;
+
+/// Radio range, in meters
+const RANGE: f64 = 250.0;
+
+/// Route timeout, in seconds
+const TIMEOUT: u32 = 60;
+
+/// Route advertisement interval, in seconds
+const ADVERT_INTERVAL: u32 = 20;
+
+/// Side of the grid
+const SIDE: usize = 4;
+
+/// Distance between the grid nodes, in meters
+const SPACING: f64 = 200.0;
+
+struct Node {
+    router: Router,
+    x: f64,
+    y: f64,
+    up: bool,
+    seq: u8,
+}
+
+struct Field {
+    nodes: Vec<Node>,
+    now: u32,
+}
+
+impl Field {
+    /// 4x4 grid, 200 m apart: the gateway is node 0, the last row is battery-powered
+    ///
+    /// Only orthogonal neighbours are in range of each other.
+    fn new() -> Self {
+        let mut nodes = Vec::new();
+
+        for i in 0..SIDE * SIDE {
+            let (row, col) = (i / SIDE, i % SIDE);
+            let role = match (row, col) {
+                (0, 0) => Role::Gateway,
+                (3, _) => Role::Leaf,
+                _ => Role::Relay,
+            };
+
+            nodes.push(Node {
+                router: Router::new(Address(i as u8), role, TIMEOUT),
+                x: col as f64 * SPACING,
+                y: row as f64 * SPACING,
+                up: true,
+                seq: 0,
+            });
+        }
+
+        Field { nodes, now: 0 }
+    }
+
+    /// Returns the RSSI of the link between `a` and `b`, if they can hear each other
+    fn rssi(&self, a: usize, b: usize) -> Option<i8> {
+        let (a, b) = (&self.nodes[a], &self.nodes[b]);
+        let d = ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt();
+
+        if a.up && b.up && d <= RANGE {
+            Some((-40.0 - 27.0 * d.max(1.0).log10()) as i8)
+        } else {
+            None
+        }
+    }
+
+    /// Transmits a frame from node `from`, collecting the packets delivered to their destination
+    fn transmit(
+        &mut self,
+        from: usize,
+        frame: &[u8],
+        delivered: &mut Vec<(usize, Packet<'static>)>,
+    ) {
+        let now = self.now;
+        let mut relays = Vec::new();
+
+        for to in 0..self.nodes.len() {
+            let rssi = match self.rssi(from, to) {
+                Some(rssi) if to != from => rssi,
+                _ => continue,
+            };
+
+            // Every frame is leaked to keep the simulation simple
+            let frame: &'static [u8] = Box::leak(frame.to_vec().into_boxed_slice());
+            let frame = Frame::decode(frame).unwrap();
+
+            match self.nodes[to]
+                .router
+                .receive(&frame, Some(rssi), now)
+                .unwrap()
+            {
+                Received::Local(packet) => delivered.push((to, packet)),
+                Received::Relay(packet) => relays.push((to, packet)),
+                Received::Ignored => {}
+            }
+        }
+
+        for (relay, packet) in relays {
+            let mut buf = [0; FIFO_SIZE];
+            let len = self.nodes[relay].router.encode(&packet, &mut buf).unwrap();
+
+            self.transmit(relay, &buf[..len], delivered);
+        }
+    }
+
+    /// Sends a packet from `src` to `dst`, returning the node that received it
+    fn send(&mut self, src: usize, dst: usize, kind: Kind) -> Option<usize> {
+        let node = &mut self.nodes[src];
+        node.seq = node.seq.wrapping_add(1);
+
+        let packet = Packet::new(
+            node.router.address(),
+            Address(dst as u8),
+            kind,
+            node.seq,
+            b"payload",
+        );
+
+        let mut buf = [0; FIFO_SIZE];
+        let len = node.router.encode(&packet, &mut buf).unwrap();
+
+        let mut delivered = Vec::new();
+        self.transmit(src, &buf[..len], &mut delivered);
+
+        delivered
+            .into_iter()
+            .find(|(_, p)| p.src == Address(src as u8) && p.kind == kind)
+            .map(|(to, _)| to)
+    }
+
+    /// Advances the simulation by `seconds`
+    fn run(&mut self, seconds: u32) {
+        for _ in 0..seconds {
+            self.now += 1;
+
+            for i in 0..self.nodes.len() {
+                let node = &mut self.nodes[i];
+
+                // Spread the adverts over the interval
+                if !node.up || !(self.now + i as u32).is_multiple_of(ADVERT_INTERVAL) {
+                    continue;
+                }
+
+                if let Some(advert) = node.router.advert() {
+                    let mut payload = [0; route::ADVERT_LEN];
+                    let n = advert.encode(&mut payload).unwrap();
+                    let packet = Packet::new(
+                        node.router.address(),
+                        Address::BROADCAST,
+                        Kind::RouteAdvert,
+                        0,
+                        &payload[..n],
+                    );
+
+                    let mut buf = [0; FIFO_SIZE];
+                    let len = node.router.encode(&packet, &mut buf).unwrap();
+
+                    self.transmit(i, &buf[..len], &mut Vec::new());
+                }
+            }
+
+            for node in self.nodes.iter_mut() {
+                node.router.expire(self.now);
+            }
+        }
+    }
+
+    /// Returns the shortest hop count from every node to the gateway, through the relays that are
+    /// up
+    fn shortest_hops(&self) -> Vec<Option<u8>> {
+        let mut hops = vec![None; self.nodes.len()];
+        let mut queue = VecDeque::new();
+
+        hops[0] = Some(0);
+        queue.push_back(0);
+
+        while let Some(i) = queue.pop_front() {
+            // Leaves, on the last row, never forward frames
+            if i / SIDE == SIDE - 1 {
+                continue;
+            }
+
+            for j in 0..self.nodes.len() {
+                if hops[j].is_none() && self.rssi(i, j).is_some() && i != j {
+                    hops[j] = hops[i].map(|h| h + 1);
+                    queue.push_back(j);
+                }
+            }
+        }
+
+        hops
+    }
+
+    /// Checks the hop counts and that every node can talk to the gateway and back
+    fn check(&mut self) {
+        let hops = self.shortest_hops();
+
+        for i in 1..self.nodes.len() {
+            if !self.nodes[i].up {
+                continue;
+            }
+
+            let router = &self.nodes[i].router;
+            assert_eq!(router.hops(), hops[i], "hops of node {}", i);
+
+            // The parent is one hop closer to the gateway
+            let parent = router.parent().unwrap().0 as usize;
+            assert_eq!(hops[parent].map(|h| h + 1), hops[i], "parent of node {}", i);
+            assert!(self.rssi(i, parent).is_some());
+
+            assert_eq!(
+                self.send(i, 0, Kind::Telemetry),
+                Some(0),
+                "uplink of node {}",
+                i
+            );
+            assert_eq!(
+                self.send(0, i, Kind::Command),
+                Some(i),
+                "downlink of node {}",
+                i
+            );
+        }
+    }
+}
+
+/// Feeds `router` an advert from `from`, received with `rssi` dBm at time `now`
+fn advert(router: &mut Router, from: u8, hops: u8, parent: u8, rssi: i8, now: u32) {
+    let mut payload = [0; route::ADVERT_LEN];
+    let advert = Advert {
+        hops,
+        parent: Address(parent),
+    };
+    let n = advert.encode(&mut payload).unwrap();
+
+    let header = Header {
+        kind: Kind::RouteAdvert,
+        dst: Address::BROADCAST,
+        src: Address(from),
+        seq: 0,
+    };
+
+    match router.receive(&Frame::new(header, &payload[..n]), Some(rssi), now) {
+        Ok(Received::Ignored) => {}
+        other => panic!("advert not consumed: {:?}", other),
+    }
+}
+
+/// Feeds `router` a routed frame from `from`, carrying a packet from `src` to `dst`
+fn routed(router: &mut Router, from: u8, src: u8, dst: u8, ttl: u8) -> Received<'static> {
+    let payload: &'static [u8] =
+        Box::leak(vec![src, dst, ttl, Kind::Telemetry as u8, 0].into_boxed_slice());
+
+    let header = Header {
+        kind: Kind::Routed,
+        dst: router.address(),
+        src: Address(from),
+        seq: 0,
+    };
+
+    router
+        .receive(&Frame::new(header, payload), Some(-60), 0)
+        .unwrap()
+}
+
+#[test]
+fn tree_forms() {
+    let mut field = Field::new();
+
+    // Every advert interval extends the tree by at least one hop
+    field.run(8 * ADVERT_INTERVAL);
+
+    // On the grid, the hop count is the Manhattan distance from the gateway
+    for i in 1..field.nodes.len() {
+        let (row, col) = (i / SIDE, i % SIDE);
+
+        assert_eq!(field.nodes[i].router.hops(), Some((row + col) as u8));
+    }
+
+    field.check();
+}
+
+#[test]
+fn relay_drops_out() {
+    let mut field = Field::new();
+    field.run(8 * ADVERT_INTERVAL);
+
+    // Switch off the relay closest to the gateway, on the first column
+    field.nodes[4].up = false;
+
+    // Its last advert is at most an interval old, so its child keeps it until the timeout
+    field.run(TIMEOUT - ADVERT_INTERVAL);
+    assert_eq!(field.nodes[8].router.parent(), Some(Address(4)));
+    assert_eq!(field.nodes[0].router.next_hop(Address(4)), Some(Address(4)));
+
+    field.run(ADVERT_INTERVAL + 1);
+    assert_eq!(field.nodes[0].router.next_hop(Address(4)), None);
+
+    for node in &field.nodes {
+        assert_ne!(node.router.parent(), Some(Address(4)));
+    }
+
+    // The first column now goes around through the second one
+    field.run(8 * ADVERT_INTERVAL);
+    assert_eq!(field.nodes[8].router.hops(), Some(4));
+    assert_eq!(field.nodes[12].router.hops(), Some(5));
+
+    field.check();
+}
+
+#[test]
+fn parent_by_hops_then_rssi() {
+    let mut router = Router::new(Address(10), Role::Leaf, TIMEOUT);
+
+    advert(&mut router, 1, 1, 0, -80, 0);
+    assert_eq!(router.parent(), Some(Address(1)));
+    assert_eq!(router.hops(), Some(2));
+
+    // Same hop count, much stronger link
+    advert(&mut router, 2, 1, 0, -70, 0);
+    assert_eq!(router.parent(), Some(Address(2)));
+
+    // Fewer hops beat a stronger link
+    advert(&mut router, 0, 0, 0, -95, 0);
+    assert_eq!(router.parent(), Some(Address(0)));
+    assert_eq!(router.hops(), Some(1));
+}
+
+#[test]
+fn parent_hysteresis() {
+    let mut router = Router::new(Address(10), Role::Leaf, TIMEOUT);
+
+    advert(&mut router, 1, 1, 0, -80, 0);
+
+    // Slightly stronger links do not make the parent change
+    advert(&mut router, 2, 1, 0, -76, 0);
+    assert_eq!(router.parent(), Some(Address(1)));
+
+    advert(&mut router, 3, 1, 0, -70, 0);
+    assert_eq!(router.parent(), Some(Address(3)));
+}
+
+#[test]
+fn weak_links_last() {
+    let mut router = Router::new(Address(10), Role::Leaf, TIMEOUT);
+
+    // Only used while there is no alternative
+    advert(&mut router, 1, 0, 0, route::MIN_RSSI - 5, 0);
+    assert_eq!(router.parent(), Some(Address(1)));
+
+    advert(&mut router, 2, 3, 0, -90, 0);
+    assert_eq!(router.parent(), Some(Address(2)));
+    assert_eq!(router.hops(), Some(4));
+}
+
+#[test]
+fn loop_rejection() {
+    let mut router = Router::new(Address(10), Role::Relay, TIMEOUT);
+
+    advert(&mut router, 1, 2, 0, -90, 0);
+    assert_eq!(router.parent(), Some(Address(1)));
+
+    // A neighbour routing through this node is never a parent, however good
+    advert(&mut router, 2, 0, 10, -50, 0);
+    assert_eq!(router.parent(), Some(Address(1)));
+
+    // Nor is one too far from the gateway
+    advert(&mut router, 3, MAX_HOPS, 0, -50, 0);
+    assert_eq!(router.parent(), Some(Address(1)));
+
+    // The parent switching to this node is dropped
+    advert(&mut router, 1, 3, 10, -90, 1);
+    assert_eq!(router.parent(), None);
+    assert_eq!(router.advert(), None);
+}
+
+#[test]
+fn relaying() {
+    let mut router = Router::new(Address(10), Role::Relay, TIMEOUT);
+    advert(&mut router, 1, 0, 1, -70, 0);
+
+    // Upward packets follow the parent, with one hop less to live
+    match routed(&mut router, 20, 20, 0, 4) {
+        Received::Relay(packet) => assert_eq!((packet.dst, packet.ttl), (Address(0), 3)),
+        other => panic!("not relayed: {:?}", other),
+    }
+
+    // The source is now reachable through the node that forwarded its packet
+    assert_eq!(router.next_hop(Address(20)), Some(Address(20)));
+
+    // Packets whose time to live runs out are dropped
+    match routed(&mut router, 20, 20, 0, 1) {
+        Received::Ignored => {}
+        other => panic!("relayed: {:?}", other),
+    }
+
+    // Packets are never sent back to the previous hop
+    match routed(&mut router, 1, 0, 0, 4) {
+        Received::Ignored => {}
+        other => panic!("bounced: {:?}", other),
+    }
+
+    // Packets for this node are delivered
+    match routed(&mut router, 1, 0, 10, 4) {
+        Received::Local(packet) => assert_eq!(packet.src, Address(0)),
+        other => panic!("not delivered: {:?}", other),
+    }
+}
+
+#[test]
+fn leaves_do_not_relay() {
+    let mut router = Router::new(Address(10), Role::Leaf, TIMEOUT);
+    advert(&mut router, 1, 0, 1, -70, 0);
+
+    assert_eq!(router.advert(), None);
+
+    match routed(&mut router, 20, 20, 0, 4) {
+        Received::Ignored => {}
+        other => panic!("relayed: {:?}", other),
+    }
+}
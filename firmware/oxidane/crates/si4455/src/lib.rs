@@ -2,6 +2,7 @@
 
 extern crate embedded_hal as hal;
 extern crate generic_array;
+extern crate nb;
 
 mod defs;
 pub use defs::*;
@@ -15,6 +16,8 @@ use hal::spi::{Mode, Phase, Polarity};
 pub enum Error<E> {
     CommandError,
     Busy,
+    BufferTooSmall,
+    Crc,
     Spi(E),
 }
 
@@ -134,6 +137,14 @@ where
         })
     }
 
+    /// Returns `true` while a packet is being transmitted.
+    pub fn is_transmitting(&mut self) -> Result<bool, Error<E>> {
+        // The main state is in the lower nibble
+        let state = self.state()?.state & 0x0F;
+
+        Ok(state == State::Tx as u8 || state == State::TxTune as u8)
+    }
+
     /// Starts transmission of a packet.
     ///
     /// Note: this function does not wait for the transmission to finish.
@@ -148,21 +159,15 @@ where
         // Wait for the device to finish the previous transmission
         let mut retries = 0xF000;
 
-        while retries > 0 {
-            let state = self.state()?.state;
+        while self.is_transmitting()? {
+            retries -= 1;
 
-            if (state & State::Tx as u8) == 0 && (state & State::TxTune as u8) == 0 {
-                break;
-            } else {
-                retries -= 1;
+            // If the device is still busy exit with an error
+            if retries == 0 {
+                return Err(Error::Busy);
             }
         }
 
-        // If the device is still busy exit with an error
-        if retries == 0 {
-            return Err(Error::Busy);
-        }
-
         // Write to TX FIFO
         self.write(Command::WRITE_TX_FIFO as u8, packet)?;
 
@@ -179,6 +184,63 @@ where
         )
     }
 
+    /// Reads a received packet into `buf`, returning its length.
+    ///
+    /// Packets failing the CRC check are dropped and reported as `Error::Crc`. The radio stays in
+    /// RX mode after a packet has been received, so there is no need to call `listen` again.
+    pub fn receive(&mut self, buf: &mut [u8]) -> nb::Result<usize, Error<E>> {
+        // Nothing to do until the radio asserts its interrupt line
+        if self.nirq.is_high() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let ints = self.get_int_status()?;
+
+        if ints.ph_pending & PH_CRC_ERROR != 0 {
+            // Drop the corrupted packet
+            self.fifo_info(FIFO_RESET_RX)?;
+            return Err(nb::Error::Other(Error::Crc));
+        }
+
+        if ints.ph_pending & PH_PACKET_RX == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let count = self.fifo_info(0)?.0 as usize;
+
+        if count > buf.len() {
+            self.fifo_info(FIFO_RESET_RX)?;
+            return Err(nb::Error::Other(Error::BufferTooSmall));
+        }
+
+        self.read_rx_fifo(&mut buf[..count])?;
+
+        Ok(count)
+    }
+
+    /// Returns the RSSI latched during the reception of the last packet, in dBm.
+    pub fn latched_rssi(&mut self) -> Result<i16, Error<E>> {
+        let mut resp = [0; 8];
+
+        // Leave the modem interrupts pending
+        self.transfer(Command::GET_MODEM_STATUS as u8, &[0xFF], &mut resp)?;
+
+        // RSSI [dBm] = RSSI / 2 - MODEM_RSSI_COMP - 70, with MODEM_RSSI_COMP = 0x40 [EZRadio API]
+        Ok(resp[3] as i16 / 2 - 0x40 - 70)
+    }
+
+    /// Returns the RSSI of the current channel, in dBm.
+    ///
+    /// Sampled while no packet is being received, this is the noise floor of the channel.
+    pub fn current_rssi(&mut self) -> Result<i16, Error<E>> {
+        let mut resp = [0; 8];
+
+        // Leave the modem interrupts pending
+        self.transfer(Command::GET_MODEM_STATUS as u8, &[0xFF], &mut resp)?;
+
+        Ok(resp[2] as i16 / 2 - 0x40 - 70)
+    }
+
     /// Puts the radio in RX mode, listening for new packets.
     pub fn listen(&mut self, channel: u8, length: u16) -> Result<(), Error<E>> {
         // Clear pending interrupts
@@ -198,6 +260,13 @@ where
         )
     }
 
+    /// Puts the radio in the Sleep state, with the lowest consumption.
+    ///
+    /// The next command wakes the radio up, e.g. `listen` or `transmit`.
+    pub fn sleep(&mut self) -> Result<(), Error<E>> {
+        self.write(Command::CHANGE_STATE as u8, &[State::Sleep as u8])
+    }
+
     /// Resets the radio to its initial state [AN692, §4.4].
     fn reset<D>(&mut self, delay: &mut D) -> Result<(), Error<E>>
     where
@@ -263,6 +332,26 @@ where
         Ok(())
     }
 
+    /// Retrieves the RX FIFO byte count and TX FIFO free space, optionally resetting the FIFOs.
+    fn fifo_info(&mut self, reset: u8) -> Result<(u8, u8), Error<E>> {
+        let mut resp = [0; 2];
+
+        self.transfer(Command::FIFO_INFO as u8, &[reset], &mut resp)?;
+
+        Ok((resp[0], resp[1]))
+    }
+
+    /// Reads a chunk of data from the RX FIFO.
+    fn read_rx_fifo(&mut self, rx: &mut [u8]) -> Result<(), Error<E>> {
+        // Reading the FIFO does not need to wait for CTS
+        self.ncs.set_low();
+        self.spi.write(&[Command::READ_RX_FIFO as u8])?;
+        self.spi.transfer(rx)?;
+        self.ncs.set_high();
+
+        Ok(())
+    }
+
     /// Blocks until the radio is ready to receive a new command.
     fn wait_for_cts(&mut self) -> Result<(), Error<E>> {
         // Send a NOP command and wait for the response, it means the radio is ready
@@ -320,6 +409,13 @@ pub const MODE: Mode = Mode {
 // Clear-to-send
 const CTS_READY: u8 = 0xFF;
 
+// Packet handler interrupts
+const PH_CRC_ERROR: u8 = 0x08;
+const PH_PACKET_RX: u8 = 0x10;
+
+// FIFO_INFO arguments
+const FIFO_RESET_RX: u8 = 0x02;
+
 // Radio commands
 #[allow(unused)]
 #[allow(non_camel_case_types)]
@@ -328,13 +424,17 @@ enum Command {
     PART_INFO = 0x01,
     POWER_UP = 0x02,
     FUNC_INFO = 0x10,
+    FIFO_INFO = 0x15,
     EZCONFIG_CHECK = 0x19,
     GET_INT_STATUS = 0x20,
+    GET_MODEM_STATUS = 0x22,
     START_TX = 0x31,
     START_RX = 0x32,
     REQUEST_DEVICE_STATE = 0x33,
+    CHANGE_STATE = 0x34,
     READ_CMD_BUFF = 0x44,
     WRITE_TX_FIFO = 0x66,
+    READ_RX_FIFO = 0x77,
 }
 
 // Device states
@@ -0,0 +1,237 @@
+//! Analog to Digital Converter (ADC)
+
+use core::ptr;
+
+use hal::adc::{Channel, OneShot};
+use nb;
+use stm32l151::{ADC, RCC};
+use void::Void;
+
+use gpio::gpioa::{PA0, PA1, PA2, PA3, PA4, PA5, PA6, PA7};
+use gpio::gpiob::{PB0, PB1, PB12, PB13, PB14, PB15};
+use gpio::Analog;
+use rcc::APB2;
+
+// Factory calibration values, measured with VDDA = 3.0 V
+const VREFINT_CAL: u32 = 0x1FF8_0078;
+const TS_CAL1: u32 = 0x1FF8_007A;
+const TS_CAL2: u32 = 0x1FF8_007E;
+
+/// VDDA during the factory calibration, in millivolts
+const CAL_VDDA: u32 = 3000;
+/// Temperature of the `TS_CAL1` measurement, in hundredths of degree Celsius
+const TS_CAL1_TEMP: i32 = 30_00;
+/// Temperature of the `TS_CAL2` measurement, in hundredths of degree Celsius
+const TS_CAL2_TEMP: i32 = 110_00;
+
+/// Highest value of a 12-bit conversion
+const FULL_SCALE: u32 = 4095;
+
+/// Sample time of the conversions, in ADC clock cycles
+///
+/// Sources with a higher impedance need a longer sample time.
+#[derive(Clone, Copy)]
+pub enum SampleTime {
+    /// 4 cycles
+    Cycles4 = 0b000,
+    /// 9 cycles
+    Cycles9 = 0b001,
+    /// 16 cycles
+    Cycles16 = 0b010,
+    /// 24 cycles
+    Cycles24 = 0b011,
+    /// 48 cycles
+    Cycles48 = 0b100,
+    /// 96 cycles
+    Cycles96 = 0b101,
+    /// 192 cycles
+    Cycles192 = 0b110,
+    /// 384 cycles
+    Cycles384 = 0b111,
+}
+
+/// Internal voltage reference, channel 17
+pub struct VRefInt {
+    _0: (),
+}
+
+/// Internal temperature sensor, channel 16
+pub struct TemperatureSensor {
+    _0: (),
+}
+
+macro_rules! channels {
+    ($($Pin:ty: $channel:expr,)+) => {
+        $(
+            impl Channel<ADC> for $Pin {
+                type ID = u8;
+
+                fn channel() -> u8 {
+                    $channel
+                }
+            }
+        )+
+    }
+}
+
+channels! {
+    PA0<Analog>: 0,
+    PA1<Analog>: 1,
+    PA2<Analog>: 2,
+    PA3<Analog>: 3,
+    PA4<Analog>: 4,
+    PA5<Analog>: 5,
+    PA6<Analog>: 6,
+    PA7<Analog>: 7,
+    PB0<Analog>: 8,
+    PB1<Analog>: 9,
+    TemperatureSensor: 16,
+    VRefInt: 17,
+    PB12<Analog>: 18,
+    PB13<Analog>: 19,
+    PB14<Analog>: 20,
+    PB15<Analog>: 21,
+}
+
+/// ADC performing single 12-bit conversions
+pub struct Adc {
+    adc: ADC,
+    sample_time: SampleTime,
+}
+
+impl Adc {
+    /// Powers up the ADC
+    ///
+    /// The ADC is always clocked by the HSI oscillator, which gets enabled if it was not already.
+    pub fn adc(adc: ADC, apb2: &mut APB2) -> Self {
+        // NOTE(unsafe) the clock configuration is frozen, this only adds the HSI oscillator
+        let rcc = unsafe { &*RCC::ptr() };
+        rcc.cr.modify(|_, w| w.hsion().set_bit());
+        while rcc.cr.read().hsirdy().bit_is_clear() {}
+
+        // enable or reset ADC
+        apb2.enr().modify(|_, w| w.adc1en().set_bit());
+        apb2.rstr().modify(|_, w| w.adc1rst().set_bit());
+        apb2.rstr().modify(|_, w| w.adc1rst().clear_bit());
+
+        // ADON: power up the ADC, defaults to single 12-bit right aligned conversions
+        adc.cr2.write(|w| w.adon().set_bit());
+        while adc.sr.read().adons().bit_is_clear() {}
+
+        Adc {
+            adc,
+            sample_time: SampleTime::Cycles16,
+        }
+    }
+
+    /// Sets the sample time of the conversions of the external channels
+    pub fn set_sample_time(&mut self, sample_time: SampleTime) {
+        self.sample_time = sample_time;
+    }
+
+    /// Enables the internal voltage reference and temperature sensor
+    ///
+    /// Both take up to 10 µs to start up, conversions started before then are inaccurate.
+    pub fn enable_internal_channels(&mut self) -> (VRefInt, TemperatureSensor) {
+        self.adc.ccr.modify(|_, w| w.tsvrefe().set_bit());
+
+        (VRefInt { _0: () }, TemperatureSensor { _0: () })
+    }
+
+    /// Measures the analog supply voltage, in millivolts
+    ///
+    /// The result is the reference to use in `millivolts` and `read_temperature`, and the battery
+    /// voltage when the microcontroller is powered directly by the battery.
+    pub fn read_vdda(&mut self, _vref: &mut VRefInt) -> u16 {
+        let sample = self.convert(VRefInt::channel(), SampleTime::Cycles192);
+
+        // NOTE(unsafe) read-only calibration value, stored by the factory
+        let cal = u32::from(unsafe { ptr::read_volatile(VREFINT_CAL as *const u16) });
+
+        (CAL_VDDA * cal / u32::from(sample).max(1)) as u16
+    }
+
+    /// Measures the temperature of the chip, in hundredths of degree Celsius
+    ///
+    /// `vdda` is the analog supply voltage in millivolts, see `read_vdda`.
+    pub fn read_temperature(&mut self, _sensor: &mut TemperatureSensor, vdda: u16) -> i32 {
+        // The sensor needs a sampling time of at least 10 µs
+        let sample = self.convert(TemperatureSensor::channel(), SampleTime::Cycles384);
+
+        // NOTE(unsafe) read-only calibration values, stored by the factory
+        let (cal1, cal2) = unsafe {
+            (
+                i32::from(ptr::read_volatile(TS_CAL1 as *const u16)),
+                i32::from(ptr::read_volatile(TS_CAL2 as *const u16)),
+            )
+        };
+
+        // The sample as if measured with the VDDA of the calibration, with one more digit
+        let sample = (u32::from(sample) * u32::from(vdda) * 10 / CAL_VDDA) as i32;
+
+        (sample - cal1 * 10) * (TS_CAL2_TEMP - TS_CAL1_TEMP) / ((cal2 - cal1) * 10) + TS_CAL1_TEMP
+    }
+
+    /// Converts a sample to millivolts, given the analog supply voltage `vdda` in millivolts
+    pub fn millivolts(sample: u16, vdda: u16) -> u16 {
+        (u32::from(sample) * u32::from(vdda) / FULL_SCALE) as u16
+    }
+
+    /// Releases the ADC peripheral, powering it down
+    pub fn free(self) -> ADC {
+        self.adc.cr2.write(|w| w.adon().clear_bit());
+
+        self.adc
+    }
+
+    /// Performs a single conversion of `channel`
+    fn convert(&mut self, channel: u8, sample_time: SampleTime) -> u16 {
+        // SMP: 3 bits per channel, SMPR3 holds channels 0 to 9, SMPR2 10 to 19, SMPR1 20 to 29
+        let offset = 3 * (u32::from(channel) % 10);
+        let mask = !(0b111 << offset);
+        let smp = (sample_time as u32) << offset;
+
+        match channel {
+            0...9 => self
+                .adc
+                .smpr3
+                .modify(|r, w| unsafe { w.bits((r.bits() & mask) | smp) }),
+            10...19 => self
+                .adc
+                .smpr2
+                .modify(|r, w| unsafe { w.bits((r.bits() & mask) | smp) }),
+            _ => self
+                .adc
+                .smpr1
+                .modify(|r, w| unsafe { w.bits((r.bits() & mask) | smp) }),
+        }
+
+        // L: a single conversion in the regular sequence
+        // SQ1: `channel` as its first conversion
+        self.adc.sqr1.modify(|_, w| unsafe { w.l().bits(0) });
+        self.adc.sqr5.write(|w| unsafe { w.sq1().bits(channel) });
+
+        // The new configuration takes a few cycles to apply
+        while self.adc.sr.read().rcnr().bit_is_set() {}
+
+        self.adc.cr2.modify(|_, w| w.swstart().set_bit());
+        while self.adc.sr.read().eoc().bit_is_clear() {}
+
+        // Reading DR clears EOC
+        self.adc.dr.read().regular_data().bits()
+    }
+}
+
+impl<WORD, PIN> OneShot<ADC, WORD, PIN> for Adc
+where
+    WORD: From<u16>,
+    PIN: Channel<ADC, ID = u8>,
+{
+    type Error = Void;
+
+    fn read(&mut self, _pin: &mut PIN) -> nb::Result<WORD, Void> {
+        let sample_time = self.sample_time;
+
+        Ok(WORD::from(self.convert(PIN::channel(), sample_time)))
+    }
+}
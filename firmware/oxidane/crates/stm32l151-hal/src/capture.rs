@@ -0,0 +1,483 @@
+//! Input capture and pulse measurement
+//!
+//! A `CaptureTimer` counts at about 1 MHz, then one of its channels latches the counter on the
+//! edges of a pin (see `timer::ChannelPin`). The counter overflows are counted in software to
+//! extend the timestamps to 32 bits, as long as the channel is polled at least once per counter
+//! period, 65.5 ms.
+//!
+//! To count pulses instead, e.g. the ones of a flow meter, a `PulseCounter` clocks the counter of
+//! TIM2, TIM3, TIM4 or TIM9 with the edges of a channel 1 or 2 pin: the hardware does the
+//! counting, without interrupts, and the count is read at leisure.
+
+use core::marker::PhantomData;
+
+use cast::u16;
+use nb;
+use stm32l151::{TIM10, TIM11, TIM2, TIM3, TIM4, TIM9};
+
+use rcc::{APB1, APB2, Clocks};
+use timer::{self, ChannelPin, C1, C2, C3, C4};
+
+/// Frequency the counter aims for, the resolution of the measurements
+const TICK_FREQ: u32 = 1_000_000;
+
+/// Input capture error
+#[derive(Debug)]
+pub enum Error {
+    /// An edge was captured before the previous one was read, and was lost
+    Overcapture,
+    /// No edge was captured before the timeout
+    Timeout,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+/// Signal edge
+#[derive(Clone, Copy)]
+pub enum Edge {
+    /// Low to high transition
+    Rising,
+    /// High to low transition
+    Falling,
+    /// Any transition
+    Both,
+}
+
+/// Level of a pulse
+#[derive(Clone, Copy)]
+pub enum Pulse {
+    /// Starts with a rising edge, ends with a falling edge
+    High,
+    /// Starts with a falling edge, ends with a rising edge
+    Low,
+}
+
+/// Number of edges per capture
+#[derive(Clone, Copy)]
+pub enum Prescaler {
+    /// Every edge is captured
+    Div1 = 0b00,
+    /// One edge every 2 is captured
+    Div2 = 0b01,
+    /// One edge every 4 is captured
+    Div4 = 0b10,
+    /// One edge every 8 is captured
+    Div8 = 0b11,
+}
+
+/// Timer configured as a free running counter for input capture
+pub struct CaptureTimer<TIM> {
+    tim: TIM,
+    freq: u32,
+}
+
+/// Input capture channel of a timer
+///
+/// The channel owns the timer, the only one updating the overflow count.
+pub struct InputCapture<TIM, CHANNEL, PIN> {
+    tim: TIM,
+    pin: PIN,
+    freq: u32,
+    overflows: u16,
+    _channel: PhantomData<CHANNEL>,
+}
+
+impl<TIM, CHANNEL, PIN> InputCapture<TIM, CHANNEL, PIN> {
+    /// Returns the frequency of the counter
+    ///
+    /// The timer clock is divided down to a frequency as close to 1 MHz as possible.
+    pub fn frequency(&self) -> u32 {
+        self.freq
+    }
+
+    /// Converts a number of counter ticks to microseconds
+    pub fn micros(&self, ticks: u32) -> u32 {
+        (u64::from(ticks) * u64::from(TICK_FREQ) / u64::from(self.freq)) as u32
+    }
+}
+
+/// Timer counting the edges of a pin
+///
+/// The counter is clocked by the channel input (external clock mode 1) and wraps around after
+/// 65535 edges.
+pub struct PulseCounter<TIM, CHANNEL, PIN> {
+    tim: TIM,
+    pin: PIN,
+    _channel: PhantomData<CHANNEL>,
+}
+
+// FIXME this should be a "closed" trait
+/// Channel whose input can clock the counter -- DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait CounterInput {
+    #[doc(hidden)]
+    const INDEX: u32;
+    /// Trigger selection of the filtered channel input, SMCR.TS
+    #[doc(hidden)]
+    const TS: u8;
+}
+
+unsafe impl CounterInput for C1 {
+    const INDEX: u32 = 0;
+    const TS: u8 = 0b101;
+}
+
+unsafe impl CounterInput for C2 {
+    const INDEX: u32 = 1;
+    const TS: u8 = 0b110;
+}
+
+/// Returns the polarity bits of a channel in CCER, CCxNP and CCxP
+fn polarity(edge: Edge) -> u32 {
+    match edge {
+        Edge::Rising => 0b0000,
+        Edge::Falling => 0b0010,
+        Edge::Both => 0b1010,
+    }
+}
+
+macro_rules! hal {
+    ($($TIMX:ident: ($timX:ident, $APB:ident, $timXen:ident, $timXrst:ident, $pclkX:ident, $ppreX:ident, [
+        $($CX:ident: ($channelX:ident, $ccmrX:ident, $ccrX:ident, $ccXif:ident, $ccXof:ident, $i:expr),)+
+    ]),)+) => {
+        $(
+            impl CaptureTimer<$TIMX> {
+                /// Configures a TIM peripheral as a free running counter
+                pub fn $timX(tim: $TIMX, clocks: Clocks, apb: &mut $APB) -> Self {
+                    // enable or reset $TIMX
+                    apb.enr().modify(|_, w| w.$timXen().set_bit());
+                    apb.rstr().modify(|_, w| w.$timXrst().set_bit());
+                    apb.rstr().modify(|_, w| w.$timXrst().clear_bit());
+
+                    let clock = timer::timer_clock(clocks.$pclkX(), clocks.$ppreX());
+                    let psc = u16((clock / TICK_FREQ).max(1) - 1).unwrap();
+                    tim.psc.write(|w| unsafe { w.psc().bits(psc) });
+                    tim.arr.write(|w| unsafe { w.arr().bits(0xFFFF) });
+
+                    // URS: only counter overflows generate an update event, not UG
+                    tim.cr1.write(|w| w.urs().set_bit());
+
+                    // Trigger an update event to load the prescaler value
+                    tim.egr.write(|w| w.ug().set_bit());
+
+                    // start counter
+                    tim.cr1.modify(|_, w| w.cen().set_bit());
+
+                    CaptureTimer {
+                        tim,
+                        freq: clock / (u32::from(psc) + 1),
+                    }
+                }
+
+                $(
+                    /// Captures the edges of `pin` on a channel
+                    pub fn $channelX<PIN>(
+                        self,
+                        pin: PIN,
+                        edge: Edge,
+                    ) -> InputCapture<$TIMX, $CX, PIN>
+                    where
+                        PIN: ChannelPin<$TIMX, $CX>,
+                    {
+                        // CCxS: input capture of the channel input
+                        // NOTE(bits) the device crate only gives access to CCMRx in output mode
+                        let offset = 8 * ($i % 2);
+                        self.tim.$ccmrX.modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0xFF << offset)) | (0b01 << offset))
+                        });
+
+                        let mut capture: InputCapture<$TIMX, $CX, PIN> = InputCapture {
+                            tim: self.tim,
+                            pin,
+                            freq: self.freq,
+                            overflows: 0,
+                            _channel: PhantomData,
+                        };
+
+                        capture.set_edge(edge);
+
+                        // CCxE: enable the capture
+                        unsafe { timer::modify_ccer($TIMX::ptr(), 1 << (4 * $i), !0) }
+
+                        capture
+                    }
+                )+
+
+                /// Stops the timer and releases the TIM peripheral
+                pub fn free(self) -> $TIMX {
+                    // pause counter
+                    self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+
+                    self.tim
+                }
+            }
+
+            $(
+                impl<PIN> InputCapture<$TIMX, $CX, PIN> {
+                    /// Selects the edges to capture
+                    pub fn set_edge(&mut self, edge: Edge) {
+                        // NOTE(unsafe) the capture owns the timer
+                        unsafe {
+                            timer::modify_ccer(
+                                $TIMX::ptr(),
+                                0b1010 << (4 * $i),
+                                polarity(edge) << (4 * $i),
+                            )
+                        }
+                    }
+
+                    /// Captures one edge every few, to count fast pulses
+                    pub fn set_prescaler(&mut self, prescaler: Prescaler) {
+                        // ICxPSC
+                        let offset = 8 * ($i % 2) + 2;
+                        self.tim.$ccmrX.modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0b11 << offset)) | ((prescaler as u32) << offset))
+                        });
+                    }
+
+                    /// Returns the time of the last captured edge, in counter ticks
+                    ///
+                    /// Reports an overcapture if more than one edge was captured since the last
+                    /// call, the time is then the one of the last edge.
+                    pub fn capture(&mut self) -> nb::Result<u32, Error> {
+                        let sr = self.tim.sr.read();
+
+                        if sr.$ccXif().bit_is_clear() {
+                            self.count_overflows();
+                            return Err(nb::Error::WouldBlock);
+                        }
+
+                        // Reading CCR clears CCxIF
+                        let ccr = self.tim.$ccrX.read().bits() as u16;
+
+                        // A pending overflow came before the capture if it is early in the period
+                        if ccr < 0x8000 {
+                            self.count_overflows();
+                        }
+
+                        let time = u32::from(self.overflows) << 16 | u32::from(ccr);
+                        self.count_overflows();
+
+                        if sr.$ccXof().bit_is_set() {
+                            self.tim.sr.modify(|_, w| w.$ccXof().clear_bit());
+                            return Err(nb::Error::Other(Error::Overcapture));
+                        }
+
+                        Ok(time)
+                    }
+
+                    /// Returns the current time, in counter ticks
+                    pub fn now(&mut self) -> u32 {
+                        self.count_overflows();
+
+                        let cnt = self.tim.cnt.read().bits() as u16;
+                        let mut overflows = self.overflows;
+
+                        // An overflow right before the read may not be counted yet
+                        if self.tim.sr.read().uif().bit_is_set() && cnt < 0x8000 {
+                            overflows = overflows.wrapping_add(1);
+                        }
+
+                        u32::from(overflows) << 16 | u32::from(cnt)
+                    }
+
+                    /// Waits for the next edge, returning its time in counter ticks
+                    ///
+                    /// Edges captured before the call are discarded.
+                    pub fn wait_edge(&mut self, timeout_us: u32) -> Result<u32, Error> {
+                        self.discard();
+
+                        let start = self.now();
+
+                        loop {
+                            match self.capture() {
+                                Ok(time) => return Ok(time),
+                                Err(nb::Error::Other(e)) => return Err(e),
+                                Err(nb::Error::WouldBlock) => {}
+                            }
+
+                            let now = self.now();
+                            if self.micros(now.wrapping_sub(start)) > timeout_us {
+                                return Err(Error::Timeout);
+                            }
+                        }
+                    }
+
+                    /// Measures the width of the next `pulse`, in microseconds
+                    ///
+                    /// Both edges must come within `timeout_us` of the previous event. The edge
+                    /// selection changes in between, and pulses shorter than a few microseconds
+                    /// are missed.
+                    pub fn measure_pulse(
+                        &mut self,
+                        pulse: Pulse,
+                        timeout_us: u32,
+                    ) -> Result<u32, Error> {
+                        let (start, end) = match pulse {
+                            Pulse::High => (Edge::Rising, Edge::Falling),
+                            Pulse::Low => (Edge::Falling, Edge::Rising),
+                        };
+
+                        self.set_edge(start);
+                        let t0 = self.wait_edge(timeout_us)?;
+
+                        self.set_edge(end);
+                        let t1 = self.wait_edge(timeout_us)?;
+
+                        Ok(self.micros(t1.wrapping_sub(t0)))
+                    }
+
+                    /// Disables the capture and releases the TIM peripheral and the pin
+                    pub fn free(self) -> ($TIMX, PIN) {
+                        // NOTE(unsafe) the capture owns the timer
+                        unsafe { timer::modify_ccer($TIMX::ptr(), 1 << (4 * $i), 0) }
+
+                        // pause counter
+                        self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+
+                        (self.tim, self.pin)
+                    }
+
+                    /// Drops the pending capture, if any
+                    fn discard(&mut self) {
+                        // Reading CCR clears CCxIF
+                        self.tim.$ccrX.read();
+                        self.tim.sr.modify(|_, w| w.$ccXof().clear_bit());
+                    }
+
+                    /// Counts the counter overflow that happened since the last call, if any
+                    fn count_overflows(&mut self) {
+                        if self.tim.sr.read().uif().bit_is_set() {
+                            self.tim.sr.modify(|_, w| w.uif().clear_bit());
+                            self.overflows = self.overflows.wrapping_add(1);
+                        }
+                    }
+                }
+            )+
+        )+
+    }
+}
+
+macro_rules! counter {
+    ($($TIMX:ident: ($timX:ident, $APB:ident, $timXen:ident, $timXrst:ident),)+) => {
+        $(
+            impl<CHANNEL, PIN> PulseCounter<$TIMX, CHANNEL, PIN>
+            where
+                CHANNEL: CounterInput,
+                PIN: ChannelPin<$TIMX, CHANNEL>,
+            {
+                /// Configures a TIM peripheral to count the `edge`s of `pin`
+                pub fn $timX(tim: $TIMX, pin: PIN, edge: Edge, apb: &mut $APB) -> Self {
+                    // enable or reset $TIMX
+                    apb.enr().modify(|_, w| w.$timXen().set_bit());
+                    apb.rstr().modify(|_, w| w.$timXrst().set_bit());
+                    apb.rstr().modify(|_, w| w.$timXrst().clear_bit());
+
+                    // CCxS: input capture of the channel input, which feeds the trigger
+                    // NOTE(bits) the device crate only gives access to CCMRx in output mode
+                    let offset = 8 * CHANNEL::INDEX;
+                    tim.ccmr1_output.modify(|r, w| unsafe {
+                        w.bits((r.bits() & !(0xFF << offset)) | (0b01 << offset))
+                    });
+
+                    tim.arr.write(|w| unsafe { w.arr().bits(0xFFFF) });
+
+                    let mut counter = PulseCounter {
+                        tim,
+                        pin,
+                        _channel: PhantomData,
+                    };
+
+                    counter.set_edge(edge);
+
+                    // TS: filtered channel input, SMS: external clock mode 1
+                    counter
+                        .tim
+                        .smcr
+                        .write(|w| unsafe { w.ts().bits(CHANNEL::TS).sms().bits(0b111) });
+
+                    // start counter
+                    counter.tim.cr1.write(|w| w.cen().set_bit());
+
+                    counter
+                }
+            }
+
+            impl<CHANNEL, PIN> PulseCounter<$TIMX, CHANNEL, PIN>
+            where
+                CHANNEL: CounterInput,
+            {
+                /// Selects the edges to count
+                pub fn set_edge(&mut self, edge: Edge) {
+                    // NOTE(unsafe) the counter owns the timer
+                    unsafe {
+                        timer::modify_ccer(
+                            $TIMX::ptr(),
+                            0b1010 << (4 * CHANNEL::INDEX),
+                            polarity(edge) << (4 * CHANNEL::INDEX),
+                        )
+                    }
+                }
+
+                /// Returns the number of edges counted, modulo 65536
+                ///
+                /// Read it at least once every 65536 edges, and take the difference with the
+                /// previous count with `wrapping_sub`.
+                pub fn count(&self) -> u16 {
+                    self.tim.cnt.read().bits() as u16
+                }
+
+                /// Restarts the count from 0
+                pub fn reset(&mut self) {
+                    self.tim.cnt.reset();
+                }
+
+                /// Stops the timer and releases the TIM peripheral and the pin
+                pub fn free(self) -> ($TIMX, PIN) {
+                    // pause counter, back to the internal clock
+                    self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+                    self.tim.smcr.reset();
+
+                    (self.tim, self.pin)
+                }
+            }
+        )+
+    }
+}
+
+hal! {
+    TIM2: (tim2, APB1, tim2en, tim2rst, pclk1, ppre1, [
+        C1: (channel1, ccmr1_output, ccr1, cc1if, cc1of, 0),
+        C2: (channel2, ccmr1_output, ccr2, cc2if, cc2of, 1),
+        C3: (channel3, ccmr2_output, ccr3, cc3if, cc3of, 2),
+        C4: (channel4, ccmr2_output, ccr4, cc4if, cc4of, 3),
+    ]),
+    TIM3: (tim3, APB1, tim3en, tim3rst, pclk1, ppre1, [
+        C1: (channel1, ccmr1_output, ccr1, cc1if, cc1of, 0),
+        C2: (channel2, ccmr1_output, ccr2, cc2if, cc2of, 1),
+        C3: (channel3, ccmr2_output, ccr3, cc3if, cc3of, 2),
+        C4: (channel4, ccmr2_output, ccr4, cc4if, cc4of, 3),
+    ]),
+    TIM4: (tim4, APB1, tim4en, tim4rst, pclk1, ppre1, [
+        C1: (channel1, ccmr1_output, ccr1, cc1if, cc1of, 0),
+        C2: (channel2, ccmr1_output, ccr2, cc2if, cc2of, 1),
+        C3: (channel3, ccmr2_output, ccr3, cc3if, cc3of, 2),
+        C4: (channel4, ccmr2_output, ccr4, cc4if, cc4of, 3),
+    ]),
+    TIM9: (tim9, APB2, tim9en, tim9rst, pclk2, ppre2, [
+        C1: (channel1, ccmr1_output, ccr1, cc1if, cc1of, 0),
+        C2: (channel2, ccmr1_output, ccr2, cc2if, cc2of, 1),
+    ]),
+    TIM10: (tim10, APB2, tim10en, tm10rst, pclk2, ppre2, [
+        C1: (channel1, ccmr1_output, ccr1, cc1if, cc1of, 0),
+    ]),
+    TIM11: (tim11, APB2, tim11en, tm11rst, pclk2, ppre2, [
+        C1: (channel1, ccmr1_output, ccr1, cc1if, cc1of, 0),
+    ]),
+}
+
+counter! {
+    TIM2: (tim2, APB1, tim2en, tim2rst),
+    TIM3: (tim3, APB1, tim3en, tim3rst),
+    TIM4: (tim4, APB1, tim4en, tim4rst),
+    TIM9: (tim9, APB2, tim9en, tim9rst),
+}
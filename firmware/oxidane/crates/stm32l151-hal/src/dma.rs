@@ -0,0 +1,292 @@
+//! Direct Memory Access (DMA)
+//!
+//! The peripherals hand a buffer over to a DMA channel and return a `Transfer`, which owns the
+//! buffer, the channel and the peripheral until the transfer is complete. The buffers must be
+//! `'static` so that dropping a `Transfer` in progress cannot leave the DMA writing to the stack.
+//!
+//! The requests of the peripherals are wired to fixed channels:
+//!
+//! | Channel | Requests                     |
+//! |---------|------------------------------|
+//! | 2       | SPI1 RX, USART3 TX           |
+//! | 3       | SPI1 TX, USART3 RX           |
+//! | 4       | SPI2 RX, USART1 TX           |
+//! | 5       | SPI2 TX, USART1 RX           |
+//! | 6       | USART2 RX                    |
+//! | 7       | USART2 TX                    |
+//!
+//! The requests sharing a channel are ORed, so a peripheral only raises its requests during its
+//! own transfers, see `TransferPayload`.
+
+use core::marker::PhantomData;
+use core::sync::atomic::{self, Ordering};
+
+use cast::u16;
+use stm32l151::DMA1;
+
+use rcc::AHB;
+
+/// Interrupt event
+pub enum Event {
+    /// Half of the data has been transferred
+    HalfTransfer,
+    /// The transfer is complete
+    TransferComplete,
+}
+
+/// The DMA reads the buffer (type state)
+pub struct R;
+/// The DMA writes the buffer (type state)
+pub struct W;
+
+/// Direction of a transfer
+#[derive(Clone, Copy)]
+pub(crate) enum Direction {
+    /// From the peripheral to the buffer
+    PeripheralToMemory,
+    /// From the buffer to the peripheral
+    MemoryToPeripheral,
+}
+
+/// Extension trait to split a DMA peripheral in independent channels
+pub trait DmaExt {
+    /// The channels to split the DMA into
+    type Channels;
+
+    /// Splits the DMA peripheral into independent channels
+    fn split(self, ahb: &mut AHB) -> Self::Channels;
+}
+
+/// DMA channel, or pair of channels of a full duplex transfer
+pub trait DmaChannel {
+    /// Returns `true` if the current transfer is complete
+    fn is_complete(&self) -> bool;
+
+    /// Disables the channel, aborting the current transfer, and clears its interrupt flags
+    fn stop(&mut self);
+}
+
+/// Peripheral serving a transfer
+pub trait TransferPayload {
+    /// Enables the DMA requests of the peripheral, once the channels are started
+    fn start(&mut self);
+
+    /// Disables the DMA requests of the peripheral
+    fn stop(&mut self);
+}
+
+impl<RX, TX> DmaChannel for (RX, TX)
+where
+    RX: DmaChannel,
+    TX: DmaChannel,
+{
+    fn is_complete(&self) -> bool {
+        // The receiving channel is the last to complete
+        self.0.is_complete()
+    }
+
+    fn stop(&mut self) {
+        self.0.stop();
+        self.1.stop();
+    }
+}
+
+/// DMA transfer in progress
+pub struct Transfer<MODE, BUFFER, CHANNEL, PAYLOAD> {
+    _mode: PhantomData<MODE>,
+    buffer: BUFFER,
+    channel: CHANNEL,
+    payload: PAYLOAD,
+}
+
+impl<BUFFER, CHANNEL, PAYLOAD> Transfer<R, BUFFER, CHANNEL, PAYLOAD>
+where
+    PAYLOAD: TransferPayload,
+{
+    pub(crate) fn r(buffer: BUFFER, channel: CHANNEL, mut payload: PAYLOAD) -> Self {
+        payload.start();
+
+        Transfer {
+            _mode: PhantomData,
+            buffer,
+            channel,
+            payload,
+        }
+    }
+}
+
+impl<BUFFER, CHANNEL, PAYLOAD> Transfer<W, BUFFER, CHANNEL, PAYLOAD>
+where
+    PAYLOAD: TransferPayload,
+{
+    pub(crate) fn w(buffer: BUFFER, channel: CHANNEL, mut payload: PAYLOAD) -> Self {
+        payload.start();
+
+        Transfer {
+            _mode: PhantomData,
+            buffer,
+            channel,
+            payload,
+        }
+    }
+}
+
+impl<MODE, BUFFER, CHANNEL, PAYLOAD> Transfer<MODE, BUFFER, CHANNEL, PAYLOAD>
+where
+    CHANNEL: DmaChannel,
+    PAYLOAD: TransferPayload,
+{
+    /// Returns `true` if the transfer is complete
+    pub fn is_done(&self) -> bool {
+        self.channel.is_complete()
+    }
+
+    /// Waits for the transfer to complete, disables the DMA requests of the peripheral, then
+    /// releases the buffer, the channel and the peripheral
+    pub fn wait(mut self) -> (BUFFER, CHANNEL, PAYLOAD) {
+        while !self.is_done() {}
+
+        self.payload.stop();
+        self.channel.stop();
+
+        // Make sure the buffer is read only after the DMA is done writing it
+        atomic::compiler_fence(Ordering::SeqCst);
+
+        (self.buffer, self.channel, self.payload)
+    }
+}
+
+macro_rules! dma {
+    ($($CX:ident: ($chX:ident, $ccrX:ident, $cndtrX:ident, $cparX:ident, $cmarX:ident, $tcifX:ident,
+                   $cgifX:ident),)+) => {
+        /// DMA1 channels
+        pub struct Channels {
+            $(
+                /// Channel
+                pub $chX: $CX,
+            )+
+        }
+
+        impl DmaExt for DMA1 {
+            type Channels = Channels;
+
+            fn split(self, ahb: &mut AHB) -> Channels {
+                // enable or reset DMA1
+                ahb.enr().modify(|_, w| w.dma1en().set_bit());
+                ahb.rstr().modify(|_, w| w.dma1rst().set_bit());
+                ahb.rstr().modify(|_, w| w.dma1rst().clear_bit());
+
+                Channels {
+                    $(
+                        $chX: $CX { _0: () },
+                    )+
+                }
+            }
+        }
+
+        $(
+            /// DMA1 channel
+            pub struct $CX {
+                _0: (),
+            }
+
+            // NOTE(unsafe) every channel only accesses its own registers, and its own bits of the
+            // stateless IFCR register
+            impl $CX {
+                /// Starts listening for an `event`
+                pub fn listen(&mut self, event: Event) {
+                    let dma = unsafe { &*DMA1::ptr() };
+                    match event {
+                        Event::HalfTransfer => dma.$ccrX.modify(|_, w| w.htie().set_bit()),
+                        Event::TransferComplete => dma.$ccrX.modify(|_, w| w.tcie().set_bit()),
+                    }
+                }
+
+                /// Stops listening for an `event`
+                pub fn unlisten(&mut self, event: Event) {
+                    let dma = unsafe { &*DMA1::ptr() };
+                    match event {
+                        Event::HalfTransfer => dma.$ccrX.modify(|_, w| w.htie().clear_bit()),
+                        Event::TransferComplete => dma.$ccrX.modify(|_, w| w.tcie().clear_bit()),
+                    }
+                }
+
+                /// Configures a transfer of `len` bytes between the data register at
+                /// `peripheral` and the buffer at `memory`, leaving the channel disabled
+                // NOTE(dead_code) required since no peripheral uses channel 1 yet
+                #[allow(dead_code)]
+                pub(crate) fn configure(
+                    &mut self,
+                    peripheral: u32,
+                    memory: u32,
+                    len: usize,
+                    direction: Direction,
+                ) {
+                    let dma = unsafe { &*DMA1::ptr() };
+
+                    self.stop();
+
+                    let len = u16(len).expect("buffer too long");
+                    dma.$cndtrX.write(|w| unsafe { w.ndt().bits(len) });
+                    dma.$cparX.write(|w| unsafe { w.pa().bits(peripheral) });
+                    dma.$cmarX.write(|w| unsafe { w.ma().bits(memory) });
+
+                    // MINC: increment the buffer address, not the data register one
+                    // DIR: transfer direction
+                    // PSIZE, MSIZE: 8-bit data, the reset values
+                    // CIRC: stop after `len` bytes
+                    let from_memory = match direction {
+                        Direction::PeripheralToMemory => false,
+                        Direction::MemoryToPeripheral => true,
+                    };
+                    dma.$ccrX.modify(|_, w| {
+                        w.minc()
+                            .set_bit()
+                            .pinc()
+                            .clear_bit()
+                            .dir()
+                            .bit(from_memory)
+                            .circ()
+                            .clear_bit()
+                            .mem2mem()
+                            .clear_bit()
+                    });
+                }
+
+                /// Enables the channel, the transfer starts at the next request of the
+                /// peripheral
+                // NOTE(dead_code) see `configure`
+                #[allow(dead_code)]
+                pub(crate) fn start(&mut self) {
+                    // Make sure the buffer is written before the DMA starts reading it
+                    atomic::compiler_fence(Ordering::SeqCst);
+
+                    unsafe { (*DMA1::ptr()).$ccrX.modify(|_, w| w.en().set_bit()) }
+                }
+            }
+
+            impl DmaChannel for $CX {
+                fn is_complete(&self) -> bool {
+                    // NOTE(unsafe) atomic read with no side effects
+                    unsafe { (*DMA1::ptr()).isr.read().$tcifX().bit_is_set() }
+                }
+
+                fn stop(&mut self) {
+                    let dma = unsafe { &*DMA1::ptr() };
+                    dma.$ccrX.modify(|_, w| w.en().clear_bit());
+                    dma.ifcr.write(|w| w.$cgifX().set_bit());
+                }
+            }
+        )+
+    }
+}
+
+dma! {
+    C1: (ch1, ccr1, cndtr1, cpar1, cmar1, tcif1, cgif1),
+    C2: (ch2, ccr2, cndtr2, cpar2, cmar2, tcif2, cgif2),
+    C3: (ch3, ccr3, cndtr3, cpar3, cmar3, tcif3, cgif3),
+    C4: (ch4, ccr4, cndtr4, cpar4, cmar4, tcif4, cgif4),
+    C5: (ch5, ccr5, cndtr5, cpar5, cmar5, tcif5, cgif5),
+    C6: (ch6, ccr6, cndtr6, cpar6, cmar6, tcif6, cgif6),
+    C7: (ch7, ccr7, cndtr7, cpar7, cmar7, tcif7, cgif7),
+}
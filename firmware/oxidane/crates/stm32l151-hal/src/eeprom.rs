@@ -0,0 +1,207 @@
+//! Data EEPROM
+//!
+//! The 4 KB data EEPROM endures about 300 000 write cycles, against 10 000 for the program memory,
+//! and is written one byte, half-word or word at a time without erasing a page first. Erased data
+//! EEPROM reads as zero.
+//!
+//! Every address of this module is an offset from the start of the data EEPROM.
+
+use core::ptr;
+
+use flash::{Error, PECR};
+
+/// Address of the first byte of the data EEPROM
+pub const START: u32 = 0x0808_0000;
+
+/// Size of the data EEPROM, in bytes
+pub const SIZE: u32 = 4 * 1024;
+
+/// Data EEPROM, unlocked for erasing and programming
+pub struct Eeprom<'a> {
+    pecr: &'a mut PECR,
+}
+
+impl<'a> Eeprom<'a> {
+    pub(crate) fn new(pecr: &'a mut PECR) -> Self {
+        // FTDW: erase the word before programming it only when required
+        pecr.flash().pecr.modify(|_, w| w.ftdw().clear_bit());
+
+        Eeprom { pecr }
+    }
+
+    /// Reads `buffer.len()` bytes starting at `offset`
+    pub fn read(&self, offset: u32, buffer: &mut [u8]) -> Result<(), Error> {
+        check(offset, buffer.len(), 1)?;
+
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = read_byte(offset + i as u32);
+        }
+
+        Ok(())
+    }
+
+    /// Reads the word at `offset`
+    pub fn read_word(&self, offset: u32) -> Result<u32, Error> {
+        check(offset, 4, 4)?;
+
+        // NOTE(unsafe) the address is word aligned and within the data EEPROM
+        Ok(unsafe { ptr::read_volatile((START + offset) as *const u32) })
+    }
+
+    /// Writes `data` starting at `offset`
+    ///
+    /// Only the words that change are programmed, sparing the write cycles of the others.
+    pub fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Error> {
+        check(offset, data.len(), 1)?;
+
+        let mut i = 0;
+        while i < data.len() {
+            let address = offset + i as u32;
+
+            if address % 4 == 0 && data.len() - i >= 4 {
+                let word = u32::from(data[i])
+                    | u32::from(data[i + 1]) << 8
+                    | u32::from(data[i + 2]) << 16
+                    | u32::from(data[i + 3]) << 24;
+
+                if self.read_word(address)? != word {
+                    self.program_word(address, word)?;
+                }
+                i += 4;
+            } else {
+                if read_byte(address) != data[i] {
+                    self.program_byte(address, data[i])?;
+                }
+                i += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Programs the byte at `offset`
+    pub fn program_byte(&mut self, offset: u32, byte: u8) -> Result<(), Error> {
+        check(offset, 1, 1)?;
+
+        if byte == 0 {
+            // Category 1 devices can not program a zero byte, the whole word is programmed instead
+            let shift = 8 * (offset % 4);
+            return self.program_masked(offset - offset % 4, 0xFF << shift);
+        }
+
+        // NOTE(unsafe) the data EEPROM is unlocked and the address is within it
+        unsafe { ptr::write_volatile((START + offset) as *mut u8, byte) };
+
+        self.pecr.wait()
+    }
+
+    /// Programs the half-word at `offset`
+    pub fn program_half_word(&mut self, offset: u32, half_word: u16) -> Result<(), Error> {
+        check(offset, 2, 2)?;
+
+        if half_word == 0 {
+            // Category 1 devices can not program a zero half-word, the whole word is programmed
+            // instead
+            let shift = 8 * (offset % 4);
+            return self.program_masked(offset - offset % 4, 0xFFFF << shift);
+        }
+
+        // NOTE(unsafe) the data EEPROM is unlocked and the address is half-word aligned
+        unsafe { ptr::write_volatile((START + offset) as *mut u16, half_word) };
+
+        self.pecr.wait()
+    }
+
+    /// Programs the word at `offset`
+    pub fn program_word(&mut self, offset: u32, word: u32) -> Result<(), Error> {
+        check(offset, 4, 4)?;
+
+        if word == 0 {
+            return self.erase_word(offset);
+        }
+
+        // NOTE(unsafe) the data EEPROM is unlocked and the address is word aligned
+        unsafe { ptr::write_volatile((START + offset) as *mut u32, word) };
+
+        self.pecr.wait()
+    }
+
+    /// Erases the word at `offset`
+    pub fn erase_word(&mut self, offset: u32) -> Result<(), Error> {
+        check(offset, 4, 4)?;
+
+        let flash = self.pecr.flash();
+
+        flash
+            .pecr
+            .modify(|_, w| w.erase().set_bit().data().set_bit());
+
+        // Writing a zero in the word starts the erase operation
+        // NOTE(unsafe) the data EEPROM is unlocked and the address is word aligned
+        unsafe { ptr::write_volatile((START + offset) as *mut u32, 0) };
+
+        let result = self.pecr.wait();
+
+        flash
+            .pecr
+            .modify(|_, w| w.erase().clear_bit().data().clear_bit());
+
+        result
+    }
+
+    /// Erases `len` bytes starting at `offset`, both multiple of 4
+    pub fn erase(&mut self, offset: u32, len: usize) -> Result<(), Error> {
+        check(offset, len, 4)?;
+        if len % 4 != 0 {
+            return Err(Error::Size);
+        }
+
+        for address in (offset..offset + len as u32).step_by(4) {
+            if self.read_word(address)? != 0 {
+                self.erase_word(address)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Clears the bits of `mask` in the word at `offset`
+    fn program_masked(&mut self, offset: u32, mask: u32) -> Result<(), Error> {
+        let word = self.read_word(offset)? & !mask;
+
+        self.erase_word(offset)?;
+
+        if word != 0 {
+            // NOTE(unsafe) the data EEPROM is unlocked and the address is word aligned
+            unsafe { ptr::write_volatile((START + offset) as *mut u32, word) };
+
+            self.pecr.wait()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<'a> Drop for Eeprom<'a> {
+    fn drop(&mut self) {
+        self.pecr.lock();
+    }
+}
+
+/// Checks that `len` bytes starting at `offset` lie within the data EEPROM, and that `offset` is
+/// a multiple of `align`
+fn check(offset: u32, len: usize, align: u32) -> Result<(), Error> {
+    if offset % align != 0 {
+        return Err(Error::Alignment);
+    }
+
+    match offset.checked_add(len as u32) {
+        Some(end) if len as u64 <= u64::from(SIZE) && end <= SIZE => Ok(()),
+        _ => Err(Error::OutOfBounds),
+    }
+}
+
+fn read_byte(offset: u32) -> u8 {
+    // NOTE(unsafe) the data EEPROM is always readable and the caller checked the address
+    unsafe { ptr::read_volatile((START + offset) as *const u8) }
+}
@@ -0,0 +1,171 @@
+//! External interrupts (EXTI)
+//!
+//! Every GPIO pin can drive the EXTI line of its number, see `ExtiPin`, and the lines are
+//! configured through the `Exti` controller. The lines 0 to 4 have an interrupt vector each, while
+//! the lines 5 to 9 share `EXTI9_5` and the lines 10 to 15 share `EXTI15_10`. With the "rt"
+//! feature the handlers are declared with the `interrupt!` macro of the device crate, and must
+//! clear the pending bit of the pins that fired.
+
+use stm32l151::{Interrupt, EXTI, SYSCFG};
+
+use rcc::APB2;
+
+/// Signal edges that trigger an interrupt
+pub enum Edge {
+    /// Low to high transitions
+    Rising,
+    /// High to low transitions
+    Falling,
+    /// Both transitions
+    Both,
+}
+
+/// External interrupt controller
+pub struct Exti {
+    exti: EXTI,
+}
+
+impl Exti {
+    /// Constrains the EXTI peripheral, with every line masked
+    pub fn exti(exti: EXTI) -> Self {
+        exti.imr.reset();
+        exti.emr.reset();
+
+        Exti { exti }
+    }
+
+    /// Releases the EXTI peripheral
+    pub fn free(self) -> EXTI {
+        self.exti
+    }
+
+    /// Selects the signal edges that trigger the interrupt of `line`
+    pub(crate) fn trigger_on_edge(&mut self, line: u8, edge: Edge) {
+        let (rising, falling) = match edge {
+            Edge::Rising => (true, false),
+            Edge::Falling => (false, true),
+            Edge::Both => (true, true),
+        };
+
+        self.exti
+            .rtsr
+            .modify(|r, w| unsafe { w.bits(set_bit(r.bits(), line, rising)) });
+        self.exti
+            .ftsr
+            .modify(|r, w| unsafe { w.bits(set_bit(r.bits(), line, falling)) });
+    }
+
+    /// Unmasks or masks the interrupt of `line`
+    pub(crate) fn set_interrupt_mask(&mut self, line: u8, enabled: bool) {
+        self.exti
+            .imr
+            .modify(|r, w| unsafe { w.bits(set_bit(r.bits(), line, enabled)) });
+    }
+}
+
+/// System configuration controller, routing the GPIO pins to the EXTI lines
+pub struct Syscfg {
+    syscfg: SYSCFG,
+}
+
+impl Syscfg {
+    /// Enables the SYSCFG peripheral, with every EXTI line driven by GPIOA
+    pub fn syscfg(syscfg: SYSCFG, apb2: &mut APB2) -> Self {
+        // enable or reset SYSCFG
+        apb2.enr().modify(|_, w| w.syscfgen().set_bit());
+        apb2.rstr().modify(|_, w| w.syscfgrst().set_bit());
+        apb2.rstr().modify(|_, w| w.syscfgrst().clear_bit());
+
+        Syscfg { syscfg }
+    }
+
+    /// Releases the SYSCFG peripheral
+    pub fn free(self) -> SYSCFG {
+        self.syscfg
+    }
+
+    /// Drives EXTI `line` with the pin of the same number of GPIO `port`, 0 being GPIOA
+    pub(crate) fn select(&mut self, line: u8, port: u8) {
+        // EXTIx: 4 bits per line, 4 lines per register
+        let offset = 4 * (line % 4);
+        let mask = !(0b1111 << offset);
+        let bits = u32::from(port) << offset;
+
+        match line {
+            0...3 => self
+                .syscfg
+                .exticr1
+                .modify(|r, w| unsafe { w.bits((r.bits() & mask) | bits) }),
+            4...7 => self
+                .syscfg
+                .exticr2
+                .modify(|r, w| unsafe { w.bits((r.bits() & mask) | bits) }),
+            8...11 => self
+                .syscfg
+                .exticr3
+                .modify(|r, w| unsafe { w.bits((r.bits() & mask) | bits) }),
+            _ => self
+                .syscfg
+                .exticr4
+                .modify(|r, w| unsafe { w.bits((r.bits() & mask) | bits) }),
+        }
+    }
+}
+
+/// GPIO input pin acting as an external interrupt source
+pub trait ExtiPin {
+    /// Connects the pin to the EXTI line of its number
+    ///
+    /// A line serves one pin at a time: connecting PB5 disconnects PA5.
+    fn make_interrupt_source(&mut self, syscfg: &mut Syscfg);
+
+    /// Selects the signal edges that trigger the interrupt
+    fn trigger_on_edge(&mut self, exti: &mut Exti, edge: Edge);
+
+    /// Unmasks the interrupt of the line
+    ///
+    /// The interrupt vector must be unmasked in the NVIC as well, see `interrupt`.
+    fn enable_interrupt(&mut self, exti: &mut Exti);
+
+    /// Masks the interrupt of the line
+    fn disable_interrupt(&mut self, exti: &mut Exti);
+
+    /// Clears the pending bit of the line, from the interrupt handler
+    fn clear_interrupt_pending_bit(&mut self);
+
+    /// Returns `true` if an edge was detected since the pending bit was cleared
+    fn check_interrupt(&self) -> bool;
+
+    /// Returns the interrupt vector of the line, shared with other lines from 5 up
+    fn interrupt(&self) -> Interrupt;
+}
+
+pub(crate) fn clear_pending(line: u8) {
+    // NOTE(unsafe) atomic write to a write-1-to-clear register, the other lines are unaffected
+    unsafe { (*EXTI::ptr()).pr.write(|w| w.bits(1 << line)) }
+}
+
+pub(crate) fn is_pending(line: u8) -> bool {
+    // NOTE(unsafe) atomic read with no side effects
+    unsafe { (*EXTI::ptr()).pr.read().bits() & (1 << line) != 0 }
+}
+
+pub(crate) fn interrupt(line: u8) -> Interrupt {
+    match line {
+        0 => Interrupt::EXTI0,
+        1 => Interrupt::EXTI1,
+        2 => Interrupt::EXTI2,
+        3 => Interrupt::EXTI3,
+        4 => Interrupt::EXTI4,
+        5...9 => Interrupt::EXTI9_5,
+        _ => Interrupt::EXTI15_10,
+    }
+}
+
+fn set_bit(bits: u32, line: u8, value: bool) -> u32 {
+    if value {
+        bits | (1 << line)
+    } else {
+        bits & !(1 << line)
+    }
+}
@@ -1,7 +1,71 @@
 //! Flash memory
+//!
+//! `ProgramMemory` only erases and programs the program memory, whose size is read from the flash
+//! size register, at its `0x0800_0000` address rather than its alias at 0. It also refuses to
+//! touch the image that is currently running, which spans from its vector table to the end of its
+//! `.data` initializers.
 
+use core::ptr;
+
+use cortex_m::interrupt;
+use cortex_m::peripheral::SCB;
 use stm32l151::{flash, FLASH};
 
+use eeprom::Eeprom;
+
+// PEKEYR unlock sequence
+const PEKEY1: u32 = 0x89AB_CDEF;
+const PEKEY2: u32 = 0x0203_0405;
+
+// PRGKEYR unlock sequence
+const PRGKEY1: u32 = 0x8C9D_AEBF;
+const PRGKEY2: u32 = 0x1314_1516;
+
+// OPTKEYR unlock sequence
+const OPTKEY1: u32 = 0xFBEA_D9C8;
+const OPTKEY2: u32 = 0x2425_2627;
+
+/// Address of the program memory, also aliased at 0 when booting from it
+const PROGRAM_START: u32 = 0x0800_0000;
+
+/// Address of the flash size register, holding the size of the program memory in KB
+const FLASH_SIZE: u32 = 0x1FF8_004C;
+
+/// Address of the option bytes
+const OPTION_START: u32 = 0x1FF8_0000;
+
+/// Size of a program memory page, the smallest erasable unit
+pub const PAGE_SIZE: u32 = 256;
+
+/// Size of a program memory half-page, the largest programmable unit
+pub const HALF_PAGE_SIZE: u32 = PAGE_SIZE / 2;
+
+/// Number of words in a half-page
+const HALF_PAGE_WORDS: usize = HALF_PAGE_SIZE as usize / 4;
+
+/// Address of the FLASH status register, for the code running from RAM
+const FLASH_SR: u32 = 0x4002_3C18;
+
+/// Busy flag of the FLASH status register
+const FLASH_SR_BSY: u32 = 1 << 0;
+
+/// Flash programming error
+#[derive(Debug)]
+pub enum Error {
+    /// The target address is write protected
+    WriteProtection,
+    /// The target address is not correctly aligned
+    Alignment,
+    /// The size of the programmed data is invalid
+    Size,
+    /// The target address is outside of the memory
+    OutOfBounds,
+    /// The target address belongs to the running image
+    RunningImage,
+    #[doc(hidden)]
+    _Extensible,
+}
+
 /// Extension trait to constrain the FLASH peripheral
 pub trait FlashExt {
     /// Constrains the FLASH peripheral to play nicely with the other abstractions
@@ -12,6 +76,7 @@ impl FlashExt for FLASH {
     fn constrain(self) -> Parts {
         Parts {
             acr: ACR { _0: () },
+            pecr: PECR { _0: () },
         }
     }
 }
@@ -20,6 +85,8 @@ impl FlashExt for FLASH {
 pub struct Parts {
     /// Opaque ACR register
     pub acr: ACR,
+    /// Opaque PECR register
+    pub pecr: PECR,
 }
 
 /// Opaque ACR register
@@ -33,3 +100,384 @@ impl ACR {
         unsafe { &(*FLASH::ptr()).acr }
     }
 }
+
+/// Opaque PECR register
+pub struct PECR {
+    _0: (),
+}
+
+impl PECR {
+    /// Unlocks the program memory for erasing and programming
+    ///
+    /// The memory is locked again when the returned value is dropped.
+    pub fn unlock_program(&mut self) -> ProgramMemory {
+        // PELOCK must be cleared first, then PRGLOCK
+        self.unlock_pe();
+
+        let flash = self.flash();
+        if flash.pecr.read().prglock().bit_is_set() {
+            flash.prgkeyr.write(|w| unsafe { w.prgkeyr().bits(PRGKEY1) });
+            flash.prgkeyr.write(|w| unsafe { w.prgkeyr().bits(PRGKEY2) });
+        }
+
+        ProgramMemory { pecr: self }
+    }
+
+    /// Unlocks the option bytes for programming
+    ///
+    /// The option bytes are locked again when the returned value is dropped.
+    pub fn unlock_options(&mut self) -> OptionBytes {
+        // PELOCK must be cleared first, then OPTLOCK
+        self.unlock_pe();
+
+        let flash = self.flash();
+
+        if flash.pecr.read().optlock().bit_is_set() {
+            flash.optkeyr.write(|w| unsafe { w.optkeyr().bits(OPTKEY1) });
+            flash.optkeyr.write(|w| unsafe { w.optkeyr().bits(OPTKEY2) });
+        }
+
+        OptionBytes { pecr: self }
+    }
+
+    /// Unlocks the data EEPROM for erasing and programming
+    ///
+    /// The memory is locked again when the returned value is dropped.
+    pub fn unlock_eeprom(&mut self) -> Eeprom {
+        self.unlock_pe();
+
+        Eeprom::new(self)
+    }
+
+    /// Clears PELOCK, unlocking the data EEPROM and PECR
+    pub(crate) fn unlock_pe(&mut self) {
+        let flash = self.flash();
+
+        if flash.pecr.read().pelock().bit_is_set() {
+            flash.pekeyr.write(|w| unsafe { w.pekeyr().bits(PEKEY1) });
+            flash.pekeyr.write(|w| unsafe { w.pekeyr().bits(PEKEY2) });
+        }
+    }
+
+    /// Sets PELOCK, locking the data EEPROM, the program memory and PECR
+    pub(crate) fn lock(&mut self) {
+        self.flash().pecr.modify(|_, w| w.pelock().set_bit());
+    }
+
+    /// Waits for the end of the current operation and reports its errors
+    pub(crate) fn wait(&self) -> Result<(), Error> {
+        let flash = self.flash();
+
+        while flash.sr.read().bsy().bit_is_set() {}
+
+        let sr = flash.sr.read();
+
+        let result = if sr.wrperr().bit_is_set() {
+            Err(Error::WriteProtection)
+        } else if sr.pgaerr().bit_is_set() {
+            Err(Error::Alignment)
+        } else if sr.sizerr().bit_is_set() {
+            Err(Error::Size)
+        } else {
+            Ok(())
+        };
+
+        // Error flags are cleared by writing 1
+        flash
+            .sr
+            .write(|w| w.wrperr().set_bit().pgaerr().set_bit().sizerr().set_bit());
+
+        result
+    }
+
+    pub(crate) fn flash(&self) -> &flash::RegisterBlock {
+        // NOTE(unsafe) this proxy grants exclusive access to the program/erase registers
+        unsafe { &*FLASH::ptr() }
+    }
+}
+
+/// Program memory, unlocked for erasing and programming
+pub struct ProgramMemory<'a> {
+    pecr: &'a mut PECR,
+}
+
+impl<'a> ProgramMemory<'a> {
+    /// Erases the page starting at `address`
+    ///
+    /// Erased program memory reads as zero.
+    pub fn erase_page(&mut self, address: u32) -> Result<(), Error> {
+        if address % PAGE_SIZE != 0 {
+            return Err(Error::Alignment);
+        }
+        check_bounds(address, PAGE_SIZE, program_size())?;
+        check_not_running(address, PAGE_SIZE)?;
+
+        let flash = self.flash();
+
+        flash
+            .pecr
+            .modify(|_, w| w.erase().set_bit().prog().set_bit());
+
+        // Writing a zero in the page starts the erase operation
+        // NOTE(unsafe) the program memory is unlocked and the address is page aligned
+        unsafe { ptr::write_volatile(address as *mut u32, 0) };
+
+        let result = self.wait();
+
+        flash
+            .pecr
+            .modify(|_, w| w.erase().clear_bit().prog().clear_bit());
+
+        result
+    }
+
+    /// Programs the word at `address`, which must have been erased
+    pub fn program_word(&mut self, address: u32, word: u32) -> Result<(), Error> {
+        if address % 4 != 0 {
+            return Err(Error::Alignment);
+        }
+        check_bounds(address, 4, program_size())?;
+        check_not_running(address, 4)?;
+
+        // NOTE(unsafe) the program memory is unlocked and the address is word aligned
+        unsafe { ptr::write_volatile(address as *mut u32, word) };
+
+        self.wait()
+    }
+
+    /// Programs the half-page starting at `address`, which must have been erased
+    ///
+    /// This is about 30 times faster than programming the words one by one. Interrupts are
+    /// disabled while the half-page is programmed.
+    ///
+    /// Debug builds program the words one by one, see `write_half_page`.
+    pub fn program_half_page(
+        &mut self,
+        address: u32,
+        words: &[u32; HALF_PAGE_WORDS],
+    ) -> Result<(), Error> {
+        if address % HALF_PAGE_SIZE != 0 {
+            return Err(Error::Alignment);
+        }
+        check_bounds(address, HALF_PAGE_SIZE, program_size())?;
+        check_not_running(address, HALF_PAGE_SIZE)?;
+
+        if cfg!(debug_assertions) {
+            for (i, &word) in words.iter().enumerate() {
+                self.program_word(address + 4 * i as u32, word)?;
+            }
+
+            return Ok(());
+        }
+
+        let flash = self.flash();
+
+        // The previous operation must be over before FPRG is set
+        self.wait()?;
+
+        flash
+            .pecr
+            .modify(|_, w| w.fprg().set_bit().prog().set_bit());
+
+        // NOTE(unsafe) the program memory is unlocked and the address is half-page aligned
+        interrupt::free(|_| unsafe { write_half_page(address as *mut u32, words.as_ptr()) });
+
+        let result = self.wait();
+
+        flash
+            .pecr
+            .modify(|_, w| w.fprg().clear_bit().prog().clear_bit());
+
+        result
+    }
+
+    /// Reads the word at `address`
+    pub fn read_word(&self, address: u32) -> u32 {
+        // NOTE(unsafe) program memory is always readable
+        unsafe { ptr::read_volatile(address as *const u32) }
+    }
+
+    /// Waits for the end of the current operation and reports its errors
+    fn wait(&self) -> Result<(), Error> {
+        self.pecr.wait()
+    }
+
+    fn flash(&self) -> &flash::RegisterBlock {
+        self.pecr.flash()
+    }
+}
+
+impl<'a> Drop for ProgramMemory<'a> {
+    fn drop(&mut self) {
+        // Setting PELOCK locks the program memory as well
+        self.pecr.lock();
+    }
+}
+
+/// Option byte, stored in the low half-word of a word whose high half-word is its complement
+#[derive(Clone, Copy, Debug)]
+pub enum OptionByte {
+    /// Read protection level: 0xAA for level 0, 0xCC for level 2, anything else for level 1
+    ReadProtection = 0x0,
+    /// Brown-out reset level, software or hardware IWDG, and reset on Stop and Standby entry
+    User = 0x4,
+    /// Write protection of the 4 KB sectors 0 to 15, one bit per sector
+    WriteProtection = 0x8,
+}
+
+/// Option bytes, unlocked for programming
+///
+/// The programmed values apply once loaded, at the next power on reset or by `launch`.
+pub struct OptionBytes<'a> {
+    pecr: &'a mut PECR,
+}
+
+impl<'a> OptionBytes<'a> {
+    /// Reads the programmed value of an option byte
+    pub fn read(&self, option: OptionByte) -> u16 {
+        // NOTE(unsafe) the option bytes are always readable
+        unsafe { ptr::read_volatile((OPTION_START + option as u32) as *const u16) }
+    }
+
+    /// Programs an option byte, along with its complement
+    ///
+    /// Programming a read protection level of 2 can not be undone, and disables debugging for good.
+    pub fn program(&mut self, option: OptionByte, value: u16) -> Result<(), Error> {
+        let word = u32::from(!value) << 16 | u32::from(value);
+
+        // NOTE(unsafe) the option bytes are unlocked and the address is word aligned
+        unsafe { ptr::write_volatile((OPTION_START + option as u32) as *mut u32, word) };
+
+        self.pecr.wait()
+    }
+
+    /// Loads the programmed option bytes, which resets the microcontroller
+    pub fn launch(self) -> ! {
+        self.pecr
+            .flash()
+            .pecr
+            .modify(|_, w| w.obl_launch().set_bit());
+
+        loop {}
+    }
+}
+
+impl<'a> Drop for OptionBytes<'a> {
+    fn drop(&mut self) {
+        // Setting PELOCK locks the option bytes as well
+        self.pecr.lock();
+    }
+}
+
+/// Programs the 32 words of a half-page, which must be written one after the other without any
+/// other access to the program memory
+///
+/// This function is copied to RAM along with the `.data` section, and must not call any function
+/// left in the program memory: no slice indexing and its panic, no register API, only raw
+/// pointers. The volatile accesses and pointer offsets are only inlined in optimized builds,
+/// check with `arm-none-eabi-objdump -d` that the function has no branch out of itself.
+#[inline(never)]
+#[link_section = ".data.stm32l151_hal.flash.write_half_page"]
+unsafe fn write_half_page(address: *mut u32, words: *const u32) {
+    let end = words.offset(HALF_PAGE_WORDS as isize);
+
+    let mut src = words;
+    let mut dst = address;
+    while src != end {
+        ptr::write_volatile(dst, *src);
+        src = src.offset(1);
+        dst = dst.offset(1);
+    }
+
+    // The half-page is programmed from RAM as well
+    while ptr::read_volatile(FLASH_SR as *const u32) & FLASH_SR_BSY != 0 {}
+}
+
+/// Returns the size of the program memory, in bytes
+fn program_size() -> u32 {
+    // NOTE(unsafe) read-only factory value with no side effects
+    u32::from(unsafe { ptr::read_volatile(FLASH_SIZE as *const u16) }) * 1024
+}
+
+/// Returns `Err(Error::OutOfBounds)` unless `size` bytes starting at `address` lie within the
+/// `program_size` bytes of the program memory
+fn check_bounds(address: u32, size: u32, program_size: u32) -> Result<(), Error> {
+    match address.checked_add(size) {
+        Some(end) if address >= PROGRAM_START && end <= PROGRAM_START + program_size => Ok(()),
+        _ => Err(Error::OutOfBounds),
+    }
+}
+
+/// Returns `Err(Error::RunningImage)` if `size` bytes starting at `address` overlap the image
+/// that is currently running
+fn check_not_running(address: u32, size: u32) -> Result<(), Error> {
+    extern "C" {
+        // Bounds of the `.data` section in RAM, and address of its initializers, the last bytes
+        // of the image, in the program memory. Provided by the cortex-m-rt linker script.
+        static __sdata: u32;
+        static __edata: u32;
+        static __sidata: u32;
+    }
+
+    // NOTE(unsafe) atomic read with no side effects
+    let vtor = unsafe { (*SCB::ptr()).vtor.read() };
+    let start = if vtor < PROGRAM_START {
+        PROGRAM_START + vtor
+    } else {
+        vtor
+    };
+
+    // NOTE(unsafe) only the addresses of the linker symbols are used
+    let end = unsafe {
+        &__sidata as *const u32 as u32
+            + (&__edata as *const u32 as u32 - &__sdata as *const u32 as u32)
+    };
+
+    if address < end && start < address + size {
+        Err(Error::RunningImage)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u32 = 64 * 1024;
+
+    #[test]
+    fn bounds() {
+        assert!(check_bounds(PROGRAM_START, PAGE_SIZE, SIZE).is_ok());
+        assert!(check_bounds(PROGRAM_START + SIZE - PAGE_SIZE, PAGE_SIZE, SIZE).is_ok());
+        assert!(check_bounds(PROGRAM_START + SIZE - 4, 4, SIZE).is_ok());
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let outside = [
+            // Alias of the program memory, RAM and peripherals
+            0,
+            0x2000_0000,
+            FLASH_SR,
+            // Past the end of the memory, data EEPROM included
+            PROGRAM_START + SIZE,
+            0x0808_0000,
+            // Close to the end of the address space
+            0xFFFF_FF80,
+        ];
+
+        for &address in &outside {
+            match check_bounds(address, HALF_PAGE_SIZE, SIZE) {
+                Err(Error::OutOfBounds) => {}
+                result => panic!("{:#010x}: {:?}", address, result),
+            }
+        }
+
+        // Straddling the end
+        match check_bounds(PROGRAM_START + SIZE - 4, 8, SIZE) {
+            Err(Error::OutOfBounds) => {}
+            result => panic!("{:?}", result),
+        }
+    }
+}
@@ -38,6 +38,9 @@ pub struct PushPull;
 /// Open drain output (type state)
 pub struct OpenDrain;
 
+/// Analog mode (type state)
+pub struct Analog;
+
 /// Alternate function 0 (type state)
 pub struct AF0;
 
@@ -87,7 +90,7 @@ pub struct AF14;
 pub struct AF15;
 
 macro_rules! gpio {
-    ($GPIOX:ident, $gpiox:ident, $gpioy:ident, $iopxenr:ident, $iopxrst:ident, $PXx:ident, [
+    ($GPIOX:ident, $gpiox:ident, $gpioy:ident, $iopxenr:ident, $iopxrst:ident, $PXx:ident, $port:expr, [
         $($PXi:ident: ($pxi:ident, $i:expr, $MODE:ty, $AFR:ident),)+
     ]) => {
         /// GPIO
@@ -96,12 +99,13 @@ macro_rules! gpio {
 
             use hal::digital::{InputPin, OutputPin, StatefulOutputPin};
             use hal::digital::toggleable;
-            use stm32l151::{$gpioy, $GPIOX};
+            use stm32l151::{$gpioy, $GPIOX, Interrupt};
 
+            use exti::{self, Edge, Exti, ExtiPin, Syscfg};
             use rcc::AHB;
             use super::{
-                AF4, AF5, AF6, AF7, Floating, GpioExt, Input, OpenDrain, Output,
-                PullDown, PullUp, PushPull,
+                AF0, AF1, AF2, AF3, AF4, AF5, AF6, AF7, Analog, Floating, GpioExt, Input,
+                OpenDrain, Output, PullDown, PullUp, PushPull,
             };
 
             /// GPIO parts
@@ -251,6 +255,98 @@ macro_rules! gpio {
                 }
 
                 impl<MODE> $PXi<MODE> {
+                    /// Configures the pin to serve as alternate function 0 (AF0)
+                    pub fn into_af0(
+                        self,
+                        moder: &mut MODER,
+                        afr: &mut $AFR,
+                    ) -> $PXi<AF0> {
+                        let offset = 2 * $i;
+
+                        // alternate function mode
+                        let mode = 0b10;
+                        moder.moder().modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0b11 << offset)) | (mode << offset))
+                        });
+
+                        let af = 0;
+                        let offset = 4 * ($i % 8);
+                        afr.afr().modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0b1111 << offset)) | (af << offset))
+                        });
+
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to serve as alternate function 1 (AF1)
+                    pub fn into_af1(
+                        self,
+                        moder: &mut MODER,
+                        afr: &mut $AFR,
+                    ) -> $PXi<AF1> {
+                        let offset = 2 * $i;
+
+                        // alternate function mode
+                        let mode = 0b10;
+                        moder.moder().modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0b11 << offset)) | (mode << offset))
+                        });
+
+                        let af = 1;
+                        let offset = 4 * ($i % 8);
+                        afr.afr().modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0b1111 << offset)) | (af << offset))
+                        });
+
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to serve as alternate function 2 (AF2)
+                    pub fn into_af2(
+                        self,
+                        moder: &mut MODER,
+                        afr: &mut $AFR,
+                    ) -> $PXi<AF2> {
+                        let offset = 2 * $i;
+
+                        // alternate function mode
+                        let mode = 0b10;
+                        moder.moder().modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0b11 << offset)) | (mode << offset))
+                        });
+
+                        let af = 2;
+                        let offset = 4 * ($i % 8);
+                        afr.afr().modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0b1111 << offset)) | (af << offset))
+                        });
+
+                        $PXi { _mode: PhantomData }
+                    }
+
+                    /// Configures the pin to serve as alternate function 3 (AF3)
+                    pub fn into_af3(
+                        self,
+                        moder: &mut MODER,
+                        afr: &mut $AFR,
+                    ) -> $PXi<AF3> {
+                        let offset = 2 * $i;
+
+                        // alternate function mode
+                        let mode = 0b10;
+                        moder.moder().modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0b11 << offset)) | (mode << offset))
+                        });
+
+                        let af = 3;
+                        let offset = 4 * ($i % 8);
+                        afr.afr().modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0b1111 << offset)) | (af << offset))
+                        });
+
+                        $PXi { _mode: PhantomData }
+                    }
+
                     /// Configures the pin to serve as alternate function 4 (AF4)
                     pub fn into_af4(
                         self,
@@ -344,6 +440,27 @@ macro_rules! gpio {
                         $PXi { _mode: PhantomData }
                     }
 
+                    /// Configures the pin to operate as an analog pin, for the ADC
+                    pub fn into_analog(
+                        self,
+                        moder: &mut MODER,
+                        pupdr: &mut PUPDR,
+                    ) -> $PXi<Analog> {
+                        let offset = 2 * $i;
+
+                        // analog mode
+                        moder
+                            .moder()
+                            .modify(|r, w| unsafe { w.bits(r.bits() | (0b11 << offset)) });
+
+                        // no pull-up or pull-down
+                        pupdr
+                            .pupdr()
+                            .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << offset)) });
+
+                        $PXi { _mode: PhantomData }
+                    }
+
                     /// Configures the pin to operate as a floating input pin
                     pub fn into_floating_input(
                         self,
@@ -469,6 +586,15 @@ macro_rules! gpio {
                     }
                 }
 
+                impl $PXi<AF4> {
+                    /// Configures the alternate function output as open drain, as required by I2C
+                    pub fn set_open_drain(&mut self, otyper: &mut OTYPER) {
+                        otyper
+                            .otyper()
+                            .modify(|r, w| unsafe { w.bits(r.bits() | (0b1 << $i)) });
+                    }
+                }
+
                 impl<MODE> $PXi<Output<MODE>> {
                     /// Erases the pin number from the type
                     ///
@@ -519,12 +645,42 @@ macro_rules! gpio {
                 }
 
                 impl<MODE> toggleable::Default for $PXi<Output<MODE>> {}
+
+                impl<MODE> ExtiPin for $PXi<Input<MODE>> {
+                    fn make_interrupt_source(&mut self, syscfg: &mut Syscfg) {
+                        syscfg.select($i, $port);
+                    }
+
+                    fn trigger_on_edge(&mut self, exti: &mut Exti, edge: Edge) {
+                        exti.trigger_on_edge($i, edge);
+                    }
+
+                    fn enable_interrupt(&mut self, exti: &mut Exti) {
+                        exti.set_interrupt_mask($i, true);
+                    }
+
+                    fn disable_interrupt(&mut self, exti: &mut Exti) {
+                        exti.set_interrupt_mask($i, false);
+                    }
+
+                    fn clear_interrupt_pending_bit(&mut self) {
+                        exti::clear_pending($i);
+                    }
+
+                    fn check_interrupt(&self) -> bool {
+                        exti::is_pending($i)
+                    }
+
+                    fn interrupt(&self) -> Interrupt {
+                        exti::interrupt($i)
+                    }
+                }
             )+
         }
     }
 }
 
-gpio!(GPIOA, gpioa, gpioa, gpiopaen, gpioarst, PAx, [
+gpio!(GPIOA, gpioa, gpioa, gpiopaen, gpioarst, PAx, 0, [
     PA0: (pa0, 0, Input<Floating>, AFRL),
     PA1: (pa1, 1, Input<Floating>, AFRL),
     PA2: (pa2, 2, Input<Floating>, AFRL),
@@ -543,7 +699,7 @@ gpio!(GPIOA, gpioa, gpioa, gpiopaen, gpioarst, PAx, [
     PA15: (pa15, 15, Input<Floating>, AFRH),
 ]);
 
-gpio!(GPIOB, gpiob, gpiob, gpiopben, gpiobrst, PBx, [
+gpio!(GPIOB, gpiob, gpiob, gpiopben, gpiobrst, PBx, 1, [
     PB0: (pb0, 0, Input<Floating>, AFRL),
     PB1: (pb1, 1, Input<Floating>, AFRL),
     PB2: (pb2, 2, Input<Floating>, AFRL),
@@ -562,7 +718,7 @@ gpio!(GPIOB, gpiob, gpiob, gpiopben, gpiobrst, PBx, [
     PB15: (pb15, 15, Input<Floating>, AFRH),
 ]);
 
-gpio!(GPIOC, gpioc, gpioc, gpiopcen, gpiocrst, PCx, [
+gpio!(GPIOC, gpioc, gpioc, gpiopcen, gpiocrst, PCx, 2, [
     PC13: (pc13, 13, Input<Floating>, AFRH),
     PC14: (pc14, 14, Input<Floating>, AFRH),
     PC15: (pc15, 15, Input<Floating>, AFRH),
@@ -0,0 +1,415 @@
+//! Inter-Integrated Circuit (I2C) bus
+
+use cortex_m::interrupt;
+use hal::blocking::delay::DelayUs;
+use hal::blocking::i2c::{Read, Write, WriteRead};
+use hal::digital::{InputPin, OutputPin};
+use stm32l151::{i2c1, I2C1, I2C2};
+
+use gpio::gpiob::{PB10, PB11, PB6, PB7, PB8, PB9};
+use gpio::AF4;
+use rcc::{APB1, Clocks};
+use time::Hertz;
+
+/// I2C error
+#[derive(Debug)]
+pub enum Error {
+    /// Bus error, a misplaced START or STOP condition
+    Bus,
+    /// Arbitration lost to another master
+    Arbitration,
+    /// The slave did not acknowledge its address or a data byte
+    Nack,
+    /// The bus is still held low by another device, see `recover`
+    Busy,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+// FIXME these should be "closed" traits
+/// SCL pin -- DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait SclPin<I2C> {}
+
+/// SDA pin -- DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait SdaPin<I2C> {}
+
+unsafe impl SclPin<I2C1> for PB6<AF4> {}
+unsafe impl SclPin<I2C1> for PB8<AF4> {}
+unsafe impl SclPin<I2C2> for PB10<AF4> {}
+
+unsafe impl SdaPin<I2C1> for PB7<AF4> {}
+unsafe impl SdaPin<I2C1> for PB9<AF4> {}
+unsafe impl SdaPin<I2C2> for PB11<AF4> {}
+
+/// Highest SCL frequency of the standard mode
+const STANDARD_MODE_MAX: u32 = 100_000;
+/// Highest SCL frequency of the fast mode
+const FAST_MODE_MAX: u32 = 400_000;
+
+/// I2C peripheral operating in master mode
+pub struct I2c<I2C, PINS> {
+    i2c: I2C,
+    pins: PINS,
+    timing: Timing,
+}
+
+/// Register values of the bus timing, kept to restore them after a reset
+#[derive(Clone, Copy)]
+struct Timing {
+    freq: u8,
+    fast: bool,
+    ccr: u16,
+    trise: u8,
+}
+
+impl Timing {
+    fn new(pclk1: Hertz, scl: Hertz) -> Self {
+        let pclk1 = pclk1.0;
+        let scl = scl.0;
+
+        let freq = pclk1 / 1_000_000;
+        assert!(freq >= 2 && freq <= 32, "impossible APB1 frequency");
+        assert!(scl > 0 && scl <= FAST_MODE_MAX, "impossible SCL frequency");
+
+        if scl <= STANDARD_MODE_MAX {
+            // Thigh = Tlow = CCR * Tpclk1, rise time up to 1000 ns
+            Timing {
+                freq: freq as u8,
+                fast: false,
+                ccr: (pclk1 / (2 * scl)).max(4) as u16,
+                trise: (freq + 1) as u8,
+            }
+        } else {
+            // DUTY = 0: Thigh = CCR * Tpclk1, Tlow = 2 * CCR * Tpclk1, rise time up to 300 ns
+            Timing {
+                freq: freq as u8,
+                fast: true,
+                ccr: (pclk1 / (3 * scl)).max(1) as u16,
+                trise: (freq * 300 / 1000 + 1) as u8,
+            }
+        }
+    }
+}
+
+/// Frees a bus held by a slave that was interrupted in the middle of a transfer
+///
+/// After a reset of the microcontroller during a read, a slave may still be driving SDA low,
+/// waiting for the rest of the clock pulses. This clocks SCL, up to 9 times, until the slave
+/// releases SDA. Call it before configuring the pins for I2C, with SCL as an open drain output and
+/// SDA as a floating input.
+pub fn recover<SCL, SDA, D>(scl: &mut SCL, sda: &SDA, delay: &mut D) -> Result<(), Error>
+where
+    SCL: OutputPin,
+    SDA: InputPin,
+    D: DelayUs<u32>,
+{
+    // 100 kHz, the lowest speed of any device on the bus
+    const HALF_PERIOD_US: u32 = 5;
+
+    scl.set_high();
+    delay.delay_us(HALF_PERIOD_US);
+
+    for _ in 0..9 {
+        if sda.is_high() {
+            return Ok(());
+        }
+
+        scl.set_low();
+        delay.delay_us(HALF_PERIOD_US);
+        scl.set_high();
+        delay.delay_us(HALF_PERIOD_US);
+    }
+
+    if sda.is_high() {
+        Ok(())
+    } else {
+        Err(Error::Busy)
+    }
+}
+
+/// Configures the peripheral, after a reset
+fn configure(i2c: &i2c1::RegisterBlock, timing: Timing) {
+    // FREQ: APB1 frequency in MHz
+    i2c.cr2.write(|w| unsafe { w.freq().bits(timing.freq) });
+
+    // F_S: standard or fast mode
+    // DUTY: Tlow/Thigh = 2
+    i2c.ccr.write(|w| unsafe {
+        w.f_s()
+            .bit(timing.fast)
+            .duty()
+            .clear_bit()
+            .ccr()
+            .bits(timing.ccr)
+    });
+
+    i2c.trise.write(|w| unsafe { w.trise().bits(timing.trise) });
+
+    // PE: enable the peripheral
+    i2c.cr1.write(|w| w.pe().set_bit());
+}
+
+/// Resets the peripheral, releasing the bus
+///
+/// The software reset is the only way out of a bus error that left the peripheral in a
+/// confused state.
+fn reset(i2c: &i2c1::RegisterBlock, timing: Timing) {
+    i2c.cr1.write(|w| w.swrst().set_bit());
+    i2c.cr1.write(|w| w.swrst().clear_bit());
+
+    configure(i2c, timing);
+}
+
+/// Waits for a status flag, reporting the errors that can happen meanwhile
+fn wait<F>(i2c: &i2c1::RegisterBlock, timing: Timing, flag: F) -> Result<(), Error>
+where
+    F: Fn(&i2c1::sr1::R) -> bool,
+{
+    loop {
+        let sr1 = i2c.sr1.read();
+
+        if sr1.berr().bit_is_set() {
+            reset(i2c, timing);
+            return Err(Error::Bus);
+        } else if sr1.arlo().bit_is_set() {
+            // The peripheral is back in slave mode, the other master owns the bus
+            i2c.sr1.modify(|_, w| w.arlo().clear_bit());
+            return Err(Error::Arbitration);
+        } else if sr1.af().bit_is_set() {
+            // Release the bus, nothing else will happen in this transfer
+            i2c.cr1.modify(|_, w| w.stop().set_bit());
+            i2c.sr1.modify(|_, w| w.af().clear_bit());
+            return Err(Error::Nack);
+        } else if flag(&sr1) {
+            return Ok(());
+        }
+    }
+}
+
+/// Sends a START (or repeated START) condition followed by the address of the slave
+fn start(i2c: &i2c1::RegisterBlock, timing: Timing, address: u8, read: bool) -> Result<(), Error> {
+    i2c.cr1.modify(|_, w| w.start().set_bit());
+    wait(i2c, timing, |sr1| sr1.sb().bit_is_set())?;
+
+    // Writing DR after reading SR1 clears SB
+    i2c.dr
+        .write(|w| unsafe { w.dr().bits((address << 1) | read as u8) });
+
+    wait(i2c, timing, |sr1| sr1.addr().bit_is_set())
+}
+
+/// Clears the ADDR flag, letting the transfer go on
+fn clear_addr(i2c: &i2c1::RegisterBlock) {
+    // ADDR is cleared by a read of SR1 followed by a read of SR2
+    i2c.sr1.read();
+    i2c.sr2.read();
+}
+
+/// Makes sure no other device is holding the bus before a START condition
+fn check_busy(i2c: &i2c1::RegisterBlock, timing: Timing) -> Result<(), Error> {
+    if i2c.sr2.read().busy().bit_is_set() {
+        // Possibly a STOP condition that was missed after an error
+        reset(i2c, timing);
+
+        if i2c.sr2.read().busy().bit_is_set() {
+            return Err(Error::Busy);
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes `bytes`, leaving the bus to the caller once the last byte is sent
+fn write(
+    i2c: &i2c1::RegisterBlock,
+    timing: Timing,
+    address: u8,
+    bytes: &[u8],
+) -> Result<(), Error> {
+    start(i2c, timing, address, false)?;
+    clear_addr(i2c);
+
+    for &byte in bytes {
+        wait(i2c, timing, |sr1| sr1.tx_e().bit_is_set())?;
+        i2c.dr.write(|w| unsafe { w.dr().bits(byte) });
+    }
+
+    // With no data, the address is all there is to send
+    if !bytes.is_empty() {
+        wait(i2c, timing, |sr1| sr1.btf().bit_is_set())?;
+    }
+
+    Ok(())
+}
+
+/// Reads `buffer`, ending the transfer with a STOP condition
+///
+/// The ACK and STOP bits must be changed while the last bytes are still being received, which
+/// requires a different sequence for one, two and more bytes.
+fn read(
+    i2c: &i2c1::RegisterBlock,
+    timing: Timing,
+    address: u8,
+    buffer: &mut [u8],
+) -> Result<(), Error> {
+    let len = buffer.len();
+
+    // A read has to receive at least one byte, nothing to do
+    if len == 0 {
+        return Ok(());
+    }
+
+    // ACK: only acknowledge the bytes followed by at least two more
+    // POS: for two bytes, the ACK bit applies to the second one
+    i2c.cr1
+        .modify(|_, w| w.ack().bit(len > 2).pos().bit(len == 2));
+
+    start(i2c, timing, address, true)?;
+
+    match len {
+        1 => {
+            // The STOP bit must be set right after clearing ADDR, before the byte is received
+            interrupt::free(|_| {
+                clear_addr(i2c);
+                i2c.cr1.modify(|_, w| w.stop().set_bit());
+            });
+
+            wait(i2c, timing, |sr1| sr1.rx_ne().bit_is_set())?;
+            buffer[0] = i2c.dr.read().dr().bits();
+        }
+        2 => {
+            clear_addr(i2c);
+
+            // Both bytes received, the second one still in the shift register
+            wait(i2c, timing, |sr1| sr1.btf().bit_is_set())?;
+            i2c.cr1.modify(|_, w| w.stop().set_bit().pos().clear_bit());
+
+            buffer[0] = i2c.dr.read().dr().bits();
+            buffer[1] = i2c.dr.read().dr().bits();
+        }
+        _ => {
+            clear_addr(i2c);
+
+            let (head, tail) = buffer.split_at_mut(len - 3);
+
+            for byte in head {
+                wait(i2c, timing, |sr1| sr1.rx_ne().bit_is_set())?;
+                *byte = i2c.dr.read().dr().bits();
+            }
+
+            // Byte N-2 in DR, N-1 in the shift register: NACK byte N
+            wait(i2c, timing, |sr1| sr1.btf().bit_is_set())?;
+            i2c.cr1.modify(|_, w| w.ack().clear_bit());
+            tail[0] = i2c.dr.read().dr().bits();
+
+            // Byte N-1 in DR, N in the shift register
+            wait(i2c, timing, |sr1| sr1.btf().bit_is_set())?;
+            i2c.cr1.modify(|_, w| w.stop().set_bit());
+            tail[1] = i2c.dr.read().dr().bits();
+
+            wait(i2c, timing, |sr1| sr1.rx_ne().bit_is_set())?;
+            tail[2] = i2c.dr.read().dr().bits();
+        }
+    }
+
+    // The next START condition must not be requested before the STOP condition is sent
+    while i2c.cr1.read().stop().bit_is_set() {}
+
+    Ok(())
+}
+
+/// Sends a STOP condition, ending a write
+fn stop(i2c: &i2c1::RegisterBlock) {
+    i2c.cr1.modify(|_, w| w.stop().set_bit());
+
+    while i2c.cr1.read().stop().bit_is_set() {}
+}
+
+macro_rules! hal {
+    ($($I2CX:ident: ($i2cX:ident, $i2cXen:ident, $i2cXrst:ident),)+) => {
+        $(
+            impl<SCL, SDA> I2c<$I2CX, (SCL, SDA)> {
+                /// Configures the I2C peripheral to operate in master mode
+                ///
+                /// Frequencies up to 100 kHz select the standard mode, up to 400 kHz the fast
+                /// mode. Both pins must be configured as open drain, see `set_open_drain`.
+                pub fn $i2cX<F>(
+                    i2c: $I2CX,
+                    pins: (SCL, SDA),
+                    freq: F,
+                    clocks: Clocks,
+                    apb1: &mut APB1,
+                ) -> Self
+                where
+                    F: Into<Hertz>,
+                    SCL: SclPin<$I2CX>,
+                    SDA: SdaPin<$I2CX>,
+                {
+                    // enable or reset $I2CX
+                    apb1.enr().modify(|_, w| w.$i2cXen().set_bit());
+                    apb1.rstr().modify(|_, w| w.$i2cXrst().set_bit());
+                    apb1.rstr().modify(|_, w| w.$i2cXrst().clear_bit());
+
+                    let timing = Timing::new(clocks.pclk1(), freq.into());
+                    configure(&i2c, timing);
+
+                    I2c { i2c, pins, timing }
+                }
+
+                /// Releases the I2C peripheral and associated pins
+                pub fn free(self) -> ($I2CX, (SCL, SDA)) {
+                    (self.i2c, self.pins)
+                }
+            }
+
+            impl<PINS> Write for I2c<$I2CX, PINS> {
+                type Error = Error;
+
+                fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Error> {
+                    check_busy(&self.i2c, self.timing)?;
+                    write(&self.i2c, self.timing, address, bytes)?;
+                    stop(&self.i2c);
+
+                    Ok(())
+                }
+            }
+
+            impl<PINS> Read for I2c<$I2CX, PINS> {
+                type Error = Error;
+
+                fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Error> {
+                    check_busy(&self.i2c, self.timing)?;
+                    read(&self.i2c, self.timing, address, buffer)
+                }
+            }
+
+            impl<PINS> WriteRead for I2c<$I2CX, PINS> {
+                type Error = Error;
+
+                fn write_read(
+                    &mut self,
+                    address: u8,
+                    bytes: &[u8],
+                    buffer: &mut [u8],
+                ) -> Result<(), Error> {
+                    check_busy(&self.i2c, self.timing)?;
+
+                    // The read starts with a repeated START condition, no STOP in between
+                    write(&self.i2c, self.timing, address, bytes)?;
+
+                    if buffer.is_empty() {
+                        stop(&self.i2c);
+                        return Ok(());
+                    }
+
+                    read(&self.i2c, self.timing, address, buffer)
+                }
+            }
+        )+
+    }
+}
+
+hal! {
+    I2C1: (i2c1, i2c1en, i2c1rst),
+    I2C2: (i2c2, i2c2en, i2c2rst),
+}
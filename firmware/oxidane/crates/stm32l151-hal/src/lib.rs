@@ -26,15 +26,30 @@ extern crate cortex_m;
 extern crate embedded_hal as hal;
 extern crate nb;
 extern crate stm32l1;
+#[cfg(feature = "usb")]
+extern crate usb_device;
 extern crate void;
 
 pub use stm32l1::stm32l151;
 
+pub mod adc;
+pub mod capture;
 pub mod delay;
+pub mod dma;
+pub mod eeprom;
+pub mod exti;
 pub mod flash;
 pub mod gpio;
+pub mod i2c;
 pub mod prelude;
+pub mod pwm;
+pub mod pwr;
 pub mod rcc;
+pub mod rtc;
 pub mod serial;
 pub mod spi;
 pub mod time;
+pub mod timer;
+#[cfg(feature = "usb")]
+pub mod usb;
+pub mod watchdog;
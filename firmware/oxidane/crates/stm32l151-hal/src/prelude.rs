@@ -1,5 +1,7 @@
 //! Prelude
 
+pub use dma::DmaExt as _stm32l151_hal_dma_DmaExt;
+pub use exti::ExtiPin as _stm32l151_hal_exti_ExtiPin;
 pub use flash::FlashExt as _stm32l151_hal_flash_FlashExt;
 pub use gpio::GpioExt as _stm32l151_hal_gpio_GpioExt;
 pub use hal::digital::ToggleableOutputPin as _stm32l151_hal_gpio_ToggleableOutputPin;
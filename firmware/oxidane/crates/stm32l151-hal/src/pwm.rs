@@ -0,0 +1,164 @@
+//! Pulse Width Modulation (PWM)
+//!
+//! A `PwmTimer` sets the frequency shared by all the channels of a timer, then each channel drives
+//! one of its pins (see `timer::ChannelPin`) with its own duty cycle.
+
+use core::marker::PhantomData;
+
+use hal::PwmPin;
+use stm32l151::{TIM10, TIM11, TIM2, TIM3, TIM4, TIM9};
+
+use rcc::{APB1, APB2, Clocks};
+use time::Hertz;
+use timer::{self, ChannelPin, C1, C2, C3, C4};
+
+/// Timer configured for PWM output
+pub struct PwmTimer<TIM> {
+    tim: TIM,
+}
+
+/// PWM output of a timer channel
+pub struct Pwm<TIM, CHANNEL, PIN> {
+    pin: PIN,
+    _tim: PhantomData<TIM>,
+    _channel: PhantomData<CHANNEL>,
+}
+
+macro_rules! hal {
+    ($($TIMX:ident: ($timX:ident, $APB:ident, $timXen:ident, $timXrst:ident, $pclkX:ident, $ppreX:ident, [
+        $($CX:ident: ($channelX:ident, $ccmrX:ident, $ocXm:ident, $ocXpe:ident, $ccrX:ident, $i:expr),)+
+    ]),)+) => {
+        $(
+            impl PwmTimer<$TIMX> {
+                /// Configures a TIM peripheral to generate PWM signals at `freq`
+                ///
+                /// The resolution of the duty cycle is the timer clock divided by `freq`, see
+                /// `PwmPin::get_max_duty`.
+                pub fn $timX<F>(tim: $TIMX, freq: F, clocks: Clocks, apb: &mut $APB) -> Self
+                where
+                    F: Into<Hertz>,
+                {
+                    // enable or reset $TIMX
+                    apb.enr().modify(|_, w| w.$timXen().set_bit());
+                    apb.rstr().modify(|_, w| w.$timXrst().set_bit());
+                    apb.rstr().modify(|_, w| w.$timXrst().clear_bit());
+
+                    let clock = timer::timer_clock(clocks.$pclkX(), clocks.$ppreX());
+                    let (psc, arr) = timer::period(clock / freq.into().0);
+                    tim.psc.write(|w| unsafe { w.psc().bits(psc) });
+                    tim.arr.write(|w| unsafe { w.arr().bits(arr) });
+
+                    // ARPE: preload the period, applied at the next update event
+                    // URS: only counter overflows generate an update event, not UG
+                    tim.cr1.write(|w| w.arpe().set_bit().urs().set_bit());
+
+                    // Trigger an update event to load the prescaler value
+                    tim.egr.write(|w| w.ug().set_bit());
+
+                    // start counter
+                    tim.cr1.modify(|_, w| w.cen().set_bit());
+
+                    PwmTimer { tim }
+                }
+
+                $(
+                    /// Configures a channel to drive `pin`, with its output disabled
+                    pub fn $channelX<PIN>(&mut self, pin: PIN) -> Pwm<$TIMX, $CX, PIN>
+                    where
+                        PIN: ChannelPin<$TIMX, $CX>,
+                    {
+                        // OCxM: PWM mode 1, high while the counter is below the duty cycle
+                        // OCxPE: preload the duty cycle, applied at the end of the period
+                        self.tim
+                            .$ccmrX
+                            .modify(|_, w| unsafe { w.$ocXm().bits(0b110).$ocXpe().set_bit() });
+
+                        Pwm {
+                            pin,
+                            _tim: PhantomData,
+                            _channel: PhantomData,
+                        }
+                    }
+                )+
+
+                /// Stops the timer and releases the TIM peripheral
+                pub fn free(self) -> $TIMX {
+                    // pause counter
+                    self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+
+                    self.tim
+                }
+            }
+
+            $(
+                impl<PIN> Pwm<$TIMX, $CX, PIN> {
+                    /// Disables the output and releases the pin
+                    pub fn free(mut self) -> PIN {
+                        self.disable();
+
+                        self.pin
+                    }
+                }
+
+                // NOTE(unsafe) every channel only accesses its own bits of the timer registers
+                impl<PIN> PwmPin for Pwm<$TIMX, $CX, PIN> {
+                    type Duty = u16;
+
+                    fn disable(&mut self) {
+                        // CCxE: output disabled
+                        unsafe { timer::modify_ccer($TIMX::ptr(), 1 << (4 * $i), 0) }
+                    }
+
+                    fn enable(&mut self) {
+                        // CCxE: output enabled
+                        unsafe { timer::modify_ccer($TIMX::ptr(), 1 << (4 * $i), !0) }
+                    }
+
+                    // NOTE(bits) the fields of CCR3 are misnamed in the device crate
+                    fn get_duty(&self) -> u16 {
+                        unsafe { (*$TIMX::ptr()).$ccrX.read().bits() as u16 }
+                    }
+
+                    fn get_max_duty(&self) -> u16 {
+                        unsafe { (*$TIMX::ptr()).arr.read().arr().bits() }
+                    }
+
+                    fn set_duty(&mut self, duty: u16) {
+                        unsafe { (*$TIMX::ptr()).$ccrX.write(|w| w.bits(u32::from(duty))) }
+                    }
+                }
+            )+
+        )+
+    }
+}
+
+hal! {
+    TIM2: (tim2, APB1, tim2en, tim2rst, pclk1, ppre1, [
+        C1: (channel1, ccmr1_output, oc1m, oc1pe, ccr1, 0),
+        C2: (channel2, ccmr1_output, oc2m, oc2pe, ccr2, 1),
+        C3: (channel3, ccmr2_output, oc3m, oc3pe, ccr3, 2),
+        C4: (channel4, ccmr2_output, oc4m, oc4pe, ccr4, 3),
+    ]),
+    TIM3: (tim3, APB1, tim3en, tim3rst, pclk1, ppre1, [
+        C1: (channel1, ccmr1_output, oc1m, oc1pe, ccr1, 0),
+        C2: (channel2, ccmr1_output, oc2m, oc2pe, ccr2, 1),
+        C3: (channel3, ccmr2_output, oc3m, oc3pe, ccr3, 2),
+        C4: (channel4, ccmr2_output, oc4m, oc4pe, ccr4, 3),
+    ]),
+    TIM4: (tim4, APB1, tim4en, tim4rst, pclk1, ppre1, [
+        C1: (channel1, ccmr1_output, oc1m, oc1pe, ccr1, 0),
+        C2: (channel2, ccmr1_output, oc2m, oc2pe, ccr2, 1),
+        C3: (channel3, ccmr2_output, oc3m, oc3pe, ccr3, 2),
+        C4: (channel4, ccmr2_output, oc4m, oc4pe, ccr4, 3),
+    ]),
+    TIM9: (tim9, APB2, tim9en, tim9rst, pclk2, ppre2, [
+        C1: (channel1, ccmr1_output, oc1m, oc1pe, ccr1, 0),
+        C2: (channel2, ccmr1_output, oc2m, oc2pe, ccr2, 1),
+    ]),
+    TIM10: (tim10, APB2, tim10en, tm10rst, pclk2, ppre2, [
+        C1: (channel1, ccmr1_output, oc1m, oc1pe, ccr1, 0),
+    ]),
+    TIM11: (tim11, APB2, tim11en, tm11rst, pclk2, ppre2, [
+        C1: (channel1, ccmr1_output, oc1m, oc1pe, ccr1, 0),
+    ]),
+}
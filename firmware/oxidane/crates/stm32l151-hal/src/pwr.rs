@@ -0,0 +1,236 @@
+//! Power control and low-power modes
+//!
+//! From the most to the least power hungry:
+//!
+//! - Sleep: the core is stopped, the peripherals keep running and any interrupt wakes it up.
+//! - Low-power sleep: Sleep with the regulator in low-power mode, SYSCLK at most 131 kHz.
+//! - Stop: every clock of the 1.8 V domain is stopped, the RAM and registers are retained. Any EXTI
+//!   line configured as an interrupt wakes the core up, including the RTC wakeup timer and alarms
+//!   on lines 20 and 17. The core resumes on the MSI oscillator, `Pwr::stop` restores the clocks.
+//! - Standby: the 1.8 V domain is powered off, only the RTC, the backup registers and the wakeup
+//!   pins keep working. Waking up resets the microcontroller.
+
+use cortex_m::asm;
+use cortex_m::peripheral::SCB;
+use stm32l151::{PWR, RCC};
+
+pub use rcc::config::VoltageRange;
+use rcc::{Clocks, APB1};
+
+/// Highest SYSCLK frequency allowed with the regulator in low-power mode, MSI range 1
+const LOW_POWER_SYSCLK: u32 = 131_072; // Hz
+
+impl VoltageRange {
+    /// Returns the range currently applied
+    pub(crate) fn current() -> Self {
+        // NOTE(unsafe) atomic read with no side effects
+        match unsafe { (*PWR::ptr()).cr.read().vos().bits() } {
+            0b01 => VoltageRange::Range1,
+            0b10 => VoltageRange::Range2,
+            _ => VoltageRange::Range3,
+        }
+    }
+
+    /// Applies the range, the PWR clock must be enabled
+    pub(crate) fn apply(self) {
+        // NOTE(unsafe) the voltage range is only changed along with the clock configuration
+        let pwr = unsafe { &*PWR::ptr() };
+
+        // VOS can only be written while the regulator is ready
+        while pwr.csr.read().vosf().bit_is_set() {}
+        pwr.cr.modify(|_, w| unsafe { w.vos().bits(self as u8) });
+        while pwr.csr.read().vosf().bit_is_set() {}
+    }
+}
+
+/// Wakeup pin, which wakes the microcontroller from Standby on a rising edge
+pub enum WakeupPin {
+    /// WKUP1, PA0
+    Pin1,
+    /// WKUP2, PC13
+    Pin2,
+}
+
+/// Power controller
+pub struct Pwr {
+    pwr: PWR,
+}
+
+impl Pwr {
+    /// Enables the PWR peripheral
+    ///
+    /// The peripheral is not reset, as that would change the voltage range selected by
+    /// `CFGR::freeze`.
+    pub fn pwr(pwr: PWR, apb1: &mut APB1) -> Self {
+        // enable PWR
+        apb1.enr().modify(|_, w| w.pwren().set_bit());
+
+        Pwr { pwr }
+    }
+
+    /// Releases the PWR peripheral
+    pub fn free(self) -> PWR {
+        self.pwr
+    }
+
+    /// Enters Sleep mode until an interrupt occurs
+    pub fn sleep(&mut self, scb: &mut SCB) {
+        scb.clear_sleepdeep();
+
+        asm::wfi();
+    }
+
+    /// Enters Low-power sleep mode until an interrupt occurs
+    ///
+    /// SYSCLK must be at most 131 kHz, see `rcc::MsiRange`. The voltage range is switched to
+    /// range 2, the only one allowed in this mode, and restored on wakeup.
+    pub fn low_power_sleep(&mut self, scb: &mut SCB, clocks: Clocks) {
+        assert!(
+            clocks.sysclk().0 <= LOW_POWER_SYSCLK,
+            "impossible low-power sleep"
+        );
+
+        VoltageRange::Range2.apply();
+
+        // LPSDSR: regulator in low-power mode while the core sleeps
+        self.pwr.cr.modify(|_, w| w.lpsdsr().set_bit());
+        scb.clear_sleepdeep();
+
+        asm::wfi();
+
+        self.pwr.cr.modify(|_, w| w.lpsdsr().clear_bit());
+
+        clocks.voltage_range().apply();
+    }
+
+    /// Switches to Low-power run mode, with the regulator in low-power mode
+    ///
+    /// SYSCLK must be at most 131 kHz, see `rcc::MsiRange`. The voltage range is switched
+    /// to range 2, the only one allowed in this mode.
+    pub fn enter_low_power_run(&mut self, clocks: Clocks) {
+        assert!(
+            clocks.sysclk().0 <= LOW_POWER_SYSCLK,
+            "impossible low-power run"
+        );
+
+        VoltageRange::Range2.apply();
+
+        // LPSDSR must be set before LPRUN
+        self.pwr.cr.modify(|_, w| w.lpsdsr().set_bit());
+        self.pwr.cr.modify(|_, w| w.lprun().set_bit());
+    }
+
+    /// Switches back to Run mode, with the regulator in main mode
+    pub fn exit_low_power_run(&mut self, clocks: Clocks) {
+        // LPRUN must be cleared before LPSDSR
+        self.pwr.cr.modify(|_, w| w.lprun().clear_bit());
+        while self.pwr.csr.read().reglpf().bit_is_set() {}
+        self.pwr.cr.modify(|_, w| w.lpsdsr().clear_bit());
+
+        clocks.voltage_range().apply();
+    }
+
+    /// Enters Stop mode until an EXTI line interrupt occurs
+    ///
+    /// Stop mode is left right away while any EXTI line or RTC flag is pending. The clocks
+    /// configured by `CFGR::freeze` are restored before returning.
+    pub fn stop(&mut self, scb: &mut SCB) {
+        // NOTE(unsafe) the clock configuration is frozen, it is restored as it was
+        let rcc = unsafe { &*RCC::ptr() };
+        let cr = rcc.cr.read();
+        let (hsi, hse, pll) = (
+            cr.hsion().bit_is_set(),
+            cr.hseon().bit_is_set(),
+            cr.pllon().bit_is_set(),
+        );
+        let sw = rcc.cfgr.read().sw().bits();
+
+        // PDDS: Stop mode on deepsleep
+        // LPSDSR: regulator in low-power mode
+        // ULP: internal voltage reference off
+        // FWU: do not wait for the internal voltage reference on wakeup
+        // CWUF: clear the wakeup flag
+        self.pwr.cr.modify(|_, w| {
+            w.pdds()
+                .clear_bit()
+                .lpsdsr()
+                .set_bit()
+                .ulp()
+                .set_bit()
+                .fwu()
+                .set_bit()
+                .cwuf()
+                .set_bit()
+        });
+        scb.set_sleepdeep();
+
+        asm::wfi();
+
+        scb.clear_sleepdeep();
+        self.pwr.cr.modify(|_, w| w.lpsdsr().clear_bit());
+
+        // The core resumes on the MSI oscillator, with the other oscillators off
+        if hsi {
+            rcc.cr.modify(|_, w| w.hsion().set_bit());
+            while rcc.cr.read().hsirdy().bit_is_clear() {}
+        }
+        if hse {
+            rcc.cr.modify(|_, w| w.hseon().set_bit());
+            while rcc.cr.read().hserdy().bit_is_clear() {}
+        }
+        if pll {
+            rcc.cr.modify(|_, w| w.pllon().set_bit());
+            while rcc.cr.read().pllrdy().bit_is_clear() {}
+        }
+        rcc.cfgr.modify(|_, w| unsafe { w.sw().bits(sw) });
+        while rcc.cfgr.read().sws().bits() != sw {}
+    }
+
+    /// Enables a wakeup pin, which then can no longer be used as a GPIO
+    pub fn enable_wakeup_pin(&mut self, pin: WakeupPin) {
+        match pin {
+            WakeupPin::Pin1 => self.pwr.csr.modify(|_, w| w.ewup1().set_bit()),
+            WakeupPin::Pin2 => self.pwr.csr.modify(|_, w| w.ewup2().set_bit()),
+        }
+    }
+
+    /// Disables a wakeup pin
+    pub fn disable_wakeup_pin(&mut self, pin: WakeupPin) {
+        match pin {
+            WakeupPin::Pin1 => self.pwr.csr.modify(|_, w| w.ewup1().clear_bit()),
+            WakeupPin::Pin2 => self.pwr.csr.modify(|_, w| w.ewup2().clear_bit()),
+        }
+    }
+
+    /// Enters Standby mode, which is only left through a reset
+    ///
+    /// The enabled wakeup pins, the RTC alarms and wakeup timer, the IWDG and the NRST pin wake
+    /// the microcontroller up.
+    pub fn standby(&mut self, scb: &mut SCB) -> ! {
+        // PDDS: Standby mode on deepsleep
+        // CWUF: clear the wakeup flag, or Standby mode is left right away
+        self.pwr
+            .cr
+            .modify(|_, w| w.pdds().set_bit().cwuf().set_bit());
+        scb.set_sleepdeep();
+
+        loop {
+            asm::wfi();
+        }
+    }
+
+    /// Disables the write protection of the RTC domain: RTC, backup registers and LSE
+    pub(crate) fn unlock_rtc_domain(&mut self) {
+        self.pwr.cr.modify(|_, w| w.dbp().set_bit());
+    }
+
+    /// Returns `true` if the microcontroller was woken up from Standby mode
+    pub fn is_standby_wakeup(&self) -> bool {
+        self.pwr.csr.read().sbf().bit_is_set()
+    }
+
+    /// Clears the flag reporting a wakeup from Standby mode
+    pub fn clear_standby_flag(&mut self) {
+        self.pwr.cr.modify(|_, w| w.csbf().set_bit());
+    }
+}
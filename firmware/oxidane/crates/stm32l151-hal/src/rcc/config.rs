@@ -0,0 +1,521 @@
+//! Clock configuration, computed from the requested frequencies without touching the hardware
+//!
+//! Nothing in here accesses a register, so the tests run on the host.
+
+use time::Hertz;
+
+use super::{
+    AHBPrescaler, APBPrescaler, Clocks, Error, MsiRange, PllDivider, PllMultiplier, PllSource,
+    SystemClock, CFGR,
+};
+
+/// Dynamic voltage scaling range of the 1.8 V domain
+///
+/// The lower the voltage, the lower the consumption and the maximum frequency.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VoltageRange {
+    /// 1.8 V, SYSCLK up to 32 MHz, required by USB
+    Range1 = 0b01,
+    /// 1.5 V, SYSCLK up to 16 MHz
+    Range2 = 0b10,
+    /// 1.2 V, SYSCLK up to 4.2 MHz, without the PLL and the HSI oscillator
+    Range3 = 0b11,
+}
+
+impl VoltageRange {
+    /// Returns `true` if the range supports a SYSCLK of `sysclk` Hz, a PLL VCO of `vco` Hz (0
+    /// without the PLL), an HSE of `hse` Hz (0 without the HSE) and using the HSI oscillator or not
+    pub(crate) fn supports(&self, sysclk: u32, vco: u32, hse: u32, hsi: bool) -> bool {
+        let (max_sysclk, max_vco, max_hse) = match *self {
+            VoltageRange::Range1 => (32_000_000, 96_000_000, 32_000_000),
+            VoltageRange::Range2 => (16_000_000, 48_000_000, 16_000_000),
+            VoltageRange::Range3 => (4_200_000, 0, 8_000_000),
+        };
+
+        sysclk <= max_sysclk
+            && vco <= max_vco
+            && hse <= max_hse
+            && !(hsi && *self == VoltageRange::Range3)
+    }
+
+    /// Returns the highest HCLK frequency at which the flash memory needs no wait state
+    pub(crate) fn max_zero_wait_state(&self) -> u32 {
+        match *self {
+            VoltageRange::Range1 => 16_000_000,
+            VoltageRange::Range2 => 8_000_000,
+            VoltageRange::Range3 => 2_100_000,
+        }
+    }
+}
+
+const HSI_FREQ: u32 = 16_000_000; // Hz
+
+/// PLL VCO frequency required by the USB peripheral
+const USB_VCO: u32 = 96_000_000; // Hz
+
+/// MSI frequencies in Hz, indexed by `MsiRange`
+const MSI_VALUES: [u32; 7] = [
+    65_536, 131_072, 262_144, 524_288, 1_048_000, 2_097_000, 4_194_000,
+];
+const PLLMUL_VALUES: [u32; 9] = [3, 4, 6, 8, 12, 16, 24, 32, 48];
+const HPRE_VALUES: [u32; 9] = [1, 2, 4, 8, 16, 64, 128, 256, 512];
+
+// Candidates of the configuration computed from the target frequencies
+const MSI_RANGES: [MsiRange; 7] = [
+    MsiRange::Range0,
+    MsiRange::Range1,
+    MsiRange::Range2,
+    MsiRange::Range3,
+    MsiRange::Range4,
+    MsiRange::Range5,
+    MsiRange::Range6,
+];
+const PLL_MULTIPLIERS: [PllMultiplier; 9] = [
+    PllMultiplier::X3,
+    PllMultiplier::X4,
+    PllMultiplier::X6,
+    PllMultiplier::X8,
+    PllMultiplier::X12,
+    PllMultiplier::X16,
+    PllMultiplier::X24,
+    PllMultiplier::X32,
+    PllMultiplier::X48,
+];
+const PLL_DIVIDERS: [PllDivider; 3] = [PllDivider::Div2, PllDivider::Div3, PllDivider::Div4];
+const AHB_PRESCALERS: [AHBPrescaler; 9] = [
+    AHBPrescaler::Div1,
+    AHBPrescaler::Div2,
+    AHBPrescaler::Div4,
+    AHBPrescaler::Div8,
+    AHBPrescaler::Div16,
+    AHBPrescaler::Div64,
+    AHBPrescaler::Div128,
+    AHBPrescaler::Div256,
+    AHBPrescaler::Div512,
+];
+const APB_PRESCALERS: [APBPrescaler; 5] = [
+    APBPrescaler::Div1,
+    APBPrescaler::Div2,
+    APBPrescaler::Div4,
+    APBPrescaler::Div8,
+    APBPrescaler::Div16,
+];
+
+/// Validated clock configuration
+pub(super) struct Config {
+    pub(super) sysclk: SystemClock,
+    pub(super) hpre: AHBPrescaler,
+    pub(super) ppre1: APBPrescaler,
+    pub(super) ppre2: APBPrescaler,
+    pub(super) range: VoltageRange,
+    pub(super) clocks: Clocks,
+}
+
+impl CFGR {
+    /// Validates the configuration and computes its frequencies, without touching the hardware
+    pub(super) fn config(&self) -> Result<Config, Error> {
+        let sysclk = match self.target_sysclk {
+            Some(freq) => self.select_sysclk(freq)?,
+            None => self.sysclk,
+        };
+
+        // Compute the SYSCLK and PLL VCO frequencies, which select the voltage range
+        let (sysclk_freq, vco, hsi, hse) = match sysclk {
+            SystemClock::PLL(src, mul, div) => {
+                let (input, hsi, hse) = match src {
+                    PllSource::HSI => (HSI_FREQ, true, 0),
+                    PllSource::HSE(freq) => (freq.0, false, freq.0),
+                };
+                if input < 2_000_000 || input > 24_000_000 {
+                    return Err(Error::PllInput);
+                }
+                let vco = input * PLLMUL_VALUES[mul as usize];
+
+                (vco / (div as u32 + 1), vco, hsi, hse)
+            }
+            SystemClock::MSI(range) => (MSI_VALUES[range as usize], 0, false, 0),
+            SystemClock::HSI => (HSI_FREQ, 0, true, 0),
+            SystemClock::HSE(freq) => (freq.0, 0, false, freq.0),
+        };
+
+        if hse != 0 {
+            let max = if self.hse_bypass {
+                32_000_000
+            } else {
+                24_000_000
+            };
+            if hse < 1_000_000 || hse > max {
+                return Err(Error::HseFrequency);
+            }
+        }
+        if self.css && hse == 0 {
+            return Err(Error::Css);
+        }
+        if self.usb && vco != USB_VCO {
+            return Err(Error::Usb);
+        }
+
+        let supported = |range: &VoltageRange| range.supports(sysclk_freq, vco, hse, hsi);
+        let range = match self.range {
+            Some(range) => Some(range).filter(&supported),
+            None => [
+                VoltageRange::Range3,
+                VoltageRange::Range2,
+                VoltageRange::Range1,
+            ]
+            .iter()
+            .cloned()
+            .find(&supported),
+        };
+        let range = range.ok_or(Error::VoltageRange)?;
+
+        // Compute clock values based on selected source
+
+        let hpre = match self.target_hclk {
+            Some(freq) => AHB_PRESCALERS
+                .iter()
+                .cloned()
+                .find(|&p| sysclk_freq / HPRE_VALUES[p as usize - 0b0111] == freq)
+                .ok_or(Error::Hclk)?,
+            None => self.hpre,
+        };
+        let hclk = sysclk_freq / HPRE_VALUES[hpre as usize - 0b0111];
+
+        let ppre1 = match self.target_pclk1 {
+            Some(freq) => APB_PRESCALERS
+                .iter()
+                .cloned()
+                .find(|&p| hclk >> (p as u8 - 0b011) == freq)
+                .ok_or(Error::Pclk1)?,
+            None => self.ppre1,
+        };
+        let ppre1_div = 1 << (ppre1 as u8 - 0b011);
+
+        let ppre2 = match self.target_pclk2 {
+            Some(freq) => APB_PRESCALERS
+                .iter()
+                .cloned()
+                .find(|&p| hclk >> (p as u8 - 0b011) == freq)
+                .ok_or(Error::Pclk2)?,
+            None => self.ppre2,
+        };
+        let ppre2_div = 1 << (ppre2 as u8 - 0b011);
+
+        Ok(Config {
+            sysclk,
+            hpre,
+            ppre1,
+            ppre2,
+            range,
+            clocks: Clocks {
+                hclk: Hertz(hclk),
+                pclk1: Hertz(hclk / ppre1_div),
+                pclk2: Hertz(hclk / ppre2_div),
+                ppre1: ppre1_div as u8,
+                ppre2: ppre2_div as u8,
+                sysclk: Hertz(sysclk_freq),
+                voltage_range: range,
+                lse: self.lse.is_some(),
+                lsi: self.lsi,
+                usb: vco == USB_VCO,
+            },
+        })
+    }
+
+    /// Selects the clock source of a SYSCLK of `freq` Hz
+    ///
+    /// The oscillators are preferred to the PLL, then the lowest PLL VCO, the HSE to the HSI.
+    fn select_sysclk(&self, freq: u32) -> Result<SystemClock, Error> {
+        if !self.usb {
+            if let Some(&range) = MSI_RANGES
+                .iter()
+                .find(|&&range| MSI_VALUES[range as usize] == freq)
+            {
+                return Ok(SystemClock::MSI(range));
+            }
+            if freq == HSI_FREQ {
+                return Ok(SystemClock::HSI);
+            }
+            if let Some(hse) = self.hse.filter(|hse| hse.0 == freq) {
+                return Ok(SystemClock::HSE(hse));
+            }
+        }
+
+        let mut best = None;
+        let sources = [self.hse.map(PllSource::HSE), Some(PllSource::HSI)];
+        for src in sources.iter().filter_map(|&src| src) {
+            let input = match src {
+                PllSource::HSI => HSI_FREQ,
+                PllSource::HSE(freq) => freq.0,
+            };
+
+            for &mul in PLL_MULTIPLIERS.iter() {
+                let vco = input * PLLMUL_VALUES[mul as usize];
+
+                for &div in PLL_DIVIDERS.iter() {
+                    let valid = vco / (div as u32 + 1) == freq && (!self.usb || vco == USB_VCO);
+                    let lower = best.map(|(best, _)| vco < best).unwrap_or(true);
+
+                    if valid && lower {
+                        best = Some((vco, SystemClock::PLL(src, mul, div)));
+                    }
+                }
+            }
+        }
+
+        best.map(|(_, sysclk)| sysclk).ok_or(Error::Sysclk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sysclk(cfgr: CFGR) -> (SystemClock, VoltageRange) {
+        let config = cfgr.config().unwrap();
+
+        (config.sysclk, config.range)
+    }
+
+    #[test]
+    fn msi() {
+        let cfgr = CFGR::new().sysclk(Hertz(2_097_000));
+
+        assert_eq!(
+            sysclk(cfgr),
+            (SystemClock::MSI(MsiRange::Range5), VoltageRange::Range3)
+        );
+    }
+
+    #[test]
+    fn hsi() {
+        // The HSI rules out range 3
+        let cfgr = CFGR::new().sysclk(Hertz(16_000_000));
+
+        assert_eq!(sysclk(cfgr), (SystemClock::HSI, VoltageRange::Range2));
+    }
+
+    #[test]
+    fn hse() {
+        let cfgr = CFGR::new().hse(Hertz(4_000_000)).sysclk(Hertz(4_000_000));
+        assert_eq!(
+            sysclk(cfgr),
+            (SystemClock::HSE(Hertz(4_000_000)), VoltageRange::Range3)
+        );
+
+        // An HSE above 16 MHz requires range 1
+        let cfgr = CFGR::new().hse(Hertz(24_000_000)).sysclk(Hertz(24_000_000));
+        assert_eq!(
+            sysclk(cfgr),
+            (SystemClock::HSE(Hertz(24_000_000)), VoltageRange::Range1)
+        );
+    }
+
+    #[test]
+    fn pll_hsi() {
+        let cfgr = CFGR::new().sysclk(Hertz(12_000_000));
+
+        assert_eq!(
+            sysclk(cfgr),
+            (
+                SystemClock::PLL(PllSource::HSI, PllMultiplier::X3, PllDivider::Div4),
+                VoltageRange::Range2
+            )
+        );
+    }
+
+    #[test]
+    fn pll_hse() {
+        // 64 MHz VCO rather than 96 or 128 MHz, the HSE rather than the HSI
+        let cfgr = CFGR::new().hse(Hertz(8_000_000)).sysclk(Hertz(32_000_000));
+
+        assert_eq!(
+            sysclk(cfgr),
+            (
+                SystemClock::PLL(
+                    PllSource::HSE(Hertz(8_000_000)),
+                    PllMultiplier::X8,
+                    PllDivider::Div2
+                ),
+                VoltageRange::Range1
+            )
+        );
+    }
+
+    #[test]
+    fn usb() {
+        let config = CFGR::new()
+            .sysclk(Hertz(32_000_000))
+            .require_usb()
+            .config()
+            .unwrap();
+
+        assert_eq!(
+            config.sysclk,
+            SystemClock::PLL(PllSource::HSI, PllMultiplier::X6, PllDivider::Div3)
+        );
+        assert_eq!(config.range, VoltageRange::Range1);
+        assert!(config.clocks.usbclk_valid());
+
+        // The HSE is skipped unless it reaches 96 MHz
+        let config = CFGR::new()
+            .hse(Hertz(12_000_000))
+            .sysclk(Hertz(24_000_000))
+            .require_usb()
+            .config()
+            .unwrap();
+
+        assert_eq!(
+            config.sysclk,
+            SystemClock::PLL(
+                PllSource::HSE(Hertz(12_000_000)),
+                PllMultiplier::X8,
+                PllDivider::Div4
+            )
+        );
+        assert!(config.clocks.usbclk_valid());
+    }
+
+    #[test]
+    fn prescalers() {
+        let config = CFGR::new()
+            .sysclk(Hertz(16_000_000))
+            .hclk(Hertz(4_000_000))
+            .pclk1(Hertz(1_000_000))
+            .pclk2(Hertz(4_000_000))
+            .config()
+            .unwrap();
+
+        assert_eq!(config.hpre, AHBPrescaler::Div4);
+        assert_eq!(config.ppre1, APBPrescaler::Div4);
+        assert_eq!(config.ppre2, APBPrescaler::Div1);
+        assert_eq!(config.clocks.sysclk(), Hertz(16_000_000));
+        assert_eq!(config.clocks.hclk(), Hertz(4_000_000));
+        assert_eq!(config.clocks.pclk1(), Hertz(1_000_000));
+        assert_eq!(config.clocks.pclk2(), Hertz(4_000_000));
+        assert_eq!(config.clocks.ppre1(), 4);
+        assert_eq!(config.clocks.ppre2(), 1);
+    }
+
+    #[test]
+    fn set_clock() {
+        let config = CFGR::new()
+            .set_clock(
+                SystemClock::MSI(MsiRange::Range6),
+                AHBPrescaler::Div2,
+                APBPrescaler::Div1,
+                APBPrescaler::Div2,
+            )
+            .config()
+            .unwrap();
+
+        assert_eq!(config.clocks.sysclk(), Hertz(4_194_000));
+        assert_eq!(config.clocks.hclk(), Hertz(2_097_000));
+        assert_eq!(config.clocks.pclk2(), Hertz(1_048_500));
+        assert!(!config.clocks.usbclk_valid());
+    }
+
+    #[test]
+    fn hse_frequency() {
+        let cfgr = CFGR::new().hse(Hertz(30_000_000)).sysclk(Hertz(30_000_000));
+        assert_eq!(cfgr.config().err(), Some(Error::HseFrequency));
+
+        // Up to 32 MHz when bypassed
+        let cfgr = CFGR::new()
+            .hse(Hertz(30_000_000))
+            .bypass_hse()
+            .sysclk(Hertz(30_000_000));
+        assert!(cfgr.config().is_ok());
+
+        let cfgr = CFGR::new().hse(Hertz(500_000)).sysclk(Hertz(500_000));
+        assert_eq!(cfgr.config().err(), Some(Error::HseFrequency));
+    }
+
+    #[test]
+    fn pll_input() {
+        let cfgr = CFGR::new().set_clock(
+            SystemClock::PLL(
+                PllSource::HSE(Hertz(1_000_000)),
+                PllMultiplier::X48,
+                PllDivider::Div2,
+            ),
+            AHBPrescaler::Div1,
+            APBPrescaler::Div1,
+            APBPrescaler::Div1,
+        );
+
+        assert_eq!(cfgr.config().err(), Some(Error::PllInput));
+    }
+
+    #[test]
+    fn sysclk_error() {
+        let cfgr = CFGR::new().sysclk(Hertz(5_000_000));
+        assert_eq!(cfgr.config().err(), Some(Error::Sysclk));
+
+        // The HSI is not used directly with a 96 MHz VCO required
+        let cfgr = CFGR::new().sysclk(Hertz(16_000_000)).require_usb();
+        assert_eq!(cfgr.config().err(), Some(Error::Sysclk));
+    }
+
+    #[test]
+    fn hclk() {
+        let cfgr = CFGR::new().sysclk(Hertz(16_000_000)).hclk(Hertz(5_000_000));
+
+        assert_eq!(cfgr.config().err(), Some(Error::Hclk));
+    }
+
+    #[test]
+    fn pclk1() {
+        let cfgr = CFGR::new()
+            .sysclk(Hertz(16_000_000))
+            .pclk1(Hertz(3_000_000));
+
+        assert_eq!(cfgr.config().err(), Some(Error::Pclk1));
+    }
+
+    #[test]
+    fn pclk2() {
+        let cfgr = CFGR::new()
+            .sysclk(Hertz(16_000_000))
+            .pclk2(Hertz(32_000_000));
+
+        assert_eq!(cfgr.config().err(), Some(Error::Pclk2));
+    }
+
+    #[test]
+    fn usb_error() {
+        let cfgr = CFGR::new().require_usb();
+
+        assert_eq!(cfgr.config().err(), Some(Error::Usb));
+    }
+
+    #[test]
+    fn voltage_range() {
+        let cfgr = CFGR::new()
+            .sysclk(Hertz(32_000_000))
+            .voltage_range(VoltageRange::Range2);
+        assert_eq!(cfgr.config().err(), Some(Error::VoltageRange));
+
+        let cfgr = CFGR::new()
+            .sysclk(Hertz(16_000_000))
+            .voltage_range(VoltageRange::Range3);
+        assert_eq!(cfgr.config().err(), Some(Error::VoltageRange));
+
+        let cfgr = CFGR::new()
+            .sysclk(Hertz(4_194_000))
+            .voltage_range(VoltageRange::Range1);
+        assert_eq!(sysclk(cfgr).1, VoltageRange::Range1);
+    }
+
+    #[test]
+    fn css() {
+        let cfgr = CFGR::new().sysclk(Hertz(16_000_000)).enable_css();
+        assert_eq!(cfgr.config().err(), Some(Error::Css));
+
+        let cfgr = CFGR::new()
+            .hse(Hertz(8_000_000))
+            .sysclk(Hertz(8_000_000))
+            .enable_css();
+        assert!(cfgr.config().is_ok());
+    }
+}
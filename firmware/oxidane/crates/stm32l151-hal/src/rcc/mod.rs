@@ -0,0 +1,748 @@
+//! Reset and Clock Control
+
+use stm32l151::{rcc, PWR, RCC};
+
+use flash::ACR;
+use pwr::VoltageRange;
+use time::Hertz;
+
+use self::config::Config;
+
+pub(crate) mod config;
+
+/// Extension trait that constrains the `RCC` peripheral
+pub trait RccExt {
+    /// Constrains the `RCC` peripheral so it plays nicely with the other abstractions
+    fn constrain(self) -> Rcc;
+}
+
+impl RccExt for RCC {
+    fn constrain(self) -> Rcc {
+        Rcc {
+            ahb: AHB { _0: () },
+            apb1: APB1 { _0: () },
+            apb2: APB2 { _0: () },
+            cfgr: CFGR::new(),
+            csr: CSR { _0: () },
+        }
+    }
+}
+
+/// Constrained RCC peripheral
+pub struct Rcc {
+    /// AMBA High-performance Bus (AHB) registers
+    pub ahb: AHB,
+    /// Advanced Peripheral Bus 1 (APB1) registers
+    pub apb1: APB1,
+    /// Advanced Peripheral Bus 2 (APB2) registers
+    pub apb2: APB2,
+    /// Clock configuration
+    pub cfgr: CFGR,
+    /// Control/status register
+    pub csr: CSR,
+}
+
+/// AMBA High-performance Bus (AHB) registers
+pub struct AHB {
+    _0: (),
+}
+
+impl AHB {
+    pub(crate) fn enr(&mut self) -> &rcc::AHBENR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*RCC::ptr()).ahbenr }
+    }
+
+    pub(crate) fn rstr(&mut self) -> &rcc::AHBRSTR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*RCC::ptr()).ahbrstr }
+    }
+}
+
+/// Advanced Peripheral Bus 1 (APB1) registers
+pub struct APB1 {
+    _0: (),
+}
+
+impl APB1 {
+    pub(crate) fn enr(&mut self) -> &rcc::APB1ENR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*RCC::ptr()).apb1enr }
+    }
+
+    pub(crate) fn rstr(&mut self) -> &rcc::APB1RSTR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*RCC::ptr()).apb1rstr }
+    }
+}
+
+/// Advanced Peripheral Bus 2 (APB2) registers
+pub struct APB2 {
+    _0: (),
+}
+
+impl APB2 {
+    pub(crate) fn enr(&mut self) -> &rcc::APB2ENR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*RCC::ptr()).apb2enr }
+    }
+
+    pub(crate) fn rstr(&mut self) -> &rcc::APB2RSTR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*RCC::ptr()).apb2rstr }
+    }
+}
+
+/// Control/status register
+pub struct CSR {
+    _0: (),
+}
+
+impl CSR {
+    /// Returns the cause of the last reset
+    ///
+    /// The reset flags accumulate until `clear_reset_flags` is called, which should follow every
+    /// read of the cause.
+    pub fn reset_cause(&mut self) -> ResetCause {
+        // NOTE(unsafe) atomic read with no side effects
+        let flags = unsafe { (*RCC::ptr()).csr.read().bits() };
+
+        if flags & (1 << 31) != 0 {
+            ResetCause::LowPower
+        } else if flags & (1 << 30) != 0 {
+            ResetCause::WindowWatchdog
+        } else if flags & (1 << 29) != 0 {
+            ResetCause::IndependentWatchdog
+        } else if flags & (1 << 28) != 0 {
+            ResetCause::Software
+        } else if flags & (1 << 27) != 0 {
+            ResetCause::PowerOn
+        } else if flags & (1 << 25) != 0 {
+            ResetCause::OptionBytes
+        } else if flags & (1 << 26) != 0 {
+            ResetCause::Pin
+        } else {
+            ResetCause::Unknown
+        }
+    }
+
+    /// Clears the reset flags, so that the next reset reports its own cause
+    pub fn clear_reset_flags(&mut self) {
+        // NOTE(unsafe) RMVF is only written through this proxy
+        unsafe { (*RCC::ptr()).csr.modify(|_, w| w.rmvf().set_bit()) }
+    }
+}
+
+/// Cause of a reset
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResetCause {
+    /// Entering Stop or Standby mode is forbidden by the option bytes
+    LowPower,
+    /// The window watchdog timed out
+    WindowWatchdog,
+    /// The independent watchdog timed out
+    IndependentWatchdog,
+    /// The software requested a reset, see `SCB::system_reset`
+    Software,
+    /// The supply voltage was turned on, or dropped below the brownout threshold
+    PowerOn,
+    /// The option bytes were reloaded
+    OptionBytes,
+    /// The NRST pin was pulled low
+    Pin,
+    /// The reset flags were cleared since the last reset
+    Unknown,
+}
+
+pub(crate) const LSE_FREQ: u32 = 32_768; // Hz
+/// Typical frequency of the LSI oscillator, which varies between 26 and 56 kHz
+pub(crate) const LSI_FREQ: u32 = 37_000; // Hz
+
+/// Clock configuration error
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The HSE frequency is out of the 1 to 24 MHz range, or 1 to 32 MHz when bypassed
+    HseFrequency,
+    /// The PLL input frequency is out of the 2 to 24 MHz range
+    PllInput,
+    /// No clock source reaches the SYSCLK frequency, with a 96 MHz PLL VCO if USB is required
+    Sysclk,
+    /// No AHB prescaler reaches the HCLK frequency
+    Hclk,
+    /// No APB1 prescaler reaches the PCLK1 frequency
+    Pclk1,
+    /// No APB2 prescaler reaches the PCLK2 frequency
+    Pclk2,
+    /// USB is required but the PLL VCO does not run at 96 MHz
+    Usb,
+    /// The SYSCLK, PLL VCO or HSE frequency exceeds the limits of the voltage range
+    VoltageRange,
+    /// The clock security system is enabled but the HSE is not used
+    Css,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+/// AHB prescaler values
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AHBPrescaler {
+    /// SYSCLK not divided
+    Div1 = 0b0111,
+    /// SYSCLK divided by 2
+    Div2 = 0b1000,
+    /// SYSCLK divided by 4
+    Div4 = 0b1001,
+    /// SYSCLK divided by 8
+    Div8 = 0b1010,
+    /// SYSCLK divided by 16
+    Div16 = 0b1011,
+    /// SYSCLK divided by 64
+    Div64 = 0b1100,
+    /// SYSCLK divided by 128
+    Div128 = 0b1101,
+    /// SYSCLK divided by 256
+    Div256 = 0b1110,
+    /// SYSCLK divided by 512
+    Div512 = 0b1111,
+}
+
+/// APB prescaler values
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum APBPrescaler {
+    /// HCLK not divided
+    Div1 = 0b011,
+    /// HCLK divided by 2
+    Div2 = 0b100,
+    /// HCLK divided by 4
+    Div4 = 0b101,
+    /// HCLK divided by 8
+    Div8 = 0b110,
+    /// HCLK divided by 16
+    Div16 = 0b111,
+}
+
+/// MSI oscillator frequency range
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MsiRange {
+    /// 65.536 kHz
+    Range0 = 0b000,
+    /// 131.072 kHz
+    Range1 = 0b001,
+    /// 262.144 kHz
+    Range2 = 0b010,
+    /// 524.288 kHz
+    Range3 = 0b011,
+    /// 1.048 MHz
+    Range4 = 0b100,
+    /// 2.097 MHz, the reset value
+    Range5 = 0b101,
+    /// 4.194 MHz
+    Range6 = 0b110,
+}
+
+/// PLL multiplication factor
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PllMultiplier {
+    /// PLLVCO = PLL clock entry x3
+    X3 = 0b0000,
+    /// PLLVCO = PLL clock entry x4
+    X4 = 0b0001,
+    /// PLLVCO = PLL clock entry x6
+    X6 = 0b0010,
+    /// PLLVCO = PLL clock entry x8
+    X8 = 0b0011,
+    /// PLLVCO = PLL clock entry x12
+    X12 = 0b0100,
+    /// PLLVCO = PLL clock entry x16
+    X16 = 0b0101,
+    /// PLLVCO = PLL clock entry x24
+    X24 = 0b0110,
+    /// PLLVCO = PLL clock entry x32
+    X32 = 0b0111,
+    /// PLLVCO = PLL clock entry x48
+    X48 = 0b1000,
+}
+
+/// PLL output division
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PllDivider {
+    /// PLL clock output = PLLVCO / 2
+    Div2 = 0b01,
+    /// PLL clock output = PLLVCO / 3
+    Div3 = 0b10,
+    /// PLL clock output = PLLVCO / 4
+    Div4 = 0b11,
+}
+
+/// PLL entry clock source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PllSource {
+    /// HSI oscillator selected as PLL input clock
+    HSI,
+    /// HSE oscillator selected as PLL input clock
+    HSE(Hertz),
+}
+
+/// System clock source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SystemClock {
+    /// MSI oscillator
+    MSI(MsiRange),
+    /// HSI oscillator
+    HSI,
+    /// HSE oscillator
+    HSE(Hertz),
+    /// PLL output
+    PLL(PllSource, PllMultiplier, PllDivider),
+}
+
+/// Microcontroller clock output (MCO) source
+#[derive(Clone, Copy)]
+pub enum McoSource {
+    /// System clock
+    SYSCLK = 0b001,
+    /// HSI oscillator
+    HSI = 0b010,
+    /// MSI oscillator
+    MSI = 0b011,
+    /// HSE oscillator
+    HSE = 0b100,
+    /// PLL output
+    PLL = 0b101,
+    /// LSI oscillator
+    LSI = 0b110,
+    /// LSE oscillator
+    LSE = 0b111,
+}
+
+/// Microcontroller clock output (MCO) prescaler values
+#[derive(Clone, Copy)]
+pub enum McoPrescaler {
+    /// MCO source not divided
+    Div1 = 0b000,
+    /// MCO source divided by 2
+    Div2 = 0b001,
+    /// MCO source divided by 4
+    Div4 = 0b010,
+    /// MCO source divided by 8
+    Div8 = 0b011,
+    /// MCO source divided by 16
+    Div16 = 0b100,
+}
+
+/// Clock configuration
+pub struct CFGR {
+    sysclk: SystemClock,
+    hpre: AHBPrescaler,
+    ppre1: APBPrescaler,
+    ppre2: APBPrescaler,
+    hse_bypass: bool,
+    css: bool,
+    lse: Option<bool>,
+    lsi: bool,
+    mco: Option<(McoSource, McoPrescaler)>,
+    target_sysclk: Option<u32>,
+    target_hclk: Option<u32>,
+    target_pclk1: Option<u32>,
+    target_pclk2: Option<u32>,
+    hse: Option<Hertz>,
+    range: Option<VoltageRange>,
+    usb: bool,
+}
+
+impl CFGR {
+    /// Reset configuration: SYSCLK from the 2.097 MHz MSI, nothing divided
+    fn new() -> Self {
+        CFGR {
+            sysclk: SystemClock::MSI(MsiRange::Range5),
+            hpre: AHBPrescaler::Div1,
+            ppre1: APBPrescaler::Div1,
+            ppre2: APBPrescaler::Div1,
+            hse_bypass: false,
+            css: false,
+            lse: None,
+            lsi: false,
+            mco: None,
+            target_sysclk: None,
+            target_hclk: None,
+            target_pclk1: None,
+            target_pclk2: None,
+            hse: None,
+            range: None,
+            usb: false,
+        }
+    }
+
+    /// Configures the system clock tree
+    pub fn set_clock(
+        mut self,
+        sysclk: SystemClock,
+        hpre: AHBPrescaler,
+        ppre1: APBPrescaler,
+        ppre2: APBPrescaler,
+    ) -> Self {
+        self.sysclk = sysclk;
+        self.hpre = hpre;
+        self.ppre1 = ppre1;
+        self.ppre2 = ppre2;
+        self
+    }
+
+    /// Sets the SYSCLK frequency, whose clock source is then selected by `freeze`
+    ///
+    /// The MSI, HSI and HSE oscillators are preferred to the PLL, then the lowest PLL VCO. This
+    /// overrides the clock source set by `set_clock`.
+    pub fn sysclk<F>(mut self, freq: F) -> Self
+    where
+        F: Into<Hertz>,
+    {
+        self.target_sysclk = Some(freq.into().0);
+        self
+    }
+
+    /// Sets the HCLK frequency, whose AHB prescaler is then selected by `freeze`
+    pub fn hclk<F>(mut self, freq: F) -> Self
+    where
+        F: Into<Hertz>,
+    {
+        self.target_hclk = Some(freq.into().0);
+        self
+    }
+
+    /// Sets the PCLK1 frequency, whose APB1 prescaler is then selected by `freeze`
+    pub fn pclk1<F>(mut self, freq: F) -> Self
+    where
+        F: Into<Hertz>,
+    {
+        self.target_pclk1 = Some(freq.into().0);
+        self
+    }
+
+    /// Sets the PCLK2 frequency, whose APB2 prescaler is then selected by `freeze`
+    pub fn pclk2<F>(mut self, freq: F) -> Self
+    where
+        F: Into<Hertz>,
+    {
+        self.target_pclk2 = Some(freq.into().0);
+        self
+    }
+
+    /// Declares the frequency of the HSE, which the SYSCLK set by `sysclk` may then use
+    pub fn hse<F>(mut self, freq: F) -> Self
+    where
+        F: Into<Hertz>,
+    {
+        self.hse = Some(freq.into());
+        self
+    }
+
+    /// Selects a voltage range, instead of the lowest one supporting the configuration
+    pub fn voltage_range(mut self, range: VoltageRange) -> Self {
+        self.range = Some(range);
+        self
+    }
+
+    /// Requires the 96 MHz PLL VCO that clocks the USB peripheral
+    pub fn require_usb(mut self) -> Self {
+        self.usb = true;
+        self
+    }
+
+    /// Drives the HSE with an external clock on OSC_IN, instead of a crystal
+    pub fn bypass_hse(mut self) -> Self {
+        self.hse_bypass = true;
+        self
+    }
+
+    /// Enables the clock security system (CSS), which monitors the HSE
+    ///
+    /// If the HSE fails, it is turned off along with the PLL, SYSCLK switches to the MSI and an NMI
+    /// is raised. The NMI handler must call `clear_css_interrupt`. The HSE must be used by
+    /// `SystemClock::HSE` or `PllSource::HSE`.
+    pub fn enable_css(mut self) -> Self {
+        self.css = true;
+        self
+    }
+
+    /// Starts the LSE oscillator, driven by an external clock on OSC32_IN if `bypass` is set
+    ///
+    /// The LSE belongs to the RTC domain: it keeps running across resets and is left untouched if
+    /// already running with the same configuration.
+    pub fn enable_lse(mut self, bypass: bool) -> Self {
+        self.lse = Some(bypass);
+        self
+    }
+
+    /// Starts the LSI oscillator
+    pub fn enable_lsi(mut self) -> Self {
+        self.lsi = true;
+        self
+    }
+
+    /// Outputs `source` divided by `prescaler` on PA8, which must be configured as AF0
+    ///
+    /// The oscillators that do not drive SYSCLK must be started separately, see `enable_lse` and
+    /// `enable_lsi`.
+    pub fn mco(mut self, source: McoSource, prescaler: McoPrescaler) -> Self {
+        self.mco = Some((source, prescaler));
+        self
+    }
+
+    /// Freezes the clock configuration, making it effective
+    ///
+    /// Unless set by `voltage_range`, the lowest voltage range supporting the configuration is
+    /// selected, see `pwr::VoltageRange`. An invalid configuration leaves the clocks untouched.
+    pub fn freeze(self, acr: &mut ACR) -> Result<Clocks, Error> {
+        let rcc = unsafe { &*RCC::ptr() };
+
+        let Config {
+            sysclk,
+            hpre,
+            ppre1,
+            ppre2,
+            range,
+            clocks,
+        } = self.config()?;
+        let hclk = clocks.hclk.0;
+
+        // The voltage must be raised before the frequencies, and lowered after them
+        rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+        let raise = (range as u8) < (VoltageRange::current() as u8);
+        if raise {
+            range.apply();
+        }
+
+        // Start the oscillator driving sysclk, the MSI is already running
+        match sysclk {
+            SystemClock::HSI => CFGR::enable_hsi(),
+            SystemClock::HSE(_) => self.enable_hse(),
+            SystemClock::PLL(src, mul, div) => self.setup_pll(src, mul, div),
+            SystemClock::MSI(_) => {}
+        }
+
+        // Configure FLASH: 64-bit access, prefetch, 1 WS above the limit of the voltage range
+        let latency = hclk > range.max_zero_wait_state();
+        acr.acr().modify(|_, w| w.acc64().set_bit());
+        acr.acr().modify(|_, w| w.prften().set_bit());
+        acr.acr().modify(|_, w| w.latency().bit(latency));
+        while acr.acr().read().latency().bit() != latency {}
+
+        // The MSI range only changes once the flash latency fits it
+        if let SystemClock::MSI(range) = sysclk {
+            rcc.cr.modify(|_, w| w.msion().set_bit());
+            while rcc.cr.read().msirdy().bit_is_clear() {}
+            rcc.icscr
+                .modify(|_, w| unsafe { w.msirange().bits(range as u8) });
+            while rcc.cr.read().msirdy().bit_is_clear() {}
+        }
+
+        // Perform clock switch
+        let sw = match sysclk {
+            SystemClock::MSI(_) => 0b00,
+            SystemClock::HSI => 0b01,
+            SystemClock::HSE(_) => 0b10,
+            SystemClock::PLL(_, _, _) => 0b11,
+        };
+        rcc.cfgr.modify(|_, w| unsafe {
+            w.hpre()
+                .bits(hpre as u8)
+                .ppre1()
+                .bits(ppre1 as u8)
+                .ppre2()
+                .bits(ppre2 as u8)
+                .sw()
+                .bits(sw)
+        });
+        while rcc.cfgr.read().sws().bits() != sw {}
+
+        if !raise {
+            range.apply();
+        }
+
+        if self.lse.is_some() {
+            // NOTE(unsafe) the write protection of the RTC domain is only ever disabled
+            unsafe { (*PWR::ptr()).cr.modify(|_, w| w.dbp().set_bit()) };
+        }
+        if let Some(bypass) = self.lse {
+            enable_lse(bypass);
+        }
+        if self.lsi {
+            enable_lsi();
+        }
+
+        if let Some((source, prescaler)) = self.mco {
+            rcc.cfgr.modify(|_, w| unsafe {
+                w.mcosel().bits(source as u8).mcopre().bits(prescaler as u8)
+            });
+        }
+
+        Ok(clocks)
+    }
+
+    /// Starts the HSI oscillator
+    fn enable_hsi() {
+        let rcc = unsafe { &*RCC::ptr() };
+
+        // Wait for HSI startup and trim it to factory values
+        rcc.cr.modify(|_, w| w.hsion().set_bit());
+        while !rcc.cr.read().hsirdy().bit_is_set() {}
+        rcc.icscr.modify(|_, w| unsafe { w.hsitrim().bits(16) });
+    }
+
+    /// Starts the HSE oscillator, along with the clock security system
+    fn enable_hse(&self) {
+        let rcc = unsafe { &*RCC::ptr() };
+
+        // HSEBYP can only be written while the HSE is off
+        rcc.cr.modify(|_, w| w.hsebyp().bit(self.hse_bypass));
+
+        // Wait for HSE startup
+        rcc.cr.modify(|_, w| w.hseon().set_bit());
+        while !rcc.cr.read().hserdy().bit_is_set() {}
+
+        if self.css {
+            rcc.cr.modify(|_, w| w.csson().set_bit());
+        }
+    }
+
+    /// Configure PLL clock output
+    fn setup_pll(&self, src: PllSource, mul: PllMultiplier, div: PllDivider) {
+        let rcc = unsafe { &*RCC::ptr() };
+
+        match src {
+            PllSource::HSI => CFGR::enable_hsi(),
+            PllSource::HSE(_) => self.enable_hse(),
+        }
+
+        // Configure PLL values
+        rcc.cfgr.modify(|_, w| unsafe {
+            w.pllmul()
+                .bits(mul as u8)
+                .plldiv()
+                .bits(div as u8)
+                .pllsrc()
+                .bit(match src {
+                    PllSource::HSI => false,
+                    PllSource::HSE(_) => true,
+                })
+        });
+
+        // Wait for PLL startup
+        rcc.cr.modify(|_, w| w.pllon().set_bit());
+        while !rcc.cr.read().pllrdy().bit_is_set() {}
+    }
+}
+
+/// Clears the clock security system interrupt, from the NMI handler
+///
+/// The frozen `Clocks` no longer apply once the HSE failed: SYSCLK is now the MSI.
+pub fn clear_css_interrupt() {
+    // NOTE(unsafe) the other bits of CIR are not used by this crate
+    unsafe { (*RCC::ptr()).cir.modify(|_, w| w.cssc().set_bit()) }
+}
+
+/// Starts the LSE oscillator, or lets an external clock through if `bypass` is set
+///
+/// The LSE belongs to the RTC domain, whose write protection must be disabled.
+pub(crate) fn enable_lse(bypass: bool) {
+    // NOTE(unsafe) the low-speed oscillators are independent of the frozen configuration
+    let rcc = unsafe { &*RCC::ptr() };
+
+    // Restarting the LSE would disturb the RTC
+    let csr = rcc.csr.read();
+    if csr.lserdy().bit_is_set() && csr.lsebyp().bit() == bypass {
+        return;
+    }
+
+    // LSEBYP can only be written while the LSE is off
+    rcc.csr.modify(|_, w| w.lseon().clear_bit());
+    while rcc.csr.read().lserdy().bit_is_set() {}
+    rcc.csr.modify(|_, w| w.lsebyp().bit(bypass));
+
+    rcc.csr.modify(|_, w| w.lseon().set_bit());
+    while rcc.csr.read().lserdy().bit_is_clear() {}
+}
+
+/// Starts the LSI oscillator
+pub(crate) fn enable_lsi() {
+    // NOTE(unsafe) the low-speed oscillators are independent of the frozen configuration
+    let rcc = unsafe { &*RCC::ptr() };
+
+    rcc.csr.modify(|_, w| w.lsion().set_bit());
+    while rcc.csr.read().lsirdy().bit_is_clear() {}
+}
+
+/// Frozen clock frequencies
+///
+/// The existence of this value indicates that the clock configuration can no longer be changed
+#[derive(Debug, Clone, Copy)]
+pub struct Clocks {
+    hclk: Hertz,
+    pclk1: Hertz,
+    pclk2: Hertz,
+    ppre1: u8,
+    ppre2: u8,
+    sysclk: Hertz,
+    voltage_range: VoltageRange,
+    lse: bool,
+    lsi: bool,
+    usb: bool,
+}
+
+impl Clocks {
+    /// Returns the frequency of the AHB
+    pub fn hclk(&self) -> Hertz {
+        self.hclk
+    }
+
+    /// Returns the frequency of the APB1
+    pub fn pclk1(&self) -> Hertz {
+        self.pclk1
+    }
+
+    /// Returns the frequency of the APB2
+    pub fn pclk2(&self) -> Hertz {
+        self.pclk2
+    }
+
+    pub(crate) fn ppre1(&self) -> u8 {
+        self.ppre1
+    }
+
+    pub(crate) fn ppre2(&self) -> u8 {
+        self.ppre2
+    }
+
+    /// Returns the system (core) frequency
+    pub fn sysclk(&self) -> Hertz {
+        self.sysclk
+    }
+
+    /// Returns the voltage range of the 1.8 V domain
+    pub fn voltage_range(&self) -> VoltageRange {
+        self.voltage_range
+    }
+
+    /// Returns `true` if the PLL VCO runs at 96 MHz, as required by the USB peripheral
+    pub fn usbclk_valid(&self) -> bool {
+        self.usb
+    }
+
+    /// Returns the frequency of the LSE oscillator, if started by `CFGR::enable_lse`
+    pub fn lse(&self) -> Option<Hertz> {
+        if self.lse {
+            Some(Hertz(LSE_FREQ))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the typical frequency of the LSI oscillator, if started by `CFGR::enable_lsi`
+    pub fn lsi(&self) -> Option<Hertz> {
+        if self.lsi {
+            Some(Hertz(LSI_FREQ))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,490 @@
+//! Real-Time Clock (RTC)
+//!
+//! The RTC and its backup registers belong to the RTC domain, which keeps running in Stop and
+//! Standby modes and across system resets: `Rtc::rtc` keeps the date and time of a running RTC.
+//!
+//! The alarms and the wakeup timer are routed to the EXTI lines 17 and 20, whose interrupt
+//! vectors are `RTC_ALARM` and `RTC_WKUP`. They wake the microcontroller up from Stop mode, see
+//! `pwr::Pwr::stop`.
+
+use stm32l151::{rtc, RCC, RTC};
+
+use exti::{self, Edge, Exti};
+use pwr::Pwr;
+use rcc::{self, LSE_FREQ, LSI_FREQ};
+
+// WPR unlock sequence
+const WPR_KEY1: u32 = 0xCA;
+const WPR_KEY2: u32 = 0x53;
+const WPR_LOCK: u32 = 0xFF;
+
+// ISR bits
+const RSF: u32 = 1 << 5;
+const INIT: u32 = 1 << 7;
+const ALRAF: u32 = 1 << 8;
+const ALRBF: u32 = 1 << 9;
+const WUTF: u32 = 1 << 10;
+
+// CR bits
+const WUTE: u32 = 1 << 10;
+const ALRAE: u32 = 1 << 8;
+const ALRBE: u32 = 1 << 9;
+const ALRAIE: u32 = 1 << 12;
+const ALRBIE: u32 = 1 << 13;
+const WUTIE: u32 = 1 << 14;
+
+// EXTI lines of the RTC events
+const ALARM_LINE: u8 = 17;
+const WAKEUP_LINE: u8 = 20;
+
+/// Asynchronous prescaler, the largest one for the lowest consumption
+const PREDIV_A: u32 = 127;
+
+/// Number of backup registers of the category 1 and 2 devices
+pub const BACKUP_REGISTERS: usize = 20;
+
+/// Number of days in each month of a non-leap year
+const MONTH_DAYS: [u8; 12] = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+
+/// Clock source of the RTC
+#[derive(Clone, Copy)]
+pub enum RtcClock {
+    /// 32.768 kHz crystal on the LSE oscillator
+    Lse,
+    /// 32.768 kHz external clock on the OSC32_IN pin
+    LseBypass,
+    /// LSI oscillator, much less accurate
+    Lsi,
+}
+
+/// Interrupt event
+pub enum Event {
+    /// Alarm A matched
+    AlarmA,
+    /// Alarm B matched
+    AlarmB,
+    /// The wakeup timer elapsed
+    Wakeup,
+}
+
+/// Alarm
+#[derive(Clone, Copy)]
+pub enum Alarm {
+    /// Alarm A
+    A,
+    /// Alarm B
+    B,
+}
+
+/// Days on which an alarm matches
+#[derive(Clone, Copy)]
+pub enum AlarmDay {
+    /// Every day
+    Daily,
+    /// A day of the month, from 1 to 31
+    Date(u8),
+    /// A day of the week, from 1 (Monday) to 7 (Sunday)
+    Weekday(u8),
+}
+
+/// Time of the day, in 24-hour format
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Time {
+    /// Hours, from 0 to 23
+    pub hours: u8,
+    /// Minutes, from 0 to 59
+    pub minutes: u8,
+    /// Seconds, from 0 to 59
+    pub seconds: u8,
+}
+
+/// Calendar date, from 2000 to 2099
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Date {
+    /// Year, from 2000 to 2099
+    pub year: u16,
+    /// Month, from 1 to 12
+    pub month: u8,
+    /// Day of the month, from 1 to 31
+    pub day: u8,
+}
+
+impl Time {
+    fn is_valid(&self) -> bool {
+        self.hours < 24 && self.minutes < 60 && self.seconds < 60
+    }
+}
+
+impl Date {
+    /// Returns the day of the week, from 1 (Monday) to 7 (Sunday)
+    pub fn weekday(&self) -> u8 {
+        // January 1st, 2000 was a Saturday
+        ((self.days_since_2000() + 5) % 7 + 1) as u8
+    }
+
+    /// Returns the number of days elapsed since January 1st, 2000
+    pub fn days_since_2000(&self) -> u32 {
+        let years = u32::from(self.year - 2000);
+        // Every fourth year is a leap year from 2000 to 2099
+        let mut days = 365 * years + (years + 3) / 4;
+
+        for month in 1..self.month {
+            days += u32::from(days_in_month(self.year, month));
+        }
+
+        days + u32::from(self.day) - 1
+    }
+
+    fn is_valid(&self) -> bool {
+        self.year >= 2000
+            && self.year <= 2099
+            && self.month >= 1
+            && self.month <= 12
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+    }
+}
+
+/// Real-time clock with calendar
+pub struct Rtc {
+    rtc: RTC,
+}
+
+impl Rtc {
+    /// Enables the RTC clocked by `clock`
+    ///
+    /// If the RTC is already running from `clock`, after a reset or a wakeup from Standby mode,
+    /// its date, time, alarms and backup registers are kept. Otherwise the RTC domain is reset
+    /// and the calendar starts from January 1st, 2000.
+    pub fn rtc(rtc: RTC, clock: RtcClock, pwr: &mut Pwr) -> Self {
+        pwr.unlock_rtc_domain();
+
+        // NOTE(unsafe) the RTC domain registers are only used by this abstraction
+        let rcc = unsafe { &*RCC::ptr() };
+
+        let (rtcsel, freq) = match clock {
+            RtcClock::Lse | RtcClock::LseBypass => (0b01, LSE_FREQ),
+            RtcClock::Lsi => (0b10, LSI_FREQ),
+        };
+
+        let csr = rcc.csr.read();
+        let running = csr.rtcen().bit_is_set() && csr.rtcsel().bits() == rtcsel;
+
+        // The LSI oscillator is stopped by any reset, unlike the LSE one
+        if let RtcClock::Lsi = clock {
+            rcc::enable_lsi();
+        }
+
+        let mut rtc = Rtc { rtc };
+        if !running {
+            // RTCRST: the clock source can only be changed by resetting the RTC domain
+            rcc.csr.modify(|_, w| w.rtcrst().set_bit());
+            rcc.csr.modify(|_, w| w.rtcrst().clear_bit());
+
+            match clock {
+                RtcClock::Lse => rcc::enable_lse(false),
+                RtcClock::LseBypass => rcc::enable_lse(true),
+                RtcClock::Lsi => {}
+            }
+
+            rcc.csr
+                .modify(|_, w| unsafe { w.rtcsel().bits(rtcsel).rtcen().set_bit() });
+
+            // The calendar is clocked at 1 Hz by the two prescalers
+            let prediv_s = freq / (PREDIV_A + 1) - 1;
+            rtc.init(|rtc| {
+                // The two prescalers are written by separate accesses
+                rtc.prer.write(|w| unsafe { w.bits(prediv_s) });
+                rtc.prer
+                    .write(|w| unsafe { w.bits((PREDIV_A << 16) | prediv_s) });
+            });
+        }
+
+        rtc
+    }
+
+    /// Releases the RTC peripheral, which keeps running
+    pub fn free(self) -> RTC {
+        self.rtc
+    }
+
+    /// Sets the date and the time
+    pub fn set_date_time(&mut self, date: Date, time: Time) {
+        assert!(date.is_valid(), "invalid date");
+        assert!(time.is_valid(), "invalid time");
+
+        let dr = (bcd((date.year - 2000) as u8) << 16)
+            | (u32::from(date.weekday()) << 13)
+            | (bcd(date.month) << 8)
+            | bcd(date.day);
+        let tr = time_bits(time);
+
+        self.init(|rtc| {
+            rtc.tr.write(|w| unsafe { w.bits(tr) });
+            rtc.dr.write(|w| unsafe { w.bits(dr) });
+        });
+    }
+
+    /// Returns the current date and time
+    pub fn date_time(&self) -> (Date, Time) {
+        self.synchronize();
+
+        // Reading TR freezes DR until it is read, so both are consistent
+        let tr = self.rtc.tr.read().bits();
+        let dr = self.rtc.dr.read().bits();
+
+        let date = Date {
+            year: 2000 + u16::from(from_bcd(dr >> 16)),
+            month: from_bcd((dr >> 8) & 0x1F),
+            day: from_bcd(dr & 0x3F),
+        };
+        let time = Time {
+            hours: from_bcd((tr >> 16) & 0x3F),
+            minutes: from_bcd((tr >> 8) & 0x7F),
+            seconds: from_bcd(tr & 0x7F),
+        };
+
+        (date, time)
+    }
+
+    /// Returns the number of seconds elapsed since January 1st, 2000 at midnight
+    ///
+    /// This is a monotonic clock for schedules, as long as the date and time are not set.
+    pub fn seconds(&self) -> u32 {
+        let (date, time) = self.date_time();
+
+        date.days_since_2000() * 86_400
+            + u32::from(time.hours) * 3_600
+            + u32::from(time.minutes) * 60
+            + u32::from(time.seconds)
+    }
+
+    /// Sets and enables an alarm, matching at `time` on `day`
+    pub fn set_alarm(&mut self, alarm: Alarm, day: AlarmDay, time: Time) {
+        assert!(time.is_valid(), "invalid time");
+
+        // MSK4: ignore the day
+        // WDSEL: DU holds the day of the week
+        let day = match day {
+            AlarmDay::Daily => 1 << 31,
+            AlarmDay::Date(date) => {
+                assert!(date >= 1 && date <= 31, "invalid date");
+                bcd(date) << 24
+            }
+            AlarmDay::Weekday(weekday) => {
+                assert!(weekday >= 1 && weekday <= 7, "invalid weekday");
+                (1 << 30) | (u32::from(weekday) << 24)
+            }
+        };
+        let bits = day | time_bits(time);
+
+        let enable = match alarm {
+            Alarm::A => ALRAE,
+            Alarm::B => ALRBE,
+        };
+
+        self.unlocked(|rtc| {
+            // The alarm can only be written while disabled
+            rtc.cr.modify(|r, w| unsafe { w.bits(r.bits() & !enable) });
+            match alarm {
+                Alarm::A => {
+                    while rtc.isr.read().alrawf().bit_is_clear() {}
+                    rtc.alrmar.write(|w| unsafe { w.bits(bits) });
+                }
+                Alarm::B => {
+                    while rtc.isr.read().alrbwf().bit_is_clear() {}
+                    rtc.alrmbr.write(|w| unsafe { w.bits(bits) });
+                }
+            }
+            rtc.cr.modify(|r, w| unsafe { w.bits(r.bits() | enable) });
+        });
+    }
+
+    /// Disables an alarm
+    pub fn disable_alarm(&mut self, alarm: Alarm) {
+        let enable = match alarm {
+            Alarm::A => ALRAE,
+            Alarm::B => ALRBE,
+        };
+
+        self.unlocked(|rtc| rtc.cr.modify(|r, w| unsafe { w.bits(r.bits() & !enable) }));
+    }
+
+    /// Enables the periodic wakeup timer, elapsing every `seconds`, from 1 to 131072
+    pub fn enable_wakeup(&mut self, seconds: u32) {
+        assert!(
+            seconds >= 1 && seconds <= 2 << 16,
+            "impossible wakeup period"
+        );
+
+        // WUCKSEL: the 1 Hz clock, with 2^16 added to WUT above 2^16 seconds
+        let (wucksel, wut) = if seconds <= 1 << 16 {
+            (0b100, seconds - 1)
+        } else {
+            (0b110, seconds - 1 - (1 << 16))
+        };
+
+        self.unlocked(|rtc| {
+            // The wakeup timer can only be written while disabled
+            rtc.cr.modify(|r, w| unsafe { w.bits(r.bits() & !WUTE) });
+            while rtc.isr.read().wutwf().bit_is_clear() {}
+
+            rtc.wutr.write(|w| unsafe { w.bits(wut) });
+            rtc.cr
+                .modify(|r, w| unsafe { w.bits((r.bits() & !0b111) | wucksel | WUTE) });
+        });
+    }
+
+    /// Disables the periodic wakeup timer
+    pub fn disable_wakeup(&mut self) {
+        self.unlocked(|rtc| rtc.cr.modify(|r, w| unsafe { w.bits(r.bits() & !WUTE) }));
+    }
+
+    /// Starts listening for an `event`, as an interrupt on its EXTI line
+    pub fn listen(&mut self, exti: &mut Exti, event: Event) {
+        let (line, enable) = event_bits(&event);
+
+        exti.trigger_on_edge(line, Edge::Rising);
+        exti.set_interrupt_mask(line, true);
+
+        self.unlocked(|rtc| rtc.cr.modify(|r, w| unsafe { w.bits(r.bits() | enable) }));
+    }
+
+    /// Stops listening for an `event`
+    ///
+    /// The EXTI line of the alarms stays enabled while the other alarm is listened to.
+    pub fn unlisten(&mut self, exti: &mut Exti, event: Event) {
+        let (line, enable) = event_bits(&event);
+
+        let cr = self.unlocked(|rtc| {
+            rtc.cr.modify(|r, w| unsafe { w.bits(r.bits() & !enable) });
+            rtc.cr.read().bits()
+        });
+
+        if cr & event_interrupts(line) == 0 {
+            exti.set_interrupt_mask(line, false);
+        }
+    }
+
+    /// Clears the flag of an `event` and its EXTI pending bit, from the interrupt handler
+    pub fn clear_interrupt(&mut self, event: Event) {
+        let (line, flag) = match event {
+            Event::AlarmA => (ALARM_LINE, ALRAF),
+            Event::AlarmB => (ALARM_LINE, ALRBF),
+            Event::Wakeup => (WAKEUP_LINE, WUTF),
+        };
+
+        // The flags are cleared by writing 0, writing 1 has no effect
+        self.rtc.isr.write(|w| unsafe { w.bits(!flag & !INIT) });
+        exti::clear_pending(line);
+    }
+
+    /// Returns `true` if an `event` occurred since its flag was cleared
+    pub fn is_pending(&self, event: Event) -> bool {
+        let isr = self.rtc.isr.read();
+
+        match event {
+            Event::AlarmA => isr.alraf().bit_is_set(),
+            Event::AlarmB => isr.alrbf().bit_is_set(),
+            Event::Wakeup => isr.wutf().bit_is_set(),
+        }
+    }
+
+    /// Reads backup register `index`
+    pub fn read_backup(&self, index: usize) -> u32 {
+        assert!(index < BACKUP_REGISTERS, "invalid backup register");
+
+        self.backup(index).read().bits()
+    }
+
+    /// Writes backup register `index`
+    pub fn write_backup(&mut self, index: usize, value: u32) {
+        assert!(index < BACKUP_REGISTERS, "invalid backup register");
+
+        self.backup(index).write(|w| unsafe { w.bits(value) });
+    }
+
+    /// Returns backup register `index`
+    fn backup(&self, index: usize) -> &rtc::BKP0R {
+        // NOTE(unsafe) the backup registers are contiguous and share the same layout
+        unsafe { &*(&self.rtc.bkp0r as *const rtc::BKP0R).offset(index as isize) }
+    }
+
+    /// Runs `f` with the write protection of the RTC registers disabled
+    fn unlocked<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&RTC) -> R,
+    {
+        self.rtc.wpr.write(|w| unsafe { w.bits(WPR_KEY1) });
+        self.rtc.wpr.write(|w| unsafe { w.bits(WPR_KEY2) });
+
+        let result = f(&self.rtc);
+
+        self.rtc.wpr.write(|w| unsafe { w.bits(WPR_LOCK) });
+
+        result
+    }
+
+    /// Runs `f` with the calendar stopped in initialization mode
+    fn init<F>(&mut self, f: F)
+    where
+        F: FnOnce(&RTC),
+    {
+        self.unlocked(|rtc| {
+            rtc.isr.modify(|_, w| w.init().set_bit());
+            while rtc.isr.read().initf().bit_is_clear() {}
+
+            f(rtc);
+
+            rtc.isr.modify(|_, w| w.init().clear_bit());
+        });
+    }
+
+    /// Waits for the calendar shadow registers to be updated
+    ///
+    /// They are stale after a wakeup from Stop mode, until the next RTC clock cycle.
+    fn synchronize(&self) {
+        // The flags are cleared by writing 0, writing 1 has no effect
+        self.rtc.isr.write(|w| unsafe { w.bits(!RSF & !INIT) });
+        while self.rtc.isr.read().rsf().bit_is_clear() {}
+    }
+}
+
+/// Returns the EXTI line of an `event` and its interrupt enable bit
+fn event_bits(event: &Event) -> (u8, u32) {
+    match *event {
+        Event::AlarmA => (ALARM_LINE, ALRAIE),
+        Event::AlarmB => (ALARM_LINE, ALRBIE),
+        Event::Wakeup => (WAKEUP_LINE, WUTIE),
+    }
+}
+
+/// Returns the interrupt enable bits of the events of an EXTI `line`
+fn event_interrupts(line: u8) -> u32 {
+    if line == ALARM_LINE {
+        ALRAIE | ALRBIE
+    } else {
+        WUTIE
+    }
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    if month == 2 && year % 4 == 0 {
+        29
+    } else {
+        MONTH_DAYS[usize::from(month - 1)]
+    }
+}
+
+/// Returns the TR (and ALRMxR) bits of `time`
+fn time_bits(time: Time) -> u32 {
+    (bcd(time.hours) << 16) | (bcd(time.minutes) << 8) | bcd(time.seconds)
+}
+
+fn bcd(value: u8) -> u32 {
+    u32::from(value / 10) << 4 | u32::from(value % 10)
+}
+
+fn from_bcd(bits: u32) -> u8 {
+    ((bits >> 4) & 0xF) as u8 * 10 + (bits & 0xF) as u8
+}
@@ -3,11 +3,13 @@
 use core::marker::PhantomData;
 use core::ptr;
 
+use cortex_m::interrupt;
 use hal::serial;
 use nb;
 use stm32l151::{USART1, USART2, USART3};
 use void::Void;
 
+use dma::{self, Direction, Transfer, TransferPayload, R, W};
 use gpio::gpioa::{PA10, PA2, PA3, PA9};
 use gpio::gpiob::{PB10, PB11, PB6, PB7};
 use gpio::AF7;
@@ -73,7 +75,8 @@ pub struct Tx<USART> {
 
 macro_rules! hal {
     ($(
-        $USARTX:ident: ($usartX:ident, $APB:ident, $usartXen:ident, $usartXrst:ident, $pclkX:ident),
+        $USARTX:ident: ($usartX:ident, $APB:ident, $usartXen:ident, $usartXrst:ident, $pclkX:ident,
+                        $txchan:ident, $rxchan:ident),
     )+) => {
         $(
             impl<TX, RX> Serial<$USARTX, (TX, RX)> {
@@ -94,9 +97,19 @@ macro_rules! hal {
                     apb.rstr().modify(|_, w| w.$usartXrst().set_bit());
                     apb.rstr().modify(|_, w| w.$usartXrst().clear_bit());
 
-                    // disable hardware flow control
-                    // TODO enable DMA
-                    // usart.cr3.write(|w| w.rtse().clear_bit().ctse().clear_bit());
+                    // DMAT, DMAR: DMA requests disabled, enabled by the transfers only as the
+                    // channels are shared
+                    // RTSE, CTSE: hardware flow control disabled
+                    usart.cr3.write(|w| {
+                        w.dmat()
+                            .clear_bit()
+                            .dmar()
+                            .clear_bit()
+                            .rtse()
+                            .clear_bit()
+                            .ctse()
+                            .clear_bit()
+                    });
 
                     let brr = clocks.$pclkX().0 / baud_rate.0;
                     assert!(brr >= 16, "impossible baud rate");
@@ -154,6 +167,95 @@ macro_rules! hal {
                 }
             }
 
+            impl Rx<$USARTX> {
+                /// Receives bytes into `buffer` through the DMA channel `chan`, until it is full
+                ///
+                /// Reception errors are not reported: the USART keeps the first byte of an
+                /// overrun and discards the following ones.
+                pub fn read_exact<B>(
+                    self,
+                    mut chan: dma::$rxchan,
+                    buffer: &'static mut B,
+                ) -> Transfer<W, &'static mut B, dma::$rxchan, Self>
+                where
+                    B: AsMut<[u8]> + ?Sized,
+                {
+                    {
+                        let slice = buffer.as_mut();
+                        // NOTE(unsafe) only the address of the register is taken
+                        let dr = unsafe { &(*$USARTX::ptr()).dr as *const _ as u32 };
+                        chan.configure(
+                            dr,
+                            slice.as_mut_ptr() as u32,
+                            slice.len(),
+                            Direction::PeripheralToMemory,
+                        );
+                    }
+                    chan.start();
+
+                    Transfer::w(buffer, chan, self)
+                }
+            }
+
+            impl Tx<$USARTX> {
+                /// Sends the bytes of `buffer` through the DMA channel `chan`
+                ///
+                /// The transfer is done when the last byte is handed over to the USART, `flush`
+                /// waits until it has been sent.
+                pub fn write_all<B>(
+                    self,
+                    mut chan: dma::$txchan,
+                    buffer: &'static B,
+                ) -> Transfer<R, &'static B, dma::$txchan, Self>
+                where
+                    B: AsRef<[u8]> + ?Sized,
+                {
+                    {
+                        let slice = buffer.as_ref();
+                        // NOTE(unsafe) only the address of the register is taken
+                        let dr = unsafe { &(*$USARTX::ptr()).dr as *const _ as u32 };
+                        chan.configure(
+                            dr,
+                            slice.as_ptr() as u32,
+                            slice.len(),
+                            Direction::MemoryToPeripheral,
+                        );
+                    }
+                    chan.start();
+
+                    Transfer::r(buffer, chan, self)
+                }
+            }
+
+            // NOTE(unsafe) CR3 is shared by both halves, and only modified in critical sections
+            impl TransferPayload for Rx<$USARTX> {
+                fn start(&mut self) {
+                    interrupt::free(|_| unsafe {
+                        (*$USARTX::ptr()).cr3.modify(|_, w| w.dmar().set_bit())
+                    });
+                }
+
+                fn stop(&mut self) {
+                    interrupt::free(|_| unsafe {
+                        (*$USARTX::ptr()).cr3.modify(|_, w| w.dmar().clear_bit())
+                    });
+                }
+            }
+
+            impl TransferPayload for Tx<$USARTX> {
+                fn start(&mut self) {
+                    interrupt::free(|_| unsafe {
+                        (*$USARTX::ptr()).cr3.modify(|_, w| w.dmat().set_bit())
+                    });
+                }
+
+                fn stop(&mut self) {
+                    interrupt::free(|_| unsafe {
+                        (*$USARTX::ptr()).cr3.modify(|_, w| w.dmat().clear_bit())
+                    });
+                }
+            }
+
             impl serial::Read<u8> for Rx<$USARTX> {
                 type Error = Error;
 
@@ -161,22 +263,31 @@ macro_rules! hal {
                     // NOTE(unsafe) atomic read with no side effects
                     let sr = unsafe { (*$USARTX::ptr()).sr.read() };
 
-                    Err(if sr.pe().bit_is_set() {
-                        nb::Error::Other(Error::Parity)
+                    let error = if sr.pe().bit_is_set() {
+                        Error::Parity
                     } else if sr.fe().bit_is_set() {
-                        nb::Error::Other(Error::Framing)
+                        Error::Framing
                     } else if sr.nf().bit_is_set() {
-                        nb::Error::Other(Error::Noise)
+                        Error::Noise
                     } else if sr.ore().bit_is_set() {
-                        nb::Error::Other(Error::Overrun)
+                        Error::Overrun
                     } else if sr.rxne().bit_is_set() {
                         // NOTE(read_volatile) see `write_volatile` below
                         return Ok(unsafe {
                             ptr::read_volatile(&(*$USARTX::ptr()).dr as *const _ as *const _)
                         });
                     } else {
-                        nb::Error::WouldBlock
-                    })
+                        return Err(nb::Error::WouldBlock);
+                    };
+
+                    // The error flags are cleared by a read of SR followed by a read of DR,
+                    // otherwise every further read would report the same error
+                    // NOTE(unsafe) atomic read, the received byte is discarded
+                    unsafe {
+                        ptr::read_volatile(&(*$USARTX::ptr()).dr as *const _ as *const u8);
+                    }
+
+                    Err(nb::Error::Other(error))
                 }
             }
 
@@ -219,7 +330,7 @@ macro_rules! hal {
 }
 
 hal! {
-    USART1: (usart1, APB2, usart1en, usart1rst, pclk2),
-    USART2: (usart2, APB1, usart2en, usart2rst, pclk1),
-    USART3: (usart3, APB1, usart3en, usart3rst, pclk1),
+    USART1: (usart1, APB2, usart1en, usart1rst, pclk2, C4, C5),
+    USART2: (usart2, APB1, usart2en, usart2rst, pclk1, C7, C6),
+    USART3: (usart3, APB1, usart3en, usart3rst, pclk1, C2, C3),
 }
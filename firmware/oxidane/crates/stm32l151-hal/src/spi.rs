@@ -6,6 +6,7 @@ use hal::spi::{FullDuplex, Mode, Phase, Polarity};
 use nb;
 use stm32l151::{SPI1, SPI2};
 
+use dma::{self, Direction, Transfer, TransferPayload, W};
 use gpio::gpioa::{PA11, PA12, PA5, PA6, PA7};
 use gpio::gpiob::{PB13, PB14, PB15, PB3, PB4, PB5};
 use gpio::AF5;
@@ -56,7 +57,8 @@ pub struct Spi<SPI, PINS> {
 }
 
 macro_rules! hal {
-    ($($SPIX:ident: ($spiX:ident, $APBX:ident, $spiXen:ident, $spiXrst:ident, $pclkX:ident),)+) => {
+    ($($SPIX:ident: ($spiX:ident, $APBX:ident, $spiXen:ident, $spiXrst:ident, $pclkX:ident,
+                     $rxchan:ident, $txchan:ident),)+) => {
         $(
             impl<SCK, MISO, MOSI> Spi<$SPIX, (SCK, MISO, MOSI)> {
                 /// Configures the SPI peripheral to operate in full duplex master mode
@@ -80,7 +82,16 @@ macro_rules! hal {
                     apb2.rstr().modify(|_, w| w.$spiXrst().clear_bit());
 
                     // SSOE: Slave Select output disabled
-                    spi.cr2.write(|w| w.ssoe().clear_bit());
+                    // TXDMAEN, RXDMAEN: DMA requests disabled, enabled by the transfers only as
+                    // the channels are shared
+                    spi.cr2.write(|w| {
+                        w.ssoe()
+                            .clear_bit()
+                            .txdmaen()
+                            .clear_bit()
+                            .rxdmaen()
+                            .clear_bit()
+                    });
 
                     let br = match clocks.$pclkX().0 / freq.into().0 {
                         0 => unreachable!(),
@@ -139,6 +150,53 @@ macro_rules! hal {
                 }
             }
 
+            impl<PINS> Spi<$SPIX, PINS> {
+                /// Sends the bytes of `buffer` through the DMA channels `chans`, replacing each one
+                /// with the byte received at the same time
+                ///
+                /// To only send data, ignore the content of the buffer when the transfer is done.
+                pub fn transfer_dma<B>(
+                    self,
+                    chans: (dma::$rxchan, dma::$txchan),
+                    buffer: &'static mut B,
+                ) -> Transfer<W, &'static mut B, (dma::$rxchan, dma::$txchan), Self>
+                where
+                    B: AsMut<[u8]> + ?Sized,
+                {
+                    let (mut rx, mut tx) = chans;
+                    {
+                        let slice = buffer.as_mut();
+                        let dr = &self.spi.dr as *const _ as u32;
+                        let memory = slice.as_mut_ptr() as u32;
+
+                        // The transmitting channel always reads a byte before the receiving
+                        // channel overwrites it, so the same buffer serves both directions
+                        rx.configure(dr, memory, slice.len(), Direction::PeripheralToMemory);
+                        tx.configure(dr, memory, slice.len(), Direction::MemoryToPeripheral);
+                    }
+
+                    // The receiving channel must be ready before the first byte is clocked out
+                    rx.start();
+                    tx.start();
+
+                    Transfer::w(buffer, (rx, tx), self)
+                }
+            }
+
+            impl<PINS> TransferPayload for Spi<$SPIX, PINS> {
+                fn start(&mut self) {
+                    self.spi
+                        .cr2
+                        .modify(|_, w| w.rxdmaen().set_bit().txdmaen().set_bit());
+                }
+
+                fn stop(&mut self) {
+                    self.spi
+                        .cr2
+                        .modify(|_, w| w.rxdmaen().clear_bit().txdmaen().clear_bit());
+                }
+            }
+
             impl<PINS> FullDuplex<u8> for Spi<$SPIX, PINS> {
                 type Error = Error;
 
@@ -189,8 +247,8 @@ macro_rules! hal {
 }
 
 hal! {
-    SPI1: (spi1, APB2, spi1en, spi1rst, pclk2),
-    SPI2: (spi2, APB1, spi2en, spi2rst, pclk1),
+    SPI1: (spi1, APB2, spi1en, spi1rst, pclk2, C2, C3),
+    SPI2: (spi2, APB1, spi2en, spi2rst, pclk1, C4, C5),
 }
 
 // FIXME not working
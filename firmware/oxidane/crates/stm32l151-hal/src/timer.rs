@@ -0,0 +1,229 @@
+//! Timers
+
+use core::ptr;
+
+use cast::{u16, u32};
+use hal::timer::{CountDown, Periodic};
+use nb;
+use stm32l151::{TIM10, TIM11, TIM2, TIM3, TIM4, TIM6, TIM7, TIM9};
+use void::Void;
+
+use gpio::gpioa::{PA0, PA1, PA15, PA2, PA3, PA5, PA6, PA7};
+use gpio::gpiob::{PB0, PB1, PB10, PB11, PB12, PB13, PB14, PB15, PB3, PB4, PB5, PB6, PB7, PB8, PB9};
+use gpio::{AF1, AF2, AF3};
+use rcc::{APB1, APB2, Clocks};
+use time::Hertz;
+
+/// Offset of the CCER register, the same for every timer
+const CCER_OFFSET: isize = 0x20;
+
+/// Interrupt event
+pub enum Event {
+    /// Timer timed out / count down ended
+    TimeOut,
+}
+
+/// Hardware timer
+pub struct Timer<TIM> {
+    clocks: Clocks,
+    tim: TIM,
+}
+
+/// Channel 1 (type state)
+pub struct C1;
+/// Channel 2 (type state)
+pub struct C2;
+/// Channel 3 (type state)
+pub struct C3;
+/// Channel 4 (type state)
+pub struct C4;
+
+// FIXME these should be "closed" traits
+/// Timer channel pin -- DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait ChannelPin<TIM, CHANNEL> {}
+
+unsafe impl ChannelPin<TIM2, C1> for PA0<AF1> {}
+unsafe impl ChannelPin<TIM2, C1> for PA5<AF1> {}
+unsafe impl ChannelPin<TIM2, C1> for PA15<AF1> {}
+unsafe impl ChannelPin<TIM2, C2> for PA1<AF1> {}
+unsafe impl ChannelPin<TIM2, C2> for PB3<AF1> {}
+unsafe impl ChannelPin<TIM2, C3> for PA2<AF1> {}
+unsafe impl ChannelPin<TIM2, C3> for PB10<AF1> {}
+unsafe impl ChannelPin<TIM2, C4> for PA3<AF1> {}
+unsafe impl ChannelPin<TIM2, C4> for PB11<AF1> {}
+
+unsafe impl ChannelPin<TIM3, C1> for PA6<AF2> {}
+unsafe impl ChannelPin<TIM3, C1> for PB4<AF2> {}
+unsafe impl ChannelPin<TIM3, C2> for PA7<AF2> {}
+unsafe impl ChannelPin<TIM3, C2> for PB5<AF2> {}
+unsafe impl ChannelPin<TIM3, C3> for PB0<AF2> {}
+unsafe impl ChannelPin<TIM3, C4> for PB1<AF2> {}
+
+unsafe impl ChannelPin<TIM4, C1> for PB6<AF2> {}
+unsafe impl ChannelPin<TIM4, C2> for PB7<AF2> {}
+unsafe impl ChannelPin<TIM4, C3> for PB8<AF2> {}
+unsafe impl ChannelPin<TIM4, C4> for PB9<AF2> {}
+
+unsafe impl ChannelPin<TIM9, C1> for PA2<AF3> {}
+unsafe impl ChannelPin<TIM9, C1> for PB13<AF3> {}
+unsafe impl ChannelPin<TIM9, C2> for PA3<AF3> {}
+unsafe impl ChannelPin<TIM9, C2> for PB14<AF3> {}
+
+unsafe impl ChannelPin<TIM10, C1> for PA6<AF3> {}
+unsafe impl ChannelPin<TIM10, C1> for PB8<AF3> {}
+unsafe impl ChannelPin<TIM10, C1> for PB12<AF3> {}
+
+unsafe impl ChannelPin<TIM11, C1> for PA7<AF3> {}
+unsafe impl ChannelPin<TIM11, C1> for PB9<AF3> {}
+unsafe impl ChannelPin<TIM11, C1> for PB15<AF3> {}
+
+/// Returns the frequency of the timers of an APB, twice the APB frequency if it is divided
+pub(crate) fn timer_clock(pclk: Hertz, ppre: u8) -> u32 {
+    if ppre == 1 {
+        pclk.0
+    } else {
+        2 * pclk.0
+    }
+}
+
+/// Returns the PSC and ARR values of a counter period of `ticks` timer clock cycles
+///
+/// The counter counts from 0 to ARR included, with PSC + 1 cycles per count.
+pub(crate) fn period(ticks: u32) -> (u16, u16) {
+    assert!(ticks >= 2, "impossible timeout");
+
+    let psc = u16((ticks - 1) / (1 << 16)).expect("impossible timeout");
+    let arr = u16(ticks / (u32(psc) + 1) - 1).unwrap();
+
+    (psc, arr)
+}
+
+/// Sets the CCER bits selected by `mask` to `bits`
+///
+/// The register block of TIM9 in the device crate lacks CCER, so it is accessed directly for
+/// every timer.
+pub(crate) unsafe fn modify_ccer<TIM>(tim: *const TIM, mask: u32, bits: u32) {
+    let ccer = (tim as *const u8).offset(CCER_OFFSET) as *mut u32;
+
+    ptr::write_volatile(ccer, (ptr::read_volatile(ccer) & !mask) | (bits & mask));
+}
+
+macro_rules! hal {
+    ($($TIMX:ident: ($timX:ident, $APB:ident, $timXen:ident, $timXrst:ident, $pclkX:ident, $ppreX:ident),)+) => {
+        $(
+            impl Periodic for Timer<$TIMX> {}
+
+            impl CountDown for Timer<$TIMX> {
+                type Time = Hertz;
+
+                fn start<T>(&mut self, timeout: T)
+                where
+                    T: Into<Hertz>,
+                {
+                    // pause
+                    self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+                    // restart counter
+                    self.tim.cnt.reset();
+
+                    let frequency = timeout.into().0;
+                    assert!(frequency > 0, "impossible timeout");
+
+                    let clock = timer_clock(self.clocks.$pclkX(), self.clocks.$ppreX());
+                    let (psc, arr) = period(clock / frequency);
+                    self.tim.psc.write(|w| unsafe { w.psc().bits(psc) });
+                    self.tim.arr.write(|w| unsafe { w.arr().bits(arr) });
+
+                    // Trigger an update event to load the prescaler value
+                    self.tim.egr.write(|w| w.ug().set_bit());
+                    // URS keeps UG from setting UIF, but a timeout of the previous count down may
+                    // still be pending
+                    self.tim.sr.modify(|_, w| w.uif().clear_bit());
+
+                    // start counter
+                    self.tim.cr1.modify(|_, w| w.cen().set_bit());
+                }
+
+                fn wait(&mut self) -> nb::Result<(), Void> {
+                    if self.tim.sr.read().uif().bit_is_clear() {
+                        Err(nb::Error::WouldBlock)
+                    } else {
+                        self.tim.sr.modify(|_, w| w.uif().clear_bit());
+                        Ok(())
+                    }
+                }
+            }
+
+            impl Timer<$TIMX> {
+                /// Configures a TIM peripheral as a periodic count down timer
+                ///
+                /// Panics if `timeout` is 0 Hz, or longer than the counter can count at the timer
+                /// clock; the same goes for `CountDown::start`.
+                pub fn $timX<T>(tim: $TIMX, timeout: T, clocks: Clocks, apb: &mut $APB) -> Self
+                where
+                    T: Into<Hertz>,
+                {
+                    // enable or reset $TIMX
+                    apb.enr().modify(|_, w| w.$timXen().set_bit());
+                    apb.rstr().modify(|_, w| w.$timXrst().set_bit());
+                    apb.rstr().modify(|_, w| w.$timXrst().clear_bit());
+
+                    // URS: only counter overflows generate an update event, not UG
+                    tim.cr1.write(|w| w.urs().set_bit());
+
+                    let mut timer = Timer { clocks, tim };
+                    timer.start(timeout);
+
+                    timer
+                }
+
+                /// Starts listening for an `event`
+                pub fn listen(&mut self, event: Event) {
+                    match event {
+                        Event::TimeOut => {
+                            // Enable update event interrupt
+                            self.tim.dier.modify(|_, w| w.uie().set_bit());
+                        }
+                    }
+                }
+
+                /// Stops listening for an `event`
+                pub fn unlisten(&mut self, event: Event) {
+                    match event {
+                        Event::TimeOut => {
+                            // Disable update event interrupt
+                            self.tim.dier.modify(|_, w| w.uie().clear_bit());
+                        }
+                    }
+                }
+
+                /// Clears the update flag, from the interrupt handler
+                pub fn clear_interrupt(&mut self, event: Event) {
+                    match event {
+                        Event::TimeOut => {
+                            self.tim.sr.modify(|_, w| w.uif().clear_bit());
+                        }
+                    }
+                }
+
+                /// Stops the timer and releases the TIM peripheral
+                pub fn free(self) -> $TIMX {
+                    // pause counter
+                    self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+
+                    self.tim
+                }
+            }
+        )+
+    }
+}
+
+hal! {
+    TIM2: (tim2, APB1, tim2en, tim2rst, pclk1, ppre1),
+    TIM3: (tim3, APB1, tim3en, tim3rst, pclk1, ppre1),
+    TIM4: (tim4, APB1, tim4en, tim4rst, pclk1, ppre1),
+    TIM6: (tim6, APB1, tim6en, tim6rst, pclk1, ppre1),
+    TIM7: (tim7, APB1, tim7en, tim7rst, pclk1, ppre1),
+    TIM9: (tim9, APB2, tim9en, tim9rst, pclk2, ppre2),
+    TIM10: (tim10, APB2, tim10en, tm10rst, pclk2, ppre2),
+    TIM11: (tim11, APB2, tim11en, tm11rst, pclk2, ppre2),
+}
@@ -0,0 +1,517 @@
+//! USB full-speed device
+//!
+//! `UsbBus` implements the bus driver of the [`usb-device`] stack, on which the device classes,
+//! such as the CDC-ACM serial port of [`usbd-serial`], are built. It requires the "usb" feature.
+//!
+//! The USB peripheral is clocked by the PLL VCO divided by 2, which must run at 96 MHz, see
+//! `rcc::CFGR::require_usb`. The device is polled either from the main loop or from the `USB_LP`
+//! interrupt handler.
+//!
+//! [`usb-device`]: https://docs.rs/usb-device/~0.2
+//! [`usbd-serial`]: https://docs.rs/usbd-serial/~0.1
+
+use core::ptr;
+
+use cortex_m::asm;
+use cortex_m::interrupt::{self, Mutex};
+use stm32l151::{usb, SYSCFG, USB};
+use usb_device::bus::{PollResult, UsbBusAllocator};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{Result, UsbDirection, UsbError};
+
+use exti::Syscfg;
+use gpio::gpioa::{PA11, PA12};
+use gpio::{Floating, Input};
+use rcc::{Clocks, APB1};
+
+/// Number of endpoints of the peripheral, each with an IN and an OUT direction
+const EP_COUNT: usize = 8;
+
+/// Address of the packet memory, shared by the CPU and the USB peripheral
+const PMA_ADDRESS: usize = 0x4000_6000;
+/// Size of the packet memory, in bytes
+const PMA_SIZE: u16 = 512;
+/// Size of the buffer descriptor table, at the start of the packet memory
+const BTABLE_SIZE: u16 = 8 * EP_COUNT as u16;
+
+// EPnR bits
+const EP_CTR_RX: u32 = 1 << 15;
+const EP_DTOG_RX: u32 = 1 << 14;
+const EP_STAT_RX: u32 = 0b11 << 12;
+const EP_SETUP: u32 = 1 << 11;
+const EP_CTR_TX: u32 = 1 << 7;
+const EP_DTOG_TX: u32 = 1 << 6;
+const EP_STAT_TX: u32 = 0b11 << 4;
+/// EP_TYPE, EP_KIND and EA, the bits which keep the written value
+const EP_RW: u32 = 0b111 << 8 | 0b1111;
+
+// STAT_RX and STAT_TX values
+const STAT_STALL: u32 = 0b01;
+const STAT_NAK: u32 = 0b10;
+const STAT_VALID: u32 = 0b11;
+
+/// Allocated buffers of an endpoint
+#[derive(Clone, Copy)]
+struct Endpoint {
+    ep_type: Option<EndpointType>,
+    /// Offset and size of the IN buffer in the packet memory
+    tx: Option<(u16, u16)>,
+    /// Offset and size of the OUT buffer in the packet memory
+    rx: Option<(u16, u16)>,
+}
+
+/// USB bus driver
+pub struct UsbBus {
+    usb: Mutex<USB>,
+    _pins: (PA11<Input<Floating>>, PA12<Input<Floating>>),
+    endpoints: [Endpoint; EP_COUNT],
+    /// Offset of the first free byte in the packet memory
+    next_buffer: u16,
+    /// Startup time of the transceiver, in SYSCLK cycles
+    startup: u32,
+}
+
+impl UsbBus {
+    /// Takes control of the USB peripheral and of its DM (PA11) and DP (PA12) pins
+    ///
+    /// The device connects to the host, through the internal pull-up of DP, once enabled by
+    /// `UsbDeviceBuilder::build`. The SYSCFG peripheral controls this pull-up.
+    pub fn usb(
+        usb: USB,
+        pins: (PA11<Input<Floating>>, PA12<Input<Floating>>),
+        clocks: Clocks,
+        apb1: &mut APB1,
+        _syscfg: &mut Syscfg,
+    ) -> UsbBusAllocator<Self> {
+        assert!(clocks.usbclk_valid(), "impossible USB clock");
+
+        // enable or reset USB
+        apb1.enr().modify(|_, w| w.usben().set_bit());
+        apb1.rstr().modify(|_, w| w.usbrst().set_bit());
+        apb1.rstr().modify(|_, w| w.usbrst().clear_bit());
+
+        set_pull_up(false);
+
+        UsbBusAllocator::new(UsbBus {
+            usb: Mutex::new(usb),
+            _pins: pins,
+            endpoints: [Endpoint {
+                ep_type: None,
+                tx: None,
+                rx: None,
+            }; EP_COUNT],
+            next_buffer: BTABLE_SIZE,
+            // tSTARTUP: 1 us
+            startup: clocks.sysclk().0 / 1_000_000 + 1,
+        })
+    }
+
+    /// Returns the allocated endpoint of `ep_addr`
+    fn endpoint(&self, ep_addr: EndpointAddress) -> Result<&Endpoint> {
+        self.endpoints
+            .get(ep_addr.index())
+            .filter(|ep| ep.ep_type.is_some())
+            .ok_or(UsbError::InvalidEndpoint)
+    }
+}
+
+impl ::usb_device::bus::UsbBus for UsbBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8,
+    ) -> Result<EndpointAddress> {
+        // Endpoint 0 is only allocated on request, for the control pipe
+        let (first, last) = match ep_addr {
+            Some(ep_addr) if ep_addr.index() < EP_COUNT => (ep_addr.index(), ep_addr.index()),
+            Some(_) => return Err(UsbError::InvalidEndpoint),
+            None => (1, EP_COUNT - 1),
+        };
+
+        let index = (first..last + 1)
+            .find(|&index| {
+                let ep = &self.endpoints[index];
+                let free = match ep_dir {
+                    UsbDirection::In => ep.tx.is_none(),
+                    UsbDirection::Out => ep.rx.is_none(),
+                };
+
+                free && ep.ep_type.map(|t| t == ep_type).unwrap_or(true)
+            }).ok_or(if ep_addr.is_some() {
+                UsbError::InvalidEndpoint
+            } else {
+                UsbError::EndpointOverflow
+            })?;
+
+        // The OUT buffers are made of 2 or 32 byte blocks
+        let size = match ep_dir {
+            UsbDirection::In => (max_packet_size + 1) & !1,
+            UsbDirection::Out => rx_count(max_packet_size).1,
+        };
+        if max_packet_size > 1023 || self.next_buffer + size > PMA_SIZE {
+            return Err(UsbError::EndpointMemoryOverflow);
+        }
+
+        let buffer = Some((self.next_buffer, size));
+        self.next_buffer += size;
+
+        let ep = &mut self.endpoints[index];
+        ep.ep_type = Some(ep_type);
+        match ep_dir {
+            UsbDirection::In => ep.tx = buffer,
+            UsbDirection::Out => ep.rx = buffer,
+        }
+
+        Ok(EndpointAddress::from_parts(index, ep_dir))
+    }
+
+    fn enable(&mut self) {
+        let startup = self.startup;
+
+        interrupt::free(|cs| {
+            let usb = self.usb.borrow(cs);
+
+            // PDWN: power the transceiver up, then wait for it to start
+            usb.usb_cntr.modify(|_, w| w.pdwn().clear_bit());
+            asm::delay(startup);
+
+            // FRES: leave the reset state
+            usb.usb_cntr.modify(|_, w| w.fres().clear_bit());
+            usb.istr.write(|w| unsafe { w.bits(0) });
+            usb.btable.write(|w| unsafe { w.btable().bits(0) });
+
+            usb.usb_cntr.modify(|_, w| {
+                w.ctrm()
+                    .set_bit()
+                    .resetm()
+                    .set_bit()
+                    .suspm()
+                    .set_bit()
+                    .wkupm()
+                    .set_bit()
+            });
+        });
+
+        set_pull_up(true);
+    }
+
+    fn reset(&self) {
+        interrupt::free(|cs| {
+            let usb = self.usb.borrow(cs);
+
+            for (index, ep) in self.endpoints.iter().enumerate() {
+                let ep_type = match ep.ep_type {
+                    Some(ep_type) => ep_type,
+                    None => continue,
+                };
+
+                let (tx_addr, _) = ep.tx.unwrap_or((0, 0));
+                let (rx_addr, rx_size) = ep.rx.unwrap_or((0, 0));
+                let btable = btable(index);
+                pma_write(btable, tx_addr);
+                pma_write(btable + 2, 0);
+                pma_write(btable + 4, rx_addr);
+                pma_write(btable + 6, rx_count(rx_size).0);
+
+                let ep_type = match ep_type {
+                    EndpointType::Bulk => 0b00,
+                    EndpointType::Control => 0b01,
+                    EndpointType::Isochronous => 0b10,
+                    EndpointType::Interrupt => 0b11,
+                };
+
+                // The endpoint registers are cleared by the bus reset
+                let epr = epr(usb, index);
+                let r = unsafe { ptr::read_volatile(epr) };
+                let stat_rx = if ep.rx.is_some() { STAT_VALID } else { 0 };
+                let stat_tx = if ep.tx.is_some() { STAT_NAK } else { 0 };
+                let w = ep_type << 9
+                    | index as u32
+                    | (r & (EP_DTOG_RX | EP_DTOG_TX))
+                    | ((r & EP_STAT_RX) ^ stat_rx << 12)
+                    | ((r & EP_STAT_TX) ^ stat_tx << 4);
+                unsafe { ptr::write_volatile(epr, w) };
+            }
+
+            usb.daddr.write(|w| w.ef().set_bit());
+        });
+    }
+
+    fn set_device_address(&self, addr: u8) {
+        interrupt::free(|cs| {
+            let usb = self.usb.borrow(cs);
+
+            usb.daddr
+                .write(|w| unsafe { w.ef().set_bit().add().bits(addr) });
+        });
+    }
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        let (addr, size) = self
+            .endpoint(ep_addr)?
+            .tx
+            .ok_or(UsbError::InvalidEndpoint)?;
+        if buf.len() > usize::from(size) {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        interrupt::free(|cs| {
+            let usb = self.usb.borrow(cs);
+            let index = ep_addr.index();
+
+            // The previous packet has not been sent yet
+            if stat_tx(usb, index) == STAT_VALID {
+                return Err(UsbError::WouldBlock);
+            }
+
+            for (i, chunk) in buf.chunks(2).enumerate() {
+                let half_word = u16::from(chunk[0]) | u16::from(*chunk.get(1).unwrap_or(&0)) << 8;
+                pma_write(addr + 2 * i as u16, half_word);
+            }
+
+            let btable = btable(index);
+            pma_write(btable + 2, buf.len() as u16);
+
+            set_stat_tx(usb, index, STAT_VALID);
+
+            Ok(buf.len())
+        })
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        let (addr, _) = self
+            .endpoint(ep_addr)?
+            .rx
+            .ok_or(UsbError::InvalidEndpoint)?;
+
+        interrupt::free(|cs| {
+            let usb = self.usb.borrow(cs);
+            let index = ep_addr.index();
+            let epr = epr(usb, index);
+
+            let r = unsafe { ptr::read_volatile(epr) };
+            if r & EP_CTR_RX == 0 {
+                return Err(UsbError::WouldBlock);
+            }
+
+            let btable = btable(index);
+            let count = usize::from(pma_read(btable + 6) & 0x3FF);
+
+            let result = if count > buf.len() {
+                Err(UsbError::BufferOverflow)
+            } else {
+                for (i, chunk) in buf[..count].chunks_mut(2).enumerate() {
+                    let half_word = pma_read(addr + 2 * i as u16);
+                    chunk[0] = half_word as u8;
+                    if let Some(byte) = chunk.get_mut(1) {
+                        *byte = (half_word >> 8) as u8;
+                    }
+                }
+
+                Ok(count)
+            };
+
+            // CTR_RX is cleared by writing 0, the other flag by writing 1
+            unsafe { ptr::write_volatile(epr, (r & EP_RW) | EP_CTR_TX) };
+            set_stat_rx(usb, index, STAT_VALID);
+
+            result
+        })
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        interrupt::free(|cs| {
+            let usb = self.usb.borrow(cs);
+            let index = ep_addr.index();
+
+            match (ep_addr.direction(), stalled) {
+                (UsbDirection::In, true) => set_stat_tx(usb, index, STAT_STALL),
+                (UsbDirection::Out, true) => set_stat_rx(usb, index, STAT_STALL),
+                // Clearing the halt feature resets the data toggle to DATA0
+                (UsbDirection::In, false) => if stat_tx(usb, index) == STAT_STALL {
+                    reset_dtog(usb, index, EP_DTOG_TX);
+                    set_stat_tx(usb, index, STAT_NAK);
+                },
+                (UsbDirection::Out, false) => if stat_rx(usb, index) == STAT_STALL {
+                    reset_dtog(usb, index, EP_DTOG_RX);
+                    set_stat_rx(usb, index, STAT_VALID);
+                },
+            }
+        });
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        interrupt::free(|cs| {
+            let usb = self.usb.borrow(cs);
+            let index = ep_addr.index();
+
+            match ep_addr.direction() {
+                UsbDirection::In => stat_tx(usb, index) == STAT_STALL,
+                UsbDirection::Out => stat_rx(usb, index) == STAT_STALL,
+            }
+        })
+    }
+
+    fn suspend(&self) {
+        interrupt::free(|cs| {
+            let usb = self.usb.borrow(cs);
+
+            // FSUSP must be set before LPMODE
+            usb.usb_cntr.modify(|_, w| w.fsusp().set_bit());
+            usb.usb_cntr.modify(|_, w| w.lpmode().set_bit());
+        });
+    }
+
+    fn resume(&self) {
+        interrupt::free(|cs| {
+            let usb = self.usb.borrow(cs);
+
+            // LPMODE is cleared by the hardware on wakeup
+            usb.usb_cntr
+                .modify(|_, w| w.fsusp().clear_bit().lpmode().clear_bit());
+        });
+    }
+
+    fn poll(&self) -> PollResult {
+        interrupt::free(|cs| {
+            let usb = self.usb.borrow(cs);
+            let istr = usb.istr.read();
+
+            // The ISTR flags are cleared by writing 0, the other flags by writing 1
+            if istr.wkup().bit_is_set() {
+                usb.istr.write(|w| unsafe { w.bits(!(1 << 12) & 0xFFFF) });
+                PollResult::Resume
+            } else if istr.reset().bit_is_set() {
+                usb.istr.write(|w| unsafe { w.bits(!(1 << 10) & 0xFFFF) });
+                PollResult::Reset
+            } else if istr.susp().bit_is_set() {
+                usb.istr.write(|w| unsafe { w.bits(!(1 << 11) & 0xFFFF) });
+                PollResult::Suspend
+            } else if istr.ctr().bit_is_set() {
+                let (mut ep_out, mut ep_in_complete, mut ep_setup) = (0, 0, 0);
+
+                for index in 0..EP_COUNT {
+                    let epr = epr(usb, index);
+                    let r = unsafe { ptr::read_volatile(epr) };
+                    let bit = 1 << index;
+
+                    // CTR_RX is cleared by `read`
+                    if r & EP_CTR_RX != 0 {
+                        if r & EP_SETUP != 0 {
+                            ep_setup |= bit;
+                        } else {
+                            ep_out |= bit;
+                        }
+                    }
+
+                    if r & EP_CTR_TX != 0 {
+                        ep_in_complete |= bit;
+                        unsafe { ptr::write_volatile(epr, (r & EP_RW) | EP_CTR_RX) };
+                    }
+                }
+
+                PollResult::Data {
+                    ep_out,
+                    ep_in_complete,
+                    ep_setup,
+                }
+            } else {
+                PollResult::None
+            }
+        })
+    }
+}
+
+/// Returns the COUNTn_RX value of an OUT buffer of `size` bytes, along with the actual size of
+/// the buffer
+fn rx_count(size: u16) -> (u16, u16) {
+    if size <= 62 {
+        // BL_SIZE = 0: NUM_BLOCK blocks of 2 bytes
+        let blocks = (size + 1) / 2;
+        (blocks << 10, blocks * 2)
+    } else {
+        // BL_SIZE = 1: NUM_BLOCK + 1 blocks of 32 bytes
+        let blocks = (size + 31) / 32;
+        (1 << 15 | (blocks - 1) << 10, blocks * 32)
+    }
+}
+
+/// Returns the offset of the buffer descriptors of endpoint `index` in the packet memory: the
+/// address and the count of the IN buffer, then of the OUT buffer
+fn btable(index: usize) -> u16 {
+    BTABLE_SIZE / EP_COUNT as u16 * index as u16
+}
+
+/// Returns a pointer to the EPnR register of endpoint `index`
+fn epr(usb: &usb::RegisterBlock, index: usize) -> *mut u32 {
+    // NOTE(unsafe) the EPnR registers follow each other, 4 bytes apart
+    unsafe { (&usb.usb_ep0r as *const _ as *mut u32).add(index) }
+}
+
+/// Returns the STAT_RX field of endpoint `index`
+fn stat_rx(usb: &usb::RegisterBlock, index: usize) -> u32 {
+    (unsafe { ptr::read_volatile(epr(usb, index)) } & EP_STAT_RX) >> 12
+}
+
+/// Returns the STAT_TX field of endpoint `index`
+fn stat_tx(usb: &usb::RegisterBlock, index: usize) -> u32 {
+    (unsafe { ptr::read_volatile(epr(usb, index)) } & EP_STAT_TX) >> 4
+}
+
+/// Sets the STAT_RX field of endpoint `index`, which toggles when written 1
+fn set_stat_rx(usb: &usb::RegisterBlock, index: usize, stat: u32) {
+    let epr = epr(usb, index);
+
+    unsafe {
+        let r = ptr::read_volatile(epr);
+        let w = (r & EP_RW) | EP_CTR_RX | EP_CTR_TX | ((r & EP_STAT_RX) ^ stat << 12);
+        ptr::write_volatile(epr, w);
+    }
+}
+
+/// Sets the STAT_TX field of endpoint `index`, which toggles when written 1
+fn set_stat_tx(usb: &usb::RegisterBlock, index: usize, stat: u32) {
+    let epr = epr(usb, index);
+
+    unsafe {
+        let r = ptr::read_volatile(epr);
+        let w = (r & EP_RW) | EP_CTR_RX | EP_CTR_TX | ((r & EP_STAT_TX) ^ stat << 4);
+        ptr::write_volatile(epr, w);
+    }
+}
+
+/// Clears the data toggle bit `dtog` of endpoint `index`, which toggles when written 1
+fn reset_dtog(usb: &usb::RegisterBlock, index: usize, dtog: u32) {
+    let epr = epr(usb, index);
+
+    unsafe {
+        let r = ptr::read_volatile(epr);
+        ptr::write_volatile(epr, (r & EP_RW) | EP_CTR_RX | EP_CTR_TX | (r & dtog));
+    }
+}
+
+/// Reads the half-word at `offset` in the packet memory
+fn pma_read(offset: u16) -> u16 {
+    // NOTE(unsafe) every half-word of the packet memory is mapped to a word of the CPU
+    unsafe { ptr::read_volatile((PMA_ADDRESS + 2 * usize::from(offset)) as *const u16) }
+}
+
+/// Writes the half-word at `offset` in the packet memory
+fn pma_write(offset: u16, half_word: u16) {
+    // NOTE(unsafe) every half-word of the packet memory is mapped to a word of the CPU
+    unsafe {
+        ptr::write_volatile(
+            (PMA_ADDRESS + 2 * usize::from(offset)) as *mut u16,
+            half_word,
+        )
+    }
+}
+
+/// Connects or disconnects the internal pull-up of DP, which signals the device to the host
+fn set_pull_up(enable: bool) {
+    // NOTE(unsafe) USB_PU is only written by this driver, and SYSCFG is enabled by `Syscfg`
+    unsafe { (*SYSCFG::ptr()).pmc.modify(|_, w| w.usb_pu().bit(enable)) }
+}
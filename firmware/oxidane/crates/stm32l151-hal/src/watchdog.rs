@@ -0,0 +1,169 @@
+//! Watchdogs
+//!
+//! Once started, neither watchdog can be stopped until the next reset. `rcc::CSR::reset_cause`
+//! tells if a reset was caused by a watchdog.
+
+use cast::u32;
+use hal::watchdog::{Watchdog, WatchdogEnable};
+use stm32l151::{IWDG, WWDG};
+
+use rcc::{Clocks, APB1, LSI_FREQ};
+use time::MilliSeconds;
+
+// KR keys
+const KR_RELOAD: u16 = 0xAAAA;
+const KR_ACCESS: u16 = 0x5555;
+const KR_START: u16 = 0xCCCC;
+
+/// Largest reload value of the independent watchdog
+const IWDG_MAX_RELOAD: u32 = 0xFFF;
+
+/// Highest value of the window watchdog counter, it resets the microcontroller below 0x40
+const WWDG_MAX_COUNTER: u32 = 0x7F;
+/// Number of counter values before the window watchdog resets the microcontroller
+const WWDG_MAX_TICKS: u32 = WWDG_MAX_COUNTER - 0x3F;
+
+/// Independent watchdog, clocked by the LSI oscillator
+///
+/// The LSI frequency varies between 26 and 56 kHz from one chip to another: the actual timeout
+/// can be as short as two thirds of the requested period.
+pub struct IndependentWatchdog {
+    iwdg: IWDG,
+}
+
+impl IndependentWatchdog {
+    /// Wraps the IWDG peripheral, the watchdog is enabled by `start`
+    pub fn iwdg(iwdg: IWDG) -> Self {
+        IndependentWatchdog { iwdg }
+    }
+
+    /// Releases the IWDG peripheral, a started watchdog keeps running
+    pub fn free(self) -> IWDG {
+        self.iwdg
+    }
+}
+
+impl WatchdogEnable for IndependentWatchdog {
+    type Time = MilliSeconds;
+
+    fn start<T>(&mut self, period: T)
+    where
+        T: Into<MilliSeconds>,
+    {
+        let ticks = period.into().0 * (LSI_FREQ / 1000);
+
+        // PR: prescaler of 4 << PR, the smallest one that fits the reload value
+        let pr = (0..7)
+            .find(|&pr| ticks / (4 << pr) <= IWDG_MAX_RELOAD)
+            .expect("impossible watchdog period");
+        let rl = (ticks / (4 << pr)).max(1) - 1;
+
+        // Starting the watchdog also starts the LSI oscillator
+        self.iwdg.kr.write(|w| unsafe { w.key().bits(KR_START) });
+
+        self.iwdg.kr.write(|w| unsafe { w.key().bits(KR_ACCESS) });
+        self.iwdg.pr.write(|w| unsafe { w.pr().bits(pr as u8) });
+        self.iwdg.rlr.write(|w| unsafe { w.rl().bits(rl as u16) });
+
+        // The new values apply once written to the LSI clock domain
+        while {
+            let sr = self.iwdg.sr.read();
+            sr.pvu().bit_is_set() || sr.rvu().bit_is_set()
+        } {}
+
+        self.feed();
+    }
+}
+
+impl Watchdog for IndependentWatchdog {
+    fn feed(&mut self) {
+        self.iwdg.kr.write(|w| unsafe { w.key().bits(KR_RELOAD) });
+    }
+}
+
+/// Window watchdog, clocked by the APB1
+///
+/// Feeding the watchdog too early, before the window opens, also resets the microcontroller. The
+/// window is open during the whole period unless restricted by `set_window`.
+pub struct WindowWatchdog {
+    clocks: Clocks,
+    wwdg: WWDG,
+    counter: u8,
+}
+
+impl WindowWatchdog {
+    /// Enables the clock of the WWDG peripheral, the watchdog is enabled by `start`
+    pub fn wwdg(wwdg: WWDG, clocks: Clocks, apb1: &mut APB1) -> Self {
+        // enable WWDG
+        apb1.enr().modify(|_, w| w.wwdgen().set_bit());
+
+        WindowWatchdog {
+            clocks,
+            wwdg,
+            counter: WWDG_MAX_COUNTER as u8,
+        }
+    }
+
+    /// Restricts feeding the watchdog to the last `window` of the period
+    pub fn set_window<T>(&mut self, window: T)
+    where
+        T: Into<MilliSeconds>,
+    {
+        let wdgtb = u32::from(self.wwdg.cfr.read().wdgtb().bits());
+        let ticks = self.ticks(window.into(), wdgtb).min(WWDG_MAX_TICKS);
+
+        // W: the counter value below which feeding is allowed
+        self.wwdg
+            .cfr
+            .modify(|_, w| w.w().bits((0x3F + ticks) as u8));
+    }
+
+    /// Releases the WWDG peripheral, a started watchdog keeps running
+    pub fn free(self) -> WWDG {
+        self.wwdg
+    }
+
+    /// Returns the number of counter ticks in `time` with the prescaler `wdgtb`
+    fn ticks(&self, time: MilliSeconds, wdgtb: u32) -> u32 {
+        // The counter is clocked by PCLK1 / 4096 / 2^WDGTB
+        let ticks =
+            u64::from(time.0) * u64::from(self.clocks.pclk1().0) / (1_000 * (4096 << wdgtb));
+
+        u32(ticks).unwrap_or(u32::max_value())
+    }
+}
+
+impl WatchdogEnable for WindowWatchdog {
+    type Time = MilliSeconds;
+
+    fn start<T>(&mut self, period: T)
+    where
+        T: Into<MilliSeconds>,
+    {
+        let period = period.into();
+
+        // WDGTB: prescaler of 1 << WDGTB, the smallest one that fits the period
+        let wdgtb = (0..4)
+            .find(|&wdgtb| self.ticks(period, wdgtb) <= WWDG_MAX_TICKS)
+            .expect("impossible watchdog period");
+        let ticks = self.ticks(period, wdgtb).max(1);
+        self.counter = (0x3F + ticks) as u8;
+
+        // W: feeding allowed during the whole period
+        self.wwdg
+            .cfr
+            .write(|w| w.wdgtb().bits(wdgtb as u8).w().bits(WWDG_MAX_COUNTER as u8));
+
+        // WDGA: enable the watchdog, along with the first counter value
+        let counter = self.counter;
+        self.wwdg.cr.write(|w| w.t().bits(counter).wdga().set_bit());
+    }
+}
+
+impl Watchdog for WindowWatchdog {
+    fn feed(&mut self) {
+        let counter = self.counter;
+
+        self.wwdg.cr.write(|w| w.t().bits(counter).wdga().set_bit());
+    }
+}
@@ -0,0 +1,90 @@
+//! CDC-ACM serial port over USB, echoing back what the host sends
+//!
+//! The example runs from the active slot, behind the bootloader, like the firmware:
+//!
+//! ```text
+//! cargo +nightly build --release --example usb_serial --features usb
+//! ```
+//!
+//! On a Linux host the board shows up as `/dev/ttyACM0`.
+
+#![no_main]
+#![no_std]
+
+extern crate cortex_m;
+#[macro_use]
+extern crate cortex_m_rt as rt;
+extern crate panic_abort;
+extern crate stm32l151_hal as hal;
+extern crate usb_device;
+extern crate usbd_serial;
+
+use hal::exti::Syscfg;
+use hal::prelude::*;
+use hal::stm32l151;
+use hal::usb::UsbBus;
+use rt::ExceptionFrame;
+use usb_device::prelude::*;
+use usbd_serial::{SerialPort, USB_CLASS_CDC};
+
+entry!(main);
+
+fn main() -> ! {
+    let p = stm32l151::Peripherals::take().unwrap();
+
+    let mut flash = p.FLASH.constrain();
+    let mut rcc = p.RCC.constrain();
+
+    // 32 MHz SYSCLK from a 96 MHz PLL VCO, which also clocks the USB peripheral
+    let clocks = rcc
+        .cfgr
+        .sysclk(32.mhz())
+        .require_usb()
+        .freeze(&mut flash.acr)
+        .unwrap();
+
+    let gpioa = p.GPIOA.split(&mut rcc.ahb);
+    let mut syscfg = Syscfg::syscfg(p.SYSCFG, &mut rcc.apb2);
+
+    let usb_bus = UsbBus::usb(
+        p.USB,
+        (gpioa.pa11, gpioa.pa12),
+        clocks,
+        &mut rcc.apb1,
+        &mut syscfg,
+    );
+
+    let mut serial = SerialPort::new(&usb_bus);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
+        .manufacturer("Oxidane")
+        .product("Serial port")
+        .serial_number("0001")
+        .device_class(USB_CLASS_CDC)
+        .build();
+
+    loop {
+        if !usb_dev.poll(&mut [&mut serial]) {
+            continue;
+        }
+
+        let mut buf = [0; 64];
+        if let Ok(count) = serial.read(&mut buf) {
+            let mut offset = 0;
+            while offset < count {
+                if let Ok(len) = serial.write(&buf[offset..count]) {
+                    offset += len;
+                }
+            }
+        }
+    }
+}
+
+exception!(*, default_handler);
+
+fn default_handler(_irqn: i16) {}
+
+exception!(HardFault, hard_fault);
+
+fn hard_fault(ef: &ExceptionFrame) -> ! {
+    panic!("HardFault at {:#?}", ef);
+}
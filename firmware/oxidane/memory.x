@@ -1,6 +1,11 @@
 /* Memory layour for the STM32L151C8U6 */
+/* The application runs from the active slot, after the bootloader (see oxidane-boot) */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  FLASH : ORIGIN = 0x08002000, LENGTH = 26K
   RAM : ORIGIN = 0x20000000, LENGTH = 16K
 }
+
+/* The image must leave the staging slot and the boot records untouched */
+ASSERT(LOADADDR(.data) + SIZEOF(.data) <= ORIGIN(FLASH) + LENGTH(FLASH),
+       "the application does not fit in the active slot of oxidane-boot");
@@ -0,0 +1,124 @@
+use embedded_hal::digital::OutputPin;
+use proto::command::{ErrorCode, Handler, Thresholds};
+
+/// Telemetry reporting interval used until the gateway sets a different one, in seconds
+pub const DEFAULT_REPORT_INTERVAL: u16 = 60;
+
+/// Irrigation application state
+pub struct App<PUMP> {
+    pump: PUMP,
+    irrigation_left: u16,
+    report_interval: u16,
+    report_countdown: u16,
+    report_pending: bool,
+    reboot_pending: bool,
+}
+
+impl<PUMP> App<PUMP>
+where
+    PUMP: OutputPin,
+{
+    pub fn new(mut pump: PUMP) -> Self {
+        pump.set_low();
+
+        App {
+            pump,
+            irrigation_left: 0,
+            report_interval: DEFAULT_REPORT_INTERVAL,
+            report_countdown: DEFAULT_REPORT_INTERVAL,
+            report_pending: true,
+            reboot_pending: false,
+        }
+    }
+
+    /// Advances the application clock by one second.
+    pub fn tick(&mut self) {
+        if self.irrigation_left > 0 {
+            self.irrigation_left -= 1;
+
+            if self.irrigation_left == 0 {
+                self.pump.set_low();
+            }
+        }
+
+        self.report_countdown -= 1;
+
+        if self.report_countdown == 0 {
+            self.report_countdown = self.report_interval;
+            self.report_pending = true;
+        }
+    }
+
+    /// Returns `true` once for every telemetry report that should be sent.
+    pub fn take_report(&mut self) -> bool {
+        let pending = self.report_pending;
+        self.report_pending = false;
+        pending
+    }
+
+    /// Returns for how many seconds the application can go without `tick`, i.e. until the next
+    /// report or the end of the irrigation.
+    pub fn idle_time(&self) -> u16 {
+        if self.irrigation_left > 0 || self.report_pending {
+            0
+        } else {
+            self.report_countdown - 1
+        }
+    }
+
+    /// Returns `true` if the gateway asked for a reboot.
+    pub fn reboot_pending(&self) -> bool {
+        self.reboot_pending
+    }
+}
+
+impl<PUMP> Handler for App<PUMP>
+where
+    PUMP: OutputPin,
+{
+    fn start_irrigation(&mut self, duration_s: u16) -> Result<(), ErrorCode> {
+        if duration_s == 0 {
+            return Err(ErrorCode::InvalidArgument);
+        }
+
+        self.irrigation_left = duration_s;
+        self.pump.set_high();
+
+        Ok(())
+    }
+
+    fn stop_irrigation(&mut self) -> Result<(), ErrorCode> {
+        self.irrigation_left = 0;
+        self.pump.set_low();
+
+        Ok(())
+    }
+
+    fn set_thresholds(&mut self, _thresholds: Thresholds) -> Result<(), ErrorCode> {
+        // Automatic irrigation needs the humidity, which the node does not measure yet
+        Err(ErrorCode::Unsupported)
+    }
+
+    fn request_report(&mut self) -> Result<(), ErrorCode> {
+        self.report_pending = true;
+
+        Ok(())
+    }
+
+    fn reboot(&mut self) -> Result<(), ErrorCode> {
+        self.reboot_pending = true;
+
+        Ok(())
+    }
+
+    fn set_report_interval(&mut self, interval_s: u16) -> Result<(), ErrorCode> {
+        if interval_s == 0 {
+            return Err(ErrorCode::InvalidArgument);
+        }
+
+        self.report_interval = interval_s;
+        self.report_countdown = interval_s;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,38 @@
+use cortex_m::peripheral::DWT;
+use hal::time::MonoTimer;
+
+/// Millisecond clock on top of the cycle counter
+///
+/// The cycle counter wraps in a couple of minutes at full speed, so `millis` must be called at
+/// least that often to keep track of time.
+pub struct Clock {
+    cycles_per_ms: u32,
+    last: u32,
+    millis: u32,
+}
+
+impl Clock {
+    pub fn new(timer: &MonoTimer) -> Self {
+        Clock {
+            cycles_per_ms: timer.frequency().0 / 1000,
+            last: DWT::get_cycle_count(),
+            millis: 0,
+        }
+    }
+
+    /// Returns the milliseconds elapsed since the clock was created, wrapping after ~49 days
+    pub fn millis(&mut self) -> u32 {
+        let ms = DWT::get_cycle_count().wrapping_sub(self.last) / self.cycles_per_ms;
+
+        // Carry the leftover cycles over to the next call, so that no time is lost
+        self.last = self.last.wrapping_add(ms * self.cycles_per_ms);
+        self.millis = self.millis.wrapping_add(ms);
+
+        self.millis
+    }
+
+    /// Accounts for `ms` milliseconds spent with the cycle counter stopped, e.g. in Stop mode
+    pub fn advance(&mut self, ms: u32) {
+        self.millis = self.millis.wrapping_add(ms);
+    }
+}
@@ -0,0 +1,209 @@
+//! Gateway mode
+//!
+//! Built with the `gateway` feature, the board is a packet forwarder for the host daemon: the
+//! radio stays in RX, every received frame goes to the host over USART1 together with its RSSI
+//! and reception time, and the host hands back the frames to transmit (see `proto::serial`).
+//! Routing, beacons, channel hopping and the application protocol are all up to the host; the
+//! board only reports the quality of the channel it listens on.
+
+use core::fmt::Debug;
+
+use embedded_hal::blocking::spi;
+use embedded_hal::digital::{InputPin, OutputPin, ToggleableOutputPin};
+use embedded_hal::serial::{Read, Write};
+use hal::serial::{Rx, Tx};
+use hal::stm32l151::USART1;
+use nb;
+use proto::serial::{Decoder, Message, MAX_ENCODED_LEN};
+use proto::FIFO_SIZE;
+use si4455::{self, Si4455};
+
+use clock::Clock;
+
+/// Frames from the host waiting for the radio
+const TX_QUEUE_LEN: usize = 4;
+
+/// Interval between two channel status reports, in milliseconds
+const STATUS_INTERVAL: u32 = 1000;
+
+#[derive(Clone, Copy)]
+struct Outgoing {
+    channel: u8,
+    frame: [u8; FIFO_SIZE],
+    len: usize,
+}
+
+/// Framed link to the host
+struct Link {
+    tx: Tx<USART1>,
+    rx: Rx<USART1>,
+    decoder: Decoder,
+    queue: [Outgoing; TX_QUEUE_LEN],
+    head: usize,
+    len: usize,
+    // Channel to listen on once the queued frames are sent
+    listen: Option<u8>,
+}
+
+impl Link {
+    fn new(tx: Tx<USART1>, rx: Rx<USART1>) -> Self {
+        Link {
+            tx,
+            rx,
+            decoder: Decoder::new(),
+            queue: [Outgoing {
+                channel: 0,
+                frame: [0; FIFO_SIZE],
+                len: 0,
+            }; TX_QUEUE_LEN],
+            head: 0,
+            len: 0,
+            listen: None,
+        }
+    }
+
+    /// Processes the bytes received from the host, queueing the frames to transmit and the channel
+    /// to listen on
+    fn poll(&mut self) {
+        loop {
+            let byte = match self.rx.read() {
+                Ok(byte) => byte,
+                Err(nb::Error::WouldBlock) => return,
+                // The byte is lost, the CRC check drops the message it belongs to
+                Err(nb::Error::Other(_)) => continue,
+            };
+
+            match self.decoder.feed(byte) {
+                Ok(Some(Message::Transmit { channel, frame })) => {
+                    // The host paces its frames, drop the newest if it does not
+                    if self.len == TX_QUEUE_LEN {
+                        continue;
+                    }
+
+                    let slot = &mut self.queue[(self.head + self.len) % TX_QUEUE_LEN];
+                    slot.channel = channel;
+                    slot.frame[..frame.len()].copy_from_slice(frame);
+                    slot.len = frame.len();
+                    self.len += 1;
+                }
+                Ok(Some(Message::Listen { channel })) => self.listen = Some(channel),
+                _ => {}
+            }
+        }
+    }
+
+    /// Removes the oldest frame to transmit from the queue
+    fn pop(&mut self) -> Option<Outgoing> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let outgoing = self.queue[self.head];
+        self.head = (self.head + 1) % TX_QUEUE_LEN;
+        self.len -= 1;
+
+        Some(outgoing)
+    }
+
+    /// Sends a message to the host, still accepting the bytes the host sends meanwhile
+    fn send(&mut self, message: &Message) {
+        let mut buf = [0; MAX_ENCODED_LEN];
+        let n = message.encode(&mut buf).unwrap();
+
+        for &byte in &buf[..n] {
+            while self.tx.write(byte).is_err() {
+                self.poll();
+            }
+        }
+    }
+}
+
+/// Forwards the radio traffic to and from the host, forever
+pub fn run<E, SPI, NCS, SDN, NIRQ, LED>(
+    mut radio: Si4455<SPI, NCS, SDN, NIRQ>,
+    tx: Tx<USART1>,
+    rx: Rx<USART1>,
+    mut clock: Clock,
+    mut led: LED,
+) -> !
+where
+    E: Debug,
+    SPI: spi::Write<u8, Error = E> + spi::Transfer<u8, Error = E>,
+    NCS: OutputPin,
+    SDN: OutputPin,
+    NIRQ: InputPin,
+    LED: ToggleableOutputPin,
+{
+    let mut link = Link::new(tx, rx);
+    let mut buf = [0; FIFO_SIZE];
+    let mut channel = 0;
+    let mut crc_errors = 0_u8;
+    let mut last_status = clock.millis();
+
+    // Radio errors cannot be logged, USART1 carries the host link: a frame that cannot be sent is
+    // lost as if over the air, and a radio that cannot listen is set up again with the next
+    // channel status
+    let mut listening = radio.listen(channel, 0).is_ok();
+
+    loop {
+        // Also keeps the clock running, see `Clock::millis`
+        let now = clock.millis();
+
+        link.poll();
+
+        match radio.receive(&mut buf) {
+            Ok(len) => {
+                let rssi = radio.latched_rssi().ok().map(|dbm| dbm.max(-127) as i8);
+
+                link.send(&Message::Received {
+                    timestamp: now,
+                    rssi,
+                    channel,
+                    frame: &buf[..len],
+                });
+                led.toggle();
+            }
+            Err(nb::Error::Other(si4455::Error::Crc)) => crc_errors = crc_errors.saturating_add(1),
+            // Other receive errors cannot be reported to anyone, the frame is simply lost
+            Err(_) => {}
+        }
+
+        let status_due = now.wrapping_sub(last_status) >= STATUS_INTERVAL;
+        let idle = link.len == 0 && link.listen.is_none() && !status_due;
+
+        // Never wait for the radio, the host may be sending more frames
+        if idle || radio.is_transmitting().unwrap_or(true) {
+            continue;
+        }
+
+        let outgoing = link.pop();
+        let next = match outgoing {
+            Some(ref outgoing) => outgoing.channel,
+            None => link.listen.take().unwrap_or(channel),
+        };
+
+        // Sampled between frames, the RSSI of the channel is its noise floor
+        if status_due || next != channel {
+            let noise = radio.current_rssi().ok().map(|dbm| dbm.max(-127) as i8);
+
+            link.send(&Message::ChannelStatus {
+                channel,
+                noise,
+                crc_errors,
+            });
+            crc_errors = 0;
+            last_status = now;
+        }
+
+        if let Some(outgoing) = outgoing {
+            // The radio goes back to RX on the same channel once the frame is sent
+            channel = outgoing.channel;
+            listening = radio
+                .transmit(channel, &outgoing.frame[..outgoing.len])
+                .is_ok();
+        } else if next != channel || (status_due && !listening) {
+            channel = next;
+            listening = radio.listen(channel, 0).is_ok();
+        }
+    }
+}
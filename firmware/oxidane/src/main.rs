@@ -8,24 +8,78 @@ extern crate embedded_hal;
 extern crate panic_abort;
 #[macro_use]
 extern crate nb;
+extern crate oxidane_boot as boot;
+extern crate oxidane_proto as proto;
 extern crate si4455;
 extern crate stm32l151_hal as hal;
 
+mod app;
+mod clock;
+mod gateway;
 mod log;
+mod ota_key;
 mod radio_config;
+mod version;
 
-use core::fmt::Write;
+use core::fmt::{Debug, Write};
 
+use boot::update::Updater;
+use clock::Clock;
+use embedded_hal::blocking::spi;
+use embedded_hal::digital::{InputPin, OutputPin};
+use hal::adc::Adc;
 use hal::delay::Delay;
+use hal::exti::Exti;
 use hal::prelude::*;
+use hal::pwr::Pwr;
 use hal::rcc::{AHBPrescaler, APBPrescaler, PllDivider, PllMultiplier, PllSource, SystemClock};
+use hal::rtc::{Event, Rtc, RtcClock};
 use hal::serial::Serial;
 use hal::spi::Spi;
-use hal::stm32l151;
+use hal::stm32l151::{self, Interrupt};
+use hal::time::MonoTimer;
 use log::Logger;
+use proto::beacon::{Beacon, TimeSync};
+use proto::channel::{self, ChannelMask};
+use proto::fragment::{self, Fragment, Reassembler};
+use proto::link::{Address, Frame, Kind};
+use proto::route::{self, Packet, Received, Role, Router};
+use proto::telemetry::{Reading, SensorType, Telemetry};
+use proto::{command, ota, FIFO_SIZE};
 use rt::ExceptionFrame;
 use si4455::Si4455;
 
+use app::App;
+
+/// Link-layer address of this node
+const NODE_ADDRESS: Address = Address(0x01);
+
+/// Routing role of this node, relays must be mains-powered as they never sleep
+#[cfg(feature = "relay")]
+const ROLE: Role = Role::Relay;
+#[cfg(not(feature = "relay"))]
+const ROLE: Role = Role::Leaf;
+
+/// Interval between route advertisements, for relays, in seconds
+const ADVERT_INTERVAL: u32 = 30;
+
+/// Time after which a silent neighbour or route is forgotten, in seconds
+const ROUTE_TIMEOUT: u32 = 4 * ADVERT_INTERVAL;
+
+/// Time without beacons after which a node in hopping mode looks for the gateway again, in
+/// milliseconds
+const SYNC_TIMEOUT: u32 = 5 * 60 * 1000;
+
+/// Time after which an incomplete fragmented message is dropped, in seconds
+const REASSEMBLY_TIMEOUT: u32 = 30;
+
+/// Time the radio listens before the expected arrival of a beacon, in milliseconds
+const BEACON_GUARD: u32 = 50;
+
+/// Time the radio keeps listening after the last received frame, for the downlink traffic that
+/// follows the beacons, in milliseconds
+const RX_WINDOW: u32 = 1000;
+
 entry!(main);
 
 fn main() -> ! {
@@ -43,9 +97,12 @@ fn main() -> ! {
             APBPrescaler::Div2,
             APBPrescaler::Div2,
         )
-        .freeze(&mut flash.acr);
+        .freeze(&mut flash.acr)
+        .unwrap();
 
     let mut delay = Delay::new(cp.SYST, clocks);
+    let timer = MonoTimer::new(cp.DWT, clocks);
+    let mut scb = cp.SCB;
 
     let mut gpioa = p.GPIOA.split(&mut rcc.ahb);
     let mut gpiob = p.GPIOB.split(&mut rcc.ahb);
@@ -56,15 +113,17 @@ fn main() -> ! {
         .pb4
         .into_push_pull_output(&mut gpiob.moder, &mut gpiob.otyper);
 
-    /* Debug UART */
-    let mut log = {
+    /* Battery voltage and temperature of the microcontroller, powered straight from the battery */
+    let mut adc = Adc::adc(p.ADC, &mut rcc.apb2);
+    let (mut vrefint, mut temperature) = adc.enable_internal_channels();
+
+    /* Debug UART, or link to the host in gateway mode */
+    let (tx, rx) = {
         let tx = gpioa.pa9.into_af7(&mut gpioa.moder, &mut gpioa.afrh);
         let rx = gpioa.pa10.into_af7(&mut gpioa.moder, &mut gpioa.afrh);
 
         let uart = Serial::usart1(p.USART1, (tx, rx), 115_200.bps(), clocks, &mut rcc.apb2);
-        let (tx, _) = uart.split();
-
-        Logger::new(tx)
+        uart.split()
     };
 
     /* Si4455 */
@@ -114,21 +173,354 @@ fn main() -> ! {
         ).unwrap()
     };
 
-    si4455.listen(0, 0).unwrap();
+    /* The radio works: if this is the first boot after an update, keep the new firmware */
+    let confirmed = boot::confirm(&mut flash.pecr.unlock_program()).unwrap();
+
+    if cfg!(feature = "gateway") {
+        gateway::run(si4455, tx, rx, Clock::new(&timer), led);
+    }
+
+    let mut log = Logger::new(tx);
+
+    if confirmed {
+        write!(&mut log, "Firmware update confirmed\n").ok();
+    }
+
+    /* Pump switch, on the expansion header */
+    let pump = gpiob
+        .pb5
+        .into_push_pull_output(&mut gpiob.moder, &mut gpiob.otyper);
+
+    let mut app = App::new(pump);
+
+    /* RTC wakeup timer, for leaves to sleep in Stop mode between the beacons */
+    let mut pwr = Pwr::pwr(p.PWR, &mut rcc.apb1);
+    let mut rtc = Rtc::rtc(p.RTC, RtcClock::Lse, &mut pwr);
+    let mut exti = Exti::exti(p.EXTI);
+    let mut nvic = cp.NVIC;
+
+    rtc.listen(&mut exti, Event::Wakeup);
+    nvic.enable(Interrupt::RTC_WKUP);
+
+    let mut updater = Updater::new(*ota_key::PUBLIC_KEY, version::VERSION);
+    let mut message = [0; ota::MAX_REQUEST_LEN];
+    let mut reassembler = Reassembler::new(&mut message, REASSEMBLY_TIMEOUT);
+    let mut update_pending = false;
+
+    let mut router = Router::new(NODE_ADDRESS, ROLE, ROUTE_TIMEOUT);
+
+    let mut seq = 0;
+    let mut rssi = None;
+    let mut rx = [0; FIFO_SIZE];
+    let mut clock = Clock::new(&timer);
+    let mut time_sync = TimeSync::new();
+    let mut second = clock.millis();
+    let mut uptime = 0;
+    let mut channels = ChannelMask::NONE;
+    let mut channel = 0;
+    let mut last_rx = clock.millis();
+
+    let mut listening = listen(&mut si4455, channel, &mut log);
 
     loop {
-        let part = si4455.get_part_info().unwrap();
-        let func = si4455.get_func_info().unwrap();
+        if cfg!(feature = "channel-hopping") {
+            let now = clock.millis();
+            let synchronised = time_sync
+                .age(now)
+                .map(|age| age < SYNC_TIMEOUT)
+                .unwrap_or(false);
+
+            let next = match time_sync.utc_millis(now) {
+                Some(utc) if synchronised => channel::hop_channel(channels, utc),
+                _ => channel::scan_channel(now),
+            };
+
+            if next != channel {
+                channel = next;
+                listening = listen(&mut si4455, channel, &mut log);
+            }
+        }
+
+        match si4455.receive(&mut rx) {
+            Ok(len) => {
+                last_rx = clock.millis();
+                rssi = si4455.latched_rssi().ok().map(|dbm| dbm.max(-127) as i8);
+
+                let received = Frame::decode(&rx[..len])
+                    .map_err(route::Error::from)
+                    .and_then(|frame| router.receive(&frame, rssi, uptime));
+
+                match received {
+                    Ok(Received::Local(ref packet)) if packet.kind == Kind::Command => {
+                        let response = command::dispatch(&mut app, packet.seq, packet.payload);
+
+                        let mut payload = [0; command::RESPONSE_LEN];
+                        let n = response.encode(&mut payload).unwrap();
+                        let packet = next_packet(Kind::Response, &mut seq, &payload[..n]);
+
+                        send(&mut si4455, &router, channel, &packet, &mut log);
+                    }
+                    Ok(Received::Local(ref packet)) if packet.kind == Kind::Beacon => {
+                        match Beacon::decode(packet.payload) {
+                            Ok(ref beacon) => {
+                                let now = clock.millis();
+                                time_sync.update(beacon, now);
+                                channels = beacon.channels;
+
+                                let wakeup = time_sync.wakeup(now, BEACON_GUARD).unwrap_or(now);
+                                write!(
+                                    &mut log,
+                                    "Beacon: drift {} ppm, next RX window in {} ms\n",
+                                    time_sync.drift(),
+                                    wakeup.wrapping_sub(now)
+                                ).ok();
+                            }
+                            Err(e) => {
+                                write!(&mut log, "Invalid beacon: {:?}\n", e).ok();
+                            }
+                        }
+                    }
+                    Ok(Received::Local(ref packet)) if packet.kind == Kind::Fragment => {
+                        let fragment = match Fragment::decode(packet.payload) {
+                            Ok(fragment) => fragment,
+                            Err(e) => {
+                                write!(&mut log, "Invalid fragment: {:?}\n", e).ok();
+                                continue;
+                            }
+                        };
+
+                        let status = match reassembler.push(&fragment, uptime) {
+                            Ok(Some(request)) => match ota::Request::decode(request) {
+                                Ok(ref request) => Some(
+                                    updater.handle(&mut flash.pecr.unlock_program(), request),
+                                ),
+                                Err(e) => {
+                                    write!(&mut log, "Invalid OTA request: {:?}\n", e).ok();
+                                    None
+                                }
+                            },
+                            Ok(None) => None,
+                            Err(e) => {
+                                write!(&mut log, "Invalid fragment: {:?}\n", e).ok();
+                                continue;
+                            }
+                        };
 
-        write!(&mut log, "Sending...").ok();
-        si4455.transmit(0, b"Hello Rust!\n").unwrap();
-        write!(&mut log, "done!\n").ok();
+                        // Report the missing fragments at the end of every transfer round
+                        if fragment.index + 1 == fragment.count || status.is_some() {
+                            if let Some(ack) = reassembler.ack() {
+                                let mut payload = [0; fragment::ACK_LEN];
+                                let n = ack.encode(&mut payload).unwrap();
+                                let packet =
+                                    next_packet(Kind::FragmentAck, &mut seq, &payload[..n]);
 
-        led.toggle();
-        //delay.delay_ms(1000_u16);
+                                send(&mut si4455, &router, channel, &packet, &mut log);
+                            }
+                        }
+
+                        if let Some(status) = status {
+                            let mut payload = [0; ota::STATUS_LEN];
+                            let n = status.encode(&mut payload).unwrap();
+                            let packet = next_packet(Kind::OtaStatus, &mut seq, &payload[..n]);
+
+                            send(&mut si4455, &router, channel, &packet, &mut log);
+
+                            // The bootloader installs the new firmware at the next reset
+                            update_pending = status.state == ota::State::Verified;
+                        }
+                    }
+                    Ok(Received::Relay(ref packet)) => {
+                        send(&mut si4455, &router, channel, packet, &mut log);
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        write!(&mut log, "Invalid frame: {:?}\n", e).ok();
+                    }
+                }
+            }
+            Err(nb::Error::WouldBlock) => {}
+            Err(nb::Error::Other(e)) => {
+                write!(&mut log, "RX error: {:?}\n", e).ok();
+            }
+        }
+
+        if app.reboot_pending() || update_pending {
+            // Give the radio enough time to send the response at 2.4 kbps
+            delay.delay_ms(250_u16);
+            scb.system_reset();
+        }
+
+        if clock.millis().wrapping_sub(second) >= 1000 {
+            second = second.wrapping_add(1000);
+            uptime += 1;
+            reassembler.expire(uptime);
+            router.expire(uptime);
+            app.tick();
+            led.toggle();
+
+            if !listening {
+                listening = listen(&mut si4455, channel, &mut log);
+            }
+
+            if uptime % ADVERT_INTERVAL == 0 {
+                if let Some(advert) = router.advert() {
+                    let mut payload = [0; route::ADVERT_LEN];
+                    let n = advert.encode(&mut payload).unwrap();
+
+                    seq = seq.wrapping_add(1);
+                    let packet = Packet::new(
+                        NODE_ADDRESS,
+                        Address::BROADCAST,
+                        Kind::RouteAdvert,
+                        seq,
+                        &payload[..n],
+                    );
+
+                    send(&mut si4455, &router, channel, &packet, &mut log);
+                }
+            }
+        }
+
+        if ROLE == Role::Leaf {
+            let now = clock.millis();
+            let synchronised = time_sync
+                .age(now)
+                .map(|age| age < SYNC_TIMEOUT)
+                .unwrap_or(false);
+            let receiving = reassembler
+                .ack()
+                .map(|ack| !ack.is_complete())
+                .unwrap_or(false);
+            let idle = synchronised && !receiving && now.wrapping_sub(last_rx) >= RX_WINDOW;
+
+            // Up to a second is spent waiting for the RTC, see below
+            let seconds = match time_sync.wakeup(now, BEACON_GUARD) {
+                Some(wakeup) if idle => (wakeup.wrapping_sub(now) / 1000)
+                    .saturating_sub(1)
+                    .min(u32::from(app.idle_time())),
+                _ => 0,
+            };
+
+            if seconds > 0 {
+                if let Err(e) = si4455.sleep() {
+                    write!(&mut log, "Radio sleep error: {:?}\n", e).ok();
+                }
+
+                // The wakeup timer counts whole RTC seconds: starting it right after one begins
+                // makes the time spent in Stop mode, with the cycle counter stopped, exact
+                let start = rtc.seconds();
+                while rtc.seconds() == start {}
+
+                rtc.enable_wakeup(seconds);
+
+                // The RTC_WKUP interrupt only wakes the core up, it is cleared before it is taken
+                cortex_m::interrupt::free(|_| {
+                    while !rtc.is_pending(Event::Wakeup) {
+                        pwr.stop(&mut scb);
+                    }
+
+                    rtc.disable_wakeup();
+                    rtc.clear_interrupt(Event::Wakeup);
+                });
+
+                clock.advance(seconds * 1000);
+
+                listening = listen(&mut si4455, channel, &mut log);
+            }
+        }
+
+        if app.take_report() {
+            let vdda = adc.read_vdda(&mut vrefint);
+            let mut telemetry = Telemetry::new(vdda, rssi);
+
+            // The chip sleeps most of the time, its temperature follows the ambient one
+            telemetry
+                .push(Reading {
+                    sensor: SensorType::Temperature,
+                    value: adc.read_temperature(&mut temperature, vdda) as i16,
+                })
+                .unwrap();
+
+            let mut payload = [0; FIFO_SIZE];
+            let n = telemetry.encode(&mut payload).unwrap();
+            let packet = next_packet(Kind::Telemetry, &mut seq, &payload[..n]);
+
+            if send(&mut si4455, &router, channel, &packet, &mut log) {
+                write!(&mut log, "Report sent\n").ok();
+            }
+        }
     }
 }
 
+/// Routes `packet` and starts its transmission, returning `false` if it could not be sent
+///
+/// Errors are only logged: a lost frame is recovered by the protocol, never by resetting the node.
+fn send<E, SPI, NCS, SDN, NIRQ, W>(
+    radio: &mut Si4455<SPI, NCS, SDN, NIRQ>,
+    router: &Router,
+    channel: u8,
+    packet: &Packet,
+    log: &mut W,
+) -> bool
+where
+    E: Debug,
+    SPI: spi::Write<u8, Error = E> + spi::Transfer<u8, Error = E>,
+    NCS: OutputPin,
+    SDN: OutputPin,
+    NIRQ: InputPin,
+    W: Write,
+{
+    let mut frame = [0; FIFO_SIZE];
+
+    let len = match router.encode(packet, &mut frame) {
+        Ok(len) => len,
+        Err(e) => {
+            write!(log, "Invalid {:?} packet: {:?}\n", packet.kind, e).ok();
+            return false;
+        }
+    };
+
+    match radio.transmit(channel, &frame[..len]) {
+        Ok(()) => true,
+        Err(e) => {
+            write!(log, "TX error: {:?}\n", e).ok();
+            false
+        }
+    }
+}
+
+/// Starts receiving on `channel`, returning `false` if the radio could not be set up
+///
+/// Errors are only logged, the main loop tries again a second later.
+fn listen<E, SPI, NCS, SDN, NIRQ, W>(
+    radio: &mut Si4455<SPI, NCS, SDN, NIRQ>,
+    channel: u8,
+    log: &mut W,
+) -> bool
+where
+    E: Debug,
+    SPI: spi::Write<u8, Error = E> + spi::Transfer<u8, Error = E>,
+    NCS: OutputPin,
+    SDN: OutputPin,
+    NIRQ: InputPin,
+    W: Write,
+{
+    match radio.listen(channel, 0) {
+        Ok(()) => true,
+        Err(e) => {
+            write!(log, "RX error: {:?}\n", e).ok();
+            false
+        }
+    }
+}
+
+/// Builds the next packet addressed to the gateway
+fn next_packet<'a>(kind: Kind, seq: &mut u8, payload: &'a [u8]) -> Packet<'a> {
+    *seq = seq.wrapping_add(1);
+
+    Packet::new(NODE_ADDRESS, Address::GATEWAY, kind, *seq, payload)
+}
+
 exception!(*, default_handler);
 
 fn default_handler(_irqn: i16) {}
@@ -0,0 +1,5 @@
+/// ed25519 public key verifying the firmware update manifests
+///
+/// Embedded at build time from the file named by the `OXIDANE_OTA_KEY` environment variable, see
+/// `build.rs`.
+pub const PUBLIC_KEY: &[u8; 32] = include_bytes!(env!("OXIDANE_OTA_KEY_PATH"));
@@ -0,0 +1,5 @@
+//! Version of this firmware, from the package version, see `build.rs`
+//!
+//! Firmware updates are only accepted if their manifest carries a greater version.
+
+include!(concat!(env!("OUT_DIR"), "/version.rs"));
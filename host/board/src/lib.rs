@@ -0,0 +1,37 @@
+//! Serial port of a gateway board
+//!
+//! Shared by the host tools talking to a board running the node firmware in gateway mode, so that
+//! they all agree on the line settings. The messages exchanged over the port are defined in
+//! `oxidane-proto::serial`.
+
+#![deny(missing_docs)]
+#![deny(warnings)]
+
+extern crate serial;
+
+use std::io;
+use std::time::Duration;
+
+use serial::{SerialPort, SystemPort};
+
+/// Time a read waits for the gateway board before giving control back to the caller
+pub const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Opens the serial port of the gateway board: 115200 8N1, no flow control
+///
+/// Reads time out after `READ_TIMEOUT` when the board is silent.
+pub fn open(path: &str) -> io::Result<SystemPort> {
+    let mut port = serial::open(path)?;
+
+    port.reconfigure(&|settings| {
+        settings.set_baud_rate(serial::Baud115200)?;
+        settings.set_char_size(serial::Bits8);
+        settings.set_parity(serial::ParityNone);
+        settings.set_stop_bits(serial::Stop1);
+        settings.set_flow_control(serial::FlowNone);
+        Ok(())
+    })?;
+    port.set_timeout(READ_TIMEOUT)?;
+
+    Ok(port)
+}
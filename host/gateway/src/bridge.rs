@@ -0,0 +1,783 @@
+//! Radio to MQTT bridge
+
+use std::io::{self, Read, Write};
+use std::str;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use proto::beacon::Beacon;
+use proto::channel::{self, Blacklist, ChannelMask};
+use proto::command::{Command, Response, Thresholds};
+use proto::link::{Address, Frame, Header, Kind};
+use proto::route::{Packet, Received, Role, Router};
+use proto::telemetry::{SensorType, Telemetry};
+use proto::{ota, FIFO_SIZE};
+
+use mqtt::{self, Publish};
+use port::{ChannelStatus, Incoming, Link};
+
+/// Root of every topic
+const PREFIX: &str = "oxidane";
+
+/// Interval between route advertisements, in seconds, as on the relays
+const ADVERT_INTERVAL: u64 = 30;
+
+/// Time after which a silent neighbour or route is forgotten, in seconds
+const ROUTE_TIMEOUT: u32 = 4 * ADVERT_INTERVAL as u32;
+
+/// Topic names of the commands, by id
+const COMMANDS: [&str; 6] = [
+    "start_irrigation",
+    "stop_irrigation",
+    "set_thresholds",
+    "request_report",
+    "reboot",
+    "set_report_interval",
+];
+
+/// Time a node has to respond to a command, in seconds
+const RESPONSE_TIMEOUT: u32 = 10;
+
+/// Time after which a blacklisted channel gets another chance, in seconds
+const BLACKLIST_TIMEOUT: u32 = 30 * 60;
+
+/// Command waiting for the response of a node
+struct Pending {
+    node: Address,
+    command: u8,
+    channel: u8,
+    sent: u32,
+}
+
+/// Bridge between the gateway board and the MQTT broker
+pub struct Bridge<P> {
+    link: Link<P>,
+    mqtt: mqtt::Client,
+    router: Router,
+    seq: u8,
+    start: Instant,
+    beacon_interval: u16,
+    next_beacon: Instant,
+    next_advert: Instant,
+    hopping: bool,
+    blacklist: Blacklist,
+    // Channels announced in the last beacon, and the current one
+    channels: ChannelMask,
+    channel: u8,
+    pending: Vec<Pending>,
+}
+
+impl<P> Bridge<P>
+where
+    P: Read + Write,
+{
+    /// Creates a bridge broadcasting a beacon every `beacon_interval` seconds, and subscribes to
+    /// the command topics
+    ///
+    /// With `hopping`, the gateway hops over the channels that the blacklist leaves enabled and
+    /// announces them in the beacons; otherwise everything happens on channel 0.
+    pub fn new(
+        link: Link<P>,
+        mut mqtt: mqtt::Client,
+        beacon_interval: u16,
+        hopping: bool,
+    ) -> io::Result<Self> {
+        mqtt.subscribe(&format!("{}/+/command/+", PREFIX))?;
+
+        let now = Instant::now();
+
+        Ok(Bridge {
+            link,
+            mqtt,
+            router: Router::new(Address::GATEWAY, Role::Gateway, ROUTE_TIMEOUT),
+            seq: 0,
+            start: now,
+            beacon_interval,
+            next_beacon: now,
+            next_advert: now,
+            hopping,
+            blacklist: Blacklist::new(BLACKLIST_TIMEOUT),
+            channels: ChannelMask::NONE,
+            channel: 0,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Serves the radio network and the broker until an I/O error occurs
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            if let Some(incoming) = self.link.receive()? {
+                self.uplink(&incoming)?;
+            }
+
+            while let Some(status) = self.link.channel_status() {
+                self.channel_status(&status);
+            }
+
+            while let Some(publish) = self.mqtt.poll()? {
+                if let Err(e) = self.downlink(&publish) {
+                    warn!("{}: {}", publish.topic, e);
+                }
+            }
+
+            self.housekeeping()?;
+        }
+    }
+
+    // Ticks of the router, in seconds
+    fn now(&self) -> u32 {
+        self.start.elapsed().as_secs() as u32
+    }
+
+    fn housekeeping(&mut self) -> io::Result<()> {
+        // Follow the sequence announced in the last beacon, as the nodes do
+        let channel = channel::hop_channel(self.channels, utc_millis()?);
+        if channel != self.channel {
+            self.channel = channel;
+            self.link.listen(channel)?;
+        }
+
+        let now = Instant::now();
+
+        if now >= self.next_advert {
+            self.next_advert = now + Duration::from_secs(ADVERT_INTERVAL);
+
+            if let Some(advert) = self.router.advert() {
+                let mut payload = [0; 2];
+                let n = advert.encode(&mut payload).unwrap();
+
+                self.send(Address::BROADCAST, Kind::RouteAdvert, &payload[..n])?;
+            }
+        }
+
+        if now >= self.next_beacon {
+            self.next_beacon = now + Duration::from_secs(u64::from(self.beacon_interval));
+
+            let channels = if self.hopping {
+                self.blacklist.channels(self.now())
+            } else {
+                ChannelMask::NONE
+            };
+
+            if channels != self.channels {
+                info!("hopping over channels {:08b}", channels.0);
+            }
+
+            // Sampled right before the transmission, as the nodes expect
+            let utc = utc_millis()?;
+            let beacon = Beacon {
+                time: (utc / 1000) as u32,
+                millis: (utc % 1000) as u16,
+                interval: self.beacon_interval,
+                channels,
+            };
+
+            let mut payload = [0; FIFO_SIZE];
+            let n = beacon.encode(&mut payload).unwrap();
+
+            // Still on the old sequence, the nodes switch over on reception
+            self.send(Address::BROADCAST, Kind::Beacon, &payload[..n])?;
+            self.channels = channels;
+        }
+
+        let now = self.now();
+        self.router.expire(now);
+        self.expire_commands(now);
+
+        Ok(())
+    }
+
+    fn channel_status(&mut self, status: &ChannelStatus) {
+        if let Some(noise) = status.noise {
+            self.blacklist.noise(status.channel, i16::from(noise));
+        }
+
+        for _ in 0..status.crc_errors {
+            self.blacklist.failure(status.channel);
+        }
+    }
+
+    // Counts the commands left without a response as failures of their channel
+    fn expire_commands(&mut self, now: u32) {
+        let blacklist = &mut self.blacklist;
+
+        self.pending.retain(|pending| {
+            if now.wrapping_sub(pending.sent) < RESPONSE_TIMEOUT {
+                return true;
+            }
+
+            let name = command_name(pending.command);
+            warn!("node {}: no response to {}", pending.node.0, name);
+            blacklist.failure(pending.channel);
+
+            false
+        });
+    }
+
+    fn send(&mut self, dst: Address, kind: Kind, payload: &[u8]) -> io::Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+
+        let mut buf = [0; FIFO_SIZE];
+        let n = if dst == Address::BROADCAST {
+            // Broadcasts are never routed
+            let header = Header {
+                kind,
+                dst,
+                src: Address::GATEWAY,
+                seq: self.seq,
+            };
+
+            Frame::new(header, payload)
+                .encode(&mut buf)
+                .map_err(invalid)?
+        } else {
+            let packet = Packet::new(Address::GATEWAY, dst, kind, self.seq, payload);
+
+            self.router.encode(&packet, &mut buf).map_err(invalid)?
+        };
+
+        self.link.transmit(self.channel, &buf[..n])
+    }
+
+    fn publish(
+        &mut self,
+        node: Address,
+        topic: &str,
+        payload: &str,
+        retain: bool,
+    ) -> io::Result<()> {
+        let topic = format!("{}/{}/{}", PREFIX, node.0, topic);
+        debug!("{} {}", topic, payload);
+
+        self.mqtt.publish(&topic, payload.as_bytes(), retain)
+    }
+
+    fn uplink(&mut self, incoming: &Incoming) -> io::Result<()> {
+        let frame = match Frame::decode(&incoming.frame) {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("invalid frame on channel {}: {:?}", incoming.channel, e);
+                self.blacklist.failure(incoming.channel);
+                return Ok(());
+            }
+        };
+        self.blacklist.success(incoming.channel);
+
+        let now = self.now();
+        let packet = match self.router.receive(&frame, incoming.rssi, now) {
+            Ok(Received::Local(packet)) => packet,
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                warn!("invalid frame from node {}: {:?}", frame.header.src.0, e);
+                return Ok(());
+            }
+        };
+
+        let node = packet.src;
+        debug!(
+            "node {}: {:?} frame at {} ms on channel {}",
+            node.0, packet.kind, incoming.timestamp, incoming.channel
+        );
+
+        if let Some(rssi) = incoming.rssi {
+            // Of the last hop, for routed packets
+            self.publish(node, "rssi", &rssi.to_string(), true)?;
+        }
+
+        match packet.kind {
+            Kind::Telemetry => match Telemetry::decode(packet.payload) {
+                Ok(telemetry) => {
+                    info!("node {}: {}", node.0, telemetry);
+
+                    for reading in telemetry.readings() {
+                        let value = format_value(reading.sensor, reading.value);
+                        self.publish(node, reading.sensor.name(), &value, true)?;
+                    }
+
+                    let battery = format!("{:.3}", f64::from(telemetry.battery_mv) / 1000.0);
+                    self.publish(node, "battery", &battery, true)?;
+                }
+                Err(e) => warn!("invalid telemetry from node {}: {:?}", node.0, e),
+            },
+            Kind::Response => match Response::decode(packet.payload) {
+                Ok(response) => {
+                    let name = command_name(response.command);
+                    let result = match response.result {
+                        Ok(()) => "ok".to_owned(),
+                        Err(code) => format!("{:?}", code),
+                    };
+
+                    self.pending
+                        .retain(|p| p.node != node || p.command != response.command);
+
+                    info!("node {}: {} {}", node.0, name, result);
+                    self.publish(node, &format!("response/{}", name), &result, false)?;
+                }
+                Err(e) => warn!("invalid response from node {}: {:?}", node.0, e),
+            },
+            Kind::OtaStatus => match ota::Status::decode(packet.payload) {
+                Ok(status) => {
+                    let mut payload = format!("{:?} {}", status.state, status.offset);
+                    if let Some(error) = status.error {
+                        payload.push_str(&format!(" {:?}", error));
+                    }
+
+                    self.publish(node, "ota", &payload, false)?;
+                }
+                Err(e) => warn!("invalid update status from node {}: {:?}", node.0, e),
+            },
+            kind => debug!("node {}: ignoring {:?} frame", node.0, kind),
+        }
+
+        Ok(())
+    }
+
+    fn downlink(&mut self, publish: &Publish) -> Result<(), String> {
+        // oxidane/<node>/command/<name>
+        let levels: Vec<&str> = publish.topic.split('/').collect();
+        let (node, name) = match levels[..] {
+            [_, node, "command", name] => (node, name),
+            _ => return Err("not a command topic".to_owned()),
+        };
+
+        let dst = match node {
+            "all" => Address::BROADCAST,
+            _ => node
+                .parse()
+                .map(Address)
+                .map_err(|_| format!("invalid node address {:?}", node))?,
+        };
+
+        let args = str::from_utf8(&publish.payload).map_err(|_| "payload is not UTF-8")?;
+        let command = parse_command(name, args)?;
+
+        let mut payload = [0; FIFO_SIZE];
+        let n = command
+            .encode(&mut payload)
+            .map_err(|e| format!("{:?}", e))?;
+
+        info!("node {}: {:?}", node, command);
+        self.send(dst, Kind::Command, &payload[..n])
+            .map_err(|e| e.to_string())?;
+
+        // Broadcasts get any number of responses
+        if dst != Address::BROADCAST {
+            self.pending.push(Pending {
+                node: dst,
+                command: command.id(),
+                channel: self.channel,
+                sent: self.now(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the UTC time, in milliseconds since the Unix epoch
+fn utc_millis() -> io::Result<u64> {
+    let utc = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(io::Error::other)?;
+
+    Ok(utc.as_secs() * 1000 + u64::from(utc.subsec_millis()))
+}
+
+fn invalid<E>(e: E) -> io::Error
+where
+    E: ::std::fmt::Debug,
+{
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e))
+}
+
+/// Formats a reading in units of measure, e.g. `21.50` for a temperature
+fn format_value(sensor: SensorType, value: i16) -> String {
+    match sensor.scale() {
+        1 => value.to_string(),
+        scale => {
+            let digits = if scale == 100 { 2 } else { 1 };
+            format!("{:.*}", digits, f64::from(value) / f64::from(scale))
+        }
+    }
+}
+
+/// Parses a value in units of measure into the fixed point scale of `sensor`
+fn parse_value(sensor: SensorType, value: &str) -> Result<i16, String> {
+    let scaled = value
+        .parse::<f64>()
+        .map(|v| (v * f64::from(sensor.scale())).round())
+        .map_err(|_| format!("invalid {} {:?}", sensor.name(), value))?;
+
+    if scaled < f64::from(i16::MIN) || scaled > f64::from(i16::MAX) {
+        return Err(format!("{} out of range: {}", sensor.name(), value));
+    }
+
+    Ok(scaled as i16)
+}
+
+fn parse_command(name: &str, args: &str) -> Result<Command, String> {
+    let args: Vec<&str> = args.split_whitespace().collect();
+
+    let seconds = |arg: &str| {
+        arg.parse::<u16>()
+            .map_err(|_| format!("invalid number of seconds {:?}", arg))
+    };
+
+    match (name, &args[..]) {
+        ("start_irrigation", [duration]) => Ok(Command::StartIrrigation {
+            duration_s: seconds(duration)?,
+        }),
+        ("stop_irrigation", []) => Ok(Command::StopIrrigation),
+        ("set_thresholds", [on, off, temperature]) => Ok(Command::SetThresholds(Thresholds {
+            humidity_on: parse_value(SensorType::Humidity, on)?,
+            humidity_off: parse_value(SensorType::Humidity, off)?,
+            temperature_min: parse_value(SensorType::Temperature, temperature)?,
+        })),
+        ("request_report", []) => Ok(Command::RequestReport),
+        ("reboot", []) => Ok(Command::Reboot),
+        ("set_report_interval", [interval]) => Ok(Command::SetReportInterval {
+            interval_s: seconds(interval)?,
+        }),
+        _ if COMMANDS.contains(&name) => Err(format!("wrong number of arguments for {}", name)),
+        _ => Err(format!("unknown command {:?}", name)),
+    }
+}
+
+/// Returns the topic name of the command with id `id`
+fn command_name(id: u8) -> &'static str {
+    COMMANDS
+        .get((id as usize).wrapping_sub(1))
+        .cloned()
+        .unwrap_or("unknown")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    use simulator::Simulator;
+
+    use super::*;
+
+    /// Time waited for the simulator or the broker before failing a test
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Stand-in for the MQTT broker, handing the packets sent by the bridge over to the test
+    struct Broker {
+        stream: TcpStream,
+        packets: Receiver<(u8, Vec<u8>)>,
+    }
+
+    impl Broker {
+        fn start() -> (Broker, mqtt::Client) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (streams_tx, streams) = mpsc::channel();
+            let (packets_tx, packets) = mpsc::channel();
+
+            thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+
+                let (header, _) = read_packet(&mut stream).unwrap();
+                assert_eq!(header, 0x10, "expected CONNECT");
+                stream.write_all(&[0x20, 2, 0, 0]).unwrap();
+                streams_tx.send(stream.try_clone().unwrap()).unwrap();
+
+                while let Some(packet) = read_packet(&mut stream) {
+                    if packets_tx.send(packet).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            let client = mqtt::Client::connect(&addr.to_string(), "test", 60).unwrap();
+            let stream = streams.recv_timeout(TIMEOUT).unwrap();
+
+            (Broker { stream, packets }, client)
+        }
+
+        fn next(&self) -> (u8, Vec<u8>) {
+            self.packets.recv_timeout(TIMEOUT).unwrap()
+        }
+
+        // Returns the next `n` publications, as (topic, payload, retain)
+        fn published(&self, n: usize) -> Vec<(String, String, bool)> {
+            (0..n)
+                .map(|_| {
+                    let (header, body) = self.next();
+                    assert_eq!(header & 0xF0, 0x30, "expected PUBLISH");
+
+                    let len = (body[0] as usize) << 8 | body[1] as usize;
+                    let topic = str::from_utf8(&body[2..2 + len]).unwrap().to_owned();
+                    let payload = str::from_utf8(&body[2 + len..]).unwrap().to_owned();
+
+                    (topic, payload, header & 0x01 != 0)
+                })
+                .collect()
+        }
+
+        fn publish(&mut self, topic: &str, payload: &str) {
+            let len = 2 + topic.len() + payload.len();
+            assert!(len < 128, "invalid test publication");
+
+            let mut packet = vec![0x30, len as u8, 0, topic.len() as u8];
+            packet.extend_from_slice(topic.as_bytes());
+            packet.extend_from_slice(payload.as_bytes());
+
+            self.stream.write_all(&packet).unwrap();
+        }
+    }
+
+    fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).ok()?;
+        let header = byte[0];
+
+        let mut len = 0;
+        let mut shift = 0;
+        loop {
+            stream.read_exact(&mut byte).ok()?;
+            len |= (byte[0] as usize & 0x7F) << shift;
+            shift += 7;
+
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).ok()?;
+
+        Some((header, body))
+    }
+
+    // Bridge to a simulated board with a single node, at address 1
+    fn bridge() -> (Bridge<Simulator>, Broker) {
+        start(false)
+    }
+
+    fn start(hopping: bool) -> (Bridge<Simulator>, Broker) {
+        let (broker, client) = Broker::start();
+        let bridge = Bridge::new(Link::new(Simulator::new(1)), client, 60, hopping).unwrap();
+
+        let (header, body) = broker.next();
+        assert_eq!(header, 0x82, "expected SUBSCRIBE");
+        assert_eq!(&body[4..body.len() - 1], b"oxidane/+/command/+");
+
+        (bridge, broker)
+    }
+
+    // Waits for the next frame of the simulated board and bridges it to the broker
+    fn uplink(bridge: &mut Bridge<Simulator>) {
+        let deadline = Instant::now() + TIMEOUT;
+
+        loop {
+            if let Some(incoming) = bridge.link.receive().unwrap() {
+                return bridge.uplink(&incoming).unwrap();
+            }
+
+            assert!(Instant::now() < deadline, "no frame from the simulator");
+        }
+    }
+
+    // Waits for the next message of the broker and bridges it to the simulated board
+    fn downlink(bridge: &mut Bridge<Simulator>) -> Result<(), String> {
+        let deadline = Instant::now() + TIMEOUT;
+
+        loop {
+            if let Some(publish) = bridge.mqtt.poll().unwrap() {
+                return bridge.downlink(&publish);
+            }
+
+            assert!(Instant::now() < deadline, "no message from the broker");
+        }
+    }
+
+    fn publication(topic: &str, payload: &str, retain: bool) -> (String, String, bool) {
+        (topic.to_owned(), payload.to_owned(), retain)
+    }
+
+    #[test]
+    fn telemetry() {
+        let (mut bridge, broker) = bridge();
+
+        // First periodic report of the node
+        uplink(&mut bridge);
+
+        let published = broker.published(7);
+        let topics: Vec<&str> = published.iter().map(|p| &p.0[..]).collect();
+        assert_eq!(
+            topics,
+            [
+                "oxidane/1/rssi",
+                "oxidane/1/temperature",
+                "oxidane/1/humidity",
+                "oxidane/1/light",
+                "oxidane/1/pressure",
+                "oxidane/1/water_level",
+                "oxidane/1/battery",
+            ]
+        );
+        assert!(published.iter().all(|p| p.2));
+
+        // Temperature and light follow the simulated daily cycle
+        let expected = [
+            (0, "-72"),
+            (2, "40.00"),
+            (4, "1013.2"),
+            (5, "1"),
+            (6, "3.300"),
+        ];
+        for &(i, payload) in &expected {
+            assert_eq!(published[i].1, payload, "{}", published[i].0);
+        }
+    }
+
+    #[test]
+    fn commands() {
+        let (mut bridge, mut broker) = bridge();
+
+        // The gateway learns the route to the node from its first report
+        uplink(&mut bridge);
+        broker.published(7);
+
+        broker.publish("oxidane/1/command/request_report", "");
+        downlink(&mut bridge).unwrap();
+        uplink(&mut bridge);
+        assert_eq!(
+            broker.published(2),
+            [
+                publication("oxidane/1/rssi", "-72", true),
+                publication("oxidane/1/response/request_report", "ok", false),
+            ]
+        );
+
+        // The requested report follows the response
+        uplink(&mut bridge);
+        assert_eq!(broker.published(7)[0].0, "oxidane/1/rssi");
+
+        broker.publish("oxidane/1/command/set_report_interval", "0");
+        downlink(&mut bridge).unwrap();
+        uplink(&mut bridge);
+        assert_eq!(
+            broker.published(2)[1],
+            publication(
+                "oxidane/1/response/set_report_interval",
+                "InvalidArgument",
+                false
+            )
+        );
+
+        broker.publish("oxidane/all/command/set_thresholds", "30 45 5");
+        downlink(&mut bridge).unwrap();
+        uplink(&mut bridge);
+        assert_eq!(
+            broker.published(2)[1],
+            publication("oxidane/1/response/set_thresholds", "ok", false)
+        );
+    }
+
+    #[test]
+    fn invalid_commands() {
+        let (mut bridge, mut broker) = bridge();
+
+        let cases = [
+            ("oxidane/1/status", "", "not a command topic"),
+            ("oxidane/x/command/reboot", "", "invalid node address \"x\""),
+            (
+                "oxidane/1/command/explode",
+                "",
+                "unknown command \"explode\"",
+            ),
+            (
+                "oxidane/1/command/start_irrigation",
+                "",
+                "wrong number of arguments for start_irrigation",
+            ),
+            (
+                "oxidane/1/command/start_irrigation",
+                "-1",
+                "invalid number of seconds \"-1\"",
+            ),
+            (
+                "oxidane/1/command/set_thresholds",
+                "30 45 400",
+                "temperature out of range: 400",
+            ),
+        ];
+
+        for &(topic, payload, error) in &cases {
+            broker.publish(topic, payload);
+            assert_eq!(downlink(&mut bridge), Err(error.to_owned()), "{}", topic);
+        }
+    }
+
+    #[test]
+    fn hopping() {
+        let (mut bridge, _broker) = start(true);
+
+        // A noisy channel, and one corrupting frames
+        bridge.channel_status(&ChannelStatus {
+            channel: 3,
+            noise: Some(-60),
+            crc_errors: 0,
+        });
+        bridge.channel_status(&ChannelStatus {
+            channel: 6,
+            noise: Some(-110),
+            crc_errors: channel::MAX_FAILURES,
+        });
+
+        // The first beacon announces the remaining channels
+        bridge.housekeeping().unwrap();
+        assert_eq!(bridge.channels, ChannelMask(0b1011_0111));
+
+        bridge.housekeeping().unwrap();
+        assert!(bridge.channels.contains(bridge.channel));
+    }
+
+    #[test]
+    fn no_hopping() {
+        let (mut bridge, _broker) = bridge();
+
+        bridge.channel_status(&ChannelStatus {
+            channel: 0,
+            noise: Some(-60),
+            crc_errors: 0,
+        });
+        bridge.housekeeping().unwrap();
+        bridge.housekeeping().unwrap();
+
+        assert_eq!(bridge.channels, ChannelMask::NONE);
+        assert_eq!(bridge.channel, 0);
+    }
+
+    #[test]
+    fn missing_responses() {
+        let (mut bridge, mut broker) = bridge();
+
+        uplink(&mut bridge);
+        broker.published(7);
+
+        broker.publish("oxidane/1/command/request_report", "");
+        downlink(&mut bridge).unwrap();
+        assert_eq!(bridge.pending.len(), 1);
+        uplink(&mut bridge);
+        assert!(bridge.pending.is_empty());
+
+        // The responses of the simulated node are never bridged
+        for _ in 0..channel::MAX_FAILURES {
+            broker.publish("oxidane/1/command/reboot", "");
+            downlink(&mut bridge).unwrap();
+        }
+
+        let now = bridge.now();
+        bridge.expire_commands(now + RESPONSE_TIMEOUT - 1);
+        assert_eq!(bridge.pending.len(), channel::MAX_FAILURES as usize);
+
+        bridge.expire_commands(now + RESPONSE_TIMEOUT);
+        assert!(bridge.pending.is_empty());
+        assert!(!bridge.blacklist.channels(now).contains(0));
+    }
+}
@@ -0,0 +1,122 @@
+//! Oxidane gateway daemon
+//!
+//! Bridges the radio network to an MQTT broker, through a gateway board connected over a USB
+//! serial port (see `proto::serial`). See the README for the topics and for testing without
+//! hardware.
+
+extern crate clap;
+extern crate env_logger;
+#[macro_use]
+extern crate log;
+extern crate oxidane_board as board;
+extern crate oxidane_proto as proto;
+
+mod bridge;
+mod mqtt;
+mod port;
+mod simulator;
+
+use std::io::{self, Read, Write};
+use std::process;
+
+use clap::{App, Arg, ArgMatches};
+
+use bridge::Bridge;
+use port::Link;
+use simulator::Simulator;
+
+/// MQTT keep alive interval, in seconds
+const KEEP_ALIVE: u16 = 60;
+
+fn main() {
+    env_logger::init();
+
+    let matches = App::new("oxidane-gateway")
+        .about("Bridges the Oxidane radio network to an MQTT broker")
+        .arg(
+            Arg::with_name("port")
+                .help("Serial port of the gateway board, e.g. /dev/ttyUSB0")
+                .required_unless("simulate")
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("simulate")
+                .long("simulate")
+                .value_name("NODES")
+                .help("Simulates a gateway board with NODES field nodes instead")
+                .conflicts_with("port"),
+        )
+        .arg(
+            Arg::with_name("broker")
+                .long("broker")
+                .short("b")
+                .value_name("HOST:PORT")
+                .help("MQTT broker")
+                .default_value("localhost:1883"),
+        )
+        .arg(
+            Arg::with_name("client-id")
+                .long("client-id")
+                .value_name("ID")
+                .help("MQTT client identifier")
+                .default_value("oxidane-gateway"),
+        )
+        .arg(
+            Arg::with_name("beacon-interval")
+                .long("beacon-interval")
+                .value_name("SECONDS")
+                .help("Interval between time beacons")
+                .default_value("60"),
+        )
+        .arg(
+            Arg::with_name("hopping")
+                .long("hopping")
+                .help("Hops over the channels, for nodes built with the channel-hopping feature"),
+        )
+        .get_matches();
+
+    if let Err(e) = run(&matches) {
+        error!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches) -> io::Result<()> {
+    let beacon_interval = matches
+        .value_of("beacon-interval")
+        .unwrap()
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid beacon interval"))?;
+
+    match matches.value_of("simulate") {
+        Some(nodes) => {
+            let nodes = nodes
+                .parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid node count"))?;
+
+            info!("simulating {} nodes", nodes);
+            serve(matches, Simulator::new(nodes), beacon_interval)
+        }
+        None => {
+            let path = matches.value_of("port").unwrap();
+
+            info!("opening {}", path);
+            serve(matches, board::open(path)?, beacon_interval)
+        }
+    }
+}
+
+fn serve<P>(matches: &ArgMatches, port: P, beacon_interval: u16) -> io::Result<()>
+where
+    P: Read + Write,
+{
+    let broker = matches.value_of("broker").unwrap();
+    let client_id = matches.value_of("client-id").unwrap();
+
+    info!("connecting to {}", broker);
+    let mqtt = mqtt::Client::connect(broker, client_id, KEEP_ALIVE)?;
+
+    let hopping = matches.is_present("hopping");
+
+    Bridge::new(Link::new(port), mqtt, beacon_interval, hopping)?.run()
+}
@@ -0,0 +1,329 @@
+//! Minimal MQTT 3.1.1 client
+//!
+//! Just what the bridge needs: a clean session, QoS 0 publications and subscriptions, and keep
+//! alive pings. Incoming packets are polled with a short read timeout, so that the caller can
+//! interleave the broker with the serial link in a single thread.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// Time waited for the broker to acknowledge the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time `Client::poll` waits for incoming data
+const POLL_TIMEOUT: Duration = Duration::from_millis(10);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+
+/// Application message received from the broker
+#[derive(Debug)]
+pub struct Publish {
+    /// Topic the message was published to
+    pub topic: String,
+    /// Message payload
+    pub payload: Vec<u8>,
+}
+
+/// Connection to an MQTT broker
+pub struct Client {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    keep_alive: Duration,
+    last_sent: Instant,
+    packet_id: u16,
+}
+
+impl Client {
+    /// Connects to the broker at `addr` (`host:port`) as `client_id`
+    ///
+    /// The broker drops the connection if no packet is sent for more than `keep_alive` seconds;
+    /// `poll` pings it as needed. A `keep_alive` of 0 disables the mechanism.
+    pub fn connect(addr: &str, client_id: &str, keep_alive: u16) -> io::Result<Client> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+
+        let mut client = Client {
+            stream,
+            buf: Vec::new(),
+            keep_alive: Duration::from_secs(u64::from(keep_alive)),
+            last_sent: Instant::now(),
+            packet_id: 0,
+        };
+
+        let mut body = Vec::new();
+        put_str(&mut body, "MQTT");
+        body.push(4); // protocol level 3.1.1
+        body.push(0x02); // clean session
+        body.extend_from_slice(&[(keep_alive >> 8) as u8, keep_alive as u8]);
+        put_str(&mut body, client_id);
+        client.send(CONNECT, &body)?;
+
+        let (header, body) = loop {
+            if let Some(packet) = client.next_packet()? {
+                break packet;
+            }
+
+            if !client.fill()? {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no CONNACK from the broker",
+                ));
+            }
+        };
+
+        if header != CONNACK || body.len() != 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected response to CONNECT",
+            ));
+        }
+
+        if body[1] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("broker refused the connection (return code {})", body[1]),
+            ));
+        }
+
+        client.stream.set_read_timeout(Some(POLL_TIMEOUT))?;
+
+        Ok(client)
+    }
+
+    /// Publishes `payload` to `topic` with QoS 0
+    ///
+    /// A retained message is delivered to the future subscribers too.
+    pub fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> io::Result<()> {
+        let mut body = Vec::new();
+        put_str(&mut body, topic);
+        body.extend_from_slice(payload);
+
+        self.send(PUBLISH | retain as u8, &body)
+    }
+
+    /// Subscribes to the topics matching `filter` with QoS 0
+    pub fn subscribe(&mut self, filter: &str) -> io::Result<()> {
+        self.packet_id = self.packet_id.wrapping_add(1).max(1);
+
+        let mut body = vec![(self.packet_id >> 8) as u8, self.packet_id as u8];
+        put_str(&mut body, filter);
+        body.push(0);
+
+        self.send(SUBSCRIBE, &body)
+    }
+
+    /// Returns the next message received from the broker, if any
+    ///
+    /// Must be called regularly to keep the connection alive.
+    pub fn poll(&mut self) -> io::Result<Option<Publish>> {
+        if self.keep_alive > Duration::from_secs(0)
+            && self.last_sent.elapsed() >= self.keep_alive / 2
+        {
+            self.send(PINGREQ, &[])?;
+        }
+
+        loop {
+            while let Some((header, body)) = self.next_packet()? {
+                match header & 0xF0 {
+                    PUBLISH => return self.receive(header, &body).map(Some),
+                    SUBACK if body.get(2) == Some(&0x80) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::PermissionDenied,
+                            "broker refused the subscription",
+                        ))
+                    }
+                    SUBACK | PINGRESP => {}
+                    _ => debug!("ignoring MQTT packet {:#04x}", header),
+                }
+            }
+
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn receive(&mut self, header: u8, body: &[u8]) -> io::Result<Publish> {
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed PUBLISH");
+
+        if body.len() < 2 {
+            return Err(malformed());
+        }
+
+        let len = (body[0] as usize) << 8 | body[1] as usize;
+        let topic = body.get(2..2 + len).ok_or_else(malformed)?;
+        let topic = String::from_utf8(topic.to_vec()).map_err(|_| malformed())?;
+
+        // QoS 1 and 2 messages carry a packet id; only QoS 0 is requested, acknowledge anyway
+        let mut payload = &body[2 + len..];
+        if header & 0x06 != 0 {
+            let id = payload.get(..2).ok_or_else(malformed)?.to_vec();
+            payload = &payload[2..];
+
+            if header & 0x06 == 0x02 {
+                self.send(PUBACK, &id)?;
+            }
+        }
+
+        Ok(Publish {
+            topic,
+            payload: payload.to_vec(),
+        })
+    }
+
+    fn send(&mut self, header: u8, body: &[u8]) -> io::Result<()> {
+        let mut packet = vec![header];
+
+        let mut len = body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+
+            if len == 0 {
+                packet.push(byte);
+                break;
+            }
+
+            packet.push(byte | 0x80);
+        }
+
+        packet.extend_from_slice(body);
+
+        self.stream.write_all(&packet)?;
+        self.last_sent = Instant::now();
+
+        Ok(())
+    }
+
+    // Reads whatever the broker sent, returning `false` if there was nothing
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 1024];
+
+        match self.stream.read(&mut buf) {
+            Ok(0) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "broker closed the connection",
+            )),
+            Ok(n) => {
+                self.buf.extend_from_slice(&buf[..n]);
+                Ok(true)
+            }
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Extracts the next complete packet from the input buffer
+    fn next_packet(&mut self) -> io::Result<Option<(u8, Vec<u8>)>> {
+        let mut len = 0;
+        let mut shift = 0;
+        let mut offset = 1;
+
+        loop {
+            let byte = match self.buf.get(offset) {
+                Some(&byte) => byte,
+                None => return Ok(None),
+            };
+
+            len |= (byte as usize & 0x7F) << shift;
+            shift += 7;
+            offset += 1;
+
+            if byte & 0x80 == 0 {
+                break;
+            }
+
+            if offset > 4 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "malformed remaining length",
+                ));
+            }
+        }
+
+        if self.buf.len() < offset + len {
+            return Ok(None);
+        }
+
+        let header = self.buf[0];
+        let body = self.buf[offset..offset + len].to_vec();
+        self.buf.drain(..offset + len);
+
+        Ok(Some((header, body)))
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&[(s.len() >> 8) as u8, s.len() as u8]);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn connect_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Accepts the connection but never acknowledges it
+        let broker = thread::spawn(move || listener.accept().unwrap());
+
+        let err = Client::connect(&addr.to_string(), "test", 60)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn keep_alive_disabled() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut header = [0; 2];
+            stream.read_exact(&mut header).unwrap();
+            let mut connect = vec![0; header[1] as usize];
+            stream.read_exact(&mut connect).unwrap();
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+
+            // Anything after CONNECT would be a ping
+            stream.set_read_timeout(Some(CONNECT_TIMEOUT)).unwrap();
+            let mut buf = [0; 1];
+            stream.read(&mut buf).unwrap()
+        });
+
+        let mut client = Client::connect(&addr.to_string(), "test", 0).unwrap();
+        for _ in 0..10 {
+            assert!(client.poll().unwrap().is_none());
+        }
+        drop(client);
+
+        assert_eq!(
+            broker.join().unwrap(),
+            0,
+            "unexpected packet from the client"
+        );
+    }
+}
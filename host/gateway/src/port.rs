@@ -0,0 +1,129 @@
+//! Serial link to the gateway board
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+use proto::serial::{Decoder, Message, MAX_ENCODED_LEN};
+
+/// Radio frame received by the gateway board
+#[derive(Debug)]
+pub struct Incoming {
+    /// Reception time, in milliseconds of the board clock
+    pub timestamp: u32,
+    /// RSSI of the frame in dBm, if known
+    pub rssi: Option<i8>,
+    /// Channel the frame was received on
+    pub channel: u8,
+    /// Radio frame
+    pub frame: Vec<u8>,
+}
+
+/// Quality of the channel the gateway board listens on
+#[derive(Debug)]
+pub struct ChannelStatus {
+    /// Channel the board listens on
+    pub channel: u8,
+    /// RSSI of the channel between frames in dBm, if known
+    pub noise: Option<i8>,
+    /// Frames dropped for a bad CRC since the last status
+    pub crc_errors: u8,
+}
+
+/// Message level access to the gateway board
+pub struct Link<P> {
+    port: P,
+    decoder: Decoder,
+    incoming: VecDeque<Incoming>,
+    status: VecDeque<ChannelStatus>,
+}
+
+impl<P> Link<P>
+where
+    P: Read + Write,
+{
+    /// Creates a link over `port`, whose reads must time out when the board is silent
+    pub fn new(port: P) -> Self {
+        Link {
+            port,
+            decoder: Decoder::new(),
+            incoming: VecDeque::new(),
+            status: VecDeque::new(),
+        }
+    }
+
+    /// Returns the next frame received by the board, if any
+    pub fn receive(&mut self) -> io::Result<Option<Incoming>> {
+        if let Some(incoming) = self.incoming.pop_front() {
+            return Ok(Some(incoming));
+        }
+
+        let mut buf = [0; 256];
+        let n = match self.port.read(&mut buf) {
+            Ok(n) => n,
+            Err(ref e)
+                if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+
+        for &byte in &buf[..n] {
+            match self.decoder.feed(byte) {
+                Ok(Some(Message::Received {
+                    timestamp,
+                    rssi,
+                    channel,
+                    frame,
+                })) => self.incoming.push_back(Incoming {
+                    timestamp,
+                    rssi,
+                    channel,
+                    frame: frame.to_vec(),
+                }),
+                Ok(Some(Message::ChannelStatus {
+                    channel,
+                    noise,
+                    crc_errors,
+                })) => self.status.push_back(ChannelStatus {
+                    channel,
+                    noise,
+                    crc_errors,
+                }),
+                Ok(Some(message)) => warn!("unexpected message from the board: {:?}", message),
+                Ok(None) => {}
+                // Line noise or a board reset, the decoder resynchronises on the next message
+                Err(e) => warn!("serial link error: {:?}", e),
+            }
+        }
+
+        Ok(self.incoming.pop_front())
+    }
+
+    /// Returns the next channel status reported by the board, if any
+    ///
+    /// The statuses are collected by `receive`.
+    pub fn channel_status(&mut self) -> Option<ChannelStatus> {
+        self.status.pop_front()
+    }
+
+    /// Asks the board to transmit `frame` on `channel`
+    pub fn transmit(&mut self, channel: u8, frame: &[u8]) -> io::Result<()> {
+        self.send(&Message::Transmit { channel, frame })
+    }
+
+    /// Asks the board to listen on `channel`, once the frames already sent are transmitted
+    pub fn listen(&mut self, channel: u8) -> io::Result<()> {
+        self.send(&Message::Listen { channel })
+    }
+
+    fn send(&mut self, message: &Message) -> io::Result<()> {
+        let mut buf = [0; MAX_ENCODED_LEN];
+        let n = message
+            .encode(&mut buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))?;
+
+        self.port.write_all(&buf[..n])?;
+        self.port.flush()
+    }
+}
@@ -0,0 +1,320 @@
+//! Simulated gateway board
+//!
+//! Stands in for the serial port of a real board, with a few field nodes in range of the gateway.
+//! The nodes join the routing tree, send telemetry periodically and execute the commands sent to
+//! them, so that the whole bridge can be exercised without any hardware.
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Instant;
+
+use proto::command::{self, ErrorCode, Handler, Thresholds};
+use proto::link::{Address, Frame, Kind};
+use proto::route::{Packet, Received, Role, Router};
+use proto::serial::{Decoder, Message, MAX_ENCODED_LEN};
+use proto::telemetry::{Reading, SensorType, Telemetry, WATER_LEVEL_LOW};
+use proto::FIFO_SIZE;
+
+use board::READ_TIMEOUT;
+
+/// RSSI of every simulated link, in dBm
+const RSSI: i8 = -72;
+
+/// Initial reporting interval of the simulated nodes, in seconds
+const REPORT_INTERVAL: u16 = 30;
+
+/// Duration of the automatic irrigation, in milliseconds
+const AUTO_IRRIGATION: u64 = 60_000;
+
+/// Route timeout of the simulated nodes, in seconds
+const ROUTE_TIMEOUT: u32 = 120;
+
+struct Node {
+    router: Router,
+    seq: u8,
+    report_interval: u16,
+    next_report: u64,
+    pump_until: Option<u64>,
+    thresholds: Thresholds,
+    humidity: i16,
+    now: u64,
+}
+
+impl Node {
+    fn new(address: Address) -> Self {
+        Node {
+            router: Router::new(address, Role::Leaf, ROUTE_TIMEOUT),
+            seq: 0,
+            report_interval: REPORT_INTERVAL,
+            // Spread the first reports
+            next_report: 1000 * u64::from(address.0),
+            pump_until: None,
+            thresholds: Thresholds {
+                humidity_on: 3000,
+                humidity_off: 4500,
+                temperature_min: 500,
+            },
+            humidity: 4000,
+            now: 0,
+        }
+    }
+
+    // Daily cycles are 10 minutes long
+    fn phase(&self) -> f64 {
+        self.now as f64 / 600_000.0 * 2.0 * PI + f64::from(self.router.address().0)
+    }
+
+    fn temperature(&self) -> i16 {
+        1500 + (1000.0 * self.phase().sin()) as i16
+    }
+
+    fn telemetry(&self) -> Telemetry {
+        let battery_mv = 3300_u16.saturating_sub((self.now / 3_600_000) as u16);
+        let mut telemetry = Telemetry::new(battery_mv, Some(RSSI));
+
+        let readings = [
+            (SensorType::Temperature, self.temperature()),
+            (SensorType::Humidity, self.humidity),
+            (SensorType::Light, 500 + (400.0 * self.phase().sin()) as i16),
+            (SensorType::Pressure, 10132),
+            (SensorType::WaterLevel, WATER_LEVEL_LOW),
+        ];
+
+        for &(sensor, value) in &readings {
+            telemetry.push(Reading { sensor, value }).unwrap();
+        }
+
+        telemetry
+    }
+
+    // Advances the simulation to `now`, collecting the payloads to transmit in `out`
+    fn run(&mut self, now: u64, out: &mut Vec<(Kind, Vec<u8>)>) {
+        let elapsed = now - self.now;
+        self.now = now;
+        self.router.expire((now / 1000) as u32);
+
+        // Soil dries out slowly, and gets wet quickly while the pump is on
+        let watering = self.pump_until.map(|until| now < until).unwrap_or(false);
+        let delta = if watering {
+            elapsed / 100
+        } else {
+            elapsed / 10_000
+        } as i16;
+        self.humidity = if watering {
+            (self.humidity + delta).min(9000)
+        } else {
+            (self.humidity - delta).max(1000)
+        };
+
+        let thresholds = self.thresholds;
+        if watering && self.humidity > thresholds.humidity_off {
+            self.pump_until = None;
+        } else if !watering {
+            self.pump_until = None;
+
+            if self.humidity < thresholds.humidity_on
+                && self.temperature() >= thresholds.temperature_min
+            {
+                self.pump_until = Some(now + AUTO_IRRIGATION);
+            }
+        }
+
+        if now >= self.next_report {
+            self.next_report = now + 1000 * u64::from(self.report_interval);
+
+            let mut payload = [0; FIFO_SIZE];
+            let n = self.telemetry().encode(&mut payload).unwrap();
+            out.push((Kind::Telemetry, payload[..n].to_vec()));
+        }
+    }
+}
+
+impl Handler for Node {
+    fn start_irrigation(&mut self, duration_s: u16) -> Result<(), ErrorCode> {
+        if duration_s == 0 {
+            return Err(ErrorCode::InvalidArgument);
+        }
+
+        self.pump_until = Some(self.now + 1000 * u64::from(duration_s));
+        Ok(())
+    }
+
+    fn stop_irrigation(&mut self) -> Result<(), ErrorCode> {
+        self.pump_until = None;
+        Ok(())
+    }
+
+    fn set_thresholds(&mut self, thresholds: Thresholds) -> Result<(), ErrorCode> {
+        if thresholds.humidity_on >= thresholds.humidity_off {
+            return Err(ErrorCode::InvalidArgument);
+        }
+
+        self.thresholds = thresholds;
+        Ok(())
+    }
+
+    fn request_report(&mut self) -> Result<(), ErrorCode> {
+        self.next_report = self.now;
+        Ok(())
+    }
+
+    fn reboot(&mut self) -> Result<(), ErrorCode> {
+        info!("simulated node {} reboots", self.router.address().0);
+        Ok(())
+    }
+
+    fn set_report_interval(&mut self, interval_s: u16) -> Result<(), ErrorCode> {
+        if interval_s == 0 {
+            return Err(ErrorCode::InvalidArgument);
+        }
+
+        self.report_interval = interval_s;
+        self.next_report = self.now + 1000 * u64::from(interval_s);
+        Ok(())
+    }
+}
+
+/// Simulated gateway board, a drop-in replacement for its serial port
+pub struct Simulator {
+    nodes: Vec<Node>,
+    start: Instant,
+    decoder: Decoder,
+    output: VecDeque<u8>,
+}
+
+impl Simulator {
+    /// Creates a board with `nodes` field nodes in range, at addresses `1..=nodes`
+    pub fn new(nodes: u8) -> Self {
+        Simulator {
+            nodes: (1..=nodes).map(|a| Node::new(Address(a))).collect(),
+            start: Instant::now(),
+            decoder: Decoder::new(),
+            output: VecDeque::new(),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        let elapsed = self.start.elapsed();
+        elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
+    }
+
+    fn run(&mut self) {
+        let now = self.now();
+
+        for i in 0..self.nodes.len() {
+            let mut out = Vec::new();
+            self.nodes[i].run(now, &mut out);
+
+            for (kind, payload) in out {
+                self.send(i, kind, &payload);
+            }
+        }
+    }
+
+    // Transmits a packet from node `i` to the gateway
+    fn send(&mut self, i: usize, kind: Kind, payload: &[u8]) {
+        let now = self.now();
+        let node = &mut self.nodes[i];
+        node.seq = node.seq.wrapping_add(1);
+
+        let packet = Packet::new(
+            node.router.address(),
+            Address::GATEWAY,
+            kind,
+            node.seq,
+            payload,
+        );
+
+        let mut frame = [0; FIFO_SIZE];
+        let len = node.router.encode(&packet, &mut frame).unwrap();
+
+        let message = Message::Received {
+            timestamp: now as u32,
+            rssi: Some(RSSI),
+            channel: 0,
+            frame: &frame[..len],
+        };
+
+        let mut buf = [0; MAX_ENCODED_LEN];
+        let n = message.encode(&mut buf).unwrap();
+        self.output.extend(&buf[..n]);
+    }
+
+    // Delivers a frame transmitted by the gateway to the nodes
+    fn transmit(&mut self, frame: &[u8]) {
+        let frame = match Frame::decode(frame) {
+            Ok(frame) => frame,
+            Err(e) => return warn!("simulator: invalid frame: {:?}", e),
+        };
+
+        let now = self.now();
+        let mut responses = Vec::new();
+
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            match node.router.receive(&frame, Some(RSSI), (now / 1000) as u32) {
+                Ok(Received::Local(ref packet)) if packet.kind == Kind::Command => {
+                    let response = command::dispatch(node, packet.seq, packet.payload);
+
+                    let mut payload = [0; command::RESPONSE_LEN];
+                    let n = response.encode(&mut payload).unwrap();
+                    responses.push((i, payload[..n].to_vec()));
+                }
+                Ok(_) => {}
+                Err(e) => warn!("simulator: node {}: {:?}", node.router.address().0, e),
+            }
+        }
+
+        for (i, payload) in responses {
+            self.send(i, Kind::Response, &payload);
+        }
+    }
+}
+
+impl Read for Simulator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.run();
+
+        if self.output.is_empty() {
+            thread::sleep(READ_TIMEOUT);
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "no data"));
+        }
+
+        let n = buf.len().min(self.output.len());
+        for (byte, out) in buf.iter_mut().zip(self.output.drain(..n)) {
+            *byte = out;
+        }
+
+        Ok(n)
+    }
+}
+
+impl Write for Simulator {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            let frame = match self.decoder.feed(byte) {
+                Ok(Some(Message::Transmit { frame, .. })) => frame.to_vec(),
+                // The simulated nodes hear the gateway on every channel
+                Ok(Some(Message::Listen { .. })) => continue,
+                Ok(Some(message)) => {
+                    warn!("simulator: unexpected message {:?}", message);
+                    continue;
+                }
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("simulator: serial link error: {:?}", e);
+                    continue;
+                }
+            };
+
+            self.transmit(&frame);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! Decoding of the captured frames
+
+use std::fmt::Write;
+use std::time::SystemTime;
+
+use proto::beacon::Beacon;
+use proto::command::{Command, Response};
+use proto::fragment::{Ack, Fragment};
+use proto::link::{Address, Frame, Kind};
+use proto::ota::Status;
+use proto::route::{Advert, Packet};
+use proto::telemetry::Telemetry;
+
+/// Frame received by the gateway board
+pub struct Capture {
+    /// Host time when the frame was read, for live captures
+    pub time: Option<SystemTime>,
+    /// Reception time, in milliseconds of the board clock
+    pub timestamp: u32,
+    /// RSSI of the frame in dBm, if known
+    pub rssi: Option<i8>,
+    /// Channel the frame was received on
+    pub channel: u8,
+    /// Radio frame
+    pub frame: Vec<u8>,
+}
+
+impl Capture {
+    /// Decodes the link-layer frame and the end-to-end packet it carries
+    pub fn decode(&self) -> Result<(Frame<'_>, Packet<'_>), String> {
+        let frame = Frame::decode(&self.frame).map_err(|e| format!("invalid frame: {:?}", e))?;
+        let packet = Packet::decode(&frame).map_err(|e| format!("invalid packet: {:?}", e))?;
+
+        Ok((frame, packet))
+    }
+
+    /// Returns `true` if the frame was sent by or to one of `nodes`, at any hop
+    ///
+    /// Every frame matches an empty list; broadcasts only match their sender.
+    pub fn matches(&self, nodes: &[u8]) -> bool {
+        if nodes.is_empty() {
+            return true;
+        }
+
+        let addresses = match self.decode() {
+            Ok((frame, packet)) => [frame.header.src, frame.header.dst, packet.src, packet.dst],
+            Err(_) => return false,
+        };
+
+        addresses.iter().any(|a| nodes.contains(&a.0))
+    }
+}
+
+/// Returns a short text description of the payload of `packet`
+pub fn describe(packet: &Packet) -> Result<String, String> {
+    let payload = packet.payload;
+    let mut s = String::new();
+
+    match packet.kind {
+        Kind::Telemetry => {
+            let telemetry = Telemetry::decode(payload).map_err(|e| format!("{:?}", e))?;
+            write!(s, "{}", telemetry).unwrap();
+        }
+        Kind::Command => {
+            let command = Command::decode(payload).map_err(|e| format!("{:?}", e))?;
+            write!(s, "{:?}", command).unwrap();
+        }
+        Kind::Response => {
+            let response = Response::decode(payload).map_err(|e| format!("{:?}", e))?;
+            write!(
+                s,
+                "command {:#04x}, seq {}: ",
+                response.command, response.seq
+            )
+            .unwrap();
+
+            match response.result {
+                Ok(()) => s.push_str("ok"),
+                Err(code) => write!(s, "{:?}", code).unwrap(),
+            }
+        }
+        Kind::Fragment => {
+            let fragment = Fragment::decode(payload).map_err(|e| format!("{:?}", e))?;
+            write!(
+                s,
+                "transfer {}, fragment {}/{}, {} bytes",
+                fragment.transfer,
+                fragment.index + 1,
+                fragment.count,
+                fragment.data.len()
+            )
+            .unwrap();
+        }
+        Kind::FragmentAck => {
+            let ack = Ack::decode(payload).map_err(|e| format!("{:?}", e))?;
+            let received = (0..ack.count).filter(|&i| ack.is_received(i)).count();
+            write!(
+                s,
+                "transfer {}, {}/{} fragments received",
+                ack.transfer, received, ack.count
+            )
+            .unwrap();
+        }
+        Kind::OtaStatus => {
+            let status = Status::decode(payload).map_err(|e| format!("{:?}", e))?;
+            write!(s, "{:?}, offset {}", status.state, status.offset).unwrap();
+
+            if let Some(error) = status.error {
+                write!(s, ", error {:?}", error).unwrap();
+            }
+        }
+        Kind::Beacon => {
+            let beacon = Beacon::decode(payload).map_err(|e| format!("{:?}", e))?;
+            write!(
+                s,
+                "time {}.{:03}, next in {} s, channels {:#04x}",
+                beacon.time, beacon.millis, beacon.interval, beacon.channels.0
+            )
+            .unwrap();
+        }
+        Kind::RouteAdvert => {
+            let advert = Advert::decode(payload).map_err(|e| format!("{:?}", e))?;
+            write!(s, "hops {}, parent {}", advert.hops, advert.parent.0).unwrap();
+        }
+        // Unwrapped by `Packet::decode`
+        Kind::Routed => {}
+    }
+
+    Ok(s)
+}
+
+/// Formats an address, `all` for the broadcast address
+pub fn address(address: Address) -> String {
+    if address == Address::BROADCAST {
+        "all".to_owned()
+    } else {
+        address.0.to_string()
+    }
+}
+
+/// Formats bytes as lowercase hexadecimal
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
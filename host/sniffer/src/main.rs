@@ -0,0 +1,249 @@
+//! Oxidane radio sniffer
+//!
+//! Decodes the frames forwarded by a gateway board, live from its serial port or from a capture
+//! of the serial stream, and prints them or exports them for offline analysis. See the README.
+
+extern crate clap;
+extern crate oxidane_board as board;
+extern crate oxidane_proto as proto;
+
+mod decode;
+mod output;
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::process;
+use std::time::SystemTime;
+
+use clap::{App, Arg, ArgMatches};
+use proto::serial::{Decoder, Message};
+
+use decode::Capture;
+use output::{Json, Output, Pcap, Text};
+
+fn main() {
+    let matches = App::new("oxidane-sniffer")
+        .about("Decodes the radio frames forwarded by an Oxidane gateway board")
+        .arg(
+            Arg::with_name("input")
+                .help("Serial port of the gateway board, or capture of its serial stream")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("node")
+                .long("node")
+                .short("n")
+                .value_name("ADDRESS")
+                .help("Only shows the frames sent by or to this node, may be repeated")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .short("f")
+                .value_name("FORMAT")
+                .help("Output format")
+                .possible_values(&["text", "json", "pcap"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .value_name("FILE")
+                .help("Writes the output to FILE instead of the standard output"),
+        )
+        .arg(
+            Arg::with_name("capture")
+                .long("capture")
+                .value_name("FILE")
+                .help("Also saves the raw serial stream to FILE, for later replay"),
+        )
+        .get_matches();
+
+    if let Err(e) = run(&matches) {
+        eprintln!("oxidane-sniffer: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches) -> io::Result<()> {
+    let nodes = match matches.values_of("node") {
+        Some(values) => values
+            .map(|n| {
+                n.parse().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("invalid node {:?}", n))
+                })
+            })
+            .collect::<io::Result<Vec<u8>>>()?,
+        None => Vec::new(),
+    };
+
+    let out: Box<dyn Write> = match matches.value_of("output") {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+
+    let mut output: Box<dyn Output> = match matches.value_of("format").unwrap() {
+        "json" => Box::new(Json::new(out)),
+        "pcap" => Box::new(Pcap::new(out)?),
+        _ => Box::new(Text::new(out)),
+    };
+
+    let mut capture = match matches.value_of("capture") {
+        Some(path) => Some(File::create(path)?),
+        None => None,
+    };
+
+    let path = matches.value_of("input").unwrap();
+    let live = File::open(path)?.metadata()?.file_type().is_char_device();
+
+    let mut input: Box<dyn Read> = if live {
+        Box::new(board::open(path)?)
+    } else {
+        Box::new(File::open(path)?)
+    };
+
+    let capture = capture.as_mut().map(|file| file as &mut dyn Write);
+
+    sniff(&mut *input, live, capture, &nodes, &mut *output)
+}
+
+/// Decodes the serial stream read from `input`, until the end of a capture file
+///
+/// The raw stream is also copied to `capture`, and the frames sent by or to one of `nodes` are
+/// written to `output`.
+fn sniff(
+    input: &mut dyn Read,
+    live: bool,
+    mut capture: Option<&mut dyn Write>,
+    nodes: &[u8],
+    output: &mut dyn Output,
+) -> io::Result<()> {
+    let mut decoder = Decoder::new();
+    let mut buf = [0; 256];
+
+    loop {
+        let n = match input.read(&mut buf) {
+            // End of the capture file
+            Ok(0) if !live => return Ok(()),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+
+        if let Some(ref mut capture) = capture {
+            capture.write_all(&buf[..n])?;
+        }
+
+        for &byte in &buf[..n] {
+            let capture = match decoder.feed(byte) {
+                Ok(Some(Message::Received {
+                    timestamp,
+                    rssi,
+                    channel,
+                    frame,
+                })) => Capture {
+                    time: if live { Some(SystemTime::now()) } else { None },
+                    timestamp,
+                    rssi,
+                    channel,
+                    frame: frame.to_vec(),
+                },
+                // Frames sent by the host in a capture of both directions, and channel statuses
+                Ok(Some(_)) | Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("oxidane-sniffer: serial link error: {:?}", e);
+                    continue;
+                }
+            };
+
+            if capture.matches(nodes) {
+                output.write(&capture)?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str;
+
+    use super::*;
+
+    /// Serial stream of a gateway board: a frame of every kind, plain and routed, a frame sent
+    /// by the host, line noise, an invalid payload and an invalid frame
+    const CAPTURE: &[u8] = include_bytes!("../fixtures/capture.bin");
+
+    // Replays the capture file through `output`
+    fn replay<O>(nodes: &[u8], mut output: O)
+    where
+        O: Output,
+    {
+        sniff(&mut &CAPTURE[..], false, None, nodes, &mut output).unwrap();
+    }
+
+    #[test]
+    fn text() {
+        let mut out = Vec::new();
+        replay(&[], Text::new(&mut out));
+
+        assert_eq!(
+            str::from_utf8(&out).unwrap(),
+            include_str!("../fixtures/capture.txt")
+        );
+    }
+
+    #[test]
+    fn json() {
+        let mut out = Vec::new();
+        replay(&[], Json::new(&mut out));
+
+        assert_eq!(
+            str::from_utf8(&out).unwrap(),
+            include_str!("../fixtures/capture.jsonl")
+        );
+    }
+
+    #[test]
+    fn node_filter() {
+        let mut out = Vec::new();
+        replay(&[7], Text::new(&mut out));
+
+        // Frames routed to or from node 7, through node 3
+        let expected: Vec<&str> = include_str!("../fixtures/capture.txt")
+            .lines()
+            .filter(|line| line.contains(" 7 "))
+            .collect();
+        assert_eq!(expected.len(), 3);
+        assert_eq!(
+            str::from_utf8(&out).unwrap().lines().collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn capture() {
+        let mut copy = Vec::new();
+        let mut out = Vec::new();
+
+        sniff(
+            &mut &CAPTURE[..],
+            false,
+            Some(&mut copy),
+            &[],
+            &mut Pcap::new(&mut out).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(copy, CAPTURE);
+
+        // Header, then one record per frame with the board time
+        assert_eq!(&out[20..24], &147_u32.to_le_bytes());
+        assert_eq!(&out[24..28], &1_u32.to_le_bytes());
+        assert_eq!(&out[28..32], &0_u32.to_le_bytes());
+    }
+}
@@ -0,0 +1,214 @@
+//! Output formats
+
+use std::io::{self, Write};
+use std::time::{Duration, UNIX_EPOCH};
+
+use proto::link::Kind;
+
+use decode::{self, Capture};
+
+/// pcap link type reserved for private use, see the README for the record layout
+const LINKTYPE_USER0: u32 = 147;
+
+/// Sink of the captured frames
+pub trait Output {
+    /// Writes a captured frame
+    fn write(&mut self, capture: &Capture) -> io::Result<()>;
+}
+
+/// Human readable output, one frame per line
+pub struct Text<W> {
+    out: W,
+}
+
+impl<W> Text<W>
+where
+    W: Write,
+{
+    /// Creates a new text output
+    pub fn new(out: W) -> Self {
+        Text { out }
+    }
+}
+
+impl<W> Output for Text<W>
+where
+    W: Write,
+{
+    fn write(&mut self, capture: &Capture) -> io::Result<()> {
+        write!(
+            self.out,
+            "{:>10.3} ch{} {:>4} dBm  ",
+            f64::from(capture.timestamp) / 1000.0,
+            capture.channel,
+            capture
+                .rssi
+                .map(|rssi| rssi.to_string())
+                .unwrap_or_else(|| "?".to_owned())
+        )?;
+
+        let (frame, packet) = match capture.decode() {
+            Ok(decoded) => decoded,
+            Err(e) => return writeln!(self.out, "{} [{}]", e, decode::hex(&capture.frame)),
+        };
+
+        write!(
+            self.out,
+            "{:>3} -> {:<3} #{:<3} ",
+            decode::address(packet.src),
+            decode::address(packet.dst),
+            packet.seq
+        )?;
+
+        if frame.header.kind == Kind::Routed {
+            write!(
+                self.out,
+                "(hop {} -> {}, ttl {}) ",
+                decode::address(frame.header.src),
+                decode::address(frame.header.dst),
+                packet.ttl
+            )?;
+        }
+
+        match decode::describe(&packet) {
+            Ok(description) => writeln!(self.out, "{:?}: {}", packet.kind, description),
+            Err(e) => writeln!(
+                self.out,
+                "{:?}: invalid payload: {} [{}]",
+                packet.kind,
+                e,
+                decode::hex(packet.payload)
+            ),
+        }
+    }
+}
+
+/// JSON lines output, one object per frame
+pub struct Json<W> {
+    out: W,
+}
+
+impl<W> Json<W>
+where
+    W: Write,
+{
+    /// Creates a new JSON lines output
+    pub fn new(out: W) -> Self {
+        Json { out }
+    }
+}
+
+impl<W> Output for Json<W>
+where
+    W: Write,
+{
+    fn write(&mut self, capture: &Capture) -> io::Result<()> {
+        let mut fields = Vec::new();
+
+        if let Some(time) = capture.time {
+            let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            fields.push(format!(
+                "\"time\":{}.{:06}",
+                time.as_secs(),
+                time.subsec_micros()
+            ));
+        }
+
+        fields.push(format!("\"timestamp\":{}", capture.timestamp));
+        fields.push(format!("\"channel\":{}", capture.channel));
+        fields.push(format!(
+            "\"rssi\":{}",
+            capture
+                .rssi
+                .map(|rssi| rssi.to_string())
+                .unwrap_or_else(|| "null".to_owned())
+        ));
+        fields.push(format!("\"frame\":\"{}\"", decode::hex(&capture.frame)));
+
+        match capture.decode() {
+            Ok((frame, packet)) => {
+                let header = frame.header;
+                fields.push(format!(
+                    "\"link\":{{\"kind\":\"{:?}\",\"src\":{},\"dst\":{},\"seq\":{}}}",
+                    header.kind, header.src.0, header.dst.0, header.seq
+                ));
+
+                if header.kind == Kind::Routed {
+                    fields.push(format!(
+                        "\"route\":{{\"kind\":\"{:?}\",\"src\":{},\"dst\":{},\"seq\":{},\"ttl\":{}}}",
+                        packet.kind, packet.src.0, packet.dst.0, packet.seq, packet.ttl
+                    ));
+                }
+
+                // Descriptions are made of names and numbers, nothing to escape
+                match decode::describe(&packet) {
+                    Ok(description) => fields.push(format!("\"payload\":\"{}\"", description)),
+                    Err(e) => fields.push(format!("\"error\":\"invalid payload: {}\"", e)),
+                }
+            }
+            Err(e) => fields.push(format!("\"error\":\"{}\"", e)),
+        }
+
+        writeln!(self.out, "{{{}}}", fields.join(","))
+    }
+}
+
+/// pcap capture file
+///
+/// Every record holds the body of the `proto::serial` `Received` message: board timestamp,
+/// RSSI, channel and radio frame.
+pub struct Pcap<W> {
+    out: W,
+}
+
+impl<W> Pcap<W>
+where
+    W: Write,
+{
+    /// Creates a new pcap output, writing the file header
+    pub fn new(mut out: W) -> io::Result<Self> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&0xA1B2_C3D4_u32.to_le_bytes());
+        header.extend_from_slice(&2_u16.to_le_bytes());
+        header.extend_from_slice(&4_u16.to_le_bytes());
+        header.extend_from_slice(&0_i32.to_le_bytes()); // GMT
+        header.extend_from_slice(&0_u32.to_le_bytes()); // timestamp accuracy
+        header.extend_from_slice(&65535_u32.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+
+        out.write_all(&header)?;
+
+        Ok(Pcap { out })
+    }
+}
+
+impl<W> Output for Pcap<W>
+where
+    W: Write,
+{
+    fn write(&mut self, capture: &Capture) -> io::Result<()> {
+        // Without the host time, fall back to the board clock
+        let time = match capture.time {
+            Some(time) => time.duration_since(UNIX_EPOCH).unwrap_or_default(),
+            None => Duration::from_millis(u64::from(capture.timestamp)),
+        };
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&capture.timestamp.to_be_bytes());
+        data.push(capture.rssi.unwrap_or(i8::MIN) as u8);
+        data.push(capture.channel);
+        data.extend_from_slice(&capture.frame);
+
+        let mut record = Vec::new();
+        record.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&time.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&data);
+
+        self.out.write_all(&record)?;
+
+        // Keep the file usable if the capture is interrupted
+        self.out.flush()
+    }
+}